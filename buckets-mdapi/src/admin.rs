@@ -0,0 +1,153 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Administrative RPCs for operators debugging a running server. These are
+//! only served when `admin.enabled` is set.
+
+use std::path::Path;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+use utils::schema::migrations;
+
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
+use crate::util::array_wrap;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GetConfigPayload {
+    pub request_id: Uuid,
+}
+
+impl HasRequestId for GetConfigPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for GetConfigPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![]
+    }
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<GetConfigPayload>, SerdeError> {
+    serde_json::from_value::<Vec<GetConfigPayload>>(value.clone())
+}
+
+/// Return the effective configuration of the server: the configuration file
+/// merged with the command line arguments and the defaults of every entry not
+/// given by either. Sensitive entries are redacted by the serialization of
+/// the configuration.
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn get_config_action(
+    msg_id: u32,
+    method: &str,
+    config: &Config,
+    _metrics: &RegisteredMetrics,
+    log: &Logger,
+    _payload: GetConfigPayload,
+    _conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    if !config.admin.enabled {
+        return Err(format!("Unsupported functon: {}", method));
+    }
+
+    debug!(log, "operation successful");
+
+    // The configuration contains no maps with non-string keys and so the
+    // conversion to JSON is not expected to fail.
+    let value =
+        serde_json::to_value(config).expect("failed to serialize Config");
+    let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+    let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+    Ok(msg)
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RepairVnodePayload {
+    pub vnode: u64,
+    pub request_id: Uuid,
+}
+
+impl HasRequestId for RepairVnodePayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for RepairVnodePayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
+/// The response to a `repairvnode` request.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RepairVnodeResponse {
+    pub vnode: u64,
+    /// The migrations applied to the vnode, in the order they were applied,
+    /// named by their file names without extension. Empty when the vnode
+    /// schema was already up to date.
+    pub applied: Vec<String>,
+}
+
+pub(crate) fn decode_repair_msg(
+    value: &Value,
+) -> Result<Vec<RepairVnodePayload>, SerdeError> {
+    serde_json::from_value::<Vec<RepairVnodePayload>>(value.clone())
+}
+
+/// Apply the `vnode` migrations that the schema of a vnode is missing, so that
+/// an operator can repair a single vnode whose schema has drifted without a
+/// schema-manager run over the whole shard.
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn repair_vnode_action(
+    msg_id: u32,
+    method: &str,
+    config: &Config,
+    _metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: RepairVnodePayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    if !config.admin.enabled {
+        return Err(format!("Unsupported functon: {}", method));
+    }
+
+    let migration_path = Path::new(&config.admin.migrations_dir).join("vnode");
+    let value = match migrations::repair_vnode_schema(
+        payload.vnode,
+        &migration_path,
+        conn,
+    ) {
+        Ok(applied) => {
+            debug!(log, "operation successful";
+                    "vnode" => payload.vnode,
+                    "applied" => applied.len());
+            let resp = RepairVnodeResponse {
+                vnode: payload.vnode,
+                applied,
+            };
+            serde_json::to_value(resp)
+                .expect("failed to serialize RepairVnodeResponse")
+        }
+        Err(e) => {
+            error!(log, "operation failed"; "error" => e.to_string());
+            BucketsMdapiError::postgres_error(e.to_string()).into_fast()
+        }
+    };
+
+    let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+    let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+    Ok(msg)
+}
@@ -0,0 +1,242 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Shedding of create requests while the database is falling behind.
+//!
+//! When writes arrive faster than the database can absorb them, the standbys
+//! fall behind the primary and requests wait longer to claim a connection.
+//! Admission control watches both signals and, while either exceeds its
+//! configured threshold, sheds new creates with an `OverloadedError` so that
+//! the primary is not pushed over. Reads, updates, and deletes are always
+//! admitted. The signals are sampled every `probe_interval_ms` by a background
+//! probe: the replication lag of the slowest standby as reported by
+//! `pg_stat_replication` on the primary, and the mean connection claim time
+//! over the last interval from the `connection_claim_times` histogram.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use prometheus::core::Collector;
+use prometheus::{Counter, HistogramVec};
+use slog::{debug, warn, Logger};
+
+use cueball::backend::Backend;
+use cueball::connection_pool::ConnectionPool;
+use cueball::resolver::Resolver;
+use utils::config::ConfigAdmission;
+
+use crate::connection::AgedConnection;
+use crate::metrics::RegisteredMetrics;
+
+const REPLICATION_LAG_SQL: &str =
+    "SELECT (COALESCE(MAX(EXTRACT(EPOCH FROM replay_lag)), 0) * 1000)::float8 \
+     FROM pg_stat_replication";
+
+/// Returns `true` if the RPC method creates objects or buckets and may be shed
+/// by admission control.
+pub fn is_create_method(method: &str) -> bool {
+    match method {
+        "createobject" | "createobjectbatch" | "copyobject"
+        | "createbucket" => true,
+        _ => false,
+    }
+}
+
+/// The most recent overload signals and the thresholds they are held to. The
+/// admission control is cheap to clone and all clones share the same signals.
+#[derive(Clone)]
+pub struct AdmissionControl {
+    enabled: bool,
+    max_replication_lag_ms: Option<u64>,
+    max_claim_ms: Option<u64>,
+    replication_lag_ms: Arc<AtomicU64>,
+    claim_ms: Arc<AtomicU64>,
+    shed: Counter,
+}
+
+impl AdmissionControl {
+    pub fn new(config: &ConfigAdmission, metrics: &RegisteredMetrics) -> Self {
+        AdmissionControl {
+            enabled: config.enabled,
+            max_replication_lag_ms: config.max_replication_lag_ms,
+            max_claim_ms: config.max_claim_ms,
+            replication_lag_ms: Arc::new(AtomicU64::new(0)),
+            claim_ms: Arc::new(AtomicU64::new(0)),
+            shed: metrics.admission_shed.clone(),
+        }
+    }
+
+    /// Record the latest replication lag and mean connection claim time, in
+    /// milliseconds.
+    pub fn observe(&self, replication_lag_ms: u64, claim_ms: u64) {
+        self.replication_lag_ms
+            .store(replication_lag_ms, Ordering::SeqCst);
+        self.claim_ms.store(claim_ms, Ordering::SeqCst);
+    }
+
+    /// Returns `false`, counting the request as shed, if the request for
+    /// `method` is a create and an overload signal exceeds its threshold.
+    pub fn admit(&self, method: &str) -> bool {
+        if !self.enabled || !is_create_method(method) {
+            return true;
+        }
+
+        let exceeds = |value: &AtomicU64, max: Option<u64>| {
+            max.map_or(false, |max| value.load(Ordering::SeqCst) > max)
+        };
+        if exceeds(&self.replication_lag_ms, self.max_replication_lag_ms)
+            || exceeds(&self.claim_ms, self.max_claim_ms)
+        {
+            self.shed.inc();
+            return false;
+        }
+        true
+    }
+}
+
+// The cumulative sum in seconds and count of connection claim times.
+fn claim_totals(claim_times: &HistogramVec) -> (f64, u64) {
+    claim_times
+        .collect()
+        .iter()
+        .flat_map(|family| family.get_metric())
+        .map(|metric| metric.get_histogram())
+        .fold((0.0, 0), |(sum, count), histogram| {
+            (
+                sum + histogram.get_sample_sum(),
+                count + histogram.get_sample_count(),
+            )
+        })
+}
+
+// The mean claim time in milliseconds between two samples of the claim totals.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+#[allow(clippy::cast_precision_loss)]
+fn mean_claim_ms(previous: (f64, u64), current: (f64, u64)) -> u64 {
+    let claims = current.1.saturating_sub(previous.1);
+    if claims == 0 {
+        return 0;
+    }
+    ((current.0 - previous.0) * 1000.0 / claims as f64).max(0.0) as u64
+}
+
+// The replication lag in milliseconds of the slowest standby of the primary.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn replication_lag_ms<R, F>(
+    pool: &ConnectionPool<AgedConnection, R, F>,
+) -> Result<u64, String>
+where
+    R: Resolver,
+    F: FnMut(&Backend) -> AgedConnection + Send + 'static,
+{
+    let mut conn = pool.claim().map_err(|e| e.to_string())?;
+    let rows = conn
+        .query(REPLICATION_LAG_SQL, &[])
+        .map_err(|e| e.to_string())?;
+    let lag: f64 = rows.first().map_or(0.0, |row| row.get(0));
+    Ok(lag.max(0.0) as u64)
+}
+
+/// Probe the replication lag and the connection claim time every
+/// `admission.probe_interval_ms` milliseconds until the process exits.
+pub fn run<R, F>(
+    pool: ConnectionPool<AgedConnection, R, F>,
+    admission: AdmissionControl,
+    config: ConfigAdmission,
+    metrics: RegisteredMetrics,
+    log: Logger,
+) where
+    R: Resolver,
+    F: FnMut(&Backend) -> AgedConnection + Send + 'static,
+{
+    let interval = Duration::from_millis(config.probe_interval_ms);
+    let mut previous = claim_totals(&metrics.connection_claim_times);
+    let mut lag_ms = 0;
+
+    loop {
+        thread::sleep(interval);
+
+        let current = claim_totals(&metrics.connection_claim_times);
+        let claim_ms = mean_claim_ms(previous, current);
+        previous = current;
+
+        // A failed probe keeps the last replication lag observed, so that a
+        // primary too overloaded to answer the probe does not clear the signal
+        // and the claim time is still observed
+        if config.max_replication_lag_ms.is_some() {
+            match replication_lag_ms(&pool) {
+                Ok(lag) => lag_ms = lag,
+                Err(e) => {
+                    warn!(log, "failed to probe replication lag";
+                        "error" => e,
+                        "last_replication_lag_ms" => lag_ms);
+                }
+            }
+        }
+
+        debug!(log, "admission probe";
+            "replication_lag_ms" => lag_ms,
+            "claim_ms" => claim_ms);
+        admission.observe(lag_ms, claim_ms);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use prometheus::Opts;
+
+    fn admission(config: &ConfigAdmission) -> AdmissionControl {
+        AdmissionControl {
+            enabled: config.enabled,
+            max_replication_lag_ms: config.max_replication_lag_ms,
+            max_claim_ms: config.max_claim_ms,
+            replication_lag_ms: Arc::new(AtomicU64::new(0)),
+            claim_ms: Arc::new(AtomicU64::new(0)),
+            shed: Counter::with_opts(Opts::new("test_shed", "test counter"))
+                .expect("failed to create counter"),
+        }
+    }
+
+    #[test]
+    fn creates_shed_under_lag() {
+        let admission = admission(&ConfigAdmission {
+            enabled: true,
+            max_replication_lag_ms: Some(1000),
+            max_claim_ms: Some(50),
+            ..Default::default()
+        });
+        assert!(admission.admit("createobject"));
+
+        admission.observe(5000, 0);
+        assert!(!admission.admit("createobject"));
+        assert!(!admission.admit("createbucket"));
+        assert!(admission.admit("getobject"));
+        assert!(admission.admit("deleteobject"));
+
+        admission.observe(0, 100);
+        assert!(!admission.admit("createobjectbatch"));
+
+        admission.observe(1000, 50);
+        assert!(admission.admit("createobject"));
+        assert_eq!(admission.shed.get(), 3.0);
+    }
+
+    #[test]
+    fn disabled_admits_everything() {
+        let admission = admission(&ConfigAdmission {
+            max_replication_lag_ms: Some(1),
+            ..Default::default()
+        });
+        admission.observe(5000, 5000);
+        assert!(admission.admit("createobject"));
+    }
+
+    #[test]
+    fn mean_claim_time_over_interval() {
+        assert_eq!(mean_claim_ms((1.0, 10), (1.5, 20)), 50);
+        assert_eq!(mean_claim_ms((1.0, 10), (1.0, 10)), 0);
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::{
+    BucketsMdapiError, BucketsMdapiInnerError, BucketsMdapiWrappedError,
+};
+
+/// The response envelope shared by the batch RPC functions. A batch in which
+/// some items fail still succeeds as a whole: the results of the items that
+/// succeeded are returned in `succeeded`, in request order, and each failed
+/// item is reported in `failed` by its index in the request along with the
+/// error that caused it to fail.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct BatchResponse<T> {
+    pub succeeded: Vec<T>,
+    pub failed: Vec<BatchFailure>,
+}
+
+/// A single failed item of a batch request.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct BatchFailure {
+    pub index: usize,
+    pub error: BucketsMdapiInnerError,
+}
+
+impl<T> BatchResponse<T> {
+    /// Build the response for a batch from the results of its items, which
+    /// must be given in the same order as the items of the request.
+    pub fn from_results<I>(results: I) -> Self
+    where
+        I: IntoIterator<Item = Result<T, BucketsMdapiError>>,
+    {
+        let mut succeeded = Vec::new();
+        let mut failed = Vec::new();
+
+        for (index, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(item) => succeeded.push(item),
+                Err(e) => failed.push(BatchFailure {
+                    index,
+                    error: BucketsMdapiWrappedError::new(e).error,
+                }),
+            }
+        }
+
+        Self { succeeded, failed }
+    }
+
+    pub fn is_partial(&self) -> bool {
+        !self.succeeded.is_empty() && !self.failed.is_empty()
+    }
+}
+
+impl<T: serde::Serialize> BatchResponse<T> {
+    pub fn into_value(self) -> Value {
+        // This conversion can only fail if the Serialize implementation of an
+        // item fails, and all response types are covered by roundtrip tests.
+        serde_json::to_value(self).expect("failed to serialize BatchResponse")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn batch_mixed_results() {
+        let results = vec![
+            Ok(String::from("a")),
+            Err(BucketsMdapiError::ObjectNotFound),
+            Ok(String::from("c")),
+            Err(BucketsMdapiError::PreconditionFailedError(
+                "if-match '\"x\"' didn't match etag 'y'".into(),
+            )),
+        ];
+
+        let response = BatchResponse::from_results(results);
+
+        assert!(response.is_partial());
+        assert_eq!(response.succeeded, vec!["a", "c"]);
+        assert_eq!(response.failed.len(), 2);
+        assert_eq!(response.failed[0].index, 1);
+        assert_eq!(response.failed[0].error.name, "ObjectNotFound");
+        assert_eq!(response.failed[1].index, 3);
+        assert_eq!(response.failed[1].error.name, "PreconditionFailedError");
+
+        assert_eq!(
+            response.into_value(),
+            json!({
+                "succeeded": [ "a", "c" ],
+                "failed": [
+                    {
+                        "index": 1,
+                        "error": {
+                            "name": "ObjectNotFound",
+                            "message": "requested object not found"
+                        }
+                    },
+                    {
+                        "index": 3,
+                        "error": {
+                            "name": "PreconditionFailedError",
+                            "message": "if-match '\"x\"' didn't match etag 'y'"
+                        }
+                    }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn batch_all_succeeded() {
+        let results: Vec<Result<u64, BucketsMdapiError>> =
+            vec![Ok(1), Ok(2), Ok(3)];
+
+        let response = BatchResponse::from_results(results);
+
+        assert!(!response.is_partial());
+        assert_eq!(response.succeeded, vec![1, 2, 3]);
+        assert!(response.failed.is_empty());
+    }
+
+    #[test]
+    fn batch_all_failed() {
+        let results: Vec<Result<u64, BucketsMdapiError>> = vec![
+            Err(BucketsMdapiError::BucketNotFound),
+            Err(BucketsMdapiError::postgres_error("boom".into())),
+        ];
+
+        let response = BatchResponse::from_results(results);
+
+        assert!(!response.is_partial());
+        assert!(response.succeeded.is_empty());
+        let indexes: Vec<usize> =
+            response.failed.iter().map(|f| f.index).collect();
+        assert_eq!(indexes, vec![0, 1]);
+        assert_eq!(response.failed[1].error.message, "boom");
+    }
+
+    #[test]
+    fn batch_roundtrip() {
+        let response = BatchResponse::from_results(vec![
+            Ok(String::from("a")),
+            Err(BucketsMdapiError::BucketNotFound),
+        ]);
+        let value = serde_json::to_value(&response).unwrap();
+        let decoded: BatchResponse<String> =
+            serde_json::from_value(value).unwrap();
+        assert_eq!(decoded, response);
+    }
+}
@@ -6,12 +6,14 @@ use serde_json::Value;
 use uuid::Uuid;
 
 use crate::error::BucketsMdapiError;
-use crate::types::{HasRequestId, RowSlice};
+use crate::types::{HasRequestId, HasVnodes, RowSlice};
 
+pub mod batch_counts;
 pub mod create;
 pub mod delete;
 pub mod get;
 pub mod list;
+pub mod object_count;
 
 type Timestamptz = chrono::DateTime<chrono::Utc>;
 
@@ -29,7 +31,38 @@ impl HasRequestId for GetBucketPayload {
     }
 }
 
-pub type DeleteBucketPayload = GetBucketPayload;
+impl HasVnodes for GetBucketPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DeleteBucketPayload {
+    pub owner: Uuid,
+    pub name: String,
+    pub vnode: u64,
+    pub request_id: Uuid,
+
+    /// When present, the bucket is only deleted if its maintained object
+    /// count is at or below this value. Otherwise a `BucketNotEmpty` error is
+    /// returned and the bucket is left in place. The count is only maintained
+    /// while bucket quotas are enabled, so the request is rejected otherwise.
+    #[serde(default)]
+    pub max_objects: Option<i64>,
+}
+
+impl HasRequestId for DeleteBucketPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for DeleteBucketPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct BucketResponse {
@@ -144,6 +177,24 @@ mod test {
         }
     }
 
+    impl Arbitrary for DeleteBucketPayload {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let owner = Uuid::new_v4();
+            let name = random::string(g, 32);
+            let vnode = u64::arbitrary(g);
+            let request_id = Uuid::new_v4();
+            let max_objects = Option::<i64>::arbitrary(g);
+
+            DeleteBucketPayload {
+                owner,
+                name,
+                vnode,
+                request_id,
+                max_objects,
+            }
+        }
+    }
+
     impl Arbitrary for BucketResponse {
         fn arbitrary<G: Gen>(g: &mut G) -> Self {
             let id = Uuid::new_v4();
@@ -176,6 +227,35 @@ mod test {
         }
     }
 
+    quickcheck! {
+        fn prop_delete_bucket_payload_roundtrip(msg: DeleteBucketPayload) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(delete_str) => {
+                    let decode_result: Result<DeleteBucketPayload, _> =
+                        serde_json::from_str(&delete_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_deletebucket_payload_from_json(json: GetBucketJson) -> bool {
+            // A request without max_objects deletes the bucket regardless of
+            // its object count
+            let decode_result: Result<DeleteBucketPayload, _> =
+                serde_json::from_value(json.0);
+            match decode_result {
+                Ok(payload) => payload.max_objects.is_none(),
+                Err(_) => false
+            }
+        }
+    }
+
     quickcheck! {
         fn prop_bucket_response_roundtrip(msg: BucketResponse) -> bool {
             match serde_json::to_string(&msg) {
@@ -0,0 +1,199 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Fetch the object counts of several buckets of an owner with a single Fast
+//! request.
+//!
+//! The `getbucketcounts` RPC counts the objects of each named bucket with a
+//! single query joining the bucket rows of the requested vnode to the objects
+//! of the same vnode, grouped by bucket. As for `getbucketobjectcount` the
+//! objects of a bucket are spread across every vnode, so each count covers
+//! only the objects stored in the requested vnode. Names of buckets that do
+//! not exist in the vnode are left out of the response.
+
+use std::collections::HashMap;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::sql;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
+use crate::util::{array_wrap, limit_constraint_error};
+
+/// The maximum number of buckets whose counts may be requested at once.
+pub const MAX_BUCKET_COUNTS: usize = 1024;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BucketCountsPayload {
+    pub owner: Uuid,
+    pub vnode: u64,
+    pub request_id: Uuid,
+    pub names: Vec<String>,
+}
+
+impl HasRequestId for BucketCountsPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for BucketCountsPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BucketCountsResponse {
+    /// The number of objects stored in the requested vnode of each requested
+    /// bucket that exists, keyed by bucket name.
+    pub counts: HashMap<String, i64>,
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<BucketCountsPayload>, SerdeError> {
+    serde_json::from_value::<Vec<BucketCountsPayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    _config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: BucketCountsPayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    let count = payload.names.len();
+    if count == 0 || count > MAX_BUCKET_COUNTS {
+        // Limit constraint violations are returned to as regular
+        // Fast messages to be handled by the calling application
+        let e = format!(
+            "the {} names option must contain between 1 and {} names. \
+             the request contained {}",
+            &method, MAX_BUCKET_COUNTS, count
+        );
+        let value = limit_constraint_error(e);
+        let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+        let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+        return Ok(msg);
+    }
+
+    // Make database request
+    do_counts(&payload, conn, metrics, log)
+        .and_then(|resp| {
+            // Handle the successful database response
+            debug!(log, "operation successful");
+            // The BucketCountsResponse type only contains a map with string
+            // keys, so the conversion to JSON is not expected to fail.
+            let value = serde_json::to_value(resp)
+                .expect("failed to serialize BucketCountsResponse");
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(value));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+        .or_else(|e| {
+            // Handle database error response
+            error!(log, "operation failed"; "error" => e.message());
+
+            // Database errors are returned to as regular Fast messages
+            // to be handled by the calling application
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+fn do_counts(
+    payload: &BucketCountsPayload,
+    mut conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<BucketCountsResponse, BucketsMdapiError> {
+    let sql = counts_sql(payload.vnode);
+
+    let rows = sql::query(
+        sql::Method::BucketCounts,
+        &mut conn,
+        sql.as_str(),
+        &[&payload.owner, &payload.names],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    let counts = rows
+        .iter()
+        .map(|row| (row.get("name"), row.get("count")))
+        .collect();
+    Ok(BucketCountsResponse { counts })
+}
+
+// A bucket without objects in the vnode is kept by the outer join and has a
+// count of zero, as count() skips the NULL ids of its unmatched row
+fn counts_sql(vnode: u64) -> String {
+    let vnode_str = vnode.to_string();
+    [
+        "SELECT b.name, count(o.id) AS count FROM manta_bucket_",
+        &vnode_str,
+        ".manta_bucket b LEFT JOIN manta_bucket_",
+        &vnode_str,
+        ".manta_bucket_object o \
+         ON o.owner = b.owner AND o.bucket_id = b.id \
+         WHERE b.owner = $1 AND b.name = ANY($2) \
+         GROUP BY b.id, b.name",
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use quickcheck_helpers::random;
+
+    impl Arbitrary for BucketCountsPayload {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let len = usize::arbitrary(g) % 8;
+            BucketCountsPayload {
+                owner: Uuid::new_v4(),
+                vnode: u64::arbitrary(g),
+                request_id: Uuid::new_v4(),
+                names: (0..len).map(|_| random::string(g, 32)).collect(),
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_bucket_counts_payload_roundtrip(
+            msg: BucketCountsPayload
+        ) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(counts_str) => {
+                    let decode_result: Result<BucketCountsPayload, _> =
+                        serde_json::from_str(&counts_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+}
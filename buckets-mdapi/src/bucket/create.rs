@@ -8,13 +8,14 @@ use uuid::Uuid;
 
 use cueball_postgres_connection::PostgresConnection;
 use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
 
 use crate::bucket::{bucket_already_exists, response, to_json, BucketResponse};
 use crate::error::BucketsMdapiError;
 use crate::metrics::RegisteredMetrics;
 use crate::sql;
-use crate::types::{HandlerResponse, HasRequestId};
-use crate::util::array_wrap;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
+use crate::util::{array_wrap, check_name_length};
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct CreateBucketPayload {
@@ -22,6 +23,11 @@ pub struct CreateBucketPayload {
     pub name: String,
     pub vnode: u64,
     pub request_id: Uuid,
+
+    /// An optional limit on the total number of bytes of object data the
+    /// bucket may hold. This is only enforced when bucket quotas are enabled.
+    #[serde(default)]
+    pub max_total_bytes: Option<i64>,
 }
 
 impl HasRequestId for CreateBucketPayload {
@@ -30,6 +36,12 @@ impl HasRequestId for CreateBucketPayload {
     }
 }
 
+impl HasVnodes for CreateBucketPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
 pub(crate) fn decode_msg(
     value: &Value,
 ) -> Result<Vec<CreateBucketPayload>, SerdeError> {
@@ -40,13 +52,15 @@ pub(crate) fn decode_msg(
 pub(crate) fn action(
     msg_id: u32,
     method: &str,
+    config: &Config,
     metrics: &RegisteredMetrics,
     log: &Logger,
     payload: CreateBucketPayload,
     conn: &mut PostgresConnection,
 ) -> Result<HandlerResponse, String> {
     // Make database request
-    do_create(method, &payload, conn, metrics, log)
+    check_name_length(&payload.name, config)
+        .and_then(|_| do_create(method, &payload, conn, metrics, log))
         .and_then(|maybe_resp| {
             // Handle the successful database response
             debug!(log, "operation successful");
@@ -62,15 +76,12 @@ pub(crate) fn action(
         })
         .or_else(|e| {
             // Handle database error response
-            error!(log, "operation failed"; "error" => &e);
+            error!(log, "operation failed"; "error" => e.message());
 
             // Database errors are returned to as regular Fast messages
             // to be handled by the calling application
-            let err = BucketsMdapiError::PostgresError(e);
-            let msg_data = FastMessageData::new(
-                method.into(),
-                array_wrap(err.into_fast()),
-            );
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
             let msg: HandlerResponse =
                 FastMessage::data(msg_id, msg_data).into();
             Ok(msg)
@@ -83,15 +94,20 @@ fn do_create(
     conn: &mut PostgresConnection,
     metrics: &RegisteredMetrics,
     log: &Logger,
-) -> Result<Option<BucketResponse>, String> {
-    let mut txn = (*conn).transaction().map_err(|e| e.to_string())?;
+) -> Result<Option<BucketResponse>, BucketsMdapiError> {
+    let mut txn = (*conn).transaction().map_err(BucketsMdapiError::from)?;
     let create_sql = create_sql(payload.vnode);
 
     sql::txn_query(
         sql::Method::BucketCreate,
         &mut txn,
         create_sql.as_str(),
-        &[&Uuid::new_v4(), &payload.owner, &payload.name],
+        &[
+            &Uuid::new_v4(),
+            &payload.owner,
+            &payload.name,
+            &payload.max_total_bytes,
+        ],
         metrics,
         log,
     )
@@ -99,8 +115,10 @@ fn do_create(
         txn.commit()?;
         Ok(rows)
     })
-    .map_err(|e| e.to_string())
-    .and_then(|rows| response(method, &rows))
+    .map_err(BucketsMdapiError::from)
+    .and_then(|rows| {
+        response(method, &rows).map_err(BucketsMdapiError::postgres_error)
+    })
 }
 
 fn create_sql(vnode: u64) -> String {
@@ -108,8 +126,8 @@ fn create_sql(vnode: u64) -> String {
         "INSERT INTO manta_bucket_",
         &vnode.to_string(),
         &".manta_bucket \
-          (id, owner, name) \
-          VALUES ($1, $2, $3) \
+          (id, owner, name, max_total_bytes) \
+          VALUES ($1, $2, $3, $4) \
           ON CONFLICT DO NOTHING \
           RETURNING id, owner, name, created",
     ]
@@ -154,12 +172,14 @@ mod test {
             let name = random::string(g, 32);
             let vnode = u64::arbitrary(g);
             let request_id = Uuid::new_v4();
+            let max_total_bytes = Option::<i64>::arbitrary(g);
 
             CreateBucketPayload {
                 owner,
                 name,
                 vnode,
                 request_id,
+                max_total_bytes,
             }
         }
     }
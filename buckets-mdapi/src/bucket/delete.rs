@@ -1,11 +1,14 @@
 // Copyright 2020 Joyent, Inc.
+// Copyright 2023 MNX Cloud, Inc.
 
+use postgres::Transaction;
 use serde_json::Error as SerdeError;
 use serde_json::Value;
 use slog::{debug, error, Logger};
 
 use cueball_postgres_connection::PostgresConnection;
 use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
 
 use crate::bucket::{bucket_not_found, DeleteBucketPayload};
 use crate::error::BucketsMdapiError;
@@ -24,13 +27,15 @@ pub(crate) fn decode_msg(
 pub(crate) fn action(
     msg_id: u32,
     method: &str,
+    config: &Config,
     metrics: &RegisteredMetrics,
     log: &Logger,
     payload: DeleteBucketPayload,
     conn: &mut PostgresConnection,
 ) -> Result<HandlerResponse, String> {
     // Make database request
-    do_delete(&payload, conn, metrics, log)
+    check_max_objects(&payload, config)
+        .and_then(|_| do_delete(&payload, conn, metrics, log))
         .and_then(|affected_rows| {
             // Handle the successful database response
             debug!(log, "operation successful");
@@ -53,16 +58,14 @@ pub(crate) fn action(
             Ok(msg)
         })
         .or_else(|e| {
-            // Handle database error response
-            error!(log, "operation failed"; "error" => &e);
-
-            // Database errors are returned to as regular Fast messages
-            // to be handled by the calling application
-            let err = BucketsMdapiError::PostgresError(e);
-            let msg_data = FastMessageData::new(
-                method.into(),
-                array_wrap(err.into_fast()),
-            );
+            if let BucketsMdapiError::PostgresError { .. } = &e {
+                error!(log, "operation failed"; "error" => e.message());
+            }
+
+            // Errors are returned to as regular Fast messages to be handled
+            // by the calling application
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
             let msg: HandlerResponse =
                 FastMessage::data(msg_id, msg_data).into();
             Ok(msg)
@@ -74,11 +77,15 @@ fn do_delete(
     conn: &mut PostgresConnection,
     metrics: &RegisteredMetrics,
     log: &Logger,
-) -> Result<u64, String> {
-    let mut txn = (*conn).transaction().map_err(|e| e.to_string())?;
+) -> Result<u64, BucketsMdapiError> {
+    let mut txn = (*conn).transaction().map_err(BucketsMdapiError::from)?;
     let move_sql = insert_delete_table_sql(payload.vnode);
     let delete_sql = delete_sql(payload.vnode);
 
+    if let Some(max_objects) = payload.max_objects {
+        check_object_count(&mut txn, payload, max_objects, metrics, log)?;
+    }
+
     sql::txn_execute(
         sql::Method::BucketDeleteMove,
         &mut txn,
@@ -101,7 +108,68 @@ fn do_delete(
         txn.commit()?;
         Ok(row_count)
     })
-    .map_err(|e| e.to_string())
+    .map_err(BucketsMdapiError::from)
+}
+
+// The maintained object count of a bucket only covers every object of the
+// bucket while quotas are enabled, as each object write must then name the
+// bucket's vnode, so a deletion conditional on it is refused otherwise.
+fn check_max_objects(
+    payload: &DeleteBucketPayload,
+    config: &Config,
+) -> Result<(), BucketsMdapiError> {
+    if payload.max_objects.is_some() && !config.quota.enabled {
+        Err(BucketsMdapiError::BadRequestError(
+            "max_objects requires bucket quotas to be enabled".to_string(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// Verify the maintained object count of the bucket is at most `max_objects`.
+// The bucket row is locked so that no object can be counted against it
+// between the check and the deletion. A bucket that does not exist passes the
+// check and is reported as not found by the deletion.
+fn check_object_count(
+    mut txn: &mut Transaction,
+    payload: &DeleteBucketPayload,
+    max_objects: i64,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<(), BucketsMdapiError> {
+    let rows = sql::txn_query(
+        sql::Method::BucketGet,
+        &mut txn,
+        object_count_sql(payload.vnode).as_str(),
+        &[&payload.owner, &payload.name],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    match rows.first().map(|row| row.get::<_, i64>("total_objects")) {
+        Some(total_objects) if total_objects > max_objects => {
+            Err(BucketsMdapiError::BucketNotEmpty(format!(
+                "bucket contains {} objects, more than the maximum of {} \
+                 allowed for deletion",
+                total_objects, max_objects
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn object_count_sql(vnode: u64) -> String {
+    [
+        "SELECT total_objects FROM manta_bucket_",
+        &vnode.to_string(),
+        &".manta_bucket \
+          WHERE owner = $1 \
+          AND name = $2 \
+          FOR UPDATE",
+    ]
+    .concat()
 }
 
 fn insert_delete_table_sql(vnode: u64) -> String {
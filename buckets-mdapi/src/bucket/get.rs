@@ -6,6 +6,7 @@ use slog::{debug, error, Logger};
 
 use cueball_postgres_connection::PostgresConnection;
 use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
 
 use crate::bucket::{
     bucket_not_found, response, to_json, BucketResponse, GetBucketPayload,
@@ -26,6 +27,7 @@ pub(crate) fn decode_msg(
 pub(crate) fn action(
     msg_id: u32,
     method: &str,
+    _config: &Config,
     metrics: &RegisteredMetrics,
     log: &Logger,
     payload: GetBucketPayload,
@@ -48,15 +50,12 @@ pub(crate) fn action(
         })
         .or_else(|e| {
             // Handle database error response
-            error!(log, "operation failed"; "error" => &e);
+            error!(log, "operation failed"; "error" => e.message());
 
             // Database errors are returned to as regular Fast messages
             // to be handled by the calling application
-            let err = BucketsMdapiError::PostgresError(e);
-            let msg_data = FastMessageData::new(
-                method.into(),
-                array_wrap(err.into_fast()),
-            );
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
             let msg: HandlerResponse =
                 FastMessage::data(msg_id, msg_data).into();
             Ok(msg)
@@ -69,7 +68,7 @@ fn do_get(
     mut conn: &mut PostgresConnection,
     metrics: &RegisteredMetrics,
     log: &Logger,
-) -> Result<Option<BucketResponse>, String> {
+) -> Result<Option<BucketResponse>, BucketsMdapiError> {
     let sql = get_sql(payload.vnode);
 
     sql::query(
@@ -80,8 +79,10 @@ fn do_get(
         metrics,
         log,
     )
-    .map_err(|e| e.to_string())
-    .and_then(|rows| response(method, &rows))
+    .map_err(BucketsMdapiError::from)
+    .and_then(|rows| {
+        response(method, &rows).map_err(BucketsMdapiError::postgres_error)
+    })
 }
 
 fn get_sql(vnode: u64) -> String {
@@ -1,5 +1,8 @@
 // Copyright 2020 Joyent, Inc.
 
+use std::marker::Sync;
+
+use postgres::types::ToSql;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
 use serde_json::Value;
@@ -8,12 +11,15 @@ use uuid::Uuid;
 
 use cueball_postgres_connection::PostgresConnection;
 use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
 
 use crate::bucket::{to_json, BucketResponse};
 use crate::error::BucketsMdapiError;
-use crate::metrics::RegisteredMetrics;
+use crate::metrics::{RegisteredMetrics, MAX_LIST_LIMIT};
 use crate::sql;
-use crate::types::{HandlerResponse, HasRequestId};
+use crate::types::{
+    HandlerResponse, HasRequestId, HasVnodes, ListCount, ListOrder, Timestamptz,
+};
 use crate::util::{array_wrap, limit_constraint_error};
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -24,6 +30,21 @@ pub struct ListBucketsPayload {
     pub limit: u64,
     pub marker: Option<String>,
     pub request_id: Uuid,
+
+    /// The creation time of the bucket named by the `marker`, which a listing
+    /// ordered by creation time continues after along with the marker.
+    #[serde(default)]
+    pub marker_created: Option<Timestamptz>,
+
+    /// Respond with a single `ListCount` message counting the buckets that
+    /// match the listing, regardless of its limit, instead of listing them.
+    #[serde(default)]
+    pub count_only: bool,
+
+    /// The order in which to list buckets, and in which the `marker`
+    /// continues the listing. The default is `name_asc`.
+    #[serde(default)]
+    pub order_by: ListOrder,
 }
 
 impl HasRequestId for ListBucketsPayload {
@@ -32,6 +53,12 @@ impl HasRequestId for ListBucketsPayload {
     }
 }
 
+impl HasVnodes for ListBucketsPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
 pub(crate) fn decode_msg(
     value: &Value,
 ) -> Result<Vec<ListBucketsPayload>, SerdeError> {
@@ -41,13 +68,16 @@ pub(crate) fn decode_msg(
 pub(crate) fn action(
     msg_id: u32,
     method: &str,
+    _config: &Config,
     metrics: &RegisteredMetrics,
     log: &Logger,
     payload: ListBucketsPayload,
     conn: &mut PostgresConnection,
 ) -> Result<HandlerResponse, String> {
-    // Make database request
-    if payload.limit > 0 && payload.limit <= 1024 {
+    // Make database request. The limit does not apply to a count
+    if payload.count_only
+        || (payload.limit > 0 && payload.limit <= MAX_LIST_LIMIT)
+    {
         do_list(msg_id, method, payload, conn, metrics, log)
             .and_then(|resp| {
                 // Handle the successful database response
@@ -56,14 +86,13 @@ pub(crate) fn action(
             })
             .or_else(|e| {
                 // Handle database error response
-                error!(log, "operation failed"; "error" => &e);
+                error!(log, "operation failed"; "error" => e.message());
 
                 // Database errors are returned to as regular Fast messages
                 // to be handled by the calling application
-                let err = BucketsMdapiError::PostgresError(e);
                 let msg_data = FastMessageData::new(
                     method.into(),
-                    array_wrap(err.into_fast()),
+                    array_wrap(e.into_fast()),
                 );
                 let msg: HandlerResponse =
                     FastMessage::data(msg_id, msg_data).into();
@@ -91,118 +120,121 @@ fn do_list(
     mut conn: &mut PostgresConnection,
     metrics: &RegisteredMetrics,
     log: &Logger,
-) -> Result<Vec<FastMessage>, String> {
-    let query_result = match (payload.marker, payload.prefix) {
-        (Some(marker), Some(prefix)) => {
-            let sql = list_sql_prefix_marker(payload.vnode, payload.limit);
-            let prefix = format!("{}%", prefix);
-            sql::query(
-                sql::Method::BucketList,
-                &mut conn,
-                sql.as_str(),
-                &[&payload.owner, &prefix, &marker],
-                metrics,
-                log,
-            )
-        }
-        (Some(marker), None) => {
-            let sql = list_sql_marker(payload.vnode, payload.limit);
-            sql::query(
-                sql::Method::BucketList,
-                &mut conn,
-                sql.as_str(),
-                &[&payload.owner, &marker],
-                metrics,
-                log,
-            )
-        }
-        (None, Some(prefix)) => {
-            let sql = list_sql_prefix(payload.vnode, payload.limit);
-            let prefix = format!("{}%", prefix);
-            sql::query(
-                sql::Method::BucketList,
-                &mut conn,
-                sql.as_str(),
-                &[&payload.owner, &prefix],
-                metrics,
-                log,
-            )
-        }
-        (None, None) => {
-            let sql = list_sql(payload.vnode, payload.limit);
-            sql::query(
-                sql::Method::BucketList,
-                &mut conn,
-                sql.as_str(),
-                &[&payload.owner],
-                metrics,
-                log,
-            )
-        }
-    };
+) -> Result<Vec<FastMessage>, BucketsMdapiError> {
+    let marker_created = payload
+        .order_by
+        .marker_created(payload.marker.is_some(), payload.marker_created)
+        .map_err(BucketsMdapiError::BadRequestError)?;
 
-    let mut msgs: Vec<FastMessage> = Vec::with_capacity(1024);
+    let prefix = payload.prefix.map(|prefix| format!("{}%", prefix));
+    let mut items: Vec<&(dyn ToSql + Sync)> = vec![&payload.owner];
+    if let Some(prefix) = &prefix {
+        items.push(prefix);
+    }
+    if let Some(marker) = &payload.marker {
+        items.push(marker);
+    }
+    if let Some(marker_created) = &marker_created {
+        items.push(marker_created);
+    }
+    let names =
+        names_sql(prefix.is_some(), payload.marker.is_some(), payload.order_by);
 
-    query_result.map_err(|e| e.to_string()).and_then(|rows| {
-        for row in &rows {
-            let resp = BucketResponse {
-                id: row.get("id"),
-                owner: row.get("owner"),
-                name: row.get("name"),
-                created: row.get("created"),
+    if payload.count_only {
+        let sql = count_sql(payload.vnode, &names);
+        return sql::query(
+            sql::Method::BucketCount,
+            &mut conn,
+            sql.as_str(),
+            &items,
+            metrics,
+            log,
+        )
+        .map_err(BucketsMdapiError::from)
+        .map(|rows| {
+            let count = ListCount {
+                count: rows.first().map_or(0, |row| row.get(0)),
             };
-
-            let value = to_json(resp);
+            let value = serde_json::to_value(count)
+                .expect("failed to serialize ListCount");
             let msg_data =
                 FastMessageData::new(method.into(), array_wrap(value));
-            let msg = FastMessage::data(msg_id, msg_data);
+            vec![FastMessage::data(msg_id, msg_data)]
+        });
+    }
 
-            msgs.push(msg);
-        }
-        Ok(msgs)
-    })
-}
+    let sql = list_sql(payload.vnode, &names, payload.order_by, payload.limit);
+    let query_result = sql::query(
+        sql::Method::BucketList,
+        &mut conn,
+        sql.as_str(),
+        &items,
+        metrics,
+        log,
+    );
 
-fn list_sql_prefix_marker(vnode: u64, limit: u64) -> String {
-    format!(
-        "SELECT id, owner, name, created
-        FROM manta_bucket_{}.manta_bucket
-        WHERE owner = $1 AND name like $2 AND name > $3
-        ORDER BY name ASC
-        LIMIT {}",
-        vnode, limit
-    )
+    let mut msgs: Vec<FastMessage> = Vec::with_capacity(1024);
+
+    query_result
+        .map_err(BucketsMdapiError::from)
+        .and_then(|rows| {
+            metrics.record_list_rows(method, rows.len(), payload.limit);
+            for row in &rows {
+                let resp = BucketResponse {
+                    id: row.get("id"),
+                    owner: row.get("owner"),
+                    name: row.get("name"),
+                    created: row.get("created"),
+                };
+
+                let value = to_json(resp);
+                let msg_data =
+                    FastMessageData::new(method.into(), array_wrap(value));
+                let msg = FastMessage::data(msg_id, msg_data);
+
+                msgs.push(msg);
+            }
+            Ok(msgs)
+        })
 }
 
-fn list_sql_prefix(vnode: u64, limit: u64) -> String {
-    format!(
-        "SELECT id, owner, name, created
-        FROM manta_bucket_{}.manta_bucket
-        WHERE owner = $1 AND name like $2
-        ORDER BY name ASC
-        LIMIT {}",
-        vnode, limit
-    )
+/// Build the conditions of a listing on the names of buckets, whose prefix and
+/// marker parameters follow the owner parameter. The marker continues the
+/// listing in the given order, and is followed by its creation time when the
+/// listing is ordered by creation time.
+fn names_sql(prefix: bool, marker: bool, order: ListOrder) -> String {
+    let mut sql = String::new();
+    if prefix {
+        sql.push_str(" AND name like $2");
+    }
+    if marker {
+        let param = if prefix { 3 } else { 2 };
+        sql.push_str(" AND ");
+        sql.push_str(&order.marker_sql(param));
+    }
+    sql
 }
-fn list_sql_marker(vnode: u64, limit: u64) -> String {
+
+fn list_sql(vnode: u64, names: &str, order: ListOrder, limit: u64) -> String {
     format!(
         "SELECT id, owner, name, created
         FROM manta_bucket_{}.manta_bucket
-        WHERE owner = $1 AND name > $2
-        ORDER BY name ASC
+        WHERE owner = $1{}
+        ORDER BY {}
         LIMIT {}",
-        vnode, limit
+        vnode,
+        names,
+        order.order_sql(),
+        limit
     )
 }
 
-fn list_sql(vnode: u64, limit: u64) -> String {
+fn count_sql(vnode: u64, names: &str) -> String {
     format!(
-        "SELECT id, owner, name, created
+        "SELECT count(*)
         FROM manta_bucket_{}.manta_bucket
-        WHERE owner = $1
-        ORDER BY name ASC
-        LIMIT {}",
-        vnode, limit
+        WHERE owner = $1{}",
+        vnode, names
     )
 }
 
@@ -210,6 +242,7 @@ fn list_sql(vnode: u64, limit: u64) -> String {
 mod test {
     use super::*;
 
+    use chrono::{TimeZone, Utc};
     use quickcheck::{quickcheck, Arbitrary, Gen};
     use quickcheck_helpers::random;
     use serde_json;
@@ -252,6 +285,13 @@ mod test {
             let limit = u64::arbitrary(g);
             let marker = Some(random::string(g, 32));
             let request_id = Uuid::new_v4();
+            let marker_created = if bool::arbitrary(g) {
+                Some(Utc.timestamp(i64::from(u32::arbitrary(g)), 0))
+            } else {
+                None
+            };
+            let count_only = bool::arbitrary(g);
+            let order_by = ListOrder::arbitrary(g);
 
             ListBucketsPayload {
                 owner,
@@ -260,10 +300,35 @@ mod test {
                 limit,
                 marker,
                 request_id,
+                marker_created,
+                count_only,
+                order_by,
             }
         }
     }
 
+    #[test]
+    fn listing_clauses() {
+        let names = names_sql(true, true, ListOrder::NameAsc);
+        let sql = list_sql(2, &names, ListOrder::NameAsc, 10);
+        assert!(
+            sql.contains("WHERE owner = $1 AND name like $2 AND name > $3\n")
+        );
+        assert!(sql.ends_with("LIMIT 10"));
+
+        let names = names_sql(false, true, ListOrder::NameAsc);
+        let sql = count_sql(2, &names);
+        assert!(sql.starts_with("SELECT count(*)"));
+        assert!(sql.ends_with("WHERE owner = $1 AND name > $2"));
+
+        let names = names_sql(false, true, ListOrder::CreatedDesc);
+        let sql = list_sql(2, &names, ListOrder::CreatedDesc, 10);
+        assert!(sql.contains(
+            "WHERE owner = $1 AND (created, name) < ($3::timestamptz, $2)\n"
+        ));
+        assert!(sql.contains("ORDER BY created DESC, name DESC\n"));
+    }
+
     quickcheck! {
         fn prop_list_bucket_payload_roundtrip(msg: ListBucketsPayload) -> bool {
             match serde_json::to_string(&msg) {
@@ -0,0 +1,199 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Count the objects of a single bucket in one vnode.
+//!
+//! The `getbucketobjectcount` RPC counts the rows of the object table of the
+//! requested vnode that belong to the bucket, for enforcing per-bucket object
+//! limits. Unlike the count maintained on the bucket row and returned by
+//! `getbucketcounts`, the count is taken from the objects themselves, so it
+//! includes objects created without a `bucket_vnode` or before the maintained
+//! count was added. The objects of a bucket are spread across every vnode, so
+//! the count covers only the objects stored in the requested vnode. A bucket
+//! with no objects in the vnode, including one that does not exist, has a
+//! count of zero.
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::sql;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
+use crate::util::array_wrap;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BucketObjectCountPayload {
+    pub owner: Uuid,
+    pub bucket_id: Uuid,
+    pub vnode: u64,
+    pub request_id: Uuid,
+}
+
+impl HasRequestId for BucketObjectCountPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for BucketObjectCountPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BucketObjectCountResponse {
+    pub bucket_id: Uuid,
+    /// The number of objects of the bucket stored in the requested vnode
+    pub count: i64,
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<BucketObjectCountPayload>, SerdeError> {
+    serde_json::from_value::<Vec<BucketObjectCountPayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    _config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: BucketObjectCountPayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    // Make database request
+    do_count(&payload, conn, metrics, log)
+        .and_then(|resp| {
+            // Handle the successful database response
+            debug!(log, "operation successful");
+            // The BucketObjectCountResponse type contains no maps, so the
+            // conversion to JSON is not expected to fail.
+            let value = serde_json::to_value(resp)
+                .expect("failed to serialize BucketObjectCountResponse");
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(value));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+        .or_else(|e| {
+            // Handle database error response
+            error!(log, "operation failed"; "error" => e.message());
+
+            // Database errors are returned to as regular Fast messages
+            // to be handled by the calling application
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+fn do_count(
+    payload: &BucketObjectCountPayload,
+    mut conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<BucketObjectCountResponse, BucketsMdapiError> {
+    let sql = count_sql(payload.vnode);
+
+    let rows = sql::query(
+        sql::Method::BucketObjectCount,
+        &mut conn,
+        sql.as_str(),
+        &[&payload.owner, &payload.bucket_id],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    // An aggregate without a GROUP BY always returns a single row
+    let count = rows.first().map_or(0, |row| row.get("count"));
+    Ok(BucketObjectCountResponse {
+        bucket_id: payload.bucket_id,
+        count,
+    })
+}
+
+fn count_sql(vnode: u64) -> String {
+    [
+        "SELECT count(*) AS count FROM manta_bucket_",
+        &vnode.to_string(),
+        &".manta_bucket_object WHERE owner = $1 AND bucket_id = $2",
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+
+    impl Arbitrary for BucketObjectCountPayload {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            BucketObjectCountPayload {
+                owner: Uuid::new_v4(),
+                bucket_id: Uuid::new_v4(),
+                vnode: u64::arbitrary(g),
+                request_id: Uuid::new_v4(),
+            }
+        }
+    }
+
+    impl Arbitrary for BucketObjectCountResponse {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            BucketObjectCountResponse {
+                bucket_id: Uuid::new_v4(),
+                count: i64::from(u32::arbitrary(g)),
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_bucket_object_count_payload_roundtrip(
+            msg: BucketObjectCountPayload
+        ) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(count_str) => {
+                    let decode_result: Result<BucketObjectCountPayload, _> =
+                        serde_json::from_str(&count_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_bucket_object_count_response_roundtrip(
+            msg: BucketObjectCountResponse
+        ) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(count_str) => {
+                    let decode_result: Result<BucketObjectCountResponse, _> =
+                        serde_json::from_str(&count_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+}
@@ -0,0 +1,510 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! An in-process cache of recently read object metadata.
+//!
+//! When `object_cache.enabled` is set, the objects returned by `getobject` are
+//! kept for up to `ttl_ms` milliseconds, and a later `getobject` for the same
+//! object on any connection is answered from the cache without claiming a
+//! database connection. The cache holds at most `capacity` objects and evicts
+//! the least recently used object to make room for another.
+//!
+//! Every `createobject`, `createobjectbatch`, `updateobject`,
+//! `batchupdateobjects`, `deleteobject`, `swapobjects`, `renameobject`, and
+//! `copyobject` request served by this process removes the objects it names
+//! from the cache once the request completes, before the response is sent. A
+//! read that began before such a write and completes after it is not cached, so
+//! the cache never holds an object older than a completed write. The objects a
+//! `deleteobjectsbytag` request deletes are not named by the request, so it
+//! removes every cached object of its bucket in its vnode instead.
+//! Writes served by other buckets-mdapi processes, and objects moved by the
+//! orphan scan, are only reflected once the cached object expires.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Error as IOError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use prometheus::Counter;
+use serde_json::Value;
+use slog::{debug, Logger};
+use uuid::Uuid;
+
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::{Config, ConfigObjectCache};
+
+use crate::metrics::RegisteredMetrics;
+use crate::object::{self, fold_name, ObjectResponse};
+use crate::util::array_wrap;
+
+/// Objects are cached by vnode, owner, bucket id, and folded name.
+pub type CacheKey = (u64, Uuid, Uuid, String);
+
+/// The objects of a bucket in one vnode, by vnode, owner, and bucket id.
+pub type CacheBucketKey = (u64, Uuid, Uuid);
+
+struct CacheEntry {
+    object: ObjectResponse,
+    cached_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// The keys of the cached objects ordered from least to most recently used
+    recency: BTreeMap<u64, CacheKey>,
+    uses: u64,
+    /// The number of invalidations so far, used to discard the results of
+    /// reads that overlapped an invalidation
+    generation: u64,
+}
+
+/// A cache of object metadata shared by every connection. The cache is cheap
+/// to clone and all clones share the same cached objects.
+#[derive(Clone)]
+pub struct ObjectCache {
+    enabled: bool,
+    capacity: usize,
+    ttl: Duration,
+    state: Arc<Mutex<CacheState>>,
+    hits: Counter,
+    misses: Counter,
+}
+
+impl ObjectCache {
+    pub fn new(
+        config: &ConfigObjectCache,
+        metrics: &RegisteredMetrics,
+    ) -> Self {
+        Self {
+            enabled: config.enabled && config.capacity > 0,
+            capacity: config.capacity,
+            ttl: Duration::from_millis(config.ttl_ms),
+            state: Arc::new(Mutex::new(CacheState::default())),
+            hits: metrics.object_cache_hits.clone(),
+            misses: metrics.object_cache_misses.clone(),
+        }
+    }
+
+    /// Serve `msg` with `handle`, answering a `getobject` request from the
+    /// cache when possible and keeping the cache consistent with the objects
+    /// changed by any other request. When the cache is disabled every request
+    /// is passed to `handle`.
+    pub fn serve<F>(
+        &self,
+        msg: &FastMessage,
+        config: &Config,
+        log: &Logger,
+        handle: F,
+    ) -> Result<Vec<FastMessage>, IOError>
+    where
+        F: FnOnce() -> Result<Vec<FastMessage>, IOError>,
+    {
+        if !self.enabled {
+            return handle();
+        }
+
+        let method = msg.data.m.name.as_str();
+        if method == "getobject" {
+            return self.serve_get(msg, config, log, handle);
+        }
+
+        let keys = mutated_keys(method, &msg.data.d, config);
+        let buckets = mutated_buckets(method, &msg.data.d);
+        let result = handle();
+        if !keys.is_empty() {
+            self.invalidate(&keys);
+        }
+        if !buckets.is_empty() {
+            self.invalidate_buckets(&buckets);
+        }
+        result
+    }
+
+    fn serve_get<F>(
+        &self,
+        msg: &FastMessage,
+        config: &Config,
+        log: &Logger,
+        handle: F,
+    ) -> Result<Vec<FastMessage>, IOError>
+    where
+        F: FnOnce() -> Result<Vec<FastMessage>, IOError>,
+    {
+        // Only whole objects are cached, so a request for properties stored
+        // outside of the object row is always served from the database
+        let payload = match object::get::decode_msg(&msg.data.d) {
+            Ok(mut payloads) if payloads.len() == 1 => payloads.remove(0),
+            _ => return handle(),
+        };
+        if payload.include_properties {
+            return handle();
+        }
+
+        let key = (
+            payload.vnode,
+            payload.owner,
+            payload.bucket_id,
+            fold_name(&payload.name, config),
+        );
+
+        if let Some(object) = self.get(&key) {
+            debug!(log, "object cache hit"; "name" => &key.3);
+            let value = match payload.conditions.check(Some(&object)) {
+                Ok(()) => serde_json::to_value(object)
+                    .expect("failed to serialize ObjectResponse"),
+                Err(e) => e.into_fast(),
+            };
+            let method = msg.data.m.name.as_str();
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(value));
+            return Ok(vec![FastMessage::data(msg.id, msg_data)]);
+        }
+
+        let generation = self.generation();
+        let result = handle();
+        let object = result
+            .as_ref()
+            .ok()
+            .and_then(|response| response.first())
+            .and_then(|response| response.data.d.get(0))
+            .and_then(|value| {
+                serde_json::from_value::<ObjectResponse>(value.clone()).ok()
+            });
+        if let Some(object) = object {
+            self.insert(key, object, generation);
+        }
+        result
+    }
+
+    /// Look up a cached object, counting the lookup as a hit or a miss.
+    pub fn get(&self, key: &CacheKey) -> Option<ObjectResponse> {
+        self.get_at(key, Instant::now())
+    }
+
+    fn get_at(&self, key: &CacheKey, now: Instant) -> Option<ObjectResponse> {
+        let mut state = self.state.lock().expect("object cache lock poisoned");
+        let state = &mut *state;
+
+        let fresh = match state.entries.get(key) {
+            Some(entry) => now.duration_since(entry.cached_at) < self.ttl,
+            None => {
+                self.misses.inc();
+                return None;
+            }
+        };
+
+        if !fresh {
+            if let Some(entry) = state.entries.remove(key) {
+                state.recency.remove(&entry.last_used);
+            }
+            self.misses.inc();
+            return None;
+        }
+
+        state.uses += 1;
+        let uses = state.uses;
+        let entry = state.entries.get_mut(key)?;
+        state.recency.remove(&entry.last_used);
+        state.recency.insert(uses, key.clone());
+        entry.last_used = uses;
+
+        self.hits.inc();
+        Some(entry.object.clone())
+    }
+
+    /// The current generation of the cache, to be passed to `insert` along
+    /// with an object read after it was taken.
+    pub fn generation(&self) -> u64 {
+        self.state
+            .lock()
+            .expect("object cache lock poisoned")
+            .generation
+    }
+
+    /// Cache an object read from the database. The object is discarded if any
+    /// object has been invalidated since `generation` was taken, as the read
+    /// may have raced with the write that caused the invalidation.
+    pub fn insert(
+        &self,
+        key: CacheKey,
+        object: ObjectResponse,
+        generation: u64,
+    ) {
+        self.insert_at(key, object, generation, Instant::now())
+    }
+
+    fn insert_at(
+        &self,
+        key: CacheKey,
+        object: ObjectResponse,
+        generation: u64,
+        now: Instant,
+    ) {
+        let mut state = self.state.lock().expect("object cache lock poisoned");
+        if state.generation != generation {
+            return;
+        }
+
+        state.uses += 1;
+        let uses = state.uses;
+        if let Some(previous) = state.entries.get(&key) {
+            let last_used = previous.last_used;
+            state.recency.remove(&last_used);
+        }
+        state.recency.insert(uses, key.clone());
+        state.entries.insert(
+            key,
+            CacheEntry {
+                object,
+                cached_at: now,
+                last_used: uses,
+            },
+        );
+
+        while state.entries.len() > self.capacity {
+            let oldest = match state.recency.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(evicted) = state.recency.remove(&oldest) {
+                state.entries.remove(&evicted);
+            }
+        }
+    }
+
+    /// Remove the objects named by `keys` from the cache.
+    pub fn invalidate(&self, keys: &[CacheKey]) {
+        let mut state = self.state.lock().expect("object cache lock poisoned");
+        state.generation += 1;
+        for key in keys {
+            if let Some(entry) = state.entries.remove(key) {
+                state.recency.remove(&entry.last_used);
+            }
+        }
+    }
+
+    /// Remove every object of the buckets named by `buckets` from the cache.
+    pub fn invalidate_buckets(&self, buckets: &[CacheBucketKey]) {
+        let mut state = self.state.lock().expect("object cache lock poisoned");
+        state.generation += 1;
+        let keys: Vec<CacheKey> = state
+            .entries
+            .keys()
+            .filter(|(vnode, owner, bucket_id, _)| {
+                buckets.contains(&(*vnode, *owner, *bucket_id))
+            })
+            .cloned()
+            .collect();
+        for key in &keys {
+            if let Some(entry) = state.entries.remove(key) {
+                state.recency.remove(&entry.last_used);
+            }
+        }
+    }
+}
+
+// The cache keys of the objects changed by a request for `method`. Requests
+// that do not change objects, and requests that cannot be decoded and so will
+// fail, change no objects.
+fn mutated_keys(method: &str, value: &Value, config: &Config) -> Vec<CacheKey> {
+    let key = |vnode: u64, owner: Uuid, bucket_id: Uuid, name: &str| {
+        (vnode, owner, bucket_id, fold_name(name, config))
+    };
+
+    let keys = match method {
+        "createobject" | "createobjectbatch" => {
+            object::create::decode_msg(value).map(|payloads| {
+                payloads
+                    .iter()
+                    .map(|p| key(p.vnode, p.owner, p.bucket_id, &p.name))
+                    .collect()
+            })
+        }
+        "updateobject" => object::update::decode_msg(value).map(|payloads| {
+            payloads
+                .iter()
+                .map(|p| key(p.vnode, p.owner, p.bucket_id, &p.name))
+                .collect()
+        }),
+        "deleteobject" => object::delete::decode_msg(value).map(|payloads| {
+            payloads
+                .iter()
+                .map(|p| key(p.vnode, p.owner, p.bucket_id, &p.name))
+                .collect()
+        }),
+        "batchupdateobjects" => {
+            object::batch_update::decode_msg(value).map(|payloads| {
+                payloads
+                    .iter()
+                    .flat_map(|p| {
+                        p.updates.iter().map(move |update| {
+                            key(p.vnode, p.owner, p.bucket_id, &update.name)
+                        })
+                    })
+                    .collect()
+            })
+        }
+        "swapobjects" => object::swap::decode_msg(value).map(|payloads| {
+            payloads
+                .iter()
+                .flat_map(|p| {
+                    vec![
+                        key(p.vnode, p.owner, p.bucket_id, &p.name_a),
+                        key(p.vnode, p.owner, p.bucket_id, &p.name_b),
+                    ]
+                })
+                .collect()
+        }),
+        "renameobject" => object::rename::decode_msg(value).map(|payloads| {
+            payloads
+                .iter()
+                .flat_map(|p| {
+                    vec![
+                        key(p.vnode, p.owner, p.bucket_id, &p.old_name),
+                        key(p.vnode, p.owner, p.bucket_id, &p.new_name),
+                    ]
+                })
+                .collect()
+        }),
+        "copyobject" => object::copy::decode_msg(value).map(|payloads| {
+            payloads
+                .iter()
+                .map(|p| key(p.vnode, p.owner, p.dest_bucket_id, &p.dest_name))
+                .collect()
+        }),
+        _ => Ok(vec![]),
+    };
+
+    keys.unwrap_or_default()
+}
+
+// The buckets whose objects may be changed by a request for `method` without
+// the request naming them.
+fn mutated_buckets(method: &str, value: &Value) -> Vec<CacheBucketKey> {
+    let buckets = match method {
+        "deleteobjectsbytag" => {
+            object::delete_by_tag::decode_msg(value).map(|payloads| {
+                payloads
+                    .iter()
+                    .map(|p| (p.vnode, p.owner, p.bucket_id))
+                    .collect()
+            })
+        }
+        _ => Ok(vec![]),
+    };
+
+    buckets.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use chrono::Utc;
+
+    fn test_cache(capacity: usize, ttl_ms: u64) -> ObjectCache {
+        ObjectCache {
+            enabled: true,
+            capacity,
+            ttl: Duration::from_millis(ttl_ms),
+            state: Arc::new(Mutex::new(CacheState::default())),
+            hits: Counter::new("hits", "hits").unwrap(),
+            misses: Counter::new("misses", "misses").unwrap(),
+        }
+    }
+
+    fn test_object(name: &str) -> (CacheKey, ObjectResponse) {
+        let object = ObjectResponse {
+            id: Uuid::new_v4(),
+            owner: Uuid::new_v4(),
+            bucket_id: Uuid::new_v4(),
+            name: name.into(),
+            created: Utc::now(),
+            modified: Utc::now(),
+            content_length: 5,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers: HashMap::new(),
+            sharks: vec![],
+            properties: None,
+            vnode: 1,
+            display_name: None,
+        };
+        let key = (1, object.owner, object.bucket_id, name.to_string());
+        (key, object)
+    }
+
+    #[test]
+    fn cache_hit_and_expiry() {
+        let cache = test_cache(10, 100);
+        let (key, object) = test_object("a");
+        let start = Instant::now();
+
+        assert_eq!(cache.get_at(&key, start), None);
+        cache.insert_at(key.clone(), object.clone(), 0, start);
+        assert_eq!(cache.get_at(&key, start), Some(object));
+
+        let expired_at = start + Duration::from_millis(100);
+        assert_eq!(cache.get_at(&key, expired_at), None);
+        assert_eq!(cache.hits.get(), 1.0);
+        assert_eq!(cache.misses.get(), 2.0);
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let cache = test_cache(2, 1000);
+        let (key_a, object_a) = test_object("a");
+        let (key_b, object_b) = test_object("b");
+        let (key_c, object_c) = test_object("c");
+        let now = Instant::now();
+
+        cache.insert_at(key_a.clone(), object_a, 0, now);
+        cache.insert_at(key_b.clone(), object_b, 0, now);
+        assert!(cache.get_at(&key_a, now).is_some());
+        cache.insert_at(key_c.clone(), object_c, 0, now);
+
+        assert!(cache.get_at(&key_a, now).is_some());
+        assert!(cache.get_at(&key_b, now).is_none());
+        assert!(cache.get_at(&key_c, now).is_some());
+    }
+
+    #[test]
+    fn cache_discards_reads_overlapping_invalidation() {
+        let cache = test_cache(10, 1000);
+        let (key, object) = test_object("a");
+
+        let generation = cache.generation();
+        cache.invalidate(&[key.clone()]);
+        cache.insert(key.clone(), object.clone(), generation);
+        assert_eq!(cache.get(&key), None);
+
+        cache.insert(key.clone(), object, cache.generation());
+        assert!(cache.get(&key).is_some());
+        cache.invalidate(&[key.clone()]);
+        assert_eq!(cache.get(&key), None);
+    }
+
+    #[test]
+    fn cache_invalidates_buckets() {
+        let cache = test_cache(10, 1000);
+        let (key_a, object_a) = test_object("a");
+        let (key_other, object_other) = test_object("other");
+        let key_b = (key_a.0, key_a.1, key_a.2, "b".to_string());
+        let object_b = ObjectResponse {
+            name: "b".into(),
+            ..object_a.clone()
+        };
+
+        cache.insert(key_a.clone(), object_a, cache.generation());
+        cache.insert(key_b.clone(), object_b, cache.generation());
+        cache.insert(key_other.clone(), object_other, cache.generation());
+
+        cache.invalidate_buckets(&[(key_a.0, key_a.1, key_a.2)]);
+        assert_eq!(cache.get(&key_a), None);
+        assert_eq!(cache.get(&key_b), None);
+        assert!(cache.get(&key_other).is_some());
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Report the server's view of the current time so that clients can measure
+//! their clock skew.
+//!
+//! Object `created` and `modified` times are taken from the database clock, so
+//! a client evaluating `if-modified-since` or `if-unmodified-since` against its
+//! own clock may be misled by skew. The `gettime` RPC returns the database's
+//! `current_timestamp`, the clock used for those times, along with the clock
+//! of the buckets-mdapi process and the difference between the two.
+
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::sql;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes, Timestamptz};
+use crate::util::array_wrap;
+
+const TIME_SQL: &str = "SELECT current_timestamp AS now";
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GetTimePayload {
+    pub request_id: Uuid,
+}
+
+impl HasRequestId for GetTimePayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for GetTimePayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![]
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GetTimeResponse {
+    /// The current time of the database clock, which sets object `created`
+    /// and `modified` times
+    pub time: Timestamptz,
+    /// The current time of the buckets-mdapi process clock
+    pub app_time: Timestamptz,
+    /// The number of milliseconds the process clock is ahead of the database
+    /// clock, negative when it is behind
+    pub app_skew_ms: i64,
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<GetTimePayload>, SerdeError> {
+    serde_json::from_value::<Vec<GetTimePayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    _config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    _payload: GetTimePayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    // Make database request
+    do_get_time(conn, metrics, log)
+        .and_then(|resp| {
+            // Handle the successful database response
+            debug!(log, "operation successful");
+            // The GetTimeResponse type contains no maps, so the conversion to
+            // JSON is not expected to fail.
+            let value = serde_json::to_value(resp)
+                .expect("failed to serialize GetTimeResponse");
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(value));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+        .or_else(|e| {
+            // Handle database error response
+            error!(log, "operation failed"; "error" => e.message());
+
+            // Database errors are returned to as regular Fast messages
+            // to be handled by the calling application
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+fn do_get_time(
+    mut conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<GetTimeResponse, BucketsMdapiError> {
+    let rows =
+        sql::query(sql::Method::Time, &mut conn, TIME_SQL, &[], metrics, log)
+            .map_err(BucketsMdapiError::from)?;
+    let app_time = Utc::now();
+
+    let row = rows.first().ok_or_else(|| {
+        BucketsMdapiError::postgres_error(
+            "time query returned no rows".to_string(),
+        )
+    })?;
+    let time: Timestamptz = row.get("now");
+    Ok(GetTimeResponse {
+        time,
+        app_time,
+        app_skew_ms: (app_time - time).num_milliseconds(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::Duration;
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+
+    impl Arbitrary for GetTimeResponse {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let app_skew_ms = i64::from(i32::arbitrary(g));
+            let app_time = Utc::now();
+            GetTimeResponse {
+                time: app_time - Duration::milliseconds(app_skew_ms),
+                app_time,
+                app_skew_ms,
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_get_time_response_roundtrip(msg: GetTimeResponse) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(time_str) => {
+                    let decode_result: Result<GetTimeResponse, _> =
+                        serde_json::from_str(&time_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Compact responses for sparse objects.
+//!
+//! Object responses include every field, even those that are `null` or empty,
+//! such as the `properties` and `headers` of an object that has none. A
+//! `getobject`, `getobjects`, or `listobjects` request may set `compact` in its
+//! payload to have such fields left out of the messages of its response,
+//! which reduces the size of long listings of sparse objects. A field that is
+//! left out takes its empty value. Responses are complete unless compaction is
+//! requested.
+
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+use fast_rpc::protocol::FastMessage;
+
+/// The compaction optionally requested by the client of a request.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+struct CompactOption {
+    #[serde(default)]
+    compact: bool,
+}
+
+/// Returns `true` if the request for `method` asks for a compact response.
+pub fn requested(method: &str, data: &Value) -> bool {
+    match method {
+        "getobject" | "getobjects" | "listobjects" => data
+            .get(0)
+            .and_then(|payload| {
+                serde_json::from_value::<CompactOption>(payload.clone()).ok()
+            })
+            .map_or(false, |option| option.compact),
+        _ => false,
+    }
+}
+
+/// Remove the `null` and empty fields of each message of a response.
+pub fn compact_response(msgs: &mut [FastMessage]) {
+    for msg in msgs {
+        if let Value::Array(values) = &mut msg.data.d {
+            values.iter_mut().for_each(compact_value);
+        }
+    }
+}
+
+fn compact_value(value: &mut Value) {
+    if let Value::Object(fields) = value {
+        let empty: Vec<String> = fields
+            .iter()
+            .filter(|(_, field)| is_empty(field))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in empty {
+            let _ = fields.remove(&name);
+        }
+    }
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Array(values) => values.is_empty(),
+        Value::Object(fields) => fields.is_empty(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn compact_requested_for_object_reads() {
+        let data = json!([{ "name": "cat.jpg", "compact": true }]);
+        assert!(requested("getobject", &data));
+        assert!(requested("listobjects", &data));
+        assert!(!requested("createobject", &data));
+        assert!(!requested("getobject", &json!([{ "name": "cat.jpg" }])));
+    }
+
+    #[test]
+    fn empty_fields_removed() {
+        let mut value = json!({
+            "name": "cat.jpg",
+            "content_length": 0,
+            "content_type": "",
+            "headers": {},
+            "sharks": [],
+            "properties": null,
+            "display_name": null,
+            "moved_to_garbage": false
+        });
+        compact_value(&mut value);
+        assert_eq!(
+            value,
+            json!({
+                "name": "cat.jpg",
+                "content_length": 0,
+                "content_type": "",
+                "moved_to_garbage": false
+            })
+        );
+    }
+}
@@ -7,6 +7,9 @@ use postgres::types::ToSql;
 use postgres::Transaction;
 use serde_derive::{Deserialize, Serialize};
 use slog::{trace, Logger};
+use uuid::Uuid;
+
+use utils::config::Config;
 
 use crate::error::BucketsMdapiError;
 use crate::metrics;
@@ -22,11 +25,13 @@ pub struct Conditions {
     #[serde(alias = "if-none-match")]
     pub if_none_match: Option<Vec<String>>,
 
+    // The dates are parsed when the conditions are checked, so that a date
+    // that cannot be parsed is reported as a bad request
     #[serde(alias = "if-modified-since")]
-    pub if_modified_since: Option<types::Timestamptz>,
+    pub if_modified_since: Option<String>,
 
     #[serde(alias = "if-unmodified-since")]
-    pub if_unmodified_since: Option<types::Timestamptz>,
+    pub if_unmodified_since: Option<String>,
 }
 
 impl Conditions {
@@ -41,7 +46,23 @@ impl Conditions {
         &self,
         maybe_object: Option<&ObjectResponse>,
     ) -> Result<(), BucketsMdapiError> {
-        let object = match maybe_object {
+        self.check_version(
+            maybe_object.map(|object| (object.id, object.modified)),
+        )
+    }
+
+    /// Check the conditions against the `id`, which is the etag, and the
+    /// `modified` time of an object, or against a missing object when
+    /// `maybe_version` is `None`.
+    pub fn check_version(
+        &self,
+        maybe_version: Option<(Uuid, types::Timestamptz)>,
+    ) -> Result<(), BucketsMdapiError> {
+        let if_modified_since = parse_date(self.if_modified_since.as_ref())?;
+        let if_unmodified_since =
+            parse_date(self.if_unmodified_since.as_ref())?;
+
+        let (id, last_modified) = match maybe_version {
             None => {
                 if let Some(client_etags) = &self.if_match {
                     if check_if_match_wildcard(client_etags) {
@@ -60,11 +81,10 @@ impl Conditions {
 
                 return Err(BucketsMdapiError::ObjectNotFound);
             }
-            Some(object) => object,
+            Some(version) => version,
         };
 
-        let etag = object.id.to_string();
-        let last_modified = object.modified;
+        let etag = id.to_string();
 
         if let Some(client_etags) = &self.if_match {
             if !check_if_match(&etag, client_etags) {
@@ -76,7 +96,7 @@ impl Conditions {
             }
         }
 
-        if let Some(client_unmodified) = self.if_unmodified_since {
+        if let Some(client_unmodified) = if_unmodified_since {
             if last_modified > client_unmodified {
                 return Err(error(format!(
                     "object was modified at '{}'; if-unmodified-since '{}'",
@@ -87,7 +107,7 @@ impl Conditions {
         }
 
         if let Some(client_etags) = &self.if_none_match {
-            if check_if_match(&etag, client_etags) {
+            if check_if_none_match(&etag, client_etags) {
                 return Err(error(format!(
                     "if-none-match '{}' matched etag '{}'",
                     print_etags(&client_etags),
@@ -96,7 +116,7 @@ impl Conditions {
             }
         }
 
-        if let Some(client_modified) = self.if_modified_since {
+        if let Some(client_modified) = if_modified_since {
             if last_modified <= client_modified {
                 return Err(error(format!(
                     "object was modified at '{}'; if-modified-since '{}'",
@@ -110,6 +130,68 @@ impl Conditions {
     }
 }
 
+/// A comparison operator used by `ContentLengthCondition`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ComparisonOp {
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+}
+
+impl ComparisonOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            ComparisonOp::Lt => "lt",
+            ComparisonOp::Le => "le",
+            ComparisonOp::Eq => "eq",
+            ComparisonOp::Ge => "ge",
+            ComparisonOp::Gt => "gt",
+        }
+    }
+
+    fn compare(self, lhs: i64, rhs: i64) -> bool {
+        match self {
+            ComparisonOp::Lt => lhs < rhs,
+            ComparisonOp::Le => lhs <= rhs,
+            ComparisonOp::Eq => lhs == rhs,
+            ComparisonOp::Ge => lhs >= rhs,
+            ComparisonOp::Gt => lhs > rhs,
+        }
+    }
+}
+
+/// A condition on the `content_length` of an existing object, e.g.
+/// `{ "op": "lt", "value": 1000 }` is met only by objects smaller than 1000
+/// bytes.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ContentLengthCondition {
+    pub op: ComparisonOp,
+    pub value: i64,
+}
+
+impl ContentLengthCondition {
+    pub fn check(
+        &self,
+        maybe_object: Option<&ObjectResponse>,
+    ) -> Result<(), BucketsMdapiError> {
+        let object = maybe_object.ok_or(BucketsMdapiError::ObjectNotFound)?;
+
+        if self.op.compare(object.content_length, self.value) {
+            Ok(())
+        } else {
+            Err(error(format!(
+                "content_length {} is not {} {}",
+                object.content_length,
+                self.op.as_str(),
+                self.value
+            )))
+        }
+    }
+}
+
 fn error(msg: String) -> BucketsMdapiError {
     BucketsMdapiError::PreconditionFailedError(msg)
 }
@@ -119,6 +201,7 @@ pub fn request(
     items: &[&(dyn ToSql + Sync)],
     vnode: u64,
     conditions: &Conditions,
+    config: &Config,
     metrics: &metrics::RegisteredMetrics,
     log: &Logger,
 ) -> Result<(), BucketsMdapiError> {
@@ -127,6 +210,39 @@ pub fn request(
         return Ok(());
     }
 
+    get_object(&mut txn, items, vnode, config, metrics, log)
+        .and_then(|maybe_resp| conditions.check(maybe_resp.as_ref()))
+}
+
+pub fn request_content_length(
+    mut txn: &mut Transaction,
+    items: &[&(dyn ToSql + Sync)],
+    vnode: u64,
+    condition: Option<&ContentLengthCondition>,
+    config: &Config,
+    metrics: &metrics::RegisteredMetrics,
+    log: &Logger,
+) -> Result<(), BucketsMdapiError> {
+    let condition = match condition {
+        Some(condition) => condition,
+        None => {
+            trace!(log, "request has no content_length condition; returning");
+            return Ok(());
+        }
+    };
+
+    get_object(&mut txn, items, vnode, config, metrics, log)
+        .and_then(|maybe_resp| condition.check(maybe_resp.as_ref()))
+}
+
+fn get_object(
+    mut txn: &mut Transaction,
+    items: &[&(dyn ToSql + Sync)],
+    vnode: u64,
+    config: &Config,
+    metrics: &metrics::RegisteredMetrics,
+    log: &Logger,
+) -> Result<Option<ObjectResponse>, BucketsMdapiError> {
     sql::txn_query(
         sql::Method::ObjectGet,
         &mut txn,
@@ -135,23 +251,69 @@ pub fn request(
         metrics,
         log,
     )
-    .map_err(|e| BucketsMdapiError::PostgresError(e.to_string()))
-    .and_then(|rows| response("getobject", &rows))
-    .and_then(|maybe_resp| conditions.check(maybe_resp.as_ref()))
+    .map_err(BucketsMdapiError::from)
+    .and_then(|rows| response("getobject", vnode, &rows, config, metrics, log))
+}
+
+fn parse_date(
+    maybe_date: Option<&String>,
+) -> Result<Option<types::Timestamptz>, BucketsMdapiError> {
+    maybe_date
+        .map(|date| {
+            date.parse::<types::Timestamptz>().map_err(|_| {
+                BucketsMdapiError::BadRequestError(format!(
+                    "unable to parse '{}' as a valid date",
+                    date
+                ))
+            })
+        })
+        .transpose()
 }
 
 fn check_if_match_wildcard(client_etags: &[String]) -> bool {
     client_etags.iter().any(|x| x == "*")
 }
 
+/// Compare the etag of an object with the `if-match` etags of a request using
+/// the strong comparison of RFC 7232. The etag of an object is always strong,
+/// so a weak client etag never matches.
 fn check_if_match(etag: &str, client_etags: &[String]) -> bool {
-    for client_etag in client_etags {
-        if client_etag == "*" || etag == client_etag {
+    client_etags.iter().any(|client_etag| {
+        if client_etag == "*" {
             return true;
         }
-    }
+        let (weak, opaque_tag) = parse_etag(client_etag);
+        !weak && etag == opaque_tag
+    })
+}
 
-    false
+/// Compare the etag of an object with the `if-none-match` etags of a request
+/// using the weak comparison of RFC 7232, under which a weak client etag
+/// matches the same strong etag.
+fn check_if_none_match(etag: &str, client_etags: &[String]) -> bool {
+    client_etags.iter().any(|client_etag| {
+        client_etag == "*" || etag == parse_etag(client_etag).1
+    })
+}
+
+/// Split a client etag into whether it is weak and its opaque tag. An etag is
+/// weak when prefixed with `W/`, and the opaque tag may be quoted as in an
+/// HTTP header or bare as callers have historically sent it.
+fn parse_etag(client_etag: &str) -> (bool, &str) {
+    let (weak, tag) = if client_etag.starts_with("W/") {
+        (true, &client_etag[2..])
+    } else {
+        (false, client_etag)
+    };
+
+    let opaque_tag =
+        if tag.len() >= 2 && tag.starts_with('"') && tag.ends_with('"') {
+            &tag[1..tag.len() - 1]
+        } else {
+            tag
+        };
+
+    (weak, opaque_tag)
 }
 
 fn print_etags(etags: &[String]) -> String {
@@ -166,10 +328,32 @@ fn print_etags(etags: &[String]) -> String {
 mod tests {
     use super::*;
     use chrono;
-    use quickcheck::quickcheck;
+    use quickcheck::{quickcheck, Arbitrary, Gen};
     use serde_json::{json, Value};
     use uuid::Uuid;
 
+    impl Arbitrary for ComparisonOp {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let ops = [
+                ComparisonOp::Lt,
+                ComparisonOp::Le,
+                ComparisonOp::Eq,
+                ComparisonOp::Ge,
+                ComparisonOp::Gt,
+            ];
+            ops[usize::arbitrary(g) % ops.len()]
+        }
+    }
+
+    impl Arbitrary for ContentLengthCondition {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let op = ComparisonOp::arbitrary(g);
+            let value = i64::arbitrary(g);
+
+            ContentLengthCondition { op, value }
+        }
+    }
+
     fn conditions_from_value(v: Value) -> Conditions {
         serde_json::from_value::<Conditions>(v).unwrap()
     }
@@ -269,6 +453,33 @@ mod tests {
         }
     }
 
+    quickcheck! {
+        fn precon_check_if_match_quoted(res: ObjectResponse) -> () {
+            let h = conditions_from_value(json!({
+                "if-match": [ format!("\"{}\"", res.id) ],
+            }));
+
+            assert!(h.check(Some(&res)).is_ok());
+        }
+    }
+    quickcheck! {
+        fn precon_check_if_match_weak_fail(res: ObjectResponse) -> () {
+            let client_etag = format!("W/\"{}\"", res.id);
+
+            let h = conditions_from_value(json!({
+                "if-match": [ client_etag ],
+            }));
+
+            let check_res = h.check(Some(&res));
+
+            assert!(check_res.is_err());
+            assert_eq!(
+                check_res.unwrap_err().message(),
+                format!("if-match '\"{}\"' didn't match etag '{}'", client_etag, res.id),
+            );
+        }
+    }
+
     /*
      * if-none-match
      */
@@ -325,6 +536,45 @@ mod tests {
         }
     }
 
+    quickcheck! {
+        fn precon_check_if_none_match_quoted_fail(res: ObjectResponse) -> () {
+            let h = conditions_from_value(json!({
+                "if-none-match": [ format!("\"{}\"", res.id) ],
+            }));
+
+            assert!(h.check(Some(&res)).is_err());
+        }
+    }
+    quickcheck! {
+        fn precon_check_if_none_match_weak_fail(res: ObjectResponse) -> () {
+            let h = conditions_from_value(json!({
+                "if-none-match": [ format!("W/\"{}\"", res.id) ],
+            }));
+
+            assert!(h.check(Some(&res)).is_err());
+        }
+    }
+    quickcheck! {
+        fn precon_check_if_none_match_weak(res: ObjectResponse) -> () {
+            let client_etag = format!("W/\"{}\"", Uuid::new_v4());
+
+            let h = conditions_from_value(json!({
+                "if-none-match": [ client_etag ],
+            }));
+
+            assert!(h.check(Some(&res)).is_ok());
+        }
+    }
+
+    #[test]
+    fn precon_parse_etag() {
+        assert_eq!(parse_etag("abc"), (false, "abc"));
+        assert_eq!(parse_etag("\"abc\""), (false, "abc"));
+        assert_eq!(parse_etag("W/\"abc\""), (true, "abc"));
+        assert_eq!(parse_etag("W/abc"), (true, "abc"));
+        assert_eq!(parse_etag("\""), (false, "\""));
+    }
+
     /*
      * if-modified-since
      */
@@ -366,6 +616,24 @@ mod tests {
         }
     }
 
+    quickcheck! {
+        fn precon_check_if_modified_invalid(res: ObjectResponse) -> () {
+            let h = conditions_from_value(json!({
+                "if-modified-since": "invalid date",
+            }));
+
+            let check_res = h.check(Some(&res));
+
+            assert!(check_res.is_err());
+            let err = check_res.unwrap_err();
+            assert_eq!(
+                err.message(),
+                "unable to parse 'invalid date' as a valid date".to_string(),
+            );
+            assert_eq!(err.to_string(), "BadRequestError".to_string());
+        }
+    }
+
     /*
      * if-unmodified-since
      */
@@ -402,4 +670,110 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn precon_check_if_unmodified_invalid_missing_object() {
+        // The date is rejected even when there is no object to compare it with
+        let h = conditions_from_value(json!({
+            "if-unmodified-since": "2010-13-45",
+        }));
+
+        let err = h.check(None).unwrap_err();
+        assert_eq!(
+            err,
+            BucketsMdapiError::BadRequestError(
+                "unable to parse '2010-13-45' as a valid date".into()
+            )
+        );
+    }
+
+    /*
+     * if_content_length
+     */
+    #[test]
+    fn precon_content_length_from_json() {
+        let c = serde_json::from_value::<ContentLengthCondition>(json!({
+            "op": "lt",
+            "value": 1000,
+        }))
+        .unwrap();
+        assert_eq!(c.op, ComparisonOp::Lt);
+        assert_eq!(c.value, 1000);
+
+        let bad = serde_json::from_value::<ContentLengthCondition>(json!({
+            "op": "ne",
+            "value": 1000,
+        }));
+        assert!(bad.is_err());
+    }
+
+    #[test]
+    fn precon_content_length_missing_object() {
+        let c = ContentLengthCondition {
+            op: ComparisonOp::Lt,
+            value: 1000,
+        };
+        let err = c.check(None).unwrap_err();
+        assert_eq!(err.to_string(), "ObjectNotFound".to_string());
+    }
+
+    quickcheck! {
+        fn precon_check_content_length(res: ObjectResponse) -> () {
+            let len = res.content_length;
+            let passing = vec![
+                (ComparisonOp::Le, len),
+                (ComparisonOp::Eq, len),
+                (ComparisonOp::Ge, len),
+            ];
+
+            for (op, value) in passing {
+                let c = ContentLengthCondition { op, value };
+                assert!(c.check(Some(&res)).is_ok());
+            }
+
+            if len < i64::max_value() {
+                let c = ContentLengthCondition {
+                    op: ComparisonOp::Lt,
+                    value: len + 1,
+                };
+                assert!(c.check(Some(&res)).is_ok());
+            }
+
+            if len > i64::min_value() {
+                let c = ContentLengthCondition {
+                    op: ComparisonOp::Gt,
+                    value: len - 1,
+                };
+                assert!(c.check(Some(&res)).is_ok());
+            }
+        }
+    }
+    quickcheck! {
+        fn precon_check_content_length_fail(res: ObjectResponse) -> () {
+            let len = res.content_length;
+            let failing = vec![
+                (ComparisonOp::Lt, len),
+                (ComparisonOp::Gt, len),
+            ];
+
+            for (op, value) in failing {
+                let c = ContentLengthCondition { op, value };
+                let check_res = c.check(Some(&res));
+
+                assert!(check_res.is_err());
+                let err = check_res.unwrap_err();
+                assert_eq!(
+                    err.message(),
+                    format!(
+                        "content_length {} is not {} {}",
+                        len, op.as_str(), len,
+                    ),
+                );
+                assert_eq!(
+                    err.to_string(),
+                    "PreconditionFailedError".to_string(),
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,223 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+
+use cueball::backend::Backend;
+use cueball::connection::Connection;
+use cueball::error::Error as CueballError;
+use cueball_postgres_connection::{
+    PostgresConnection, PostgresConnectionConfig,
+};
+use slog::{debug, warn, Logger};
+
+use crate::metrics::RegisteredMetrics;
+use crate::sql::StatementCache;
+use crate::types::HandlerError;
+
+/// A pooled postgres connection that records when it was established.
+/// Long-lived connections accumulate server-side state, so a connection older
+/// than the configured maximum age is closed and reestablished when it is next
+/// claimed from the pool rather than resetting the whole pool. The statements
+/// prepared on the connection are cached alongside it, and forgotten whenever
+/// the connection is reestablished.
+pub struct AgedConnection {
+    conn: PostgresConnection,
+    established: Instant,
+    statement_timeout_ms: Option<u64>,
+    statements: StatementCache,
+}
+
+impl AgedConnection {
+    /// Wrap the connection creator of `PostgresConnection` for use with a
+    /// cueball connection pool. Each session is given the statement timeout
+    /// `statement_timeout_ms` when it is established.
+    pub fn connection_creator(
+        config: PostgresConnectionConfig,
+        statement_timeout_ms: Option<u64>,
+    ) -> impl FnMut(&Backend) -> AgedConnection + Send + 'static {
+        let mut create = PostgresConnection::connection_creator(config);
+        move |backend| AgedConnection {
+            conn: create(backend),
+            established: Instant::now(),
+            statement_timeout_ms,
+            statements: StatementCache::new(),
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        self.established.elapsed()
+    }
+
+    /// The cache of the statements prepared on this connection.
+    pub fn statements(&self) -> StatementCache {
+        self.statements.clone()
+    }
+}
+
+impl Connection for AgedConnection {
+    type Error = <PostgresConnection as Connection>::Error;
+
+    fn connect(&mut self) -> Result<(), Self::Error> {
+        // Statements prepared in a previous session do not exist in the new one
+        self.statements.clear();
+        self.conn.connect()?;
+        if let Some(ms) = self.statement_timeout_ms {
+            self.conn
+                .batch_execute(&format!("SET statement_timeout = {}", ms))?;
+        }
+        self.established = Instant::now();
+        Ok(())
+    }
+
+    fn is_valid(&mut self) -> bool {
+        self.conn.is_valid()
+    }
+
+    fn has_broken(&self) -> bool {
+        self.conn.has_broken()
+    }
+
+    fn close(&mut self) -> Result<(), Self::Error> {
+        self.conn.close()
+    }
+}
+
+impl Deref for AgedConnection {
+    type Target = PostgresConnection;
+
+    fn deref(&self) -> &PostgresConnection {
+        &self.conn
+    }
+}
+
+impl DerefMut for AgedConnection {
+    fn deref_mut(&mut self) -> &mut PostgresConnection {
+        &mut self.conn
+    }
+}
+
+/// Close and reestablish a claimed connection if it is older than
+/// `max_age_secs`. Connections are never recycled when `max_age_secs` is
+/// `None`.
+pub(crate) fn recycle_expired(
+    conn: &mut AgedConnection,
+    max_age_secs: Option<u64>,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<(), String> {
+    match max_age_secs {
+        Some(max_age) if conn.age() >= Duration::from_secs(max_age) => {
+            debug!(log, "recycling connection";
+                "age_secs" => conn.age().as_secs());
+            conn.close().map_err(|e| e.to_string())?;
+            conn.connect().map_err(|e| e.to_string())?;
+            metrics.connections_recycled.inc();
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Run `attempt`, which claims a connection for a request, retrying it up to
+/// `retries` times while it fails with a transient connection error. Only the
+/// acquisition of a connection is retried, before anything has been done on
+/// behalf of the request, so retrying is safe for every method.
+pub(crate) fn retry_transient<T, F>(
+    retries: u32,
+    log: &Logger,
+    mut attempt: F,
+) -> Result<T, HandlerError>
+where
+    F: FnMut() -> Result<T, HandlerError>,
+{
+    let mut retried = 0;
+    loop {
+        match attempt() {
+            Err(ref e) if retried < retries && is_transient(e) => {
+                retried += 1;
+                warn!(log, "retrying transient connection failure";
+                    "retry" => retried, "retries" => retries);
+            }
+            result => return result,
+        }
+    }
+}
+
+// A claim timeout with no connection available, or a claimed connection that
+// could not be reestablished, may succeed with a fresh claim
+fn is_transient(err: &HandlerError) -> bool {
+    match err {
+        HandlerError::Cueball(CueballError::ClaimFailure)
+        | HandlerError::Connection(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use slog::{o, Discard};
+
+    fn flaky_claims(
+        failures: u32,
+        retries: u32,
+        err: fn() -> HandlerError,
+    ) -> (Result<u32, HandlerError>, u32) {
+        let log = Logger::root(Discard, o!());
+        let mut attempts = 0;
+        let result = retry_transient(retries, &log, || {
+            attempts += 1;
+            if attempts <= failures {
+                Err(err())
+            } else {
+                Ok(attempts)
+            }
+        });
+        (result, attempts)
+    }
+
+    fn connection_lost() -> HandlerError {
+        HandlerError::Connection("connection reset by peer".into())
+    }
+
+    #[test]
+    fn transient_failure_resolved_on_retry() {
+        let (result, attempts) = flaky_claims(2, 3, connection_lost);
+        assert_eq!(result.ok(), Some(3));
+        assert_eq!(attempts, 3);
+
+        let (result, attempts) = flaky_claims(1, 1, || {
+            HandlerError::Cueball(CueballError::ClaimFailure)
+        });
+        assert_eq!(result.ok(), Some(2));
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn retries_exhausted() {
+        let (result, attempts) = flaky_claims(3, 2, connection_lost);
+        match result {
+            Err(HandlerError::Connection(_)) => (),
+            _ => panic!("expected the connection failure to be returned"),
+        }
+        assert_eq!(attempts, 3);
+
+        // No retries are attempted by default
+        let (result, attempts) = flaky_claims(1, 0, connection_lost);
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn other_failures_not_retried() {
+        let (result, attempts) =
+            flaky_claims(1, 3, || HandlerError::WriteLimit);
+        match result {
+            Err(HandlerError::WriteLimit) => (),
+            _ => panic!("expected the write limit error to be returned"),
+        }
+        assert_eq!(attempts, 1);
+    }
+}
@@ -1,6 +1,7 @@
 // Copyright 2020 Joyent, Inc.
 // Copyright 2023 MNX Cloud, Inc.
 
+use postgres::error::{Error as PGError, SqlState};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
 
@@ -11,8 +12,23 @@ pub enum BucketsMdapiError {
     ObjectNotFound,
     LimitConstraintError(String),
     PreconditionFailedError(String),
-    PostgresError(String),
+    /// A database error, with the SQLSTATE code reported by postgres when
+    /// there is one.
+    PostgresError {
+        code: Option<String>,
+        message: String,
+    },
     ContentMd5Error(String),
+    QuotaExceeded(String),
+    AuditDisabled,
+    InvalidVnode(String),
+    UnknownDatacenter(String),
+    BucketNotEmpty(String),
+    MetadataCorruption(String),
+    BatchAborted(String),
+    TimeoutError(String),
+    ObjectPinned(String),
+    BadRequestError(String),
 }
 
 impl ToString for BucketsMdapiError {
@@ -29,8 +45,22 @@ impl ToString for BucketsMdapiError {
             BucketsMdapiError::PreconditionFailedError(_) => {
                 "PreconditionFailedError".into()
             }
-            BucketsMdapiError::PostgresError(_) => "PostgresError".into(),
+            BucketsMdapiError::PostgresError { .. } => "PostgresError".into(),
             BucketsMdapiError::ContentMd5Error(_) => "ContentMd5Error".into(),
+            BucketsMdapiError::QuotaExceeded(_) => "QuotaExceeded".into(),
+            BucketsMdapiError::AuditDisabled => "AuditDisabled".into(),
+            BucketsMdapiError::InvalidVnode(_) => "InvalidVnode".into(),
+            BucketsMdapiError::UnknownDatacenter(_) => {
+                "UnknownDatacenter".into()
+            }
+            BucketsMdapiError::BucketNotEmpty(_) => "BucketNotEmpty".into(),
+            BucketsMdapiError::MetadataCorruption(_) => {
+                "MetadataCorruption".into()
+            }
+            BucketsMdapiError::BatchAborted(_) => "BatchAborted".into(),
+            BucketsMdapiError::TimeoutError(_) => "TimeoutError".into(),
+            BucketsMdapiError::ObjectPinned(_) => "ObjectPinned".into(),
+            BucketsMdapiError::BadRequestError(_) => "BadRequestError".into(),
         }
     }
 }
@@ -49,10 +79,43 @@ impl BucketsMdapiError {
             }
             BucketsMdapiError::LimitConstraintError(msg) => msg.to_string(),
             BucketsMdapiError::PreconditionFailedError(msg) => msg.to_string(),
-            BucketsMdapiError::PostgresError(msg) => msg.to_string(),
+            BucketsMdapiError::PostgresError { message, .. } => {
+                message.to_string()
+            }
             BucketsMdapiError::ContentMd5Error(msg) => {
                 format!("content_md5 is not valid base64 encoded data: {}", msg)
             }
+            BucketsMdapiError::QuotaExceeded(msg) => msg.to_string(),
+            BucketsMdapiError::AuditDisabled => {
+                "object auditing is not enabled".into()
+            }
+            BucketsMdapiError::InvalidVnode(msg) => msg.to_string(),
+            BucketsMdapiError::UnknownDatacenter(datacenter) => {
+                format!("shark datacenter '{}' is not known", datacenter)
+            }
+            BucketsMdapiError::BucketNotEmpty(msg) => msg.to_string(),
+            BucketsMdapiError::MetadataCorruption(msg) => msg.to_string(),
+            BucketsMdapiError::BatchAborted(msg) => msg.to_string(),
+            BucketsMdapiError::TimeoutError(msg) => msg.to_string(),
+            BucketsMdapiError::ObjectPinned(msg) => msg.to_string(),
+            BucketsMdapiError::BadRequestError(msg) => msg.to_string(),
+        }
+    }
+
+    /// A database error without a SQLSTATE code, such as for a query
+    /// returning unexpected results.
+    pub fn postgres_error(message: String) -> Self {
+        BucketsMdapiError::PostgresError {
+            code: None,
+            message,
+        }
+    }
+
+    /// The SQLSTATE code of a database error.
+    pub fn sql_state(&self) -> Option<&str> {
+        match self {
+            BucketsMdapiError::PostgresError { code, .. } => code.as_deref(),
+            _ => None,
         }
     }
 
@@ -74,6 +137,34 @@ impl BucketsMdapiError {
         serde_json::to_value(BucketsMdapiWrappedError::new(self))
             .expect("failed to encode BucketsMdapiError")
     }
+
+    /*
+     * Some older callers expect errors without the outer "error" property:
+     *
+     *     { "name": "...", "message": "..." }
+     *
+     * This is only used where a compatibility setting asks for that shape.
+     */
+    pub fn into_legacy_fast(self) -> Value {
+        serde_json::to_value(BucketsMdapiWrappedError::new(self).error)
+            .expect("failed to encode BucketsMdapiError")
+    }
+}
+
+/// A statement cancelled for exceeding the statement timeout is reported as a
+/// `TimeoutError`, and any other database error as a `PostgresError` carrying
+/// the SQLSTATE code of the error.
+impl From<PGError> for BucketsMdapiError {
+    fn from(e: PGError) -> Self {
+        if e.code() == Some(&SqlState::QUERY_CANCELED) {
+            BucketsMdapiError::TimeoutError(e.to_string())
+        } else {
+            BucketsMdapiError::PostgresError {
+                code: e.code().map(|code| code.code().to_string()),
+                message: e.to_string(),
+            }
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -85,6 +176,9 @@ pub struct BucketsMdapiWrappedError {
 pub struct BucketsMdapiInnerError {
     pub name: String,
     pub message: String,
+    /// The SQLSTATE code of a `PostgresError`, when postgres reported one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
 }
 
 impl BucketsMdapiWrappedError {
@@ -92,6 +186,7 @@ impl BucketsMdapiWrappedError {
         let inner = BucketsMdapiInnerError {
             name: error.to_string(),
             message: error.message(),
+            code: error.sql_state().map(String::from),
         };
         Self { error: inner }
     }
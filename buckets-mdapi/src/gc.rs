@@ -1,12 +1,43 @@
 // Copyright 2020 Joyent, Inc.
 
+use serde_derive::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::types::RowSlice;
+use utils::config::Config;
+
+use crate::types::{RowSlice, Timestamptz};
 
 pub mod delete;
 pub mod get;
 
+/// The number of garbage records returned by a `getgcbatch` request that does
+/// not give a `limit`. This matches the size of the batch captured by the
+/// `GARBAGE_BATCH` materialized view.
+pub(crate) const DEFAULT_BATCH_LIMIT: u64 = 1000;
+
+/// The largest `limit` a `getgcbatch` request may give, which bounds the size
+/// of the Fast response.
+pub(crate) const MAX_BATCH_LIMIT: u64 = 10000;
+
+/// The position of the last garbage record returned by a keyset scan of a
+/// vnode's deleted object table.
+///
+/// Records are scanned in `(deleted_at, id)` order. Passing the cursor from one
+/// `getgcbatch` response as the `after` value of the next request resumes the
+/// scan immediately following that record. Unlike the `GARBAGE_BATCH`
+/// materialized view, which must be refreshed (re-reading every vnode's deleted
+/// object table) whenever it is drained, the position in a keyset scan does
+/// not depend on any previously captured result set. Objects deleted while the
+/// garbage collector is paging are simply appended to the end of the scan
+/// order, and rows removed by a `deletegcbatch` call do not shift the position
+/// of the rows that remain, so no record is skipped or returned twice due to
+/// churn in the table.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GarbageCursor {
+    pub deleted_at: Timestamptz,
+    pub id: Uuid,
+}
+
 pub(crate) fn refresh_garbage_view_sql() -> &'static str {
     "REFRESH MATERIALIZED VIEW GARBAGE_BATCH"
 }
@@ -19,6 +50,48 @@ pub(crate) fn update_garbage_batch_id_sql() -> &'static str {
     "UPDATE garbage_batch_id SET batch_id = $1 WHERE id = 1"
 }
 
+pub(crate) fn keyset_garbage_sql(vnode: u64) -> String {
+    format!(
+        "SELECT id, owner, bucket_id, name, created, modified, \
+         content_length, content_md5, content_type, headers, sharks, \
+         properties, deleted_at \
+         FROM manta_bucket_{}.manta_bucket_deleted_object \
+         WHERE deleted_at <= now() - make_interval(secs => $2) \
+         ORDER BY deleted_at ASC, id ASC \
+         LIMIT $1",
+        vnode
+    )
+}
+
+pub(crate) fn keyset_garbage_after_sql(vnode: u64) -> String {
+    format!(
+        "SELECT id, owner, bucket_id, name, created, modified, \
+         content_length, content_md5, content_type, headers, sharks, \
+         properties, deleted_at \
+         FROM manta_bucket_{}.manta_bucket_deleted_object \
+         WHERE (deleted_at, id) > ($1, $2) \
+         AND deleted_at <= now() - make_interval(secs => $4) \
+         ORDER BY deleted_at ASC, id ASC \
+         LIMIT $3",
+        vnode
+    )
+}
+
+/// The grace period, in seconds, during which a newly deleted object record is
+/// withheld from the garbage collector.
+///
+/// A `getobject` request that read an object's metadata just before the object
+/// was overwritten or deleted may still be streaming the object's data from
+/// the storage nodes after the old row has been moved into the deleted object
+/// table. If the garbage collector were handed that row at once it could
+/// remove the data out from under the in-flight read. Note that a `createobject`
+/// that is rolled back cannot leave garbage behind: the move of the replaced
+/// row and the insert of the new one are made in the same transaction, so the
+/// rollback undoes both.
+pub(crate) fn grace_period_secs(config: &Config) -> f64 {
+    f64::from(config.gc.grace_period_seconds)
+}
+
 pub(crate) fn handle_batch_id_result(rows: &RowSlice) -> Result<Uuid, String> {
     if rows.len() == 1 {
         let batch_id = rows[0].get("batch_id");
@@ -9,17 +9,35 @@ use uuid::Uuid;
 use cueball_postgres_connection::PostgresConnection;
 use fast_rpc::protocol::{FastMessage, FastMessageData};
 use tokio_postgres::Error as PGError;
+use utils::config::Config;
 
+use crate::error::BucketsMdapiError;
 use crate::gc;
 use crate::metrics::RegisteredMetrics;
 use crate::sql;
-use crate::types::{HandlerResponse, HasRequestId};
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
 use crate::util::array_wrap;
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct DeleteGarbagePayload {
     pub request_id: Uuid,
-    pub batch_id: Uuid,
+
+    /// The id of the materialized view batch being deleted. This is required
+    /// when the materialized view is enabled.
+    pub batch_id: Option<Uuid>,
+
+    /// The vnode whose garbage records are being deleted. This is only used,
+    /// and is required, when the materialized view is disabled.
+    #[serde(default)]
+    pub vnode: Option<u64>,
+
+    /// The ids of the garbage records returned by a keyset scan of `vnode`
+    /// that have been processed and may be removed. When the materialized view
+    /// is enabled the ids are optional, and if given only those records of the
+    /// batch are removed, as when a batch was truncated by the `getgcbatch`
+    /// limit.
+    #[serde(default)]
+    pub ids: Vec<Uuid>,
 }
 
 impl HasRequestId for DeleteGarbagePayload {
@@ -28,6 +46,12 @@ impl HasRequestId for DeleteGarbagePayload {
     }
 }
 
+impl HasVnodes for DeleteGarbagePayload {
+    fn vnodes(&self) -> Vec<u64> {
+        self.vnode.into_iter().collect()
+    }
+}
+
 pub(crate) fn decode_msg(
     value: &Value,
 ) -> Result<Vec<DeleteGarbagePayload>, SerdeError> {
@@ -38,13 +62,20 @@ pub(crate) fn decode_msg(
 pub(crate) fn action(
     msg_id: u32,
     method: &str,
+    config: &Config,
     metrics: &RegisteredMetrics,
     log: &Logger,
     payload: DeleteGarbagePayload,
     conn: &mut PostgresConnection,
 ) -> Result<HandlerResponse, String> {
     // Make database request
-    do_delete(&payload, conn, metrics, log)
+    let delete_result = if config.gc.use_materialized_view {
+        do_delete(&payload, config, conn, metrics, log)
+    } else {
+        do_keyset_delete(&payload, config, conn, metrics, log)
+    };
+
+    delete_result
         .and_then(|_affected_rows| {
             // Handle the successful database response
             debug!(log, "{} operation was successful", &method);
@@ -63,12 +94,8 @@ pub(crate) fn action(
 
             // Database errors are returned to as regular Fast messages
             // to be handled by the calling application
-            let value = array_wrap(json!({
-                "error": {
-                    "name": "PostgresError",
-                    "message": e
-                }
-            }));
+            let value =
+                array_wrap(BucketsMdapiError::postgres_error(e).into_fast());
 
             let msg_data = FastMessageData::new(method.into(), value);
             let msg: HandlerResponse =
@@ -79,10 +106,16 @@ pub(crate) fn action(
 
 fn do_delete(
     payload: &DeleteGarbagePayload,
+    config: &Config,
     conn: &mut PostgresConnection,
     metrics: &RegisteredMetrics,
     log: &Logger,
 ) -> Result<(), String> {
+    let request_batch_id = payload.batch_id.ok_or_else(|| {
+        String::from("a batch_id is required to delete a garbage batch")
+    })?;
+
+    let grace_period = gc::grace_period_secs(config);
     let mut txn = (*conn).transaction().map_err(|e| e.to_string())?;
 
     // Read the current batch id
@@ -104,13 +137,13 @@ fn do_delete(
     // due to concurrent calls to the `deletegcbatch` RPC function. If the batch
     // ids do not match we still indicate success in the response, but do not
     // actually delete the current garbage batch.
-    if batch_id != payload.batch_id {
+    if batch_id != request_batch_id {
         debug!(
             log,
             "gc batch delete request with invalid batch id. current \
              batch id: {} request batch id: {}",
-            payload.batch_id,
-            batch_id
+            batch_id,
+            request_batch_id
         );
         return Ok(());
     }
@@ -133,6 +166,13 @@ fn do_delete(
             for row in garbage_rows {
                 let schema: String = row.get("schma");
                 let id: Uuid = row.get("id");
+
+                // Records of a truncated batch that the caller was not given
+                // are left in place to be returned by the refreshed view
+                if !payload.ids.is_empty() && !payload.ids.contains(&id) {
+                    continue;
+                }
+
                 let owner: Uuid = row.get("owner");
                 let bucket_id: Uuid = row.get("bucket_id");
                 let name: String = row.get("name");
@@ -143,7 +183,7 @@ fn do_delete(
                     sql::Method::GarbageRecordDelete,
                     &mut txn,
                     delete_stmt.as_str(),
-                    &[&owner, &bucket_id, &name, &id],
+                    &[&owner, &bucket_id, &name, &id, &grace_period],
                     metrics,
                     log,
                 )
@@ -182,6 +222,53 @@ fn do_delete(
     .map_err(|e| e.to_string())
 }
 
+fn do_keyset_delete(
+    payload: &DeleteGarbagePayload,
+    config: &Config,
+    mut conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<(), String> {
+    let vnode = payload.vnode.ok_or_else(|| {
+        String::from(
+            "a vnode is required to delete garbage when the materialized view \
+             is disabled",
+        )
+    })?;
+
+    // Only the records the caller has actually processed are removed. Any
+    // garbage created since the caller's keyset scan is left in place to be
+    // returned by a later scan.
+    if payload.ids.is_empty() {
+        return Ok(());
+    }
+
+    // Records still within the grace period are left in place even if the
+    // caller asks for them to be removed
+    let grace_period = gc::grace_period_secs(config);
+
+    sql::execute(
+        sql::Method::GarbageRecordDelete,
+        &mut conn,
+        keyset_delete_garbage_sql(vnode).as_str(),
+        &[&payload.ids, &grace_period],
+        metrics,
+        log,
+    )
+    .map(|_| ())
+    .map_err(|e| e.to_string())
+}
+
+fn keyset_delete_garbage_sql(vnode: u64) -> String {
+    [
+        "DELETE FROM manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_deleted_object WHERE id = ANY($1) \
+         AND deleted_at <= now() - make_interval(secs => $2)",
+    ]
+    .concat()
+}
+
 fn get_garbage_records_sql() -> &'static str {
     "SELECT * FROM GARBAGE_BATCH"
 }
@@ -191,7 +278,8 @@ fn delete_garbage_sql(schema: String) -> String {
         "DELETE FROM ",
         &schema,
         ".manta_bucket_deleted_object WHERE owner = $1 AND bucket_id = $2 AND \
-         name = $3 AND id = $4",
+         name = $3 AND id = $4 \
+         AND deleted_at <= now() - make_interval(secs => $5)",
     ]
     .concat()
 }
@@ -222,13 +310,17 @@ mod test {
     }
 
     impl Arbitrary for DeleteGarbagePayload {
-        fn arbitrary<G: Gen>(_g: &mut G) -> Self {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
             let request_id = Uuid::new_v4();
-            let batch_id = Uuid::new_v4();
+            let batch_id = Some(Uuid::new_v4());
+            let vnode = Option::<u64>::arbitrary(g);
+            let ids = vec![Uuid::new_v4(), Uuid::new_v4()];
 
             DeleteGarbagePayload {
                 batch_id,
                 request_id,
+                vnode,
+                ids,
             }
         }
     }
@@ -2,23 +2,46 @@
 
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
-use serde_json::{json, Value};
+use serde_json::Value;
 use slog::{debug, error, Logger};
 
 use cueball_postgres_connection::PostgresConnection;
 use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
 use uuid::Uuid;
 
-use crate::gc;
+use crate::error::BucketsMdapiError;
+use crate::gc::{self, GarbageCursor};
 use crate::metrics::RegisteredMetrics;
 use crate::object::ObjectResponse;
 use crate::sql;
-use crate::types::{HandlerResponse, HasRequestId, RowSlice};
-use crate::util::array_wrap;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes, RowSlice};
+use crate::util::{array_wrap, limit_constraint_error};
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct GetGarbagePayload {
     pub request_id: Uuid,
+
+    /// The vnode whose deleted object table should be scanned. This is only
+    /// used, and is required, when the materialized view is disabled.
+    #[serde(default)]
+    pub vnode: Option<u64>,
+
+    /// The cursor returned by the previous keyset scan of `vnode`. When absent
+    /// the scan starts at the oldest garbage record.
+    #[serde(default)]
+    pub after: Option<GarbageCursor>,
+
+    /// The largest number of garbage records to return, between 1 and
+    /// 10000. A batch read from the materialized view is truncated to this
+    /// many records, in which case only the `ids` of the records returned
+    /// should be given to `deletegcbatch`.
+    #[serde(default = "default_limit")]
+    pub limit: u64,
+}
+
+fn default_limit() -> u64 {
+    gc::DEFAULT_BATCH_LIMIT
 }
 
 impl HasRequestId for GetGarbagePayload {
@@ -27,10 +50,21 @@ impl HasRequestId for GetGarbagePayload {
     }
 }
 
+impl HasVnodes for GetGarbagePayload {
+    fn vnodes(&self) -> Vec<u64> {
+        self.vnode.into_iter().collect()
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct GetGarbageResponse {
     pub batch_id: Option<Uuid>,
     pub garbage: Vec<ObjectResponse>,
+
+    /// The position of the last record in `garbage` for a keyset scan. This is
+    /// always `None` when the garbage is read from the materialized view.
+    #[serde(default)]
+    pub cursor: Option<GarbageCursor>,
 }
 
 pub(self) fn to_json(gr: GetGarbageResponse) -> Value {
@@ -52,13 +86,36 @@ pub(crate) fn decode_msg(
 pub(crate) fn action(
     msg_id: u32,
     method: &str,
+    config: &Config,
     metrics: &RegisteredMetrics,
     log: &Logger,
-    _payload: GetGarbagePayload,
+    payload: GetGarbagePayload,
     conn: &mut PostgresConnection,
 ) -> Result<HandlerResponse, String> {
+    if payload.limit == 0 || payload.limit > gc::MAX_BATCH_LIMIT {
+        // Limit constraint violations are returned to as regular
+        // Fast messages to be handled by the calling application
+        let e = format!(
+            "the {} limit option must be a value between 1 \
+             and {}. the requested limit was {}",
+            &method,
+            gc::MAX_BATCH_LIMIT,
+            &payload.limit
+        );
+        let value = limit_constraint_error(e);
+        let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+        let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+        return Ok(msg);
+    }
+
     // Make database request
-    do_get(method, conn, metrics, log)
+    let get_result = if config.gc.use_materialized_view {
+        do_get(method, payload.limit, conn, metrics, log)
+    } else {
+        do_keyset_get(&payload, config, conn, metrics, log)
+    };
+
+    get_result
         .and_then(|resp| {
             // Handle the successful database response
             debug!(log, "{} operation was successful", &method);
@@ -76,101 +133,184 @@ pub(crate) fn action(
 
             // Database errors are returned to as regular Fast messages
             // to be handled by the calling application
-            let value = array_wrap(json!({
-                "name": "PostgresError",
-                "message": e
-            }));
+            let err = BucketsMdapiError::postgres_error(e);
+            let value = if config.gc.legacy_error_envelope {
+                err.into_legacy_fast()
+            } else {
+                err.into_fast()
+            };
 
-            let msg_data = FastMessageData::new(method.into(), value);
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(value));
             let msg: HandlerResponse =
                 FastMessage::data(msg_id, msg_data).into();
             Ok(msg)
         })
 }
 
+// The batch id identifies the contents of the whole materialized view, not
+// only the records returned. When the view holds more than `limit` records the
+// response is truncated and the remaining records are returned again by later
+// requests with the same batch id, until a `deletegcbatch` refreshes the view.
 fn do_get(
     method: &str,
+    limit: u64,
     conn: &mut PostgresConnection,
     metrics: &RegisteredMetrics,
     log: &Logger,
 ) -> Result<GetGarbageResponse, String> {
     let mut txn = (*conn).transaction().map_err(|e| e.to_string())?;
     let sql = get_sql();
+    let limit = limit as i64;
     let mut batch_id: Option<Uuid> = None;
 
-    sql::txn_query(sql::Method::GarbageGet, &mut txn, sql, &[], metrics, log)
-        .map_err(|e| e.to_string())
-        .and_then(|rows| {
-            if rows.is_empty() {
-                // Try to refresh the garbage view in case the view is stale
-                sql::txn_execute(
-                    sql::Method::GarbageRefresh,
+    sql::txn_query(
+        sql::Method::GarbageGet,
+        &mut txn,
+        sql,
+        &[&limit],
+        metrics,
+        log,
+    )
+    .map_err(|e| e.to_string())
+    .and_then(|rows| {
+        if rows.is_empty() {
+            // Try to refresh the garbage view in case the view is stale
+            sql::txn_execute(
+                sql::Method::GarbageRefresh,
+                &mut txn,
+                gc::refresh_garbage_view_sql(),
+                &[],
+                metrics,
+                log,
+            )
+            .map_err(|e| e.to_string())
+            .and_then(|_| {
+                // Update the batch id
+                batch_id = Some(Uuid::new_v4());
+                sql::txn_query(
+                    sql::Method::GarbageBatchIdUpdate,
                     &mut txn,
-                    gc::refresh_garbage_view_sql(),
-                    &[],
+                    gc::update_garbage_batch_id_sql(),
+                    &[&batch_id.unwrap()],
                     metrics,
                     log,
                 )
                 .map_err(|e| e.to_string())
-                .and_then(|_| {
-                    // Update the batch id
-                    batch_id = Some(Uuid::new_v4());
-                    sql::txn_query(
-                        sql::Method::GarbageBatchIdUpdate,
-                        &mut txn,
-                        gc::update_garbage_batch_id_sql(),
-                        &[&batch_id.unwrap()],
-                        metrics,
-                        log,
-                    )
-                    .map_err(|e| e.to_string())
-                })
-                .and_then(|_| {
-                    sql::txn_query(
-                        sql::Method::GarbageGet,
-                        &mut txn,
-                        sql,
-                        &[],
-                        metrics,
-                        log,
-                    )
-                    .map_err(|e| e.to_string())
-                })
-            } else {
-                // Read the current batch id
+            })
+            .and_then(|_| {
                 sql::txn_query(
-                    sql::Method::GarbageBatchIdGet,
+                    sql::Method::GarbageGet,
                     &mut txn,
-                    gc::get_garbage_batch_id_sql(),
-                    &[],
+                    sql,
+                    &[&limit],
                     metrics,
                     log,
                 )
                 .map_err(|e| e.to_string())
-                .and_then(|batch_id_rows| {
-                    gc::handle_batch_id_result(batch_id_rows.as_ref())
-                })
-                .and_then(|b_id| {
-                    batch_id = Some(b_id);
-                    Ok(rows)
-                })
-            }
-        })
-        .and_then(|rows| {
-            // All steps completed without error so commit the transaction
-            txn.commit().map_err(|e| e.to_string())?;
-            Ok(rows)
+            })
+        } else {
+            // Read the current batch id
+            sql::txn_query(
+                sql::Method::GarbageBatchIdGet,
+                &mut txn,
+                gc::get_garbage_batch_id_sql(),
+                &[],
+                metrics,
+                log,
+            )
+            .map_err(|e| e.to_string())
+            .and_then(|batch_id_rows| {
+                gc::handle_batch_id_result(batch_id_rows.as_ref())
+            })
+            .and_then(|b_id| {
+                batch_id = Some(b_id);
+                Ok(rows)
+            })
+        }
+    })
+    .and_then(|rows| {
+        // All steps completed without error so commit the transaction
+        txn.commit().map_err(|e| e.to_string())?;
+        Ok(rows)
+    })
+    .and_then(|rows| response(method, batch_id, None, &rows))
+}
+
+fn do_keyset_get(
+    payload: &GetGarbagePayload,
+    config: &Config,
+    mut conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<GetGarbageResponse, String> {
+    let vnode = payload.vnode.ok_or_else(|| {
+        String::from(
+            "a vnode is required to read garbage when the materialized view \
+             is disabled",
+        )
+    })?;
+
+    let grace_period = gc::grace_period_secs(config);
+    let limit = payload.limit as i64;
+
+    let query_result = match &payload.after {
+        Some(after) => {
+            let sql = gc::keyset_garbage_after_sql(vnode);
+            sql::query(
+                sql::Method::GarbageGet,
+                &mut conn,
+                sql.as_str(),
+                &[&after.deleted_at, &after.id, &limit, &grace_period],
+                metrics,
+                log,
+            )
+        }
+        None => {
+            let sql = gc::keyset_garbage_sql(vnode);
+            sql::query(
+                sql::Method::GarbageGet,
+                &mut conn,
+                sql.as_str(),
+                &[&limit, &grace_period],
+                metrics,
+                log,
+            )
+        }
+    };
+
+    query_result.map_err(|e| e.to_string()).and_then(|rows| {
+        let cursor = rows.last().map(|row| GarbageCursor {
+            deleted_at: row.get("deleted_at"),
+            id: row.get("id"),
+        });
+
+        response("getgcbatch", None, Some(vnode), &rows).map(|mut resp| {
+            resp.cursor = cursor;
+            resp
         })
-        .and_then(|rows| response(method, batch_id, &rows))
+    })
 }
 
+// Garbage in the materialized view is gathered from every vnode schema, so the
+// vnode of each item is taken from the name of the schema it was found in
+fn schema_vnode(schema: &str) -> u64 {
+    schema
+        .trim_start_matches("manta_bucket_")
+        .parse()
+        .unwrap_or(0)
+}
+
+// The records are ordered so that a truncated batch returns the same records
+// each time it is read
 fn get_sql() -> &'static str {
-    "SELECT * FROM GARBAGE_BATCH"
+    "SELECT * FROM GARBAGE_BATCH ORDER BY schma, id LIMIT $1"
 }
 
 pub(self) fn response(
     _method: &str,
     batch_id: Option<Uuid>,
+    vnode: Option<u64>,
     rows: &RowSlice,
 ) -> Result<GetGarbageResponse, String> {
     let mut garbage: Vec<ObjectResponse> = Vec::with_capacity(1024);
@@ -190,19 +330,28 @@ pub(self) fn response(
             headers: row.get("headers"),
             sharks: row.get("sharks"),
             properties: row.get("properties"),
+            vnode: vnode.unwrap_or_else(|| {
+                schema_vnode(&row.get::<_, String>("schma"))
+            }),
+            display_name: None,
         };
         garbage.push(garbage_item);
     }
 
     let batch_id = if !rows.is_empty() { batch_id } else { None };
 
-    Ok(GetGarbageResponse { batch_id, garbage })
+    Ok(GetGarbageResponse {
+        batch_id,
+        garbage,
+        cursor: None,
+    })
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    use chrono::prelude::*;
     use quickcheck::{quickcheck, Arbitrary, Gen};
     use serde_json;
     use serde_json::Map;
@@ -222,10 +371,25 @@ mod test {
     }
 
     impl Arbitrary for GetGarbagePayload {
-        fn arbitrary<G: Gen>(_g: &mut G) -> Self {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
             let request_id = Uuid::new_v4();
+            let vnode = Option::<u64>::arbitrary(g);
+            let limit = u64::arbitrary(g);
+            let after = if bool::arbitrary(g) {
+                Some(GarbageCursor {
+                    deleted_at: Utc::now(),
+                    id: Uuid::new_v4(),
+                })
+            } else {
+                None
+            };
 
-            GetGarbagePayload { request_id }
+            GetGarbagePayload {
+                request_id,
+                vnode,
+                after,
+                limit,
+            }
         }
     }
 
@@ -238,7 +402,11 @@ mod test {
                 Some(Uuid::new_v4())
             };
 
-            GetGarbageResponse { batch_id, garbage }
+            GetGarbageResponse {
+                batch_id,
+                garbage,
+                cursor: None,
+            }
         }
     }
 
@@ -280,4 +448,23 @@ mod test {
             true
         }
     }
+
+    #[test]
+    fn garbage_limit_default() {
+        let mut obj = Map::new();
+        obj.insert(
+            "request_id".into(),
+            serde_json::to_value(Uuid::new_v4()).unwrap(),
+        );
+        let payload: GetGarbagePayload =
+            serde_json::from_value(Value::Object(obj))
+                .expect("failed to decode GetGarbagePayload");
+        assert_eq!(payload.limit, gc::DEFAULT_BATCH_LIMIT);
+    }
+
+    #[test]
+    fn garbage_schema_vnode() {
+        assert_eq!(schema_vnode("manta_bucket_42"), 42);
+        assert_eq!(schema_vnode("public"), 0);
+    }
 }
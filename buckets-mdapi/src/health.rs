@@ -0,0 +1,194 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! The `ping` health check RPC.
+//!
+//! A `ping` request runs a trivial query to check that the database can be
+//! reached. Served by the connection pool shared with every other request, a
+//! health check waits for a connection behind the requests already queued
+//! while the pool is saturated, and may time out and report the service down
+//! although the database is healthy. When `health_check.dedicated_connection`
+//! is set, `ping` requests are instead served by a pool of a single connection
+//! to the primary that no other request is routed to. The dedicated pool has
+//! its own resolver and its connection is never claimed by other requests, so
+//! the health check reflects the reachability of the database regardless of
+//! the load on the main pool.
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball::backend::Backend;
+use cueball::connection_pool::ConnectionPool;
+use cueball::resolver::Resolver;
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::connection::AgedConnection;
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::sql;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
+use crate::util::array_wrap;
+
+const PING_SQL: &str = "SELECT 1";
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PingPayload {
+    pub request_id: Uuid,
+}
+
+impl HasRequestId for PingPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for PingPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![]
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PingResponse {
+    /// Whether the database answered the health check query
+    pub ok: bool,
+}
+
+/// An optional dedicated pool for health checks. The health check is cheap to
+/// clone and all clones share the same pool.
+pub struct HealthCheck<R, F>
+where
+    R: Resolver,
+    F: FnMut(&Backend) -> AgedConnection + Send + 'static,
+{
+    pool: Option<ConnectionPool<AgedConnection, R, F>>,
+}
+
+impl<R, F> Clone for HealthCheck<R, F>
+where
+    R: Resolver,
+    F: FnMut(&Backend) -> AgedConnection + Send + 'static,
+{
+    fn clone(&self) -> Self {
+        HealthCheck {
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<R, F> HealthCheck<R, F>
+where
+    R: Resolver,
+    F: FnMut(&Backend) -> AgedConnection + Send + 'static,
+{
+    /// Serve health checks with `pool`. When `pool` is `None` health checks
+    /// are served by the main pool.
+    pub fn new(pool: Option<ConnectionPool<AgedConnection, R, F>>) -> Self {
+        HealthCheck { pool }
+    }
+
+    /// The dedicated pool to serve `msg` from, or `None` if the request must
+    /// be served by the main pool.
+    pub fn route(
+        &self,
+        msg: &FastMessage,
+    ) -> Option<&ConnectionPool<AgedConnection, R, F>> {
+        if msg.data.m.name == "ping" {
+            self.pool.as_ref()
+        } else {
+            None
+        }
+    }
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<PingPayload>, SerdeError> {
+    serde_json::from_value::<Vec<PingPayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    _config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    _payload: PingPayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    // Make database request
+    do_ping(conn, metrics, log)
+        .and_then(|resp| {
+            // Handle the successful database response
+            debug!(log, "operation successful");
+            // The PingResponse type contains no maps, so the conversion to
+            // JSON is not expected to fail.
+            let value = serde_json::to_value(resp)
+                .expect("failed to serialize PingResponse");
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(value));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+        .or_else(|e| {
+            // Handle database error response
+            error!(log, "operation failed"; "error" => e.message());
+
+            // Database errors are returned to as regular Fast messages
+            // to be handled by the calling application
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+fn do_ping(
+    mut conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<PingResponse, BucketsMdapiError> {
+    sql::query(sql::Method::Ping, &mut conn, PING_SQL, &[], metrics, log)
+        .map(|rows| PingResponse {
+            ok: !rows.is_empty(),
+        })
+        .map_err(BucketsMdapiError::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+
+    impl Arbitrary for PingPayload {
+        fn arbitrary<G: Gen>(_g: &mut G) -> Self {
+            PingPayload {
+                request_id: Uuid::new_v4(),
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_ping_payload_roundtrip(msg: PingPayload) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(ping_str) => {
+                    let decode_result: Result<PingPayload, _> =
+                        serde_json::from_str(&ping_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+}
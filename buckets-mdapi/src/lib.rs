@@ -2,14 +2,34 @@
 
 #![allow(clippy::module_name_repetitions)]
 
+pub mod admin;
+pub mod admission;
+pub mod batch;
 pub mod bucket;
+pub mod cache;
+pub mod clock;
+pub mod compact;
 pub mod conditional;
+pub mod connection;
 pub mod error;
 pub mod gc;
+pub mod health;
+pub mod limiter;
+pub mod log_file;
 pub mod metrics;
 pub mod object;
 pub mod opts;
+pub mod orphan;
+pub mod owner_usage;
+pub mod replica;
+pub mod selftest;
+pub mod server;
+pub mod shutdown;
 pub mod sql;
+pub mod statsd;
+pub mod timeout;
+pub mod trace;
+pub mod vnode;
 
 pub mod util {
     use std::io::Error as IOError;
@@ -27,30 +47,56 @@ pub mod util {
     use cueball::resolver::Resolver;
     use cueball_postgres_connection::PostgresConnection;
     use fast_rpc::protocol::{FastMessage, FastMessageData};
+    use utils::config::Config;
 
+    use crate::admin;
+    use crate::admission::AdmissionControl;
     use crate::bucket;
+    use crate::cache::ObjectCache;
+    use crate::clock;
+    use crate::compact;
+    use crate::connection::{self, AgedConnection};
     use crate::error::BucketsMdapiError;
     use crate::gc;
-    use crate::metrics::RegisteredMetrics;
+    use crate::health::{self, HealthCheck};
+    use crate::limiter::{
+        self, ByteBudget, BytePermit, ListPacer, WriteLimiter,
+    };
+    use crate::metrics::{
+        PoolConnectionClaim, RegisteredMetrics, HEALTH_POOL, PRIMARY_POOL,
+        REPLICA_POOL,
+    };
     use crate::object;
-    use crate::types::{HandlerError, HandlerResponse, HasRequestId};
+    use crate::replica::ReadReplica;
+    use crate::selftest;
+    use crate::timeout;
+    use crate::trace::TraceContext;
+    use crate::types::{
+        HandlerError, HandlerResponse, HasRequestId, HasVnodes, RequestOutcome,
+    };
     use crate::util;
+    use crate::vnode;
 
-    // Attempt to claim a connection from the cueball connection pool and track
-    // the time spent waiting
+    // Attempt to claim a connection from the cueball connection pool named
+    // `pool_name`, tracking the time spent waiting, and count the connection as
+    // in use for as long as the returned claim is held
     fn claim_pool_connection(
         pool: &ConnectionPool<
-            PostgresConnection,
+            AgedConnection,
             impl Resolver,
-            impl FnMut(&Backend) -> PostgresConnection + Send + 'static,
+            impl FnMut(&Backend) -> AgedConnection + Send + 'static,
         >,
+        pool_name: &str,
         metrics: &RegisteredMetrics,
     ) -> Result<
-        PoolConnection<
-            PostgresConnection,
-            impl Resolver,
-            impl FnMut(&Backend) -> PostgresConnection + Send + 'static,
-        >,
+        (
+            PoolConnection<
+                AgedConnection,
+                impl Resolver,
+                impl FnMut(&Backend) -> AgedConnection + Send + 'static,
+            >,
+            PoolConnectionClaim,
+        ),
         CueballError,
     > {
         let now = Instant::now();
@@ -69,39 +115,157 @@ pub mod util {
             .connection_claim_times
             .with_label_values(&[success])
             .observe(t);
+        metrics.statsd_timing(
+            "connection_claim_times",
+            t,
+            &[("success", success)],
+        );
 
         claim_result
+            .map(|conn| (conn, metrics.claim_pool_connection(pool_name)))
     }
 
     pub fn handle_msg(
         msg: &FastMessage,
         pool: &ConnectionPool<
-            PostgresConnection,
+            AgedConnection,
+            impl Resolver,
+            impl FnMut(&Backend) -> AgedConnection + Send + 'static,
+        >,
+        config: &Config,
+        write_limiter: &WriteLimiter,
+        metrics: &RegisteredMetrics,
+        log: &Logger,
+    ) -> Result<Vec<FastMessage>, IOError> {
+        handle_pool_msg(
+            msg,
+            pool,
+            PRIMARY_POOL,
+            config,
+            write_limiter,
+            metrics,
+            log,
+        )
+    }
+
+    // Handle a request with a connection of the pool named `pool_name`
+    fn handle_pool_msg(
+        msg: &FastMessage,
+        pool: &ConnectionPool<
+            AgedConnection,
             impl Resolver,
-            impl FnMut(&Backend) -> PostgresConnection + Send + 'static,
+            impl FnMut(&Backend) -> AgedConnection + Send + 'static,
         >,
+        pool_name: &str,
+        config: &Config,
+        write_limiter: &WriteLimiter,
         metrics: &RegisteredMetrics,
         log: &Logger,
     ) -> Result<Vec<FastMessage>, IOError> {
         let now = Instant::now();
         let mut response: Vec<FastMessage> = vec![];
 
+        // Tag every log entry for a traced request with its trace context
+        let traced_log = TraceContext::from_msg_data(&msg.data.d).logger(log);
+        let log = &traced_log;
+
         metrics.request_count.inc();
+        metrics.statsd_count("incoming_request_count", &[]);
+        metrics.record_owner_usage(&msg.data.d);
 
         let mut connection_acquired = true;
         let method = msg.data.m.name.as_str();
 
-        claim_pool_connection(pool, metrics)
-            .map_err(HandlerError::Cueball)
-            .and_then(|mut conn| {
+        // Write transactions must hold a permit from the write limiter for the
+        // duration of the request so that reads are not starved of pool
+        // connections by a burst of writes.
+        let write_permit = if limiter::is_write_method(method) {
+            write_limiter
+                .try_acquire()
+                .map(Some)
+                .ok_or(HandlerError::WriteLimit)
+        } else {
+            Ok(None)
+        };
+
+        write_permit
+            .and_then(|permit| {
+                // Claim a connection, replacing it before use if it has
+                // exceeded the maximum connection age. Nothing has been done
+                // on behalf of the request yet, so a transient failure to
+                // get a usable connection may be retried with a fresh claim
+                // regardless of the method.
+                connection::retry_transient(
+                    config.cueball.handler_retries,
+                    log,
+                    || {
+                        let (mut conn, claim) =
+                            claim_pool_connection(pool, pool_name, metrics)
+                                .map_err(HandlerError::Cueball)?;
+                        connection::recycle_expired(
+                            &mut conn,
+                            config.cueball.max_connection_age_secs,
+                            metrics,
+                            log,
+                        )
+                        .map_err(HandlerError::Connection)?;
+                        Ok((conn, claim))
+                    },
+                )
+                .map(|(conn, claim)| ((permit, claim), conn))
+            })
+            .and_then(|((_permit, _claim), mut conn)| {
+                // Apply the statement timeout of the method, or the smaller
+                // timeout requested by the client, for the request
+                let timeout_ms =
+                    timeout::request_timeout_ms(method, &msg.data.d, config);
+                timeout::set(&mut conn, timeout_ms)
+                    .map_err(HandlerError::Connection)?;
+
+                // The getobject and listobjects queries are run with
+                // statements prepared once per connection
+                let statements = conn.statements();
+
                 // Dispatch the request
-                match method {
+                let result = match method {
                     "getobject" => handle_request(
                         msg.id,
                         method,
                         object::get::decode_msg(&msg.data.d),
                         &mut conn,
-                        &object::get::action,
+                        config,
+                        &|msg_id, method, config, metrics, log, payload, pg| {
+                            object::get::action(
+                                msg_id,
+                                method,
+                                config,
+                                metrics,
+                                log,
+                                payload,
+                                pg,
+                                &statements,
+                            )
+                        },
+                        metrics,
+                        log,
+                    ),
+                    "getobjects" => handle_request(
+                        msg.id,
+                        method,
+                        object::multiget::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &object::multiget::action,
+                        metrics,
+                        log,
+                    ),
+                    "headobject" => handle_request(
+                        msg.id,
+                        method,
+                        object::head::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &object::head::action,
                         metrics,
                         log,
                     ),
@@ -110,34 +274,129 @@ pub mod util {
                         method,
                         object::create::decode_msg(&msg.data.d),
                         &mut conn,
+                        config,
                         &object::create::action,
                         metrics,
                         log,
                     ),
+                    "createobjectbatch" => handle_request(
+                        msg.id,
+                        method,
+                        object::create_batch::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &object::create_batch::action,
+                        metrics,
+                        log,
+                    ),
                     "updateobject" => handle_request(
                         msg.id,
                         method,
                         object::update::decode_msg(&msg.data.d),
                         &mut conn,
+                        config,
                         &object::update::action,
                         metrics,
                         log,
                     ),
+                    "batchupdateobjects" => handle_request(
+                        msg.id,
+                        method,
+                        object::batch_update::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &object::batch_update::action,
+                        metrics,
+                        log,
+                    ),
                     "deleteobject" => handle_request(
                         msg.id,
                         method,
                         object::delete::decode_msg(&msg.data.d),
                         &mut conn,
+                        config,
                         &object::delete::action,
                         metrics,
                         log,
                     ),
+                    "deleteobjectsbytag" => handle_request(
+                        msg.id,
+                        method,
+                        object::delete_by_tag::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &object::delete_by_tag::action,
+                        metrics,
+                        log,
+                    ),
                     "listobjects" => handle_request(
                         msg.id,
                         method,
                         object::list::decode_msg(&msg.data.d),
                         &mut conn,
-                        &object::list::action,
+                        config,
+                        &|msg_id, method, config, metrics, log, payload, pg| {
+                            object::list::action(
+                                msg_id,
+                                method,
+                                config,
+                                metrics,
+                                log,
+                                payload,
+                                pg,
+                                &statements,
+                            )
+                        },
+                        metrics,
+                        log,
+                    ),
+                    "swapobjects" => handle_request(
+                        msg.id,
+                        method,
+                        object::swap::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &object::swap::action,
+                        metrics,
+                        log,
+                    ),
+                    "renameobject" => handle_request(
+                        msg.id,
+                        method,
+                        object::rename::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &object::rename::action,
+                        metrics,
+                        log,
+                    ),
+                    "copyobject" => handle_request(
+                        msg.id,
+                        method,
+                        object::copy::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &object::copy::action,
+                        metrics,
+                        log,
+                    ),
+                    "pinobject" => handle_request(
+                        msg.id,
+                        method,
+                        object::pin::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &object::pin::action,
+                        metrics,
+                        log,
+                    ),
+                    "getobjecthistory" => handle_request(
+                        msg.id,
+                        method,
+                        object::history::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &object::history::action,
                         metrics,
                         log,
                     ),
@@ -146,15 +405,37 @@ pub mod util {
                         method,
                         bucket::get::decode_msg(&msg.data.d),
                         &mut conn,
+                        config,
                         &bucket::get::action,
                         metrics,
                         log,
                     ),
+                    "getbucketcounts" => handle_request(
+                        msg.id,
+                        method,
+                        bucket::batch_counts::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &bucket::batch_counts::action,
+                        metrics,
+                        log,
+                    ),
+                    "getbucketobjectcount" => handle_request(
+                        msg.id,
+                        method,
+                        bucket::object_count::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &bucket::object_count::action,
+                        metrics,
+                        log,
+                    ),
                     "createbucket" => handle_request(
                         msg.id,
                         method,
                         bucket::create::decode_msg(&msg.data.d),
                         &mut conn,
+                        config,
                         &bucket::create::action,
                         metrics,
                         log,
@@ -164,6 +445,7 @@ pub mod util {
                         method,
                         bucket::delete::decode_msg(&msg.data.d),
                         &mut conn,
+                        config,
                         &bucket::delete::action,
                         metrics,
                         log,
@@ -173,6 +455,7 @@ pub mod util {
                         method,
                         bucket::list::decode_msg(&msg.data.d),
                         &mut conn,
+                        config,
                         &bucket::list::action,
                         metrics,
                         log,
@@ -182,6 +465,7 @@ pub mod util {
                         method,
                         gc::get::decode_msg(&msg.data.d),
                         &mut conn,
+                        config,
                         &gc::get::action,
                         metrics,
                         log,
@@ -191,15 +475,79 @@ pub mod util {
                         method,
                         gc::delete::decode_msg(&msg.data.d),
                         &mut conn,
+                        config,
                         &gc::delete::action,
                         metrics,
                         log,
                     ),
+                    "getconfig" => handle_request(
+                        msg.id,
+                        method,
+                        admin::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &admin::get_config_action,
+                        metrics,
+                        log,
+                    ),
+                    "getvnodeusage" => handle_request(
+                        msg.id,
+                        method,
+                        vnode::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &vnode::action,
+                        metrics,
+                        log,
+                    ),
+                    "ping" => handle_request(
+                        msg.id,
+                        method,
+                        health::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &health::action,
+                        metrics,
+                        log,
+                    ),
+                    "gettime" => handle_request(
+                        msg.id,
+                        method,
+                        clock::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &clock::action,
+                        metrics,
+                        log,
+                    ),
+                    "repairvnode" => handle_request(
+                        msg.id,
+                        method,
+                        admin::decode_repair_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &admin::repair_vnode_action,
+                        metrics,
+                        log,
+                    ),
+                    "selftest" => handle_request(
+                        msg.id,
+                        method,
+                        selftest::decode_msg(&msg.data.d),
+                        &mut conn,
+                        config,
+                        &selftest::action,
+                        metrics,
+                        log,
+                    ),
                     _ => {
                         let err_msg = format!("Unsupported functon: {}", method);
                         Err(HandlerError::IO(other_error(&err_msg)))
                     }
-                }
+                };
+
+                timeout::reset(&mut conn, timeout_ms, config, log);
+                result
             })
             .or_else(|err| {
                 // An error occurred while attempting to acquire a connection
@@ -224,6 +572,22 @@ pub mod util {
                         let msg: HandlerResponse = FastMessage::data(msg.id, msg_data).into();
                         Ok(msg)
                     }
+                    HandlerError::WriteLimit => {
+                        // The maximum number of write transactions are
+                        // already in progress. Report the service as
+                        // overloaded in the same manner as a claim timeout.
+                        warn!(log, "write transaction limit reached");
+                        let value = array_wrap(json!({
+                            "error": {
+                                "name": "OverloadedError",
+                                "message": "write transaction limit reached"
+                            }
+                        }));
+
+                        let msg_data = FastMessageData::new(method.into(), value);
+                        let msg: HandlerResponse = FastMessage::data(msg.id, msg_data).into();
+                        Ok(msg)
+                    }
                     HandlerError::Cueball(err) => {
                         // Any other connection pool errors are unexpected in
                         // this context so log loudly and return an error.
@@ -234,6 +598,18 @@ pub mod util {
                 }
             })
             .and_then(|res| {
+                // Record the outcome of the request. Not found responses are
+                // an expected result and are counted apart from errors.
+                let outcome = res.outcome();
+                metrics
+                    .fast_request_outcomes
+                    .with_label_values(&[&method, outcome.as_str()])
+                    .inc();
+                metrics.statsd_count(
+                    "fast_request_outcomes",
+                    &[("method", method), ("result", outcome.as_str())],
+                );
+
                 // Add application level response to the `response` vector
                 match res {
                     HandlerResponse::Message(msg) => response.push(msg),
@@ -254,6 +630,11 @@ pub mod util {
                 metrics.fast_requests
                     .with_label_values(&[&method, success])
                     .observe(t);
+                metrics.statsd_timing(
+                    "fast_requests",
+                    t,
+                    &[("method", method), ("success", success)],
+                );
 
                 Ok(res)
             })
@@ -267,18 +648,188 @@ pub mod util {
                 metrics.fast_requests
                     .with_label_values(&[&method, "false"])
                     .observe(t);
+                metrics.statsd_timing(
+                    "fast_requests",
+                    t,
+                    &[("method", method), ("success", "false")],
+                );
+
+                metrics
+                    .fast_request_outcomes
+                    .with_label_values(&[&method, RequestOutcome::Error.as_str()])
+                    .inc();
+                metrics.statsd_count(
+                    "fast_request_outcomes",
+                    &[("method", method), ("result", RequestOutcome::Error.as_str())],
+                );
 
                 let ret_err = match err {
                     HandlerError::Cueball(cueball_err) => {
                         other_error(cueball_err.to_string().as_str())
                     }
                     HandlerError::IO(io_err) => io_err,
+                    HandlerError::Connection(msg) => other_error(&msg),
+                    HandlerError::WriteLimit => {
+                        other_error("write transaction limit reached")
+                    }
                 };
 
                 Err(ret_err)
             })
     }
 
+    /// Handle a request received on a Fast connection, throttling list
+    /// requests that exceed the connection's limit of consecutive list
+    /// requests before any connection is claimed for them, refusing requests
+    /// while the in-flight byte budget is exhausted and creates while
+    /// admission control reports the database overloaded, answering
+    /// `getobject` requests from the object cache when it is enabled,
+    /// serving read requests from the read replica when one is configured,
+    /// and compacting the response when the request asks for it.
+    ///
+    /// The response is returned with the reservation of the bytes of the
+    /// request and the response from the byte budget, which must be held until
+    /// the response has been written to the connection.
+    #[allow(clippy::too_many_arguments)]
+    pub fn handle_connection_msg(
+        msg: &FastMessage,
+        pool: &ConnectionPool<
+            AgedConnection,
+            impl Resolver,
+            impl FnMut(&Backend) -> AgedConnection + Send + 'static,
+        >,
+        replica: &ReadReplica<
+            impl FnMut(&Backend) -> AgedConnection + Send + 'static,
+        >,
+        health: &HealthCheck<
+            impl Resolver,
+            impl FnMut(&Backend) -> AgedConnection + Send + 'static,
+        >,
+        config: &Config,
+        write_limiter: &WriteLimiter,
+        byte_budget: &ByteBudget,
+        admission: &AdmissionControl,
+        list_pacer: &mut ListPacer,
+        object_cache: &ObjectCache,
+        metrics: &RegisteredMetrics,
+        log: &Logger,
+    ) -> Result<(Vec<FastMessage>, Option<BytePermit>), IOError> {
+        let method = msg.data.m.name.as_str();
+
+        if !list_pacer.admit(method) {
+            let response = overloaded(
+                msg,
+                "consecutive list request limit reached",
+                metrics,
+                log,
+            );
+            return Ok((response, None));
+        }
+
+        // Every request reserves its bytes from the in-flight byte budget
+        // until its response has been sent, so that a few very large requests
+        // cannot exhaust memory
+        let mut byte_permit =
+            match byte_budget.try_reserve(limiter::data_bytes(&msg.data.d)) {
+                Some(permit) => permit,
+                None => {
+                    let response = overloaded(
+                        msg,
+                        "in-flight byte limit reached",
+                        metrics,
+                        log,
+                    );
+                    return Ok((response, None));
+                }
+            };
+
+        // Creates are shed outright while the database is falling behind
+        if !admission.admit(method) {
+            let response = overloaded(
+                msg,
+                "create shed by admission control",
+                metrics,
+                log,
+            );
+            return Ok((response, Some(byte_permit)));
+        }
+
+        // The cache holds complete objects, so a response is compacted after
+        // it is served
+        let compact = compact::requested(method, &msg.data.d);
+        let result = object_cache.serve(msg, config, log, || {
+            if let Some(health_pool) = health.route(msg) {
+                return handle_pool_msg(
+                    msg,
+                    health_pool,
+                    HEALTH_POOL,
+                    config,
+                    write_limiter,
+                    metrics,
+                    log,
+                );
+            }
+            if let Some(replica_pool) = replica.route(msg) {
+                return handle_pool_msg(
+                    msg,
+                    replica_pool,
+                    REPLICA_POOL,
+                    config,
+                    write_limiter,
+                    metrics,
+                    log,
+                );
+            }
+            let result =
+                handle_msg(msg, pool, config, write_limiter, metrics, log);
+            replica.record(msg);
+            result
+        });
+        result.map(|mut msgs| {
+            if compact {
+                compact::compact_response(&mut msgs);
+            }
+
+            // Count the response against the in-flight byte budget
+            let bytes = msgs.iter().map(|msg| limiter::data_bytes(&msg.data.d));
+            byte_permit.grow(bytes.sum());
+            (msgs, Some(byte_permit))
+        })
+    }
+
+    // Refuse a request received on a Fast connection with an OverloadedError,
+    // in the same manner as the write transaction limit, so that the client
+    // backs off
+    fn overloaded(
+        msg: &FastMessage,
+        message: &str,
+        metrics: &RegisteredMetrics,
+        log: &Logger,
+    ) -> Vec<FastMessage> {
+        let method = msg.data.m.name.as_str();
+        warn!(log, "{}", message; "method" => method);
+        metrics
+            .fast_request_outcomes
+            .with_label_values(&[method, RequestOutcome::Error.as_str()])
+            .inc();
+        metrics.statsd_count(
+            "fast_request_outcomes",
+            &[
+                ("method", method),
+                ("result", RequestOutcome::Error.as_str()),
+            ],
+        );
+
+        let value = array_wrap(json!({
+            "error": {
+                "name": "OverloadedError",
+                "message": message
+            }
+        }));
+        let msg_data = FastMessageData::new(method.into(), value);
+        vec![FastMessage::data(msg.id, msg_data)]
+    }
+
     // Create a LimitConstraintError error object
     pub fn limit_constraint_error(msg: String) -> Value {
         serde_json::to_value(BucketsMdapiError::LimitConstraintError(msg))
@@ -333,9 +884,11 @@ pub mod util {
         method: &str,
         data: Result<Vec<X>, SerdeError>,
         conn: &mut PostgresConnection,
+        config: &Config,
         action: &dyn Fn(
             u32,
             &str,
+            &Config,
             &RegisteredMetrics,
             &Logger,
             X,
@@ -345,7 +898,7 @@ pub mod util {
         log: &Logger,
     ) -> Result<HandlerResponse, HandlerError>
     where
-        X: for<'de> serde::Deserialize<'de> + HasRequestId,
+        X: for<'de> serde::Deserialize<'de> + HasRequestId + HasVnodes,
     {
         let mut log_child = log.new(o!("method" => method.to_string()));
 
@@ -356,16 +909,74 @@ pub mod util {
             .and_then(|payload| {
                 // Add the request id to the log output
                 let req_id = payload.request_id();
-                log_child = log_child.new(o!("req_id" => req_id.to_string()));
+                let vnodes = payload.vnodes();
+                log_child = log_child.new(o!(
+                    "req_id" => req_id.to_string(),
+                    "vnodes" => format!("{:?}", vnodes),
+                ));
 
                 debug!(log_child, "parsed payload");
 
+                // Reject requests for vnodes outside of the configured range
+                // before any schema names are built from them
+                if let Some(err) = check_vnodes(&payload, config) {
+                    warn!(log_child, "invalid vnode"; "error" => err.message());
+                    let msg_data = FastMessageData::new(
+                        method.into(),
+                        array_wrap(err.into_fast()),
+                    );
+                    return Ok(FastMessage::data(msg_id, msg_data).into());
+                }
+
+                metrics.record_vnode_requests(method, &vnodes);
+
                 // Perform the action indicated by the request
-                action(msg_id, &method, metrics, &log_child, payload, conn)
+                action(
+                    msg_id, &method, config, metrics, &log_child, payload, conn,
+                )
             })
             .map_err(|e| HandlerError::IO(other_error(&e)))
     }
 
+    // Check the vnodes referenced by a request against the configured maximum
+    // vnode, returning an InvalidVnode error for the first out of range vnode
+    pub(crate) fn check_vnodes<X: HasVnodes>(
+        payload: &X,
+        config: &Config,
+    ) -> Option<BucketsMdapiError> {
+        let max_vnode = config.server.max_vnode;
+        payload
+            .vnodes()
+            .into_iter()
+            .find(|vnode| *vnode > max_vnode)
+            .map(|vnode| {
+                BucketsMdapiError::InvalidVnode(format!(
+                    "vnode {} exceeds the maximum vnode {}",
+                    vnode, max_vnode
+                ))
+            })
+    }
+
+    /// Check the `name` of an object or bucket in a request against the
+    /// configured maximum name length, returning a BadRequestError for a
+    /// longer name.
+    pub(crate) fn check_name_length(
+        name: &str,
+        config: &Config,
+    ) -> Result<(), BucketsMdapiError> {
+        let max_name_length = config.server.max_name_length;
+        if name.len() > max_name_length {
+            Err(BucketsMdapiError::BadRequestError(format!(
+                "name is {} bytes long, but the maximum name length is {} \
+                 bytes",
+                name.len(),
+                max_name_length
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn get_thread_name() -> String {
         thread::current()
             .name()
@@ -380,6 +991,8 @@ pub mod types {
 
     use postgres::error::Error as PGError;
     use postgres::row::Row;
+    use serde_derive::{Deserialize, Serialize};
+    use serde_json::Value;
     use uuid::Uuid;
 
     use cueball::error::Error as CueballError;
@@ -391,9 +1004,93 @@ pub mod types {
     pub type Hstore = HashMap<String, Option<String>>;
     pub type Timestamptz = chrono::DateTime<chrono::Utc>;
 
+    /// The response to a `listbuckets` or `listobjects` request that set
+    /// `count_only`: the number of buckets or objects matching the listing.
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    pub struct ListCount {
+        pub count: i64,
+    }
+
+    /// The order of the results of a `listbuckets` or `listobjects` request.
+    /// A listing ordered by creation time orders the buckets or objects
+    /// created at the same time by name.
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ListOrder {
+        NameAsc,
+        NameDesc,
+        CreatedAsc,
+        CreatedDesc,
+    }
+
+    impl Default for ListOrder {
+        fn default() -> Self {
+            ListOrder::NameAsc
+        }
+    }
+
+    impl ListOrder {
+        /// The `ORDER BY` expressions of a listing in this order.
+        pub(crate) fn order_sql(self) -> &'static str {
+            match self {
+                ListOrder::NameAsc => "name ASC",
+                ListOrder::NameDesc => "name DESC",
+                ListOrder::CreatedAsc => "created ASC, name ASC",
+                ListOrder::CreatedDesc => "created DESC, name DESC",
+            }
+        }
+
+        /// The condition continuing a listing in this order after the marker
+        /// name bound to `$param`. A listing ordered by creation time
+        /// continues after the creation time of the marker bound to the
+        /// following parameter, rather than one read from the marker's row,
+        /// which may have been deleted, renamed or replaced since.
+        pub(crate) fn marker_sql(self, param: usize) -> String {
+            let created_marker = |op: &str| {
+                format!(
+                    "(created, name) {} (${}::timestamptz, ${})",
+                    op,
+                    param + 1,
+                    param
+                )
+            };
+            match self {
+                ListOrder::NameAsc => format!("name > ${}", param),
+                ListOrder::NameDesc => format!("name < ${}", param),
+                ListOrder::CreatedAsc => created_marker(">"),
+                ListOrder::CreatedDesc => created_marker("<"),
+            }
+        }
+
+        /// The creation time of the marker continuing a listing in this
+        /// order, which a listing ordered by creation time must give along
+        /// with its marker, and which any other listing ignores.
+        pub(crate) fn marker_created(
+            self,
+            marker: bool,
+            marker_created: Option<Timestamptz>,
+        ) -> Result<Option<Timestamptz>, String> {
+            match self {
+                ListOrder::CreatedAsc | ListOrder::CreatedDesc if marker => {
+                    marker_created.map(Some).ok_or_else(|| {
+                        format!(
+                            "a listing ordered by {} must give the \
+                             marker_created of its marker",
+                            self.order_sql()
+                        )
+                    })
+                }
+                _ => Ok(None),
+            }
+        }
+    }
+
     pub(crate) enum HandlerError {
         Cueball(CueballError),
         IO(IOError),
+        /// A claimed connection could not be made usable
+        Connection(String),
+        WriteLimit,
     }
 
     pub(crate) enum HandlerResponse {
@@ -401,6 +1098,50 @@ pub mod types {
         Messages(Vec<FastMessage>),
     }
 
+    impl HandlerResponse {
+        /// Classify the response for request metrics. Application errors are
+        /// returned to clients as regular Fast messages whose data is of the
+        /// form `{ "error": { "name": "...", "message": "..." } }`, so the
+        /// first message of the response determines the outcome.
+        pub(crate) fn outcome(&self) -> RequestOutcome {
+            let first = match self {
+                HandlerResponse::Message(msg) => Some(msg),
+                HandlerResponse::Messages(msgs) => msgs.first(),
+            };
+
+            first
+                .and_then(|msg| msg.data.d.get(0))
+                .and_then(|value| value.get("error"))
+                .and_then(|err| err.get("name"))
+                .and_then(Value::as_str)
+                .map_or(RequestOutcome::Ok, |name| match name {
+                    "BucketNotFound" | "ObjectNotFound" => {
+                        RequestOutcome::NotFound
+                    }
+                    _ => RequestOutcome::Error,
+                })
+        }
+    }
+
+    /// The outcome of a handled request as reported by the `result` label of
+    /// the `fast_request_outcomes` metric.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum RequestOutcome {
+        Ok,
+        NotFound,
+        Error,
+    }
+
+    impl RequestOutcome {
+        pub fn as_str(self) -> &'static str {
+            match self {
+                RequestOutcome::Ok => "ok",
+                RequestOutcome::NotFound => "not_found",
+                RequestOutcome::Error => "error",
+            }
+        }
+    }
+
     impl From<FastMessage> for HandlerResponse {
         fn from(fm: FastMessage) -> Self {
             HandlerResponse::Message(fm)
@@ -421,4 +1162,11 @@ pub mod types {
     pub(crate) trait HasRequestId {
         fn request_id(&self) -> Uuid;
     }
+
+    /// This trait allows the vnodes referenced by a request to be checked
+    /// against the configured maximum vnode before the vnode numbers are
+    /// interpolated into any schema names.
+    pub(crate) trait HasVnodes {
+        fn vnodes(&self) -> Vec<u64>;
+    }
 }
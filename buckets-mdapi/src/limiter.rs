@@ -0,0 +1,326 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use prometheus::Gauge;
+use serde_json::Value;
+
+use crate::metrics::RegisteredMetrics;
+
+/// Bounds the number of write transactions that may run concurrently so that
+/// a burst of writes cannot consume every connection in the pool and starve
+/// read requests. The limiter is cheap to clone and all clones share the same
+/// count of in-flight writes.
+#[derive(Clone)]
+pub struct WriteLimiter {
+    max: Option<usize>,
+    in_flight: Arc<AtomicUsize>,
+    gauge: Gauge,
+}
+
+/// A permit to run a single write transaction. The permit is returned to the
+/// `WriteLimiter` it was acquired from when it is dropped.
+pub struct WritePermit {
+    in_flight: Arc<AtomicUsize>,
+    gauge: Gauge,
+}
+
+impl WriteLimiter {
+    /// Create a limiter allowing at most `max` concurrent write transactions.
+    /// A `max` of `None` places no limit on writes.
+    pub fn new(max: Option<usize>, metrics: &RegisteredMetrics) -> Self {
+        Self {
+            max,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            gauge: metrics.write_transactions_in_flight.clone(),
+        }
+    }
+
+    /// Attempt to acquire a permit for a write transaction. `None` is returned
+    /// if the maximum number of write transactions are already in progress.
+    pub fn try_acquire(&self) -> Option<WritePermit> {
+        let mut current = self.in_flight.load(Ordering::SeqCst);
+        loop {
+            if let Some(max) = self.max {
+                if current >= max {
+                    return None;
+                }
+            }
+
+            match self.in_flight.compare_exchange(
+                current,
+                current + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    self.gauge.inc();
+                    return Some(WritePermit {
+                        in_flight: Arc::clone(&self.in_flight),
+                        gauge: self.gauge.clone(),
+                    });
+                }
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+impl Drop for WritePermit {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.gauge.dec();
+    }
+}
+
+/// Bounds the total bytes of the requests in progress and of their responses so
+/// that a few very large requests cannot exhaust memory. The bytes of a request
+/// are reserved when it is parsed and the bytes of its response are added to
+/// the reservation once it is built, and both are released once the response
+/// has been written to the connection. A request is refused while its bytes would take
+/// the total over the maximum, unless no other request is in progress, so that
+/// a request larger than the maximum is still served when the service is idle.
+/// The budget is cheap to clone and all clones share the same byte count.
+#[derive(Clone)]
+pub struct ByteBudget {
+    max: Option<u64>,
+    in_flight: Arc<AtomicU64>,
+    gauge: Gauge,
+}
+
+/// A reservation of bytes of a `ByteBudget`. The bytes are returned to the
+/// budget they were reserved from when the permit is dropped.
+pub struct BytePermit {
+    bytes: u64,
+    in_flight: Arc<AtomicU64>,
+    gauge: Gauge,
+}
+
+impl ByteBudget {
+    /// Create a budget allowing at most `max` bytes in flight. A `max` of
+    /// `None` places no limit on the bytes in flight.
+    pub fn new(max: Option<u64>, metrics: &RegisteredMetrics) -> Self {
+        Self {
+            max,
+            in_flight: Arc::new(AtomicU64::new(0)),
+            gauge: metrics.in_flight_bytes.clone(),
+        }
+    }
+
+    /// Attempt to reserve `bytes` of the budget. `None` is returned if other
+    /// requests are in flight and the reservation would exceed the maximum.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn try_reserve(&self, bytes: u64) -> Option<BytePermit> {
+        let mut current = self.in_flight.load(Ordering::SeqCst);
+        loop {
+            if let Some(max) = self.max {
+                if current > 0 && current.saturating_add(bytes) > max {
+                    return None;
+                }
+            }
+
+            match self.in_flight.compare_exchange(
+                current,
+                current.saturating_add(bytes),
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    self.gauge.add(bytes as f64);
+                    return Some(BytePermit {
+                        bytes,
+                        in_flight: Arc::clone(&self.in_flight),
+                        gauge: self.gauge.clone(),
+                    });
+                }
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+impl BytePermit {
+    /// Add `bytes` to the reservation, regardless of the maximum, such as for
+    /// the response to a request that has already been admitted.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn grow(&mut self, bytes: u64) {
+        self.in_flight.fetch_add(bytes, Ordering::SeqCst);
+        self.gauge.add(bytes as f64);
+        self.bytes += bytes;
+    }
+}
+
+impl Drop for BytePermit {
+    #[allow(clippy::cast_precision_loss)]
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(self.bytes, Ordering::SeqCst);
+        self.gauge.sub(self.bytes as f64);
+    }
+}
+
+/// The number of bytes of the JSON encoding of the data of a Fast message.
+pub fn data_bytes(data: &Value) -> u64 {
+    serde_json::to_vec(data).map_or(0, |v| v.len() as u64)
+}
+
+/// Returns `true` if the RPC method performs a write transaction and is subject
+/// to the `WriteLimiter`.
+pub fn is_write_method(method: &str) -> bool {
+    match method {
+        "createobject" | "createobjectbatch" | "updateobject"
+        | "batchupdateobjects" | "swapobjects" | "renameobject"
+        | "copyobject" | "deleteobject" | "deleteobjectsbytag"
+        | "pinobject" | "createbucket" | "deletebucket" | "deletegcbatch"
+        | "selftest" | "repairvnode" => true,
+        _ => false,
+    }
+}
+
+/// Limits the number of consecutive list requests served on a single Fast
+/// connection so that a client paging endlessly through a listing cannot
+/// monopolize a worker. List requests are consecutive while each arrives
+/// within `idle` of the previous one. Once `max_consecutive` consecutive list
+/// requests have been served, further list requests are throttled until the
+/// connection has sent no list request for `idle`. Each connection has its own
+/// pacer.
+pub struct ListPacer {
+    max_consecutive: Option<u32>,
+    idle: Duration,
+    consecutive: u32,
+    last_list: Option<Instant>,
+}
+
+impl ListPacer {
+    /// Create a pacer allowing at most `max_consecutive` consecutive list
+    /// requests. A `max_consecutive` of `None` places no limit on listing.
+    pub fn new(max_consecutive: Option<u32>, idle: Duration) -> Self {
+        Self {
+            max_consecutive,
+            idle,
+            consecutive: 0,
+            last_list: None,
+        }
+    }
+
+    /// Record a request for `method`, returning `false` if it is a list
+    /// request that must be throttled. Requests for other methods are always
+    /// admitted and neither extend nor end a run of list requests.
+    pub fn admit(&mut self, method: &str) -> bool {
+        self.admit_at(method, Instant::now())
+    }
+
+    fn admit_at(&mut self, method: &str, now: Instant) -> bool {
+        let max = match self.max_consecutive {
+            Some(max) if is_list_method(method) => max,
+            _ => return true,
+        };
+
+        let idled = self
+            .last_list
+            .map_or(true, |last| now.duration_since(last) >= self.idle);
+        if idled {
+            self.consecutive = 0;
+        }
+
+        // A throttled request still counts as activity, so the connection
+        // must go quiet for the idle period before listing again
+        self.last_list = Some(now);
+
+        if self.consecutive < max {
+            self.consecutive += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Returns `true` if the RPC method streams a listing and is subject to the
+/// `ListPacer`.
+pub fn is_list_method(method: &str) -> bool {
+    match method {
+        "listobjects" | "listbuckets" => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn list_pacer_throttles_after_max() {
+        let idle = Duration::from_millis(100);
+        let mut pacer = ListPacer::new(Some(3), idle);
+        let start = Instant::now();
+
+        for page in 0..3 {
+            let at = start + Duration::from_millis(page * 10);
+            assert!(pacer.admit_at("listobjects", at));
+        }
+        let throttled_at = start + Duration::from_millis(30);
+        assert!(!pacer.admit_at("listobjects", throttled_at));
+        assert!(!pacer.admit_at("listbuckets", throttled_at));
+
+        // Other requests are unaffected
+        assert!(pacer.admit_at("getobject", throttled_at));
+
+        // Listing resumes once the connection has idled
+        assert!(pacer.admit_at("listobjects", throttled_at + idle));
+    }
+
+    #[test]
+    fn list_pacer_throttled_requests_extend_run() {
+        let idle = Duration::from_millis(100);
+        let mut pacer = ListPacer::new(Some(1), idle);
+        let start = Instant::now();
+
+        assert!(pacer.admit_at("listobjects", start));
+        assert!(!pacer.admit_at("listobjects", start + idle / 2));
+        assert!(!pacer.admit_at("listobjects", start + idle));
+        assert!(pacer.admit_at("listobjects", start + idle * 5 / 2));
+    }
+
+    #[test]
+    fn list_pacer_unlimited() {
+        let mut pacer = ListPacer::new(None, Duration::from_millis(100));
+        for _ in 0..1000 {
+            assert!(pacer.admit("listobjects"));
+        }
+    }
+
+    fn byte_budget(max: Option<u64>) -> ByteBudget {
+        ByteBudget {
+            max,
+            in_flight: Arc::new(AtomicU64::new(0)),
+            gauge: Gauge::new("test_in_flight_bytes", "test gauge")
+                .expect("failed to create gauge"),
+        }
+    }
+
+    #[test]
+    fn byte_budget_refuses_when_exhausted() {
+        let budget = byte_budget(Some(100));
+
+        // A request larger than the budget is served when none is in flight
+        let mut large = budget.try_reserve(150).expect("idle reservation");
+        assert!(budget.try_reserve(1).is_none());
+        drop(large);
+
+        large = budget.try_reserve(60).expect("first reservation");
+        let small = budget.try_reserve(40).expect("second reservation");
+        assert!(budget.try_reserve(1).is_none());
+        assert_eq!(budget.gauge.get(), 100.0);
+
+        // Response bytes are counted against the budget until released
+        large.grow(20);
+        drop(small);
+        assert!(budget.try_reserve(30).is_none());
+        assert!(budget.try_reserve(20).is_some());
+        drop(large);
+        assert_eq!(budget.in_flight.load(Ordering::SeqCst), 0);
+        assert_eq!(budget.gauge.get(), 0.0);
+    }
+}
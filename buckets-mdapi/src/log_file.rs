@@ -0,0 +1,153 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! A log file rotated as it grows, for deployments that do not capture the log
+//! written to stdout.
+//!
+//! The log file is rotated once it reaches `max_bytes`, or once it has been
+//! open for `max_age_secs` when that is given. Rotation renames the log file
+//! to `<path>.1`, shifting any earlier rotated files up by one, and removes the
+//! rotated file beyond `retained_files`. A log record is written with several
+//! writes, so the file is only rotated between records, at the start of a new
+//! line.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use utils::config::ConfigLogFile;
+
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    retained_files: usize,
+    file: File,
+    size: u64,
+    opened: Instant,
+    at_line_start: bool,
+}
+
+impl RotatingFile {
+    /// Open the log file described by `config` for appending, creating it if
+    /// it does not exist.
+    pub fn open(config: &ConfigLogFile) -> io::Result<Self> {
+        let path = PathBuf::from(&config.path);
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_bytes: config.max_bytes,
+            max_age: config.max_age_secs.map(Duration::from_secs),
+            retained_files: config.retained_files,
+            file,
+            size,
+            opened: Instant::now(),
+            at_line_start: true,
+        })
+    }
+
+    fn should_rotate(&self) -> bool {
+        self.at_line_start
+            && self.size > 0
+            && (self.size >= self.max_bytes
+                || self
+                    .max_age
+                    .map_or(false, |age| self.opened.elapsed() >= age))
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.retained_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let oldest = rotated_path(&self.path, self.retained_files);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for n in (1..self.retained_files).rev() {
+                let from = rotated_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate() {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        if written > 0 {
+            self.at_line_start = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// The path of the `n`th most recently rotated log file.
+pub fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use uuid::Uuid;
+
+    #[test]
+    fn log_file_rotates_at_max_bytes() {
+        let dir = std::env::temp_dir()
+            .join(format!("buckets-mdapi-log-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).expect("failed to create log directory");
+        let path = dir.join("buckets-mdapi.log");
+        let config = ConfigLogFile {
+            enabled: true,
+            path: path.to_string_lossy().into_owned(),
+            max_bytes: 64,
+            max_age_secs: None,
+            retained_files: 2,
+        };
+
+        let mut file = RotatingFile::open(&config).expect("failed to open");
+        let line = [b'x'; 31];
+        for _ in 0..3 {
+            // Rotation waits for the end of a record written in pieces
+            file.write_all(&line).expect("failed to write");
+            file.write_all(&line).expect("failed to write");
+            file.write_all(b"\n").expect("failed to write");
+        }
+        file.flush().expect("failed to flush");
+
+        let rotated = rotated_path(&path, 1);
+        assert!(rotated.exists());
+        assert_eq!(fs::read(&rotated).expect("failed to read").len(), 126);
+        assert_eq!(fs::read(&path).expect("failed to read").len(), 63);
+        assert!(!rotated_path(&path, 2).exists());
+
+        fs::remove_dir_all(&dir).expect("failed to remove log directory");
+    }
+}
@@ -1,13 +1,13 @@
 // Copyright 2020 Joyent, Inc.
 
 use std::default::Default;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
 
 use clap::{crate_name, crate_version};
-use slog::{crit, error, info, o, Drain, LevelFilter, Logger};
+use slog::{crit, error, info, o, Drain, Duplicate, LevelFilter, Logger};
 use tokio::net::TcpListener;
 use tokio::prelude::*;
 use tokio::runtime;
@@ -15,10 +15,8 @@ use tokio::runtime;
 use cueball::connection_pool::types::ConnectionPoolOptions;
 use cueball::connection_pool::ConnectionPool;
 use cueball_manatee_primary_resolver::ManateePrimaryResolver;
-use cueball_postgres_connection::{
-    PostgresConnection, PostgresConnectionConfig,
-};
-use fast_rpc::server;
+use cueball_postgres_connection::PostgresConnectionConfig;
+use cueball_static_resolver::StaticIpResolver;
 
 use utils::config::Config;
 
@@ -34,17 +32,48 @@ fn main() {
     // Read CLI arguments
     utils::config::read_cli_args(&matches, &mut config);
 
-    let log = Logger::root(
-        Mutex::new(LevelFilter::new(
-            slog_bunyan::with_name(crate_name!(), std::io::stdout()).build(),
-            config.log.level.into(),
-        ))
-        .fuse(),
-        o!("v" => crate_version!()),
-    );
+    // Retain a copy of the effective configuration for the request handlers
+    let handler_config = config.clone();
+
+    let stdout_drain =
+        slog_bunyan::with_name(crate_name!(), std::io::stdout()).build();
+    let log = if config.log.file.enabled {
+        // Write the log to the rotated log file as well as to stdout
+        let file =
+            buckets_mdapi::log_file::RotatingFile::open(&config.log.file)
+                .unwrap_or_else(|e| {
+                    eprintln!(
+                        "Failed to open log file {}: {}",
+                        config.log.file.path, e
+                    );
+                    std::process::exit(1);
+                });
+        Logger::root(
+            Mutex::new(LevelFilter::new(
+                Duplicate::new(
+                    stdout_drain,
+                    slog_bunyan::with_name(crate_name!(), file).build(),
+                ),
+                config.log.level.into(),
+            ))
+            .fuse(),
+            o!("v" => crate_version!()),
+        )
+    } else {
+        Logger::root(
+            Mutex::new(LevelFilter::new(stdout_drain, config.log.level.into()))
+                .fuse(),
+            o!("v" => crate_version!()),
+        )
+    };
 
     // Configure and start metrics server
-    let metrics = buckets_mdapi::metrics::register_metrics(&config.metrics);
+    let slow_query_threshold = config
+        .database
+        .slow_query_threshold_ms
+        .map(Duration::from_millis);
+    let metrics = buckets_mdapi::metrics::register_metrics(&config.metrics)
+        .with_slow_query_threshold(slow_query_threshold);
     let metrics_clone = metrics.clone();
     let metrics_host = config.metrics.host.clone();
     let metrics_port = config.metrics.port;
@@ -71,7 +100,7 @@ fn main() {
         });
 
     let tls_config = utils::config::tls::tls_config(
-        config.database.tls_mode,
+        config.database.tls_mode.clone(),
         config.database.certificate,
     )
     .unwrap_or_else(|e| {
@@ -79,6 +108,123 @@ fn main() {
         std::process::exit(1);
     });
 
+    // Reads are served by a pool of connections to the read replica, when one
+    // is configured, sharing the credentials of the primary. A replica host
+    // name that cannot be resolved leaves every request served by the primary.
+    let replica_pool = config.replica.host.as_ref().and_then(|host| {
+        let resolution = if host.parse::<IpAddr>().is_ok() {
+            "address"
+        } else {
+            "dns"
+        };
+        let backends = match buckets_mdapi::replica::resolve_backends(
+            host,
+            config.replica.port,
+        ) {
+            Ok(backends) => backends,
+            Err(e) => {
+                error!(log, "unable to resolve replica host, serving reads \
+                             from the primary";
+                    "host" => host, "err" => %e);
+                return None;
+            }
+        };
+        info!(log, "resolved replica host";
+            "host" => host,
+            "resolution" => resolution,
+            "backends" => backends.len());
+        let replica_tls_config = utils::config::tls::tls_config(
+            config.database.tls_mode.clone(),
+            config.database.certificate.clone(),
+        )
+        .unwrap_or_else(|e| {
+            crit!(log, "TLS configuration error"; "err" => %e);
+            std::process::exit(1);
+        });
+        let replica_pg_config = PostgresConnectionConfig {
+            user: Some(config.database.user.clone()),
+            password: None,
+            host: None,
+            port: None,
+            database: Some(config.database.database.clone()),
+            application_name: Some(config.database.application_name.clone()),
+            tls_config: replica_tls_config,
+        };
+        let replica_pool_opts = ConnectionPoolOptions {
+            max_connections: Some(config.replica.max_connections),
+            claim_timeout: config.cueball.claim_timeout,
+            log: Some(log.new(o!(
+                "component" => "ReplicaConnectionPool"
+            ))),
+            rebalancer_action_delay: config.cueball.rebalancer_action_delay,
+            decoherence_interval: None,
+            connection_check_interval: None,
+        };
+        let replica_resolver = StaticIpResolver::new(backends);
+        metrics.init_pool_connections(
+            buckets_mdapi::metrics::REPLICA_POOL,
+            config.replica.max_connections,
+        );
+        Some(ConnectionPool::new(
+            replica_pool_opts,
+            replica_resolver,
+            buckets_mdapi::connection::AgedConnection::connection_creator(
+                replica_pg_config,
+                config.database.statement_timeout_ms,
+            ),
+        ))
+    });
+
+    // Health checks are served by a dedicated connection to the primary, when
+    // configured, so that a saturated main pool does not delay them
+    let health_pool = if config.health_check.dedicated_connection {
+        let health_tls_config = utils::config::tls::tls_config(
+            config.database.tls_mode.clone(),
+            config.database.certificate.clone(),
+        )
+        .unwrap_or_else(|e| {
+            crit!(log, "TLS configuration error"; "err" => %e);
+            std::process::exit(1);
+        });
+        let health_pg_config = PostgresConnectionConfig {
+            user: Some(config.database.user.clone()),
+            password: None,
+            host: None,
+            port: None,
+            database: Some(config.database.database.clone()),
+            application_name: Some(config.database.application_name.clone()),
+            tls_config: health_tls_config,
+        };
+        let health_pool_opts = ConnectionPoolOptions {
+            max_connections: Some(1),
+            claim_timeout: config.cueball.claim_timeout,
+            log: Some(log.new(o!(
+                "component" => "HealthCheckConnectionPool"
+            ))),
+            rebalancer_action_delay: config.cueball.rebalancer_action_delay,
+            decoherence_interval: None,
+            connection_check_interval: None,
+        };
+        let health_resolver = ManateePrimaryResolver::new(
+            config.zookeeper.connection_string.clone(),
+            config.zookeeper.path.clone(),
+            Some(log.new(o!(
+                "component" => "HealthCheckManateePrimaryResolver"
+            ))),
+        );
+        metrics.init_pool_connections(buckets_mdapi::metrics::HEALTH_POOL, 1);
+        Some(ConnectionPool::new(
+            health_pool_opts,
+            health_resolver,
+            buckets_mdapi::connection::AgedConnection::connection_creator(
+                health_pg_config,
+                config.database.statement_timeout_ms,
+            ),
+        ))
+    } else {
+        None
+    };
+
     let pg_config = PostgresConnectionConfig {
         user: Some(config.database.user),
         password: None,
@@ -89,7 +235,11 @@ fn main() {
         tls_config,
     };
 
-    let connection_creator = PostgresConnection::connection_creator(pg_config);
+    let connection_creator =
+        buckets_mdapi::connection::AgedConnection::connection_creator(
+            pg_config,
+            config.database.statement_timeout_ms,
+        );
 
     //
     // TODO log the dynamic backend IP somehow? The resolver will at least emit
@@ -115,9 +265,91 @@ fn main() {
     );
 
     let pool = ConnectionPool::new(pool_opts, resolver, connection_creator);
+    metrics.init_pool_connections(
+        buckets_mdapi::metrics::PRIMARY_POOL,
+        config.cueball.max_connections,
+    );
+
+    let admission = buckets_mdapi::admission::AdmissionControl::new(
+        &config.admission,
+        &metrics,
+    );
+
+    let write_limiter = buckets_mdapi::limiter::WriteLimiter::new(
+        config.server.max_write_transactions,
+        &metrics,
+    );
+
+    let byte_budget = buckets_mdapi::limiter::ByteBudget::new(
+        config.server.max_in_flight_bytes,
+        &metrics,
+    );
+
+    let object_cache =
+        buckets_mdapi::cache::ObjectCache::new(&config.object_cache, &metrics);
+
+    let replica = buckets_mdapi::replica::ReadReplica::new(
+        replica_pool,
+        &config.replica,
+        &metrics,
+    );
+
+    let health = buckets_mdapi::health::HealthCheck::new(health_pool);
 
     info!(log, "established postgres connection pool");
 
+    if handler_config.orphan_scan.enabled {
+        let orphan_pool = pool.clone();
+        let orphan_config = handler_config.clone();
+        let orphan_metrics = metrics.clone();
+        let orphan_log = log.new(o!("component" => "OrphanScan"));
+        thread::Builder::new()
+            .name("orphan-scan".into())
+            .spawn(move || {
+                buckets_mdapi::orphan::run(
+                    orphan_pool,
+                    orphan_config,
+                    orphan_metrics,
+                    orphan_log,
+                )
+            })
+            .unwrap_or_else(|e| {
+                crit!(log, "failed to start orphan scan"; "err" => %e);
+                std::process::exit(1);
+            });
+    }
+
+    if handler_config.admission.enabled {
+        let admission_pool = pool.clone();
+        let admission_control = admission.clone();
+        let admission_config = handler_config.admission.clone();
+        let admission_metrics = metrics.clone();
+        let admission_log = log.new(o!("component" => "AdmissionProbe"));
+        thread::Builder::new()
+            .name("admission-probe".into())
+            .spawn(move || {
+                buckets_mdapi::admission::run(
+                    admission_pool,
+                    admission_control,
+                    admission_config,
+                    admission_metrics,
+                    admission_log,
+                )
+            })
+            .unwrap_or_else(|e| {
+                crit!(log, "failed to start admission probe"; "err" => %e);
+                std::process::exit(1);
+            });
+    }
+
+    buckets_mdapi::shutdown::install_handler().unwrap_or_else(|e| {
+        crit!(log, "failed to install SIGTERM handler"; "err" => %e);
+        std::process::exit(1);
+    });
+    let in_flight = buckets_mdapi::shutdown::InFlight::new();
+    let drain_timeout =
+        Duration::from_secs(config.server.shutdown_drain_timeout_secs);
+
     let addr =
         [&config.server.host, ":", &config.server.port.to_string()].concat();
     let addr = addr.parse::<SocketAddr>().unwrap();
@@ -126,6 +358,8 @@ fn main() {
     info!(log, "listening"; "address" => addr);
 
     let err_log = log.clone();
+    let drain_in_flight = in_flight.clone();
+    let shutdown_metrics = metrics.clone();
 
     let server = listener
         .incoming()
@@ -134,26 +368,54 @@ fn main() {
         )
         .for_each(move |socket| {
             let pool_clone = pool.clone();
+            let config_clone = handler_config.clone();
+            let write_limiter_clone = write_limiter.clone();
+            let byte_budget_clone = byte_budget.clone();
+            let admission_clone = admission.clone();
+            let object_cache_clone = object_cache.clone();
+            let replica_clone = replica.clone();
+            let health_clone = health.clone();
             let metrics_clone = metrics.clone();
+            let in_flight_clone = drain_in_flight.clone();
+            let mut list_pacer = buckets_mdapi::limiter::ListPacer::new(
+                handler_config.server.max_consecutive_lists,
+                Duration::from_millis(handler_config.server.list_idle_ms),
+            );
             let task_log = log.new(o!(
                 "component" => "FastServer",
                 "thread" => buckets_mdapi::util::get_thread_name()));
-            let task = server::make_task(
+            let task = buckets_mdapi::server::make_task(
                 socket,
                 move |a, c| {
-                    buckets_mdapi::util::handle_msg(
-                        a,
-                        &pool_clone,
-                        &metrics_clone,
-                        c,
-                    )
+                    in_flight_clone.track(|| {
+                        buckets_mdapi::util::handle_connection_msg(
+                            a,
+                            &pool_clone,
+                            &replica_clone,
+                            &health_clone,
+                            &config_clone,
+                            &write_limiter_clone,
+                            &byte_budget_clone,
+                            &admission_clone,
+                            &mut list_pacer,
+                            &object_cache_clone,
+                            &metrics_clone,
+                            c,
+                        )
+                    })
                 },
-                Some(&task_log),
+                &task_log,
             );
             tokio::spawn(task);
             Ok(())
         });
 
+    // The listener is dropped, and no further connections are accepted, once
+    // a shutdown is requested
+    let (stop_accepting, stop_requested) =
+        tokio::sync::oneshot::channel::<()>();
+    let server = server.select2(stop_requested).then(|_| Ok(()));
+
     let mut rt = runtime::Builder::new()
         .blocking_threads(config.tokio.blocking_threads)
         .core_threads(config.tokio.core_threads.unwrap())
@@ -165,6 +427,25 @@ fn main() {
 
     rt.spawn(server);
 
-    // Wait until the runtime becomes idle and shut it down.
-    rt.shutdown_on_idle().wait().unwrap();
+    buckets_mdapi::shutdown::wait_for_signal();
+    info!(log, "received SIGTERM, no longer accepting connections";
+        "in_flight" => in_flight.count(),
+        "timeout_secs" => drain_timeout.as_secs());
+    let _ = stop_accepting.send(());
+
+    let drain = in_flight.drain(drain_timeout);
+    shutdown_metrics.flush();
+
+    if let buckets_mdapi::shutdown::Drain::Abandoned(abandoned) = drain {
+        // A stuck request would keep the runtime from shutting down, so the
+        // process exits without waiting for it
+        error!(log, "drain timeout elapsed, abandoning requests in progress";
+            "abandoned" => abandoned);
+        std::process::exit(1);
+    }
+    info!(log, "requests in progress completed");
+
+    // Connections still open are closed as the runtime shuts down
+    rt.shutdown_now().wait().unwrap();
+    info!(log, "shutdown complete");
 }
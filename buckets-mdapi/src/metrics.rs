@@ -1,7 +1,9 @@
 // Copyright 2020 Joyent, Inc.
 
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
 use gethostname::gethostname;
 use hyper::header::{HeaderValue, CONTENT_TYPE};
@@ -12,13 +14,18 @@ use hyper::Body;
 use hyper::StatusCode;
 use hyper::{Request, Response};
 use prometheus::{
-    labels, opts, register_counter, Counter, Encoder, HistogramOpts,
-    HistogramVec, TextEncoder,
+    exponential_buckets, labels, opts, register_counter, register_gauge,
+    Counter, CounterVec, Encoder, Gauge, GaugeVec, Histogram, HistogramOpts,
+    HistogramVec, Opts, TextEncoder,
 };
+use serde_json::Value;
 use slog::{error, info, Logger};
 
 use utils::config::ConfigMetrics;
 
+use crate::owner_usage::OwnerUsage;
+use crate::statsd::StatsdEmitter;
+
 // 1.0 == 1 second
 const HISTOGRAM_BUCKETS: [f64; 28] = [
     0.0001, 0.0002, 0.0003, 0.0004, 0.0005, 0.0006, 0.0007, 0.0008, 0.0009,
@@ -26,6 +33,17 @@ const HISTOGRAM_BUCKETS: [f64; 28] = [
     0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
 ];
 
+/// The largest page of results a `listobjects` or `listbuckets` request may
+/// ask for.
+pub const MAX_LIST_LIMIT: u64 = 1024;
+
+/// The `pool` label of the connections to the primary database.
+pub const PRIMARY_POOL: &str = "primary";
+/// The `pool` label of the connections to the read replica.
+pub const REPLICA_POOL: &str = "replica";
+/// The `pool` label of the dedicated health check connection.
+pub const HEALTH_POOL: &str = "health";
+
 #[derive(Clone)]
 pub struct RegisteredMetrics {
     pub request_count: Counter,
@@ -33,15 +51,81 @@ pub struct RegisteredMetrics {
     pub fast_requests: HistogramVec,
     pub postgres_requests: HistogramVec,
     pub connection_claim_times: HistogramVec,
+    pub write_transactions_in_flight: Gauge,
+    pub in_flight_bytes: Gauge,
+    pub fast_request_outcomes: CounterVec,
+    pub connections_recycled: Counter,
+    pub object_age_at_read: Histogram,
+    pub object_create_content_length: Histogram,
+    pub orphaned_objects_tombstoned: Counter,
+    pub wide_mutations: CounterVec,
+    pub list_result_rows: HistogramVec,
+    pub list_limit_ceiling: CounterVec,
+    pub postgres_retries: CounterVec,
+    pub prepared_statement_cache_hits: CounterVec,
+    pub slow_queries: CounterVec,
+    pub slow_query_threshold: Option<Duration>,
+    pub metadata_corruption: Counter,
+    pub object_cache_hits: Counter,
+    pub object_cache_misses: Counter,
+    pub header_value_fallbacks: Counter,
+    pub replica_requests: Counter,
+    pub admission_shed: Counter,
+    pub vnode_requests: CounterVec,
+    pub vnode_groups: u64,
+    pub pool_connections_in_use: GaugeVec,
+    pub pool_connections_idle: GaugeVec,
+    pub owner_usage: Option<Arc<OwnerUsage>>,
+    pub statsd: Option<Arc<StatsdEmitter>>,
+}
+
+/// A connection claimed from a pool for a request, counted as in use rather
+/// than idle until the claim is dropped.
+pub struct PoolConnectionClaim {
+    in_use: Gauge,
+    idle: Gauge,
+}
+
+impl Drop for PoolConnectionClaim {
+    fn drop(&mut self) {
+        self.in_use.dec();
+        self.idle.inc();
+    }
 }
 
 impl RegisteredMetrics {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         request_count: Counter,
         metrics_request_count: Counter,
         fast_requests: HistogramVec,
         postgres_requests: HistogramVec,
         connection_claim_times: HistogramVec,
+        write_transactions_in_flight: Gauge,
+        in_flight_bytes: Gauge,
+        fast_request_outcomes: CounterVec,
+        connections_recycled: Counter,
+        object_age_at_read: Histogram,
+        object_create_content_length: Histogram,
+        orphaned_objects_tombstoned: Counter,
+        wide_mutations: CounterVec,
+        list_result_rows: HistogramVec,
+        list_limit_ceiling: CounterVec,
+        postgres_retries: CounterVec,
+        prepared_statement_cache_hits: CounterVec,
+        slow_queries: CounterVec,
+        metadata_corruption: Counter,
+        object_cache_hits: Counter,
+        object_cache_misses: Counter,
+        header_value_fallbacks: Counter,
+        replica_requests: Counter,
+        admission_shed: Counter,
+        vnode_requests: CounterVec,
+        vnode_groups: u64,
+        pool_connections_in_use: GaugeVec,
+        pool_connections_idle: GaugeVec,
+        owner_usage: Option<Arc<OwnerUsage>>,
+        statsd: Option<Arc<StatsdEmitter>>,
     ) -> Self {
         RegisteredMetrics {
             request_count,
@@ -49,6 +133,122 @@ impl RegisteredMetrics {
             fast_requests,
             postgres_requests,
             connection_claim_times,
+            write_transactions_in_flight,
+            in_flight_bytes,
+            fast_request_outcomes,
+            connections_recycled,
+            object_age_at_read,
+            object_create_content_length,
+            orphaned_objects_tombstoned,
+            wide_mutations,
+            list_result_rows,
+            list_limit_ceiling,
+            postgres_retries,
+            prepared_statement_cache_hits,
+            slow_queries,
+            slow_query_threshold: None,
+            metadata_corruption,
+            object_cache_hits,
+            object_cache_misses,
+            header_value_fallbacks,
+            replica_requests,
+            admission_shed,
+            vnode_requests,
+            vnode_groups,
+            pool_connections_in_use,
+            pool_connections_idle,
+            owner_usage,
+            statsd,
+        }
+    }
+
+    /// Report the queries that take longer than `threshold` as slow.
+    pub fn with_slow_query_threshold(
+        mut self,
+        threshold: Option<Duration>,
+    ) -> Self {
+        self.slow_query_threshold = threshold;
+        self
+    }
+
+    /// Count the `max_connections` of `pool` as idle, before any connection
+    /// of the pool is claimed.
+    pub fn init_pool_connections(&self, pool: &str, max_connections: u32) {
+        self.pool_connections_in_use
+            .with_label_values(&[pool])
+            .set(0.0);
+        self.pool_connections_idle
+            .with_label_values(&[pool])
+            .set(f64::from(max_connections));
+    }
+
+    /// Count a connection claimed from `pool` as in use until the returned
+    /// claim is dropped.
+    pub fn claim_pool_connection(&self, pool: &str) -> PoolConnectionClaim {
+        let claim = PoolConnectionClaim {
+            in_use: self.pool_connections_in_use.with_label_values(&[pool]),
+            idle: self.pool_connections_idle.with_label_values(&[pool]),
+        };
+        claim.in_use.inc();
+        claim.idle.dec();
+        claim
+    }
+
+    /// Count a request toward the usage of its owner, if per-owner usage is
+    /// tracked.
+    pub fn record_owner_usage(&self, data: &Value) {
+        if let Some(owner_usage) = &self.owner_usage {
+            owner_usage.record(data);
+        }
+    }
+
+    /// Count a request toward the group of each vnode it references.
+    pub fn record_vnode_requests(&self, method: &str, vnodes: &[u64]) {
+        for vnode in vnodes {
+            let group = vnode_group(*vnode, self.vnode_groups).to_string();
+            self.vnode_requests
+                .with_label_values(&[method, group.as_str()])
+                .inc();
+        }
+    }
+
+    /// Record the number of rows returned by a listing, and count the listing
+    /// if it asked for the largest page allowed.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn record_list_rows(&self, method: &str, rows: usize, limit: u64) {
+        self.list_result_rows
+            .with_label_values(&[method])
+            .observe(rows as f64);
+        if limit >= MAX_LIST_LIMIT {
+            self.list_limit_ceiling.with_label_values(&[method]).inc();
+        }
+    }
+
+    /// Mirror a counter increment to the StatsD server, if one is configured.
+    pub fn statsd_count(&self, name: &str, tags: &[(&str, &str)]) {
+        if let Some(statsd) = &self.statsd {
+            statsd.count(name, tags);
+        }
+    }
+
+    /// Mirror a histogram observation, in seconds, to the StatsD server as a
+    /// timer, if one is configured.
+    pub fn statsd_timing(
+        &self,
+        name: &str,
+        seconds: f64,
+        tags: &[(&str, &str)],
+    ) {
+        if let Some(statsd) = &self.statsd {
+            statsd.timing(name, seconds, tags);
+        }
+    }
+
+    /// Send the final value of every counter and gauge to the StatsD server,
+    /// if one is configured, before the process exits.
+    pub fn flush(&self) {
+        if let Some(statsd) = &self.statsd {
+            statsd.flush(&prometheus::gather());
         }
     }
 }
@@ -79,12 +279,140 @@ pub fn register_metrics(config: &ConfigMetrics) -> RegisteredMetrics {
     ))
     .expect("failed to register metrics_request_count counter");
 
+    let write_transactions_in_flight = register_gauge!(opts!(
+        "write_transactions_in_flight",
+        "Number of write transactions currently in progress.",
+        labels! {"datacenter" => config.datacenter.as_str(),
+                 "service" => config.service.as_str(),
+                 "server" => config.server.as_str(),
+                 "zonename" => hostname.as_str(),
+        }
+    ))
+    .expect("failed to register write_transactions_in_flight gauge");
+
+    let in_flight_bytes = register_gauge!(opts!(
+        "in_flight_bytes",
+        "Number of bytes of the requests and responses currently in progress.",
+        labels! {"datacenter" => config.datacenter.as_str(),
+                 "service" => config.service.as_str(),
+                 "server" => config.server.as_str(),
+                 "zonename" => hostname.as_str(),
+        }
+    ))
+    .expect("failed to register in_flight_bytes gauge");
+
+    let connections_recycled = register_counter!(opts!(
+        "connections_recycled_total",
+        "Total number of pooled connections reestablished for exceeding the \
+         maximum connection age.",
+        labels! {"datacenter" => config.datacenter.as_str(),
+                 "service" => config.service.as_str(),
+                 "server" => config.server.as_str(),
+                 "zonename" => hostname.as_str(),
+        }
+    ))
+    .expect("failed to register connections_recycled_total counter");
+
+    let orphaned_objects_tombstoned = register_counter!(opts!(
+        "orphaned_objects_tombstoned_total",
+        "Total number of objects of deleted buckets moved to the deleted \
+         object table by the orphan scan.",
+        labels! {"datacenter" => config.datacenter.as_str(),
+                 "service" => config.service.as_str(),
+                 "server" => config.server.as_str(),
+                 "zonename" => hostname.as_str(),
+        }
+    ))
+    .expect("failed to register orphaned_objects_tombstoned_total counter");
+
+    let metadata_corruption = register_counter!(opts!(
+        "metadata_corruption_total",
+        "Total number of objects read with a content_md5 that is not the \
+         length of an MD5 digest.",
+        labels! {"datacenter" => config.datacenter.as_str(),
+                 "service" => config.service.as_str(),
+                 "server" => config.server.as_str(),
+                 "zonename" => hostname.as_str(),
+        }
+    ))
+    .expect("failed to register metadata_corruption_total counter");
+
+    let object_cache_hits = register_counter!(opts!(
+        "object_cache_hits_total",
+        "Total number of getobject requests answered from the object cache.",
+        labels! {"datacenter" => config.datacenter.as_str(),
+                 "service" => config.service.as_str(),
+                 "server" => config.server.as_str(),
+                 "zonename" => hostname.as_str(),
+        }
+    ))
+    .expect("failed to register object_cache_hits_total counter");
+
+    let object_cache_misses = register_counter!(opts!(
+        "object_cache_misses_total",
+        "Total number of getobject requests for objects not in the object \
+         cache.",
+        labels! {"datacenter" => config.datacenter.as_str(),
+                 "service" => config.service.as_str(),
+                 "server" => config.server.as_str(),
+                 "zonename" => hostname.as_str(),
+        }
+    ))
+    .expect("failed to register object_cache_misses_total counter");
+
+    let header_value_fallbacks = register_counter!(opts!(
+        "header_value_fallbacks_total",
+        "Total number of object header values read that were not valid \
+         UTF-8.",
+        labels! {"datacenter" => config.datacenter.as_str(),
+                 "service" => config.service.as_str(),
+                 "server" => config.server.as_str(),
+                 "zonename" => hostname.as_str(),
+        }
+    ))
+    .expect("failed to register header_value_fallbacks_total counter");
+
+    let replica_requests = register_counter!(opts!(
+        "replica_requests_total",
+        "Total number of read requests served by the database read replica.",
+        labels! {"datacenter" => config.datacenter.as_str(),
+                 "service" => config.service.as_str(),
+                 "server" => config.server.as_str(),
+                 "zonename" => hostname.as_str(),
+        }
+    ))
+    .expect("failed to register replica_requests_total counter");
+
+    let admission_shed = register_counter!(opts!(
+        "admission_shed_total",
+        "Total number of create requests shed while the database is \
+         overloaded.",
+        labels! {"datacenter" => config.datacenter.as_str(),
+                 "service" => config.service.as_str(),
+                 "server" => config.server.as_str(),
+                 "zonename" => hostname.as_str(),
+        }
+    ))
+    .expect("failed to register admission_shed_total counter");
+
     let mut const_labels = HashMap::new();
     const_labels.insert("service".to_string(), config.service.clone());
     const_labels.insert("server".to_string(), config.server.clone());
     const_labels.insert("datacenter".to_string(), config.datacenter.clone());
     const_labels.insert("zonename".to_string(), hostname);
 
+    let statsd = if config.statsd.enabled {
+        let const_tags = const_labels
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let emitter = StatsdEmitter::new(&config.statsd, const_tags)
+            .expect("failed to create statsd emitter");
+        Some(Arc::new(emitter))
+    } else {
+        None
+    };
+
     let fast_requests = register_histogram(
         "fast_requests",
         "Latency of all fast requests processed.",
@@ -106,24 +434,197 @@ pub fn register_metrics(config: &ConfigMetrics) -> RegisteredMetrics {
         vec!["success"],
     );
 
+    // Object ages range from seconds to years, so the buckets grow by a factor
+    // of four from one second to roughly eight and a half years
+    let object_age_at_read = register_plain_histogram(
+        "object_age_at_read_seconds",
+        "Age of objects, from their creation, when they are read by getobject.",
+        &const_labels,
+        exponential_buckets(1.0, 4.0, 15)
+            .expect("failed to create object_age_at_read_seconds buckets"),
+    );
+
+    // Object sizes range from bytes to terabytes, so the buckets grow by a
+    // factor of four from one kibibyte to one tebibyte
+    let object_create_content_length = register_plain_histogram(
+        "object_create_content_length_bytes",
+        "Content length of objects created by createobject.",
+        &const_labels,
+        exponential_buckets(1024.0, 4.0, 16).expect(
+            "failed to create object_create_content_length_bytes buckets",
+        ),
+    );
+
+    let fast_request_outcomes = register_counter_vec(
+        "fast_request_outcomes",
+        "Count of fast requests by outcome: ok, not_found, or error.",
+        &const_labels,
+        vec!["method", "result"],
+    );
+
+    let wide_mutations = register_counter_vec(
+        "wide_mutation_total",
+        "Count of mutations of a single object that affected more rows than \
+         expected.",
+        &const_labels,
+        vec!["method"],
+    );
+
+    // Listings return between none and MAX_LIST_LIMIT rows, so the buckets
+    // double from one row to the limit
+    let list_result_rows = register_histogram_with_buckets(
+        "list_result_rows",
+        "Number of rows returned by each listing.",
+        &const_labels,
+        vec!["method"],
+        exponential_buckets(1.0, 2.0, 11)
+            .expect("failed to create list_result_rows buckets"),
+    );
+
+    let list_limit_ceiling = register_counter_vec(
+        "list_limit_ceiling_total",
+        "Count of listings that asked for the largest page allowed.",
+        &const_labels,
+        vec!["method"],
+    );
+
+    // Labelling requests by vnode would create a series for each of up to a
+    // million vnodes, so vnodes are labelled by group instead
+    let vnode_requests = register_counter_vec(
+        "vnode_requests_total",
+        "Count of requests referencing each group of vnodes.",
+        &const_labels,
+        vec!["method", "vnode_group"],
+    );
+
+    // Both are labelled by the pool the connections belong to: the primary,
+    // the read replica, or the health check connection
+    let pool_connections_in_use = register_gauge_vec(
+        "pool_connections_in_use",
+        "Number of pool connections currently claimed by requests.",
+        &const_labels,
+        vec!["pool"],
+    );
+
+    let pool_connections_idle = register_gauge_vec(
+        "pool_connections_idle",
+        "Number of pool connections, up to the maximum of the pool, not \
+         currently claimed by requests.",
+        &const_labels,
+        vec!["pool"],
+    );
+
+    let postgres_retries = register_counter_vec(
+        "postgres_retry_total",
+        "Count of transactions retried after being rolled back by the \
+         database.",
+        &const_labels,
+        vec!["method"],
+    );
+
+    let prepared_statement_cache_hits = register_counter_vec(
+        "prepared_statement_cache_hits_total",
+        "Count of queries run with a statement already prepared on the \
+         connection.",
+        &const_labels,
+        vec!["method"],
+    );
+
+    let slow_queries = register_counter_vec(
+        "slow_queries_total",
+        "Count of queries that took longer than the slow query threshold.",
+        &const_labels,
+        vec!["method"],
+    );
+
+    let owner_usage = if config.owner_usage.enabled {
+        let top_requests = register_gauge_vec(
+            "owner_top_requests",
+            "Count of requests made by the owners that made the most requests.",
+            &const_labels,
+            vec!["owner"],
+        );
+        let top_bytes = register_gauge_vec(
+            "owner_top_bytes",
+            "Count of object bytes created or updated by the owners that wrote \
+             the most bytes.",
+            &const_labels,
+            vec!["owner"],
+        );
+        Some(Arc::new(OwnerUsage::new(
+            config.owner_usage.top_n,
+            top_requests,
+            top_bytes,
+        )))
+    } else {
+        None
+    };
+
     RegisteredMetrics::new(
         request_counter,
         metrics_request_counter,
         fast_requests,
         postgres_requests,
         connection_claim_times,
+        write_transactions_in_flight,
+        in_flight_bytes,
+        fast_request_outcomes,
+        connections_recycled,
+        object_age_at_read,
+        object_create_content_length,
+        orphaned_objects_tombstoned,
+        wide_mutations,
+        list_result_rows,
+        list_limit_ceiling,
+        postgres_retries,
+        prepared_statement_cache_hits,
+        slow_queries,
+        metadata_corruption,
+        object_cache_hits,
+        object_cache_misses,
+        header_value_fallbacks,
+        replica_requests,
+        admission_shed,
+        vnode_requests,
+        config.vnode_groups,
+        pool_connections_in_use,
+        pool_connections_idle,
+        owner_usage,
+        statsd,
     )
 }
 
+/// The group a vnode is labelled with in metrics, out of `groups` groups. A
+/// `groups` of zero is treated as a single group.
+pub fn vnode_group(vnode: u64, groups: u64) -> u64 {
+    vnode % groups.max(1)
+}
+
 fn register_histogram(
     name: &str,
     description: &str,
     const_labels: &HashMap<String, String>,
     labels: Vec<&str>,
+) -> HistogramVec {
+    register_histogram_with_buckets(
+        name,
+        description,
+        const_labels,
+        labels,
+        HISTOGRAM_BUCKETS.to_vec(),
+    )
+}
+
+fn register_histogram_with_buckets(
+    name: &str,
+    description: &str,
+    const_labels: &HashMap<String, String>,
+    labels: Vec<&str>,
+    buckets: Vec<f64>,
 ) -> HistogramVec {
     let opts = HistogramOpts::new(name, description)
         .const_labels(const_labels.clone())
-        .buckets(HISTOGRAM_BUCKETS.to_vec());
+        .buckets(buckets);
     let h_vec =
         HistogramVec::new(opts, labels.as_slice()).unwrap_or_else(|_| {
             panic!(["failed to create ", name, " histogram"].concat())
@@ -136,25 +637,97 @@ fn register_histogram(
     h_vec
 }
 
-pub fn start_server(
+fn register_plain_histogram(
+    name: &str,
+    description: &str,
+    const_labels: &HashMap<String, String>,
+    buckets: Vec<f64>,
+) -> Histogram {
+    let opts = HistogramOpts::new(name, description)
+        .const_labels(const_labels.clone())
+        .buckets(buckets);
+    let h = Histogram::with_opts(opts).unwrap_or_else(|_| {
+        panic!(["failed to create ", name, " histogram"].concat())
+    });
+
+    prometheus::register(Box::new(h.clone())).unwrap_or_else(|_| {
+        panic!(["failed to register ", name, " histogram"].concat())
+    });
+
+    h
+}
+
+fn register_counter_vec(
+    name: &str,
+    description: &str,
+    const_labels: &HashMap<String, String>,
+    labels: Vec<&str>,
+) -> CounterVec {
+    let opts = Opts::new(name, description).const_labels(const_labels.clone());
+    let c_vec = CounterVec::new(opts, labels.as_slice()).unwrap_or_else(|_| {
+        panic!(["failed to create ", name, " counter"].concat())
+    });
+
+    prometheus::register(Box::new(c_vec.clone())).unwrap_or_else(|_| {
+        panic!(["failed to register ", name, " counter"].concat())
+    });
+
+    c_vec
+}
+
+fn register_gauge_vec(
+    name: &str,
+    description: &str,
+    const_labels: &HashMap<String, String>,
+    labels: Vec<&str>,
+) -> GaugeVec {
+    let opts = Opts::new(name, description).const_labels(const_labels.clone());
+    let g_vec = GaugeVec::new(opts, labels.as_slice()).unwrap_or_else(|_| {
+        panic!(["failed to create ", name, " gauge"].concat())
+    });
+
+    prometheus::register(Box::new(g_vec.clone())).unwrap_or_else(|_| {
+        panic!(["failed to register ", name, " gauge"].concat())
+    });
+
+    g_vec
+}
+
+/// The address of the metrics server, from the configured `host`, which may be
+/// an IPv4 or IPv6 address, and `port`.
+pub fn server_addr(host: &str, port: u16) -> Result<SocketAddr, String> {
+    host.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, port))
+        .map_err(|e| format!("invalid metrics host '{}': {}", host, e))
+}
+
+/// Bind the metrics server to `address` and `port`. A `port` of `0` has the
+/// operating system assign an ephemeral port. Returns the address the server
+/// is bound to along with the server, which serves requests once it is run.
+pub fn bind_server(
     address: &str,
     port: u16,
     metrics: RegisteredMetrics,
     log: &Logger,
-) {
-    let addr = [&address, ":", &port.to_string()]
-        .concat()
-        .parse::<SocketAddr>()
-        .unwrap();
+) -> Result<(SocketAddr, impl Future<Item = (), Error = ()> + Send), String> {
+    let addr = server_addr(address, port)?;
 
     let log_clone = log.clone();
 
-    let server = Server::bind(&addr)
+    let server = Server::try_bind(&addr)
+        .map_err(|e| format!("failed to bind {}: {}", addr, e))?
         .serve(move || {
             let metrics_request_count = metrics.metrics_request_count.clone();
+            let owner_usage = metrics.owner_usage.clone();
             service_fn_ok(move |_: Request<Body>| {
                 metrics_request_count.inc();
 
+                // The heaviest owners are only exported when metrics are
+                // requested, sparing requests the cost of sorting them
+                if let Some(owner_usage) = &owner_usage {
+                    owner_usage.refresh();
+                }
+
                 let metric_families = prometheus::gather();
                 let mut buffer = vec![];
                 let encoder = TextEncoder::new();
@@ -169,12 +742,65 @@ pub fn start_server(
                     .body(Body::from(buffer))
                     .unwrap()
             })
-        })
-        .map_err(
-            move |e| error!(log_clone, "metrics server error"; "error" => %e),
-        );
+        });
+
+    // The bound address differs from the configured one when the port was
+    // assigned by the operating system
+    let bound_addr = server.local_addr();
+    let server = server.map_err(
+        move |e| error!(log_clone, "metrics server error"; "error" => %e),
+    );
+
+    Ok((bound_addr, server))
+}
+
+pub fn start_server(
+    address: &str,
+    port: u16,
+    metrics: RegisteredMetrics,
+    log: &Logger,
+) {
+    let (addr, server) = bind_server(address, port, metrics, log)
+        .unwrap_or_else(|e| panic!("failed to start metrics server: {}", e));
 
     info!(log, "listening"; "address" => addr);
 
     rt::run(server);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    #[test]
+    fn vnode_group_maps_vnodes_to_configured_groups() {
+        let groups: HashSet<u64> =
+            (0..1000).map(|vnode| vnode_group(vnode, 16)).collect();
+        assert_eq!(groups.len(), 16);
+        assert!(groups.iter().all(|group| *group < 16));
+
+        assert_eq!(vnode_group(999_999, 16), 15);
+        assert_eq!(vnode_group(42, 1), 0);
+        assert_eq!(vnode_group(42, 0), 0);
+    }
+
+    #[test]
+    fn server_addr_accepts_ipv4_and_ipv6_hosts() {
+        assert_eq!(
+            server_addr("0.0.0.0", 3020),
+            Ok("0.0.0.0:3020".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(
+            server_addr("::1", 3020),
+            Ok("[::1]:3020".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(
+            server_addr("::", 0),
+            Ok("[::]:0".parse::<SocketAddr>().unwrap())
+        );
+        assert!(server_addr("localhost", 3020).is_err());
+        assert!(server_addr("[::1]", 3020).is_err());
+    }
+}
@@ -6,20 +6,39 @@ use std::vec::Vec;
 
 use base64;
 use bytes::BytesMut;
+use postgres::row::Row;
 use postgres::types::{accepts, to_sql_checked};
 use postgres::types::{FromSql, IsNull, ToSql, Type};
+use postgres::Transaction;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
+use slog::{warn, Logger};
 use uuid::Uuid;
 
+use utils::config::{Config, ContentMd5Check, HeaderValueFallback};
+
 use crate::conditional;
 use crate::error::BucketsMdapiError;
-use crate::types::{HasRequestId, Hstore, RowSlice, Timestamptz};
+use crate::metrics::RegisteredMetrics;
+use crate::sql;
+use crate::types::{HasRequestId, HasVnodes, Hstore, RowSlice, Timestamptz};
 
+pub mod batch_update;
+pub mod copy;
 pub mod create;
+pub mod create_batch;
 pub mod delete;
+pub mod delete_by_tag;
 pub mod get;
+pub mod head;
+pub mod history;
+pub mod index;
 pub mod list;
+pub mod multiget;
+pub mod pin;
+pub mod properties;
+pub mod rename;
+pub mod swap;
 pub mod update;
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -32,6 +51,30 @@ pub struct GetObjectPayload {
 
     #[serde(default)]
     pub conditions: conditional::Conditions,
+
+    /// The vnode holding the metadata of the object's bucket. When bucket
+    /// quotas are enabled this field is required by delete requests, which
+    /// lower the bucket's maintained totals by the deleted object.
+    #[serde(default)]
+    pub bucket_vnode: Option<u64>,
+
+    /// A condition on the size of the existing object. This is only evaluated
+    /// by delete requests, which fail with a `PreconditionFailedError` when the
+    /// object's `content_length` does not satisfy it.
+    #[serde(default)]
+    pub if_content_length: Option<conditional::ContentLengthCondition>,
+
+    /// Include the object's `properties` when they are stored outside of the
+    /// object row for exceeding the configured `max_inline_bytes`. This is
+    /// only evaluated by get requests.
+    #[serde(default)]
+    pub include_properties: bool,
+
+    /// Clear the pin of a pinned object so that it may be deleted. This is
+    /// only evaluated by delete requests, which otherwise fail with an
+    /// `ObjectPinned` error for a pinned object.
+    #[serde(default)]
+    pub unpin_first: bool,
 }
 
 impl HasRequestId for GetObjectPayload {
@@ -40,6 +83,14 @@ impl HasRequestId for GetObjectPayload {
     }
 }
 
+impl HasVnodes for GetObjectPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        std::iter::once(self.vnode)
+            .chain(self.bucket_vnode)
+            .collect()
+    }
+}
+
 type DeleteObjectPayload = GetObjectPayload;
 
 /// A type that represents the information about the datacenter and storage node
@@ -101,6 +152,116 @@ impl<'a> FromSql<'a> for StorageNodeIdentifier {
     accepts!(TEXT);
 }
 
+/// The headers of an object as stored in its `hstore` column, with each name
+/// and value kept as bytes so that an object whose headers are not valid UTF-8,
+/// such as those written by a legacy writer, can still be read.
+struct StoredHeaders(Vec<(Vec<u8>, Option<Vec<u8>>)>);
+
+impl StoredHeaders {
+    /// Decode the headers, encoding each name or value that is not valid UTF-8
+    /// according to `fallback`. Returns the headers and the number of names
+    /// and values that were not valid UTF-8.
+    fn decode(self, fallback: HeaderValueFallback) -> (Hstore, usize) {
+        let mut invalid = 0;
+        let mut decode_bytes = |bytes: Vec<u8>| {
+            String::from_utf8(bytes).unwrap_or_else(|e| {
+                invalid += 1;
+                match fallback {
+                    HeaderValueFallback::Lossy => {
+                        String::from_utf8_lossy(e.as_bytes()).into_owned()
+                    }
+                    HeaderValueFallback::Base64 => base64::encode(e.as_bytes()),
+                }
+            })
+        };
+
+        let headers = self
+            .0
+            .into_iter()
+            .map(|(name, value)| {
+                (decode_bytes(name), value.map(&mut decode_bytes))
+            })
+            .collect();
+        (headers, invalid)
+    }
+}
+
+impl<'a> FromSql<'a> for StoredHeaders {
+    // The binary format of an hstore is the number of entries followed by the
+    // length and bytes of each name and value, where a NULL value has a length
+    // of -1
+    fn from_sql(
+        _ty: &Type,
+        mut raw: &'a [u8],
+    ) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let count = read_hstore_len(&mut raw)?;
+        let mut headers = Vec::with_capacity(count.max(0) as usize);
+        for _ in 0..count {
+            let name = read_hstore_bytes(&mut raw)?
+                .ok_or("invalid hstore: NULL name")?;
+            let value = read_hstore_bytes(&mut raw)?;
+            headers.push((name, value));
+        }
+
+        if raw.is_empty() {
+            Ok(StoredHeaders(headers))
+        } else {
+            Err("invalid hstore: trailing bytes".into())
+        }
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        ty.name() == "hstore"
+    }
+}
+
+fn read_hstore_len(
+    raw: &mut &[u8],
+) -> Result<i32, Box<dyn Error + Sync + Send>> {
+    if raw.len() < 4 {
+        return Err("invalid hstore: truncated length".into());
+    }
+    let (len, rest) = raw.split_at(4);
+    *raw = rest;
+    Ok(i32::from_be_bytes([len[0], len[1], len[2], len[3]]))
+}
+
+fn read_hstore_bytes(
+    raw: &mut &[u8],
+) -> Result<Option<Vec<u8>>, Box<dyn Error + Sync + Send>> {
+    let len = read_hstore_len(raw)?;
+    if len < 0 {
+        return Ok(None);
+    }
+    let len = len as usize;
+    if raw.len() < len {
+        return Err("invalid hstore: truncated entry".into());
+    }
+    let (bytes, rest) = raw.split_at(len);
+    *raw = rest;
+    Ok(Some(bytes.to_vec()))
+}
+
+/// Read the `headers` of an object row. A header name or value that is not
+/// valid UTF-8 is encoded according to the configured `header_value_fallback`
+/// and counted, rather than failing the read.
+pub(crate) fn row_headers(
+    row: &Row,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Hstore {
+    let stored: StoredHeaders = row.get("headers");
+    let (headers, invalid) = stored.decode(config.server.header_value_fallback);
+    if invalid > 0 {
+        metrics.header_value_fallbacks.inc_by(invalid as f64);
+        warn!(log, "object headers are not valid UTF-8";
+            "id" => %row.get::<_, Uuid>("id"),
+            "invalid" => invalid);
+    }
+    headers
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct DeleteObjectResponse {
     pub id: Uuid,
@@ -109,6 +270,10 @@ pub struct DeleteObjectResponse {
     pub name: String,
     pub content_length: i64,
     pub shark_count: i32,
+    /// The storage nodes holding the deleted object's data, so that their
+    /// copies may be cleaned up without a second lookup
+    #[serde(default)]
+    pub sharks: Vec<StorageNodeIdentifier>,
 }
 
 impl DeleteObjectResponse {
@@ -136,6 +301,16 @@ pub struct ObjectResponse {
     pub headers: Hstore,
     pub sharks: Vec<StorageNodeIdentifier>,
     pub properties: Option<Value>,
+
+    /// The vnode the object was served from.
+    #[serde(default)]
+    pub vnode: u64,
+
+    /// The name of the object as given by the client that created it. This is
+    /// only present when `case_insensitive_names` is configured, in which case
+    /// `name` is the name folded to lowercase.
+    #[serde(default)]
+    pub display_name: Option<String>,
 }
 
 pub(self) fn to_json(objr: ObjectResponse) -> Value {
@@ -153,7 +328,11 @@ pub fn object_not_found() -> Value {
 
 pub fn response(
     method: &str,
+    vnode: u64,
     rows: &RowSlice,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
 ) -> Result<Option<ObjectResponse>, BucketsMdapiError> {
     if rows.is_empty() {
         Ok(None)
@@ -174,9 +353,11 @@ pub fn response(
                 content_length: row.get("content_length"),
                 content_md5,
                 content_type: row.get("content_type"),
-                headers: row.get("headers"),
+                headers: row_headers(row, config, metrics, log),
                 sharks: row.get("sharks"),
                 properties: row.get("properties"),
+                vnode,
+                display_name: row.try_get("display_name").unwrap_or(None),
             };
             Ok(Some(resp))
         } else {
@@ -185,7 +366,7 @@ pub fn response(
                  but 12 were expected.",
                 method, cols
             );
-            Err(BucketsMdapiError::PostgresError(err))
+            Err(BucketsMdapiError::postgres_error(err))
         }
     } else {
         let err = format!(
@@ -193,7 +374,53 @@ pub fn response(
             method,
             rows.len()
         );
-        Err(BucketsMdapiError::PostgresError(err))
+        Err(BucketsMdapiError::postgres_error(err))
+    }
+}
+
+/// The length in bytes of an MD5 digest.
+pub(crate) const CONTENT_MD5_LEN: usize = 16;
+
+/// Check that the `content_md5` of an object read from the database is the
+/// length of an MD5 digest, handling an object that fails the check according
+/// to the configured `content_md5_check`.
+pub(crate) fn check_content_md5(
+    method: &str,
+    object: &ObjectResponse,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<(), BucketsMdapiError> {
+    let check = config.server.content_md5_check;
+    if check == ContentMd5Check::Ignore {
+        return Ok(());
+    }
+
+    let len = base64::decode(&object.content_md5)
+        .map(|bytes| bytes.len())
+        .unwrap_or(0);
+    if len == CONTENT_MD5_LEN {
+        return Ok(());
+    }
+
+    metrics.metadata_corruption.inc();
+    warn!(log, "object content_md5 is not an MD5 digest";
+        "method" => method,
+        "owner" => %object.owner,
+        "bucket_id" => %object.bucket_id,
+        "name" => &object.name,
+        "id" => %object.id,
+        "content_md5_bytes" => len);
+
+    if check == ContentMd5Check::Reject {
+        let msg = format!(
+            "object {} has a content_md5 of {} bytes, but an MD5 digest is \
+             {} bytes",
+            object.id, len, CONTENT_MD5_LEN
+        );
+        Err(BucketsMdapiError::MetadataCorruption(msg))
+    } else {
+        Ok(())
     }
 }
 
@@ -215,11 +442,247 @@ pub(self) fn insert_delete_table_sql(vnode: u64) -> String {
           WHERE owner = $1 \
           AND bucket_id = $2 \
           AND name = $3 \
-          AND content_length > 0",
+          AND content_length > 0 \
+          RETURNING content_length",
     ]
     .concat()
 }
 
+/// Adjust the maintained byte and object totals of a bucket by `byte_delta`
+/// bytes and `object_delta` objects as part of the transaction that creates or
+/// deletes one of its objects. The bucket's metadata must reside in
+/// `bucket_vnode` and that vnode must be hosted by the same database as the
+/// object. If the adjustment would raise the byte total above the bucket's
+/// `max_total_bytes` a `QuotaExceeded` error is returned and the transaction
+/// must not be committed.
+#[allow(clippy::too_many_arguments)]
+pub(self) fn update_bucket_totals(
+    mut txn: &mut Transaction,
+    owner: &Uuid,
+    bucket_id: &Uuid,
+    bucket_vnode: u64,
+    byte_delta: i64,
+    object_delta: i64,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<(), BucketsMdapiError> {
+    let rows = sql::txn_query(
+        sql::Method::BucketTotalBytesUpdate,
+        &mut txn,
+        update_bucket_totals_sql(bucket_vnode).as_str(),
+        &[owner, bucket_id, &byte_delta, &object_delta],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    if rows.is_empty() {
+        return Err(BucketsMdapiError::BucketNotFound);
+    }
+
+    let total_bytes: i64 = rows[0].get("total_bytes");
+    let max_total_bytes: Option<i64> = rows[0].get("max_total_bytes");
+
+    match max_total_bytes {
+        Some(max) if byte_delta > 0 && total_bytes > max => {
+            let msg = format!(
+                "bucket quota of {} bytes exceeded: the request would raise \
+                 the bucket total to {} bytes",
+                max, total_bytes
+            );
+            Err(BucketsMdapiError::QuotaExceeded(msg))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fail with a `BadRequestError` if bucket quotas are enabled and a request
+/// that creates or removes objects does not name the `bucket_vnode` holding
+/// the bucket's maintained totals, as the totals would no longer match the
+/// objects stored in the bucket.
+pub(crate) fn check_bucket_vnode(
+    bucket_vnode: Option<u64>,
+    config: &Config,
+) -> Result<(), BucketsMdapiError> {
+    if config.quota.enabled && bucket_vnode.is_none() {
+        Err(BucketsMdapiError::BadRequestError(
+            "bucket_vnode is required when bucket quotas are enabled"
+                .to_string(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Fail with a `BadRequestError` if `content_type` is not allowed by the
+/// `allowed_content_types` of the server configuration. Types are compared
+/// ignoring case and any parameters, and an allowed type of the form `type/*`
+/// matches every subtype of `type`.
+pub(self) fn check_content_type(
+    content_type: &str,
+    config: &Config,
+) -> Result<(), BucketsMdapiError> {
+    let allowed_types = match &config.server.allowed_content_types {
+        Some(allowed_types) => allowed_types,
+        None => return Ok(()),
+    };
+
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let allowed = allowed_types.iter().any(|allowed_type| {
+        let allowed_type = allowed_type.trim().to_ascii_lowercase();
+        if allowed_type.ends_with("/*") {
+            let type_prefix = &allowed_type[..allowed_type.len() - 1];
+            allowed_type == "*/*" || media_type.starts_with(type_prefix)
+        } else {
+            media_type == allowed_type
+        }
+    });
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(BucketsMdapiError::BadRequestError(format!(
+            "content type '{}' is not allowed",
+            content_type
+        )))
+    }
+}
+
+/// Fail with an `ObjectPinned` error if the object named `name` exists and is
+/// pinned. Checked as part of the transaction of any request that would delete
+/// or replace the object, so that a pinned object never reaches the deleted
+/// object table and its data is never garbage collected.
+pub(self) fn check_not_pinned(
+    mut txn: &mut Transaction,
+    vnode: u64,
+    owner: &Uuid,
+    bucket_id: &Uuid,
+    name: &str,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<(), BucketsMdapiError> {
+    let rows = sql::txn_query(
+        sql::Method::ObjectPinCheck,
+        &mut txn,
+        pinned_sql(vnode).as_str(),
+        &[owner, bucket_id, &name],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    if rows.iter().any(|row| row.get::<_, bool>("pinned")) {
+        let msg = format!("object '{}' is pinned and cannot be removed", name);
+        Err(BucketsMdapiError::ObjectPinned(msg))
+    } else {
+        Ok(())
+    }
+}
+
+fn pinned_sql(vnode: u64) -> String {
+    [
+        "SELECT pinned FROM manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_object \
+         WHERE owner = $1 AND bucket_id = $2 AND name = $3 \
+         FOR UPDATE",
+    ]
+    .concat()
+}
+
+/// Record a mutation of an object in the audit table of the object's vnode as
+/// part of the transaction performing the mutation.
+#[allow(clippy::too_many_arguments)]
+pub(self) fn record_audit_event(
+    mut txn: &mut Transaction,
+    vnode: u64,
+    owner: &Uuid,
+    bucket_id: &Uuid,
+    name: &str,
+    object_id: &Uuid,
+    action: &str,
+    request_id: &Uuid,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<(), BucketsMdapiError> {
+    sql::txn_execute(
+        sql::Method::ObjectAuditRecord,
+        &mut txn,
+        record_audit_event_sql(vnode).as_str(),
+        &[owner, bucket_id, &name, object_id, &action, request_id],
+        metrics,
+        log,
+    )
+    .map(|_| ())
+    .map_err(BucketsMdapiError::from)
+}
+
+fn record_audit_event_sql(vnode: u64) -> String {
+    [
+        "INSERT INTO manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_object_audit \
+         (owner, bucket_id, name, object_id, action, request_id) \
+         VALUES ($1, $2, $3, $4, $5, $6)",
+    ]
+    .concat()
+}
+
+fn update_bucket_totals_sql(bucket_vnode: u64) -> String {
+    [
+        "UPDATE manta_bucket_",
+        &bucket_vnode.to_string(),
+        ".manta_bucket \
+         SET total_bytes = GREATEST(total_bytes + $3, 0), \
+         total_objects = GREATEST(total_objects + $4, 0) \
+         WHERE owner = $1 AND id = $2 \
+         RETURNING total_bytes, max_total_bytes",
+    ]
+    .concat()
+}
+
+/// The parent prefix of an object name: the part of the name up to and
+/// including the last `/`, or the empty string for a name without a `/`.
+pub fn parent_prefix(name: &str) -> &str {
+    match name.rfind('/') {
+        Some(i) => &name[..=i],
+        None => "",
+    }
+}
+
+/// The SQL expression setting the `modified` time of an updated object row,
+/// where `existing` names the row's current `modified` value. When `monotonic`
+/// is set the new time is at least a microsecond later than the existing one,
+/// even if the database clock is behind the clock that wrote the row.
+pub(crate) fn modified_sql(existing: &str, monotonic: bool) -> String {
+    if monotonic {
+        [
+            "GREATEST(current_timestamp, ",
+            existing,
+            " + interval '1 microsecond')",
+        ]
+        .concat()
+    } else {
+        "current_timestamp".to_string()
+    }
+}
+
+/// Fold an object name for storage and comparison. Names are folded to
+/// lowercase when `case_insensitive_names` is configured, so that names
+/// differing only in case refer to the same object.
+pub(crate) fn fold_name(name: &str, config: &Config) -> String {
+    if config.names.case_insensitive_names {
+        name.to_lowercase()
+    } else {
+        name.to_string()
+    }
+}
+
 pub fn get_sql(vnode: u64) -> String {
     [
         "SELECT id, owner, bucket_id, name, created, modified, content_length, \
@@ -233,6 +696,52 @@ pub fn get_sql(vnode: u64) -> String {
     .concat()
 }
 
+/// Rename the object `$3` of the owner `$1` and bucket `$2` to `$4`, returning
+/// the renamed object. The parent prefix of the new name is `$5` when
+/// `parent_prefix` is set, and the display name of the object follows it when
+/// `display_name` is set.
+pub(self) fn rename_sql(
+    vnode: u64,
+    parent_prefix: bool,
+    display_name: bool,
+) -> String {
+    let mut updates = String::from("name = $4");
+    let mut param = 5;
+    if parent_prefix {
+        updates.push_str(&format!(", parent_prefix = ${}", param));
+        param += 1;
+    }
+    let mut returning = "";
+    if display_name {
+        updates.push_str(&format!(", display_name = ${}", param));
+        returning = ", display_name";
+    }
+
+    [
+        "UPDATE manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_object SET ",
+        &updates,
+        " WHERE owner = $1 AND bucket_id = $2 AND name = $3 \
+         RETURNING id, owner, bucket_id, name, created, modified, \
+         content_length, content_md5, content_type, headers, \
+         sharks, properties",
+        returning,
+    ]
+    .concat()
+}
+
+/// Move the indexed metadata entries of the object `$3` to the name `$4`.
+pub(self) fn rename_index_sql(vnode: u64) -> String {
+    [
+        "UPDATE manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_object_index SET name = $4 \
+         WHERE owner = $1 AND bucket_id = $2 AND name = $3",
+    ]
+    .concat()
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
@@ -281,6 +790,10 @@ pub mod test {
             let vnode = u64::arbitrary(g);
             let request_id = Uuid::new_v4();
             let conditions: conditional::Conditions = Default::default();
+            let bucket_vnode = Option::<u64>::arbitrary(g);
+            let if_content_length =
+                Option::<conditional::ContentLengthCondition>::arbitrary(g);
+            let include_properties = bool::arbitrary(g);
 
             GetObjectPayload {
                 owner,
@@ -289,6 +802,10 @@ pub mod test {
                 vnode,
                 request_id,
                 conditions,
+                bucket_vnode,
+                if_content_length,
+                include_properties,
+                unpin_first: bool::arbitrary(g),
             }
         }
     }
@@ -320,6 +837,8 @@ pub mod test {
             };
             let sharks = vec![shark_1, shark_2];
             let properties = None;
+            let vnode = u64::arbitrary(g);
+            let display_name = Option::<String>::arbitrary(g);
 
             ObjectResponse {
                 id,
@@ -334,6 +853,8 @@ pub mod test {
                 headers,
                 sharks,
                 properties,
+                vnode,
+                display_name,
             }
         }
     }
@@ -347,6 +868,10 @@ pub mod test {
                 name: random::string(g, 32),
                 content_length: i64::arbitrary(g),
                 shark_count: i32::arbitrary(g),
+                sharks: vec![StorageNodeIdentifier {
+                    datacenter: random::string(g, 32),
+                    manta_storage_id: random::string(g, 32),
+                }],
             }
         }
     }
@@ -420,4 +945,133 @@ pub mod test {
             true
         }
     }
+
+    // Encode headers in the binary format of an hstore
+    fn hstore_bytes(headers: &[(&[u8], Option<&[u8]>)]) -> Vec<u8> {
+        let mut raw = (headers.len() as i32).to_be_bytes().to_vec();
+        for (name, value) in headers {
+            raw.extend_from_slice(&(name.len() as i32).to_be_bytes());
+            raw.extend_from_slice(name);
+            match value {
+                Some(value) => {
+                    raw.extend_from_slice(&(value.len() as i32).to_be_bytes());
+                    raw.extend_from_slice(value);
+                }
+                None => raw.extend_from_slice(&(-1i32).to_be_bytes()),
+            }
+        }
+        raw
+    }
+
+    #[test]
+    fn stored_headers_fallback() {
+        let raw = hstore_bytes(&[
+            (&b"m-color"[..], Some(&b"blue"[..])),
+            (&b"m-legacy"[..], Some(&b"caf\xe9"[..])),
+            (&b"m-empty"[..], None),
+        ]);
+        let decode = |fallback| {
+            StoredHeaders::from_sql(&Type::TEXT, &raw)
+                .unwrap()
+                .decode(fallback)
+        };
+
+        let (headers, invalid) = decode(HeaderValueFallback::Lossy);
+        assert_eq!(invalid, 1);
+        assert_eq!(headers["m-color"], Some("blue".to_string()));
+        assert_eq!(headers["m-legacy"], Some("caf\u{fffd}".to_string()));
+        assert_eq!(headers["m-empty"], None);
+
+        let (headers, invalid) = decode(HeaderValueFallback::Base64);
+        assert_eq!(invalid, 1);
+        assert_eq!(headers["m-color"], Some("blue".to_string()));
+        assert_eq!(headers["m-legacy"], Some(base64::encode(b"caf\xe9")));
+
+        // A truncated hstore is an error rather than a panic
+        assert!(StoredHeaders::from_sql(&Type::TEXT, &raw[..10]).is_err());
+    }
+
+    #[test]
+    fn monotonic_modified_sql() {
+        assert_eq!(modified_sql("modified", false), "current_timestamp");
+        assert_eq!(
+            modified_sql("modified", true),
+            "GREATEST(current_timestamp, modified + interval '1 microsecond')"
+        );
+    }
+
+    #[test]
+    fn object_parent_prefix() {
+        assert_eq!(parent_prefix("photos/2019/cat.jpg"), "photos/2019/");
+        assert_eq!(parent_prefix("photos/"), "photos/");
+        assert_eq!(parent_prefix("/cat.jpg"), "/");
+        assert_eq!(parent_prefix("cat.jpg"), "");
+        assert_eq!(parent_prefix(""), "");
+    }
+
+    #[test]
+    fn rename_sql_optional_columns() {
+        assert_eq!(
+            rename_sql(2, false, false),
+            "UPDATE manta_bucket_2.manta_bucket_object SET name = $4 \
+             WHERE owner = $1 AND bucket_id = $2 AND name = $3 \
+             RETURNING id, owner, bucket_id, name, created, modified, \
+             content_length, content_md5, content_type, headers, \
+             sharks, properties"
+        );
+        assert_eq!(
+            rename_sql(2, true, true),
+            "UPDATE manta_bucket_2.manta_bucket_object SET name = $4, \
+             parent_prefix = $5, display_name = $6 \
+             WHERE owner = $1 AND bucket_id = $2 AND name = $3 \
+             RETURNING id, owner, bucket_id, name, created, modified, \
+             content_length, content_md5, content_type, headers, \
+             sharks, properties, display_name"
+        );
+        assert!(rename_sql(2, false, true).contains("display_name = $5"));
+    }
+
+    #[test]
+    fn content_type_allowlist() {
+        let mut config = Config::default();
+        assert!(check_content_type("application/x-anything", &config).is_ok());
+
+        config.server.allowed_content_types =
+            Some(vec!["text/plain".into(), "image/*".into()]);
+        let allowed = |content_type: &str| match check_content_type(
+            content_type,
+            &config,
+        ) {
+            Ok(()) => true,
+            Err(BucketsMdapiError::BadRequestError(_)) => false,
+            Err(e) => panic!("unexpected content type error {:?}", e),
+        };
+
+        assert!(allowed("text/plain"));
+        assert!(allowed("Text/Plain; charset=utf-8"));
+        assert!(allowed("image/png"));
+        assert!(allowed("image/svg+xml"));
+        assert!(!allowed("text/html"));
+        assert!(!allowed("text/plainer"));
+        assert!(!allowed("image"));
+        assert!(!allowed("imagery/png"));
+        assert!(!allowed(""));
+
+        config.server.allowed_content_types = Some(vec!["*/*".into()]);
+        assert!(check_content_type("video/mp4", &config).is_ok());
+    }
+
+    #[test]
+    fn bucket_vnode_required_with_quotas() {
+        let mut config = Config::default();
+        assert!(check_bucket_vnode(None, &config).is_ok());
+        assert!(check_bucket_vnode(Some(0), &config).is_ok());
+
+        config.quota.enabled = true;
+        assert!(check_bucket_vnode(Some(0), &config).is_ok());
+        match check_bucket_vnode(None, &config) {
+            Err(BucketsMdapiError::BadRequestError(_)) => (),
+            res => panic!("unexpected bucket_vnode check result {:?}", res),
+        }
+    }
 }
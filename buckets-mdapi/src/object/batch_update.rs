@@ -0,0 +1,292 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+use std::vec::Vec;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::batch::BatchResponse;
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::object::{
+    modified_sql, record_audit_event, response, ObjectResponse,
+};
+use crate::sql;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes, Hstore};
+use crate::util::{array_wrap, limit_constraint_error};
+
+/// The maximum number of objects that may be updated by a single request.
+pub const MAX_BATCH_UPDATE_SIZE: usize = 1024;
+
+/// Update the headers of many objects of a bucket in a single transaction.
+/// Every object named in `updates` must reside in `vnode`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BatchUpdateObjectsPayload {
+    pub owner: Uuid,
+    pub bucket_id: Uuid,
+    pub vnode: u64,
+    pub request_id: Uuid,
+    pub updates: Vec<HeaderUpdate>,
+}
+
+/// A merge patch of the headers of a single object. Each header in `headers`
+/// with a value is added to the object or replaces the existing value of that
+/// header, and each header with a `null` value is removed from the object.
+/// Headers of the object that are not named in the patch are left unchanged.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct HeaderUpdate {
+    pub name: String,
+    pub headers: Hstore,
+}
+
+impl HasRequestId for BatchUpdateObjectsPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for BatchUpdateObjectsPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<BatchUpdateObjectsPayload>, SerdeError> {
+    serde_json::from_value::<Vec<BatchUpdateObjectsPayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: BatchUpdateObjectsPayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    let batch_size = payload.updates.len();
+    if batch_size == 0 || batch_size > MAX_BATCH_UPDATE_SIZE {
+        // Limit constraint violations are returned to as regular
+        // Fast messages to be handled by the calling application
+        let e = format!(
+            "the {} updates must contain between 1 and {} objects. \
+             the request contained {}",
+            &method, MAX_BATCH_UPDATE_SIZE, batch_size
+        );
+        let value = limit_constraint_error(e);
+        let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+        let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+        return Ok(msg);
+    }
+
+    // Make database request
+    do_batch_update(method, &payload, config, conn, metrics, log)
+        .and_then(|resp| {
+            // Handle the successful database response
+            debug!(log, "operation successful";
+                "succeeded" => resp.succeeded.len(),
+                "failed" => resp.failed.len(),
+            );
+            let msg_data = FastMessageData::new(
+                method.into(),
+                array_wrap(resp.into_value()),
+            );
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+        .or_else(|e| {
+            if let BucketsMdapiError::PostgresError { .. } = &e {
+                error!(log, "operation failed"; "error" => e.message());
+            }
+
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+/// Split a header merge patch into the headers to set and the names of the
+/// headers to remove.
+fn split_patch(patch: &Hstore) -> (Hstore, Vec<String>) {
+    let mut set = Hstore::new();
+    let mut remove = Vec::new();
+
+    for (key, value) in patch {
+        match value {
+            Some(_) => {
+                let _ = set.insert(key.clone(), value.clone());
+            }
+            None => remove.push(key.clone()),
+        }
+    }
+
+    (set, remove)
+}
+
+fn do_batch_update(
+    method: &str,
+    payload: &BatchUpdateObjectsPayload,
+    config: &Config,
+    conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<BatchResponse<ObjectResponse>, BucketsMdapiError> {
+    let mut txn = (*conn).transaction().map_err(BucketsMdapiError::from)?;
+    let update_sql =
+        update_headers_sql(payload.vnode, config.timestamps.monotonic_modified);
+    let mut results = Vec::with_capacity(payload.updates.len());
+
+    // An object that does not exist is reported as a failure of that item,
+    // but a database error aborts the transaction and fails the whole batch.
+    for update in &payload.updates {
+        let (set, remove) = split_patch(&update.headers);
+        let rows = sql::txn_query(
+            sql::Method::ObjectUpdate,
+            &mut txn,
+            update_sql.as_str(),
+            &[
+                &set,
+                &remove,
+                &payload.owner,
+                &payload.bucket_id,
+                &update.name,
+            ],
+            metrics,
+            log,
+        )
+        .map_err(BucketsMdapiError::from)?;
+
+        sql::check_mutation_width(
+            sql::Method::ObjectUpdate,
+            rows.len(),
+            config.server.wide_mutation_rows,
+            metrics,
+            log,
+        );
+
+        if config.audit.enabled && !rows.is_empty() {
+            record_audit_event(
+                &mut txn,
+                payload.vnode,
+                &payload.owner,
+                &payload.bucket_id,
+                &update.name,
+                &rows[0].get("id"),
+                "update",
+                &payload.request_id,
+                metrics,
+                log,
+            )?;
+        }
+
+        results.push(
+            response(method, payload.vnode, &rows, config, metrics, log)?
+                .ok_or(BucketsMdapiError::ObjectNotFound),
+        );
+    }
+
+    txn.commit().map_err(BucketsMdapiError::from)?;
+
+    Ok(BatchResponse::from_results(results))
+}
+
+fn update_headers_sql(vnode: u64, monotonic_modified: bool) -> String {
+    [
+        "UPDATE manta_bucket_",
+        &vnode.to_string(),
+        &".manta_bucket_object \
+       SET headers = delete(COALESCE(headers, ''::hstore) || $1, $2::text[]), \
+       modified = ",
+        &modified_sql("modified", monotonic_modified),
+        " WHERE owner = $3 \
+       AND bucket_id = $4 \
+       AND name = $5 \
+       RETURNING id, owner, bucket_id, name, created, modified, \
+       content_length, content_md5, content_type, headers, \
+       sharks, properties",
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use quickcheck_helpers::random;
+    use serde_json::json;
+
+    impl Arbitrary for HeaderUpdate {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let mut headers = Hstore::new();
+            let _ = headers
+                .insert(random::string(g, 32), Some(random::string(g, 32)));
+            let _ = headers.insert(random::string(g, 32), None);
+
+            HeaderUpdate {
+                name: random::string(g, 32),
+                headers,
+            }
+        }
+    }
+
+    impl Arbitrary for BatchUpdateObjectsPayload {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            BatchUpdateObjectsPayload {
+                owner: Uuid::new_v4(),
+                bucket_id: Uuid::new_v4(),
+                vnode: u64::arbitrary(g),
+                request_id: Uuid::new_v4(),
+                updates: Vec::<HeaderUpdate>::arbitrary(g),
+            }
+        }
+    }
+
+    #[test]
+    fn split_header_patch() {
+        let patch: Hstore = serde_json::from_value(json!({
+            "m-brand": "new",
+            "m-color": "blue",
+            "m-legacy": null,
+        }))
+        .unwrap();
+
+        let (set, remove) = split_patch(&patch);
+
+        assert_eq!(set.len(), 2);
+        assert_eq!(set["m-brand"], Some("new".into()));
+        assert_eq!(set["m-color"], Some("blue".into()));
+        assert_eq!(remove, vec![String::from("m-legacy")]);
+    }
+
+    quickcheck! {
+        fn prop_batch_update_payload_roundtrip(
+            msg: BatchUpdateObjectsPayload
+        ) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(update_str) => {
+                    let decode_result: Result<BatchUpdateObjectsPayload, _> =
+                        serde_json::from_str(&update_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+}
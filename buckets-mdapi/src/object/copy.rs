@@ -0,0 +1,284 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Copy the metadata of an object to a new object.
+//!
+//! A client duplicating an object within a shard would otherwise have to read
+//! the source object and send its metadata back in a `createobject` request.
+//! The `copyobject` RPC creates the destination object from the source object
+//! stored in the same vnode. The new object has a fresh id and refers to the
+//! same data on the same sharks as the source object. The destination is
+//! created exactly as by `createobject`, so an existing destination object is
+//! replaced and the `conditions` of the request apply to it.
+
+use std::vec::Vec;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::conditional;
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::object::create::{self, CreateObjectPayload, CreateObjectResponse};
+use crate::object::get;
+use crate::object::index::IndexedMetadata;
+use crate::object::{
+    check_bucket_vnode, fold_name, GetObjectPayload, ObjectResponse,
+};
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
+use crate::util::array_wrap;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CopyObjectPayload {
+    pub owner: Uuid,
+    pub source_bucket_id: Uuid,
+    pub source_name: String,
+    pub dest_bucket_id: Uuid,
+    pub dest_name: String,
+    pub vnode: u64,
+    pub request_id: Uuid,
+
+    /// Conditions on the destination object, evaluated as for a
+    /// `createobject` request.
+    #[serde(default)]
+    pub conditions: conditional::Conditions,
+
+    /// The vnode holding the metadata of the destination bucket, checked and
+    /// updated as the `bucket_vnode` of a `createobject` request when bucket
+    /// quotas are enabled.
+    #[serde(default)]
+    pub bucket_vnode: Option<u64>,
+}
+
+impl HasRequestId for CopyObjectPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for CopyObjectPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        std::iter::once(self.vnode)
+            .chain(self.bucket_vnode)
+            .collect()
+    }
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<CopyObjectPayload>, SerdeError> {
+    serde_json::from_value::<Vec<CopyObjectPayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: CopyObjectPayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    // Make database request
+    do_copy(method, &payload, config, conn, metrics, log)
+        .and_then(|resp| {
+            // Handle the successful database response
+            debug!(log, "operation successful");
+            let value = array_wrap(resp.to_json());
+            let msg_data = FastMessageData::new(method.into(), value);
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+        .or_else(|e| {
+            if let BucketsMdapiError::PostgresError { .. } = &e {
+                error!(log, "operation failed"; "error" => e.message());
+            }
+
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+fn do_copy(
+    method: &str,
+    payload: &CopyObjectPayload,
+    config: &Config,
+    conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<CreateObjectResponse, BucketsMdapiError> {
+    check_bucket_vnode(payload.bucket_vnode, config)?;
+
+    // The properties of the source object are read whether they are stored in
+    // the object row or not, and are stored for the new object according to
+    // their size as for any other created object
+    let source_payload = GetObjectPayload {
+        owner: payload.owner,
+        bucket_id: payload.source_bucket_id,
+        name: fold_name(&payload.source_name, config),
+        vnode: payload.vnode,
+        request_id: payload.request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: true,
+        unpin_first: false,
+    };
+    let source =
+        get::do_get(method, &source_payload, None, config, conn, metrics, log)?;
+
+    let display_name = if config.names.case_insensitive_names {
+        Some(payload.dest_name.clone())
+    } else {
+        None
+    };
+    let dest_payload = dest_payload(payload, source, config);
+
+    create::do_create(
+        method,
+        &dest_payload,
+        &display_name,
+        config,
+        conn,
+        metrics,
+        log,
+    )?
+    .ok_or_else(|| {
+        BucketsMdapiError::postgres_error(
+            "Create statement failed to return any results".to_string(),
+        )
+    })
+}
+
+// The create request for the copy of `source`. Indexed metadata entries are
+// not copied, and the bucket totals maintained for quotas are not updated.
+fn dest_payload(
+    payload: &CopyObjectPayload,
+    source: ObjectResponse,
+    config: &Config,
+) -> CreateObjectPayload {
+    CreateObjectPayload {
+        owner: payload.owner,
+        bucket_id: payload.dest_bucket_id,
+        name: fold_name(&payload.dest_name, config),
+        id: Uuid::new_v4(),
+        vnode: payload.vnode,
+        content_length: source.content_length,
+        content_md5: source.content_md5,
+        content_type: source.content_type,
+        headers: source.headers,
+        sharks: source.sharks,
+        properties: source.properties,
+        request_id: payload.request_id,
+        conditions: payload.conditions.clone(),
+        bucket_vnode: payload.bucket_vnode,
+        indexed_metadata: IndexedMetadata::new(),
+        dry_run: false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use chrono::Utc;
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use quickcheck_helpers::random;
+
+    use crate::object::StorageNodeIdentifier;
+
+    impl Arbitrary for CopyObjectPayload {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            CopyObjectPayload {
+                owner: Uuid::new_v4(),
+                source_bucket_id: Uuid::new_v4(),
+                source_name: random::string(g, 32),
+                dest_bucket_id: Uuid::new_v4(),
+                dest_name: random::string(g, 32),
+                vnode: u64::arbitrary(g),
+                request_id: Uuid::new_v4(),
+                conditions: Default::default(),
+                bucket_vnode: Option::<u64>::arbitrary(g),
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_copy_object_payload_roundtrip(msg: CopyObjectPayload) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(copy_str) => {
+                    let decode_result: Result<CopyObjectPayload, _> =
+                        serde_json::from_str(&copy_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+
+    #[test]
+    fn dest_payload_copies_source_metadata() {
+        let mut payload = CopyObjectPayload {
+            owner: Uuid::new_v4(),
+            source_bucket_id: Uuid::new_v4(),
+            source_name: "src".into(),
+            dest_bucket_id: Uuid::new_v4(),
+            dest_name: "Dest".into(),
+            vnode: 3,
+            request_id: Uuid::new_v4(),
+            conditions: Default::default(),
+            bucket_vnode: Some(7),
+        };
+        payload.conditions.if_none_match = Some(vec!["*".into()]);
+
+        let mut headers = HashMap::new();
+        let _ = headers.insert("m-color".to_string(), Some("blue".into()));
+        let source = ObjectResponse {
+            id: Uuid::new_v4(),
+            owner: payload.owner,
+            bucket_id: payload.source_bucket_id,
+            name: "src".into(),
+            created: Utc::now(),
+            modified: Utc::now(),
+            content_length: 5,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers,
+            sharks: vec![StorageNodeIdentifier {
+                datacenter: "us-east-1".into(),
+                manta_storage_id: "1.stor.us-east.joyent.us".into(),
+            }],
+            properties: None,
+            vnode: 3,
+            display_name: None,
+        };
+
+        let config = Config::default();
+        let dest = dest_payload(&payload, source.clone(), &config);
+        assert_ne!(dest.id, source.id);
+        assert_eq!(dest.bucket_id, payload.dest_bucket_id);
+        assert_eq!(dest.name, "Dest");
+        assert_eq!(dest.content_length, source.content_length);
+        assert_eq!(dest.content_md5, source.content_md5);
+        assert_eq!(dest.headers, source.headers);
+        assert_eq!(dest.sharks, source.sharks);
+        assert_eq!(dest.conditions, payload.conditions);
+        assert_eq!(dest.bucket_vnode, Some(7));
+    }
+}
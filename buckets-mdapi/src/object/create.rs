@@ -1,9 +1,12 @@
 // Copyright 2020 Joyent, Inc.
 // Copyright 2023 MNX Cloud, Inc.
 
+use std::marker::Sync;
 use std::vec::Vec;
 
 use base64;
+use postgres::types::ToSql;
+use postgres::Transaction;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
 use serde_json::Value;
@@ -12,17 +15,22 @@ use uuid::Uuid;
 
 use cueball_postgres_connection::PostgresConnection;
 use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
 
 use crate::conditional;
 use crate::error::BucketsMdapiError;
 use crate::metrics::RegisteredMetrics;
+use crate::object::index::{self, IndexedMetadata};
+use crate::object::properties;
 use crate::object::{
-    insert_delete_table_sql, response, to_json, ObjectResponse,
-    StorageNodeIdentifier,
+    check_bucket_vnode, check_content_type, check_not_pinned, fold_name,
+    get_sql, insert_delete_table_sql, modified_sql, parent_prefix,
+    record_audit_event, response, update_bucket_totals, ObjectResponse,
+    StorageNodeIdentifier, CONTENT_MD5_LEN,
 };
 use crate::sql;
-use crate::types::{HandlerResponse, HasRequestId, Hstore};
-use crate::util::array_wrap;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes, Hstore};
+use crate::util::{array_wrap, check_name_length};
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct CreateObjectPayload {
@@ -41,6 +49,52 @@ pub struct CreateObjectPayload {
 
     #[serde(default)]
     pub conditions: conditional::Conditions,
+
+    /// The vnode holding the metadata of the object's bucket. When bucket
+    /// quotas are enabled this field is required and the request is checked
+    /// against the bucket's `max_total_bytes`.
+    #[serde(default)]
+    pub bucket_vnode: Option<u64>,
+
+    /// Typed metadata entries that `listobjects` can filter on. These replace
+    /// any entries of an object being overwritten.
+    #[serde(default)]
+    pub indexed_metadata: IndexedMetadata,
+
+    /// Make every check of the create, including its conditions, without
+    /// creating the object. The response describes the object that would have
+    /// been created, and has `dry_run` set.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// The response to a `createobject` request: the created object, along with
+/// the outcome of the create, which has no meaning for the other requests that
+/// return an object.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CreateObjectResponse {
+    #[serde(flatten)]
+    pub object: ObjectResponse,
+
+    /// Whether the create replaced an existing object and moved it to the
+    /// deleted object table to have its data garbage collected. An existing
+    /// object without any content is replaced without being moved.
+    #[serde(default)]
+    pub moved_to_garbage: bool,
+
+    /// Whether the request set `dry_run`, in which case the object was not
+    /// created.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl CreateObjectResponse {
+    pub fn to_json(&self) -> Value {
+        // As for ObjectResponse, the only maps this type contains have string
+        // keys, and the JSON roundtrip quickcheck test verifies it serializes
+        serde_json::to_value(self)
+            .expect("failed to serialize CreateObjectResponse")
+    }
 }
 
 impl HasRequestId for CreateObjectPayload {
@@ -49,23 +103,54 @@ impl HasRequestId for CreateObjectPayload {
     }
 }
 
+impl HasVnodes for CreateObjectPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        std::iter::once(self.vnode)
+            .chain(self.bucket_vnode)
+            .collect()
+    }
+}
+
 pub(crate) fn decode_msg(
     value: &Value,
 ) -> Result<Vec<CreateObjectPayload>, SerdeError> {
     serde_json::from_value::<Vec<CreateObjectPayload>>(value.clone())
 }
 
-#[allow(clippy::needless_pass_by_value)]
+#[allow(clippy::needless_pass_by_value, clippy::cast_precision_loss)]
 pub(crate) fn action(
     msg_id: u32,
     method: &str,
+    config: &Config,
     metrics: &RegisteredMetrics,
     log: &Logger,
     payload: CreateObjectPayload,
     conn: &mut PostgresConnection,
 ) -> Result<HandlerResponse, String> {
+    // Reject a malformed content_md5 before any database work is started
+    if let Err(e) = validate_content_md5(&payload.content_md5) {
+        debug!(log, "invalid content_md5"; "error" => e.message());
+        let msg_data =
+            FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+        let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+        return Ok(msg);
+    }
+
+    let (payload, display_name) = fold_payload(payload, config);
+
     // Make database request
-    do_create(method, &payload, conn, metrics, log)
+    check_bucket_vnode(payload.bucket_vnode, config)
+        .and_then(|_| {
+            do_create(
+                method,
+                &payload,
+                &display_name,
+                config,
+                conn,
+                metrics,
+                log,
+            )
+        })
         .and_then(|maybe_resp| {
             // Handle the successful database response
             debug!(log, "operation successful");
@@ -77,7 +162,14 @@ pub(crate) fn action(
             // possible, but for completeleness we include a check for
             // the condition.
             let value = match maybe_resp {
-                Some(resp) => to_json(resp),
+                Some(resp) => {
+                    if !resp.dry_run {
+                        metrics
+                            .object_create_content_length
+                            .observe(payload.content_length as f64);
+                    }
+                    resp.to_json()
+                }
                 None => object_create_failed(),
             };
             let msg_data =
@@ -87,7 +179,7 @@ pub(crate) fn action(
             Ok(msg)
         })
         .or_else(|e| {
-            if let BucketsMdapiError::PostgresError(_) = &e {
+            if let BucketsMdapiError::PostgresError { .. } = &e {
                 error!(log, "operation failed"; "error" => e.message());
             }
 
@@ -99,31 +191,159 @@ pub(crate) fn action(
         })
 }
 
-fn do_create(
+/// Check that the `content_md5` of an object to create is a base64 encoded MD5
+/// digest.
+pub(crate) fn validate_content_md5(
+    content_md5: &str,
+) -> Result<(), BucketsMdapiError> {
+    let bytes = base64::decode(content_md5).map_err(|e| {
+        BucketsMdapiError::BadRequestError(format!(
+            "content_md5 is not valid base64 encoded data: {}",
+            e
+        ))
+    })?;
+
+    if bytes.len() == CONTENT_MD5_LEN {
+        Ok(())
+    } else {
+        Err(BucketsMdapiError::BadRequestError(format!(
+            "content_md5 is {} bytes, but an MD5 digest is {} bytes",
+            bytes.len(),
+            CONTENT_MD5_LEN
+        )))
+    }
+}
+
+/// Fold the name of the object to create for storage when names are
+/// case-insensitive, returning the folded payload and the name as given to be
+/// kept as the object's display name.
+pub(crate) fn fold_payload(
+    payload: CreateObjectPayload,
+    config: &Config,
+) -> (CreateObjectPayload, Option<String>) {
+    let display_name = if config.names.case_insensitive_names {
+        Some(payload.name.clone())
+    } else {
+        None
+    };
+    let payload = CreateObjectPayload {
+        name: fold_name(&payload.name, config),
+        ..payload
+    };
+    (payload, display_name)
+}
+
+pub(crate) fn do_create(
     method: &str,
     payload: &CreateObjectPayload,
+    display_name: &Option<String>,
+    config: &Config,
     conn: &mut PostgresConnection,
     metrics: &RegisteredMetrics,
     log: &Logger,
-) -> Result<Option<ObjectResponse>, BucketsMdapiError> {
-    let mut txn = (*conn)
-        .transaction()
-        .map_err(|e| BucketsMdapiError::PostgresError(e.to_string()))?;
-    let create_sql = create_sql(payload.vnode);
+) -> Result<Option<CreateObjectResponse>, BucketsMdapiError> {
+    sql::txn_with_retries(
+        sql::Method::ObjectCreate,
+        conn,
+        &config.database,
+        metrics,
+        log,
+        |txn| {
+            create_in_txn(
+                txn,
+                method,
+                payload,
+                display_name,
+                config,
+                metrics,
+                log,
+            )
+        },
+    )
+}
+
+/// Create the object described by `payload` as part of `txn`, which the caller
+/// must commit.
+pub(crate) fn create_in_txn(
+    mut txn: &mut Transaction,
+    method: &str,
+    payload: &CreateObjectPayload,
+    display_name: &Option<String>,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<Option<CreateObjectResponse>, BucketsMdapiError> {
+    // A dry run creates the object in a savepoint that is then rolled back, so
+    // that it fails exactly where a create would without writing anything
+    if payload.dry_run {
+        let mut savepoint =
+            txn.transaction().map_err(BucketsMdapiError::from)?;
+        let create_payload = CreateObjectPayload {
+            dry_run: false,
+            ..payload.clone()
+        };
+        let maybe_resp = create_in_txn(
+            &mut savepoint,
+            method,
+            &create_payload,
+            display_name,
+            config,
+            metrics,
+            log,
+        )?;
+        savepoint.rollback().map_err(BucketsMdapiError::from)?;
+        return Ok(maybe_resp.map(|resp| CreateObjectResponse {
+            dry_run: true,
+            ..resp
+        }));
+    }
+
+    let store_parent_prefix = config.listing.parent_prefix;
+    let store_display_name = config.names.case_insensitive_names;
+    let create_sql = create_sql(
+        payload.vnode,
+        store_parent_prefix,
+        store_display_name,
+        config.timestamps.monotonic_modified,
+    );
     let move_sql = insert_delete_table_sql(payload.vnode);
+    let max_inline_bytes = config.properties.max_inline_bytes;
+    let (inline_properties, external_properties) =
+        properties::split(&payload.properties, max_inline_bytes);
+    let mut moved_to_garbage = false;
     let content_md5_bytes = base64::decode(&payload.content_md5)
         .map_err(|e| BucketsMdapiError::ContentMd5Error(e.to_string()))?;
 
+    if let Some(known_datacenters) = &config.placement.known_datacenters {
+        check_datacenters(&payload.sharks, known_datacenters)?;
+    }
+    check_content_type(&payload.content_type, config)?;
+    check_name_length(&payload.name, config)?;
+
     conditional::request(
         &mut txn,
         &[&payload.owner, &payload.bucket_id, &payload.name],
         payload.vnode,
         &payload.conditions,
+        config,
         metrics,
         log,
     )
     .and_then(|_| {
-        sql::txn_execute(
+        // A pinned object is never replaced, as that would move it to the
+        // deleted object table
+        check_not_pinned(
+            &mut txn,
+            payload.vnode,
+            &payload.owner,
+            &payload.bucket_id,
+            &payload.name,
+            metrics,
+            log,
+        )
+    })
+    .and_then(|_| {
+        sql::txn_query(
             sql::Method::ObjectCreateMove,
             &mut txn,
             move_sql.as_str(),
@@ -131,66 +351,243 @@ fn do_create(
             metrics,
             log,
         )
-        .and_then(|_moved_rows| {
-            sql::txn_query(
-                sql::Method::ObjectCreate,
-                &mut txn,
-                create_sql.as_str(),
-                &[
-                    &payload.id,
+        .map_err(BucketsMdapiError::from)
+    })
+    .and_then(|moved_rows| {
+        moved_to_garbage = !moved_rows.is_empty();
+
+        // Any object being replaced no longer counts against the bucket
+        // quota, so only the difference in size is applied to the total, and
+        // the object count only grows when no object is replaced.
+        match payload.bucket_vnode {
+            Some(bucket_vnode) if config.quota.enabled => {
+                let replaced_bytes: i64 = moved_rows
+                    .iter()
+                    .map(|row| row.get::<_, i64>("content_length"))
+                    .sum();
+                let added_objects =
+                    if object_exists(&mut txn, payload, metrics, log)? {
+                        0
+                    } else {
+                        1
+                    };
+                update_bucket_totals(
+                    &mut txn,
                     &payload.owner,
                     &payload.bucket_id,
-                    &payload.name,
-                    &payload.content_length,
-                    &content_md5_bytes,
-                    &payload.content_type,
-                    &payload.headers,
-                    &payload.sharks,
-                    &payload.properties,
-                ],
+                    bucket_vnode,
+                    payload.content_length - replaced_bytes,
+                    added_objects,
+                    metrics,
+                    log,
+                )
+            }
+            _ => Ok(()),
+        }
+    })
+    .and_then(|_| {
+        // The external properties of any object being replaced are removed
+        // before the object row takes the id of the new object
+        if max_inline_bytes.is_some() {
+            properties::delete(
+                &mut txn,
+                payload.vnode,
+                &payload.owner,
+                &payload.bucket_id,
+                &payload.name,
                 metrics,
                 log,
-            )
-        })
-        .map_err(|e| BucketsMdapiError::PostgresError(e.to_string()))
+            )?;
+        }
+        Ok(())
+    })
+    .and_then(|_| {
+        let prefix = parent_prefix(&payload.name);
+        let mut items: Vec<&(dyn ToSql + Sync)> = vec![
+            &payload.id,
+            &payload.owner,
+            &payload.bucket_id,
+            &payload.name,
+            &payload.content_length,
+            &content_md5_bytes,
+            &payload.content_type,
+            &payload.headers,
+            &payload.sharks,
+            &inline_properties,
+        ];
+        if store_parent_prefix {
+            items.push(&prefix);
+        }
+        if store_display_name {
+            items.push(display_name);
+        }
+
+        sql::txn_query(
+            sql::Method::ObjectCreate,
+            &mut txn,
+            create_sql.as_str(),
+            &items,
+            metrics,
+            log,
+        )
+        .map_err(BucketsMdapiError::from)
     })
     .and_then(|rows| {
-        txn.commit()
-            .map_err(|e| BucketsMdapiError::PostgresError(e.to_string()))?;
+        match external_properties {
+            Some(value) if !rows.is_empty() => {
+                properties::insert(
+                    &mut txn,
+                    payload.vnode,
+                    &payload.id,
+                    value,
+                    metrics,
+                    log,
+                )?;
+            }
+            _ => (),
+        }
         Ok(rows)
     })
-    .and_then(|rows| response(method, &rows))
+    .and_then(|rows| {
+        if !rows.is_empty() {
+            index::replace_entries(
+                &mut txn,
+                payload.vnode,
+                &payload.owner,
+                &payload.bucket_id,
+                &payload.name,
+                &payload.indexed_metadata,
+                metrics,
+                log,
+            )?;
+        }
+        Ok(rows)
+    })
+    .and_then(|rows| {
+        if config.audit.enabled && !rows.is_empty() {
+            record_audit_event(
+                &mut txn,
+                payload.vnode,
+                &payload.owner,
+                &payload.bucket_id,
+                &payload.name,
+                &payload.id,
+                "create",
+                &payload.request_id,
+                metrics,
+                log,
+            )?;
+        }
+        Ok(rows)
+    })
+    .and_then(|rows| {
+        response(method, payload.vnode, &rows, config, metrics, log)
+    })
+    .map(|maybe_resp| {
+        maybe_resp.map(|object| CreateObjectResponse {
+            object,
+            moved_to_garbage,
+            dry_run: false,
+        })
+    })
 }
 
-fn create_sql(vnode: u64) -> String {
-    [
-        "INSERT INTO manta_bucket_",
-        &vnode.to_string(),
-        &".manta_bucket_object ( \
-          id, owner, bucket_id, name, content_length, content_md5, \
-          content_type, headers, sharks, properties) \
-          VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) \
-          ON CONFLICT (owner, bucket_id, name) DO UPDATE \
-          SET id = EXCLUDED.id, \
-          created = current_timestamp, \
-          modified = current_timestamp, \
-          content_length = EXCLUDED.content_length, \
-          content_md5 = EXCLUDED.content_md5, \
-          content_type = EXCLUDED.content_type, \
-          headers = EXCLUDED.headers, \
-          sharks = EXCLUDED.sharks, \
-          properties = EXCLUDED.properties \
-          RETURNING id, owner, bucket_id, name, created, modified, \
-          content_length, content_md5, content_type, headers, \
-          sharks, properties",
-    ]
-    .concat()
+// Check whether the object being created replaces an existing object. Only
+// objects with content are moved to the deleted object table when replaced,
+// so this can not be determined from the moved rows.
+fn object_exists(
+    mut txn: &mut Transaction,
+    payload: &CreateObjectPayload,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<bool, BucketsMdapiError> {
+    sql::txn_query(
+        sql::Method::ObjectGet,
+        &mut txn,
+        get_sql(payload.vnode).as_str(),
+        &[&payload.owner, &payload.bucket_id, &payload.name],
+        metrics,
+        log,
+    )
+    .map(|rows| !rows.is_empty())
+    .map_err(BucketsMdapiError::from)
+}
+
+// Verify that every shark is in one of the known datacenters
+fn check_datacenters(
+    sharks: &[StorageNodeIdentifier],
+    known_datacenters: &[String],
+) -> Result<(), BucketsMdapiError> {
+    match sharks
+        .iter()
+        .find(|shark| !known_datacenters.contains(&shark.datacenter))
+    {
+        Some(shark) => Err(BucketsMdapiError::UnknownDatacenter(
+            shark.datacenter.clone(),
+        )),
+        None => Ok(()),
+    }
+}
+
+// The parent prefix of the object is stored as the eleventh parameter when
+// `parent_prefix` is set, and the display name of the object follows it when
+// `display_name` is set. The `modified` time of an object being replaced only
+// moves forward when `monotonic_modified` is set.
+fn create_sql(
+    vnode: u64,
+    parent_prefix: bool,
+    display_name: bool,
+    monotonic_modified: bool,
+) -> String {
+    let mut columns = String::new();
+    let mut values = String::new();
+    let mut updates = String::new();
+    let mut returning = "";
+    let mut param = 11;
+    if parent_prefix {
+        columns.push_str(", parent_prefix");
+        values.push_str(&format!(", ${}", param));
+        updates.push_str(", parent_prefix = EXCLUDED.parent_prefix");
+        param += 1;
+    }
+    if display_name {
+        columns.push_str(", display_name");
+        values.push_str(&format!(", ${}", param));
+        updates.push_str(", display_name = EXCLUDED.display_name");
+        returning = ", display_name";
+    }
+
+    format!(
+        "INSERT INTO manta_bucket_{}.manta_bucket_object ( \
+         id, owner, bucket_id, name, content_length, content_md5, \
+         content_type, headers, sharks, properties{}) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10{}) \
+         ON CONFLICT (owner, bucket_id, name) DO UPDATE \
+         SET id = EXCLUDED.id, \
+         created = current_timestamp, \
+         modified = {}, \
+         content_length = EXCLUDED.content_length, \
+         content_md5 = EXCLUDED.content_md5, \
+         content_type = EXCLUDED.content_type, \
+         headers = EXCLUDED.headers, \
+         sharks = EXCLUDED.sharks, \
+         properties = EXCLUDED.properties{} \
+         RETURNING id, owner, bucket_id, name, created, modified, \
+         content_length, content_md5, content_type, headers, \
+         sharks, properties{}",
+        vnode,
+        columns,
+        values,
+        modified_sql("manta_bucket_object.modified", monotonic_modified),
+        updates,
+        returning
+    )
 }
 
 // This error is only here for completeness. In practice it should never
 // actually be called. See the invocation in this module for more information.
 fn object_create_failed() -> Value {
-    serde_json::to_value(BucketsMdapiError::PostgresError(
+    serde_json::to_value(BucketsMdapiError::postgres_error(
         "Create statement failed to return any results".to_string(),
     ))
     .expect("failed to encode a PostgresError error")
@@ -294,6 +691,13 @@ mod test {
             let properties = None;
             let request_id = Uuid::new_v4();
             let conditions: conditional::Conditions = Default::default();
+            let bucket_vnode = Option::<u64>::arbitrary(g);
+            let mut indexed_metadata = IndexedMetadata::new();
+            let _ = indexed_metadata.insert(
+                random::string(g, 16),
+                index::IndexedValue::arbitrary(g),
+            );
+            let dry_run = bool::arbitrary(g);
 
             CreateObjectPayload {
                 owner,
@@ -309,10 +713,30 @@ mod test {
                 properties,
                 request_id,
                 conditions,
+                bucket_vnode,
+                indexed_metadata,
+                dry_run,
+            }
+        }
+    }
+
+    impl Arbitrary for CreateObjectResponse {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            CreateObjectResponse {
+                object: ObjectResponse::arbitrary(g),
+                moved_to_garbage: bool::arbitrary(g),
+                dry_run: bool::arbitrary(g),
             }
         }
     }
 
+    quickcheck! {
+        fn prop_create_object_response_roundtrip(resp: CreateObjectResponse) -> bool {
+            serde_json::from_value::<CreateObjectResponse>(resp.to_json())
+                .map_or(false, |decoded_resp| decoded_resp == resp)
+        }
+    }
+
     quickcheck! {
         fn prop_create_object_payload_roundtrip(msg: CreateObjectPayload) -> bool {
             match serde_json::to_string(&msg) {
@@ -342,4 +766,62 @@ mod test {
             res1 && res2
         }
     }
+
+    #[test]
+    fn check_known_datacenters() {
+        let known = vec![String::from("us-east-1"), String::from("us-east-2")];
+        let shark = |dc: &str| StorageNodeIdentifier {
+            datacenter: dc.into(),
+            manta_storage_id: "1.stor.us-east.joyent.us".into(),
+        };
+
+        assert!(check_datacenters(&[], &known).is_ok());
+        assert!(check_datacenters(
+            &[shark("us-east-1"), shark("us-east-2")],
+            &known
+        )
+        .is_ok());
+
+        let res = check_datacenters(
+            &[shark("us-east-1"), shark("us-west-1")],
+            &known,
+        );
+        assert_eq!(
+            res,
+            Err(BucketsMdapiError::UnknownDatacenter("us-west-1".into()))
+        );
+    }
+
+    #[test]
+    fn create_sql_optional_columns() {
+        let sql = create_sql(0, true, true, false);
+        assert!(sql.contains(", parent_prefix, display_name)"));
+        assert!(sql.contains("$10, $11, $12)"));
+        assert!(sql.ends_with("sharks, properties, display_name"));
+
+        // The display name takes the place of the parent prefix when it is
+        // the only optional column stored
+        let sql = create_sql(0, false, true, false);
+        assert!(sql.contains(", display_name)"));
+        assert!(sql.contains("$10, $11)"));
+        assert!(!sql.contains("parent_prefix"));
+    }
+
+    #[test]
+    fn validate_content_md5_lengths() {
+        assert!(validate_content_md5("xzY5jJbR9rcrMRhlcmi/8g==").is_ok());
+
+        let bad_request =
+            |content_md5: &str| match validate_content_md5(content_md5) {
+                Err(BucketsMdapiError::BadRequestError(_)) => true,
+                _ => false,
+            };
+        // Empty
+        assert!(bad_request(""));
+        // Not base64
+        assert!(bad_request("not an md5 digest!"));
+        // Valid base64 of the wrong length
+        assert!(bad_request("AQI="));
+        assert!(bad_request("xzY5jJbR9rcrMRhlcmi/8gAA"));
+    }
 }
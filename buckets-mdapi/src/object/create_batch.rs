@@ -0,0 +1,294 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Create many objects with a single Fast request.
+//!
+//! The `createobjectbatch` RPC takes one `createobject` payload for each
+//! object, as the elements of the data array of the Fast message, and creates
+//! each object as `createobject` would. The response has one message for each
+//! object, in request order: the created object, or the error that prevented
+//! it from being created.
+//!
+//! The objects are created in a single transaction. The vnodes of the objects
+//! need not be the same, but must all be served by the same database. When
+//! `batch_create_atomic` is not set each object is created within a savepoint
+//! of the transaction, so an object that cannot be created is rolled back
+//! alone and the other objects of the batch are still created. When it is set
+//! the first object that cannot be created rolls back the whole batch, and the
+//! response is a single `BatchAborted` error.
+
+use std::vec::Vec;
+
+use postgres::Transaction;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::object::check_bucket_vnode;
+use crate::object::create::{self, CreateObjectPayload, CreateObjectResponse};
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
+use crate::util::{array_wrap, limit_constraint_error};
+
+/// The maximum number of objects that may be created by a single request.
+pub const MAX_BATCH_CREATE_SIZE: usize = 1024;
+
+/// The result of creating a single object of a batch.
+type ObjectResult = Result<CreateObjectResponse, BucketsMdapiError>;
+
+/// The objects of a `createobjectbatch` request.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CreateObjectBatch(pub Vec<CreateObjectPayload>);
+
+impl HasRequestId for CreateObjectBatch {
+    fn request_id(&self) -> Uuid {
+        self.0
+            .first()
+            .map_or_else(Uuid::nil, |object| object.request_id)
+    }
+}
+
+impl HasVnodes for CreateObjectBatch {
+    fn vnodes(&self) -> Vec<u64> {
+        self.0.iter().flat_map(HasVnodes::vnodes).collect()
+    }
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<CreateObjectBatch>, SerdeError> {
+    // Every element of the data array is an object of the one batch
+    create::decode_msg(value).map(|objects| vec![CreateObjectBatch(objects)])
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: CreateObjectBatch,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    let batch_size = payload.0.len();
+    if batch_size == 0 || batch_size > MAX_BATCH_CREATE_SIZE {
+        // Limit constraint violations are returned to as regular
+        // Fast messages to be handled by the calling application
+        let e = format!(
+            "the {} request must contain between 1 and {} objects. \
+             the request contained {}",
+            &method, MAX_BATCH_CREATE_SIZE, batch_size
+        );
+        let value = limit_constraint_error(e);
+        let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+        let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+        return Ok(msg);
+    }
+
+    let objects: Vec<(CreateObjectPayload, Option<String>)> = payload
+        .0
+        .into_iter()
+        .map(|object| create::fold_payload(object, config))
+        .collect();
+
+    // Make database request
+    do_create_batch(method, &objects, config, conn, metrics, log)
+        .and_then(|results| {
+            // Handle the successful database response
+            debug!(log, "operation successful";
+                "succeeded" => results.iter().filter(|r| r.is_ok()).count(),
+                "failed" => results.iter().filter(|r| r.is_err()).count(),
+            );
+            let msgs: Vec<FastMessage> = results
+                .into_iter()
+                .map(|result| {
+                    let value = match result {
+                        Ok(resp) => resp.to_json(),
+                        Err(e) => e.into_fast(),
+                    };
+                    let msg_data =
+                        FastMessageData::new(method.into(), array_wrap(value));
+                    FastMessage::data(msg_id, msg_data)
+                })
+                .collect();
+            Ok(HandlerResponse::from(msgs))
+        })
+        .or_else(|e| {
+            if let BucketsMdapiError::PostgresError { .. } = &e {
+                error!(log, "operation failed"; "error" => e.message());
+            }
+
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+fn do_create_batch(
+    method: &str,
+    objects: &[(CreateObjectPayload, Option<String>)],
+    config: &Config,
+    conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<Vec<ObjectResult>, BucketsMdapiError> {
+    let mut txn = (*conn).transaction().map_err(BucketsMdapiError::from)?;
+    let mut results = Vec::with_capacity(objects.len());
+
+    for (index, (object, display_name)) in objects.iter().enumerate() {
+        if config.server.batch_create_atomic {
+            // Returning early drops the transaction, which rolls back every
+            // object of the batch already created
+            let resp = create_object(
+                &mut txn,
+                method,
+                object,
+                display_name,
+                config,
+                metrics,
+                log,
+            )
+            .map_err(|e| batch_aborted(index, object, &e))?;
+            results.push(Ok(resp));
+        } else {
+            // A failed statement aborts the transaction it is part of, so each
+            // object is created within a savepoint that can be rolled back
+            // alone when the object cannot be created
+            let mut savepoint =
+                txn.transaction().map_err(BucketsMdapiError::from)?;
+            let result = create_object(
+                &mut savepoint,
+                method,
+                object,
+                display_name,
+                config,
+                metrics,
+                log,
+            );
+            if result.is_ok() {
+                savepoint.commit().map_err(BucketsMdapiError::from)?;
+            }
+            results.push(result);
+        }
+    }
+
+    txn.commit().map_err(BucketsMdapiError::from)?;
+
+    Ok(results)
+}
+
+fn create_object(
+    txn: &mut Transaction,
+    method: &str,
+    object: &CreateObjectPayload,
+    display_name: &Option<String>,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<CreateObjectResponse, BucketsMdapiError> {
+    check_bucket_vnode(object.bucket_vnode, config)?;
+
+    // As for createobject, an insert that returns no row is not expected
+    create::create_in_txn(
+        txn,
+        method,
+        object,
+        display_name,
+        config,
+        metrics,
+        log,
+    )?
+    .ok_or_else(|| {
+        BucketsMdapiError::postgres_error(
+            "Create statement failed to return any results".to_string(),
+        )
+    })
+}
+
+fn batch_aborted(
+    index: usize,
+    object: &CreateObjectPayload,
+    e: &BucketsMdapiError,
+) -> BucketsMdapiError {
+    BucketsMdapiError::BatchAborted(format!(
+        "no objects were created because object {} of the batch, '{}', \
+         could not be created: {}: {}",
+        index,
+        object.name,
+        e.to_string(),
+        e.message()
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use serde_json::json;
+
+    impl Arbitrary for CreateObjectBatch {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let len = usize::arbitrary(g) % 4;
+            CreateObjectBatch(
+                (0..len)
+                    .map(|_| CreateObjectPayload::arbitrary(g))
+                    .collect(),
+            )
+        }
+    }
+
+    quickcheck! {
+        fn prop_create_object_batch_from_json(
+            batch: CreateObjectBatch
+        ) -> bool {
+            let value = serde_json::to_value(&batch.0)
+                .expect("failed to convert objects to Value");
+            match decode_msg(&value) {
+                Ok(decoded) => decoded == vec![batch],
+                Err(_) => false
+            }
+        }
+    }
+
+    #[test]
+    fn batch_aborted_message() {
+        let value = json!({
+            "owner": Uuid::nil(),
+            "bucket_id": Uuid::nil(),
+            "name": "photos/cat.jpg",
+            "id": Uuid::nil(),
+            "vnode": 1,
+            "content_length": 5,
+            "content_md5": "xzY5jJbR9rcrMRhlcmi/8g==",
+            "content_type": "text/plain",
+            "headers": {},
+            "sharks": [],
+            "properties": null,
+            "request_id": Uuid::nil()
+        });
+        let object: CreateObjectPayload = serde_json::from_value(value)
+            .expect("failed to decode CreateObjectPayload");
+
+        let e = batch_aborted(
+            2,
+            &object,
+            &BucketsMdapiError::UnknownDatacenter("us-west-1".into()),
+        );
+        assert_eq!(
+            e.message(),
+            "no objects were created because object 2 of the batch, \
+             'photos/cat.jpg', could not be created: UnknownDatacenter: \
+             shark datacenter 'us-west-1' is not known"
+        );
+    }
+}
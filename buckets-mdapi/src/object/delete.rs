@@ -9,14 +9,17 @@ use slog::{debug, error, Logger};
 
 use cueball_postgres_connection::PostgresConnection;
 use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
 
 use crate::conditional;
 use crate::error::BucketsMdapiError;
 use crate::metrics::RegisteredMetrics;
 use crate::object::{
-    insert_delete_table_sql, object_not_found, DeleteObjectPayload,
-    DeleteObjectResponse,
+    check_bucket_vnode, check_not_pinned, insert_delete_table_sql,
+    object_not_found, record_audit_event, update_bucket_totals,
+    DeleteObjectPayload, DeleteObjectResponse, StorageNodeIdentifier,
 };
+use crate::object::{index, properties};
 use crate::sql;
 use crate::types::HandlerResponse;
 use crate::util::array_wrap;
@@ -31,13 +34,20 @@ pub(crate) fn decode_msg(
 pub(crate) fn action(
     msg_id: u32,
     method: &str,
+    config: &Config,
     metrics: &RegisteredMetrics,
     log: &Logger,
     payload: DeleteObjectPayload,
     conn: &mut PostgresConnection,
 ) -> Result<HandlerResponse, String> {
+    let payload = DeleteObjectPayload {
+        name: fold_name(&payload.name, config),
+        ..payload
+    };
+
     // Make database request
-    do_delete(&payload, conn, metrics, log)
+    check_bucket_vnode(payload.bucket_vnode, config)
+        .and_then(|_| do_delete(&payload, config, conn, metrics, log))
         .and_then(|deleted_objects| {
             // Handle the successful database response
             debug!(log, "operation successful");
@@ -57,7 +67,7 @@ pub(crate) fn action(
             Ok(msg)
         })
         .or_else(|e| {
-            if let BucketsMdapiError::PostgresError(_) = &e {
+            if let BucketsMdapiError::PostgresError { .. } = &e {
                 error!(log, "operation failed"; "error" => e.message());
             }
 
@@ -69,15 +79,14 @@ pub(crate) fn action(
         })
 }
 
-fn do_delete(
+pub(crate) fn do_delete(
     payload: &DeleteObjectPayload,
+    config: &Config,
     conn: &mut PostgresConnection,
     metrics: &RegisteredMetrics,
     log: &Logger,
 ) -> Result<Vec<DeleteObjectResponse>, BucketsMdapiError> {
-    let mut txn = (*conn)
-        .transaction()
-        .map_err(|e| BucketsMdapiError::PostgresError(e.to_string()))?;
+    let mut txn = (*conn).transaction().map_err(BucketsMdapiError::from)?;
     let move_sql = insert_delete_table_sql(payload.vnode);
     let delete_sql = delete_sql(payload.vnode);
 
@@ -86,9 +95,52 @@ fn do_delete(
         &[&payload.owner, &payload.bucket_id, &payload.name],
         payload.vnode,
         &payload.conditions,
+        config,
         metrics,
         log,
     )
+    .and_then(|_| {
+        conditional::request_content_length(
+            &mut txn,
+            &[&payload.owner, &payload.bucket_id, &payload.name],
+            payload.vnode,
+            payload.if_content_length.as_ref(),
+            config,
+            metrics,
+            log,
+        )
+    })
+    .and_then(|_| {
+        // A pinned object is only deleted when the request asks for its pin
+        // to be cleared first
+        if payload.unpin_first {
+            Ok(())
+        } else {
+            check_not_pinned(
+                &mut txn,
+                payload.vnode,
+                &payload.owner,
+                &payload.bucket_id,
+                &payload.name,
+                metrics,
+                log,
+            )
+        }
+    })
+    .and_then(|_| {
+        if config.properties.max_inline_bytes.is_some() {
+            properties::delete(
+                &mut txn,
+                payload.vnode,
+                &payload.owner,
+                &payload.bucket_id,
+                &payload.name,
+                metrics,
+                log,
+            )?;
+        }
+        Ok(())
+    })
     .and_then(|_| {
         sql::txn_execute(
             sql::Method::ObjectDeleteMove,
@@ -109,6 +161,14 @@ fn do_delete(
             )
         })
         .and_then(|deleted_objects| {
+            sql::check_mutation_width(
+                sql::Method::ObjectDelete,
+                deleted_objects.len(),
+                config.server.wide_mutation_rows,
+                metrics,
+                log,
+            );
+
             let mut objs = vec![];
             for row in deleted_objects {
                 /*
@@ -116,10 +176,14 @@ fn do_delete(
                  * 'content_length' is not null, as a result of that we need to be cautious
                  * while tying to get an integer out of it. Also, 'shark_count' could be
                  * null, the reason is that array_length() returns 'null' if the array is
-                 * empty instead of returning '0'. Yeah, this is weird!
+                 * empty instead of returning '0'. Yeah, this is weird! Nor is
+                 * 'sharks' constrained to be not null, so a null array is
+                 * returned as an empty list of sharks.
                  */
                 let content_length = row.try_get("content_length")?;
                 let shark_count = row.try_get("shark_count").unwrap_or(0);
+                let sharks: Option<Vec<StorageNodeIdentifier>> =
+                    row.try_get("sharks")?;
 
                 let obj = DeleteObjectResponse {
                     id: row.get("id"),
@@ -128,6 +192,7 @@ fn do_delete(
                     name: row.get("name"),
                     content_length,
                     shark_count,
+                    sharks: sharks.unwrap_or_default(),
                 };
 
                 objs.push(obj);
@@ -135,11 +200,62 @@ fn do_delete(
 
             Ok(objs)
         })
-        .map_err(|e| BucketsMdapiError::PostgresError(e.to_string()))
+        .map_err(BucketsMdapiError::from)
+    })
+    .and_then(|objs| match payload.bucket_vnode {
+        Some(bucket_vnode) if config.quota.enabled => {
+            let deleted_bytes: i64 =
+                objs.iter().map(|obj| obj.content_length).sum();
+            let deleted_objects: i64 = objs.iter().map(|_| 1).sum();
+            update_bucket_totals(
+                &mut txn,
+                &payload.owner,
+                &payload.bucket_id,
+                bucket_vnode,
+                -deleted_bytes,
+                -deleted_objects,
+                metrics,
+                log,
+            )
+            .map(|_| objs)
+        }
+        _ => Ok(objs),
+    })
+    .and_then(|objs| {
+        if !objs.is_empty() {
+            index::delete_entries(
+                &mut txn,
+                payload.vnode,
+                &payload.owner,
+                &payload.bucket_id,
+                &payload.name,
+                metrics,
+                log,
+            )?;
+        }
+        Ok(objs)
+    })
+    .and_then(|objs| {
+        if config.audit.enabled {
+            for obj in &objs {
+                record_audit_event(
+                    &mut txn,
+                    payload.vnode,
+                    &obj.owner,
+                    &obj.bucket_id,
+                    &obj.name,
+                    &obj.id,
+                    "delete",
+                    &payload.request_id,
+                    metrics,
+                    log,
+                )?;
+            }
+        }
+        Ok(objs)
     })
     .and_then(|rows| {
-        txn.commit()
-            .map_err(|e| BucketsMdapiError::PostgresError(e.to_string()))?;
+        txn.commit().map_err(BucketsMdapiError::from)?;
         Ok(rows)
     })
 }
@@ -157,6 +273,7 @@ fn delete_sql(vnode: u64) -> String {
          bucket_id, \
          name, \
          content_length, \
+         sharks, \
          array_length(sharks, 1) as shark_count",
     ]
     .concat()
@@ -0,0 +1,424 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Delete the objects of a bucket tagged with an indexed metadata entry.
+//!
+//! The `deleteobjectsbytag` RPC deletes the objects of a bucket in one vnode
+//! whose indexed metadata has an entry for `key` equal to `value`, such as every
+//! object tagged `expired = true` by a lifecycle policy. Each object is moved to
+//! the deleted object table, and its external properties and index entries
+//! removed, as by `deleteobject`. Pinned objects are never deleted.
+//!
+//! The request is destructive, so it must set `confirm` to the id of the bucket
+//! or it is rejected with a `BadRequestError`. Each request deletes at most
+//! `limit` objects, and `limit` may be no more than `MAX_DELETE_BY_TAG_LIMIT`.
+//!
+//! Objects are deleted in name order, starting after `marker`, in a single
+//! transaction per request. When a request deletes `limit` objects, more may
+//! remain and the response carries the name of the last object deleted as
+//! `next_marker`, to be passed as the `marker` of the next request. A response
+//! without a `next_marker` means no matching objects remain after `marker`.
+//!
+//! Requests are idempotent. A failed request deletes nothing, and an object
+//! already deleted no longer matches, so repeating a request, or resuming from
+//! an earlier marker, only deletes the matching objects that remain.
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::conditional::ComparisonOp;
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::object::index::{self, IndexFilter, IndexedValue};
+use crate::object::{
+    check_bucket_vnode, properties, record_audit_event, update_bucket_totals,
+};
+use crate::sql;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
+use crate::util::{array_wrap, limit_constraint_error};
+
+/// The maximum number of objects a single request may delete.
+pub const MAX_DELETE_BY_TAG_LIMIT: u64 = 1024;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DeleteByTagPayload {
+    pub owner: Uuid,
+    pub bucket_id: Uuid,
+    pub vnode: u64,
+    pub request_id: Uuid,
+    /// The indexed metadata key of the objects to delete
+    pub key: String,
+    /// The value of `key` of the objects to delete
+    pub value: IndexedValue,
+    /// The maximum number of objects to delete
+    pub limit: u64,
+    /// Only delete objects whose names sort after this name
+    #[serde(default)]
+    pub marker: Option<String>,
+    /// The id of the bucket, confirming the objects of that bucket are to be
+    /// deleted
+    #[serde(default)]
+    pub confirm: Option<Uuid>,
+    /// The vnode of the bucket, whose maintained totals are reduced by the
+    /// deleted objects when quotas are enabled
+    #[serde(default)]
+    pub bucket_vnode: Option<u64>,
+}
+
+impl HasRequestId for DeleteByTagPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for DeleteByTagPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        std::iter::once(self.vnode)
+            .chain(self.bucket_vnode)
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DeleteByTagResponse {
+    /// The number of objects deleted
+    pub deleted: u64,
+    /// The total content length of the objects deleted
+    pub deleted_bytes: i64,
+    /// The marker to continue deleting from, when more objects may remain
+    pub next_marker: Option<String>,
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<DeleteByTagPayload>, SerdeError> {
+    serde_json::from_value::<Vec<DeleteByTagPayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: DeleteByTagPayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    if payload.limit == 0 || payload.limit > MAX_DELETE_BY_TAG_LIMIT {
+        // Limit constraint violations are returned to as regular
+        // Fast messages to be handled by the calling application
+        let e = format!(
+            "the {} limit option must be a value between 1 and {}. \
+             the requested limit was {}",
+            &method, MAX_DELETE_BY_TAG_LIMIT, payload.limit
+        );
+        let value = limit_constraint_error(e);
+        let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+        let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+        return Ok(msg);
+    }
+
+    // Make database request
+    check_confirmed(&payload)
+        .and_then(|_| check_bucket_vnode(payload.bucket_vnode, config))
+        .and_then(|_| do_delete_by_tag(&payload, config, conn, metrics, log))
+        .and_then(|resp| {
+            // Handle the successful database response
+            debug!(log, "operation successful"; "deleted" => resp.deleted);
+            // The DeleteByTagResponse type contains no maps, so the
+            // conversion to JSON is not expected to fail.
+            let value = serde_json::to_value(resp)
+                .expect("failed to serialize DeleteByTagResponse");
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(value));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+        .or_else(|e| {
+            if let BucketsMdapiError::PostgresError { .. } = &e {
+                error!(log, "operation failed"; "error" => e.message());
+            }
+
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+fn check_confirmed(
+    payload: &DeleteByTagPayload,
+) -> Result<(), BucketsMdapiError> {
+    if payload.confirm == Some(payload.bucket_id) {
+        Ok(())
+    } else {
+        Err(BucketsMdapiError::BadRequestError(format!(
+            "deleting objects by tag requires confirm to be the bucket id {}",
+            payload.bucket_id
+        )))
+    }
+}
+
+#[allow(clippy::cast_possible_wrap)]
+fn do_delete_by_tag(
+    payload: &DeleteByTagPayload,
+    config: &Config,
+    conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<DeleteByTagResponse, BucketsMdapiError> {
+    let filter = IndexFilter {
+        key: payload.key.clone(),
+        op: ComparisonOp::Eq,
+        value: payload.value.clone(),
+    };
+    let marker = payload.marker.clone().unwrap_or_default();
+    let limit = payload.limit as i64;
+    let filter_items = filter.items();
+
+    let mut txn = (*conn).transaction().map_err(BucketsMdapiError::from)?;
+
+    // Lock the objects to delete so that they cannot be pinned or replaced
+    // before they are moved
+    let names: Vec<String> = sql::txn_query(
+        sql::Method::ObjectDeleteByTagSelect,
+        &mut txn,
+        select_sql(payload.vnode, &filter).as_str(),
+        &[
+            &payload.owner,
+            &payload.bucket_id,
+            &marker,
+            filter_items[0],
+            filter_items[1],
+            &limit,
+        ],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?
+    .iter()
+    .map(|row| row.get("name"))
+    .collect();
+
+    if names.is_empty() {
+        return Ok(DeleteByTagResponse {
+            deleted: 0,
+            deleted_bytes: 0,
+            next_marker: None,
+        });
+    }
+
+    sql::txn_execute(
+        sql::Method::ObjectDeleteMove,
+        &mut txn,
+        move_sql(payload.vnode).as_str(),
+        &[&payload.owner, &payload.bucket_id, &names],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    let deleted = sql::txn_query(
+        sql::Method::ObjectDeleteByTag,
+        &mut txn,
+        delete_sql(payload.vnode).as_str(),
+        &[&payload.owner, &payload.bucket_id, &names],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    let mut deleted_bytes = 0;
+    for row in &deleted {
+        // content_length is not constrained to be not null
+        let content_length: Option<i64> = row.try_get("content_length")?;
+        deleted_bytes += content_length.unwrap_or(0);
+
+        let name: String = row.get("name");
+        if config.properties.max_inline_bytes.is_some() {
+            properties::delete(
+                &mut txn,
+                payload.vnode,
+                &payload.owner,
+                &payload.bucket_id,
+                &name,
+                metrics,
+                log,
+            )?;
+        }
+        index::delete_entries(
+            &mut txn,
+            payload.vnode,
+            &payload.owner,
+            &payload.bucket_id,
+            &name,
+            metrics,
+            log,
+        )?;
+        if config.audit.enabled {
+            record_audit_event(
+                &mut txn,
+                payload.vnode,
+                &payload.owner,
+                &payload.bucket_id,
+                &name,
+                &row.get("id"),
+                "delete",
+                &payload.request_id,
+                metrics,
+                log,
+            )?;
+        }
+    }
+
+    if let Some(bucket_vnode) = payload.bucket_vnode {
+        if config.quota.enabled {
+            update_bucket_totals(
+                &mut txn,
+                &payload.owner,
+                &payload.bucket_id,
+                bucket_vnode,
+                -deleted_bytes,
+                -(deleted.len() as i64),
+                metrics,
+                log,
+            )?;
+        }
+    }
+
+    txn.commit().map_err(BucketsMdapiError::from)?;
+
+    // Objects are selected in name order, so the last name selected is where
+    // the next request continues from
+    let next_marker = if names.len() as u64 == payload.limit {
+        names.last().cloned()
+    } else {
+        None
+    };
+
+    Ok(DeleteByTagResponse {
+        deleted: deleted.len() as u64,
+        deleted_bytes,
+        next_marker,
+    })
+}
+
+fn select_sql(vnode: u64, filter: &IndexFilter) -> String {
+    [
+        "SELECT o.name FROM manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_object o \
+         WHERE o.owner = $1 AND o.bucket_id = $2 AND o.name > $3 \
+         AND NOT o.pinned ",
+        &index::filter_sql(vnode, filter, 4),
+        " ORDER BY o.name LIMIT $6 FOR UPDATE",
+    ]
+    .concat()
+}
+
+fn move_sql(vnode: u64) -> String {
+    let vnode_str = vnode.to_string();
+    [
+        "INSERT INTO manta_bucket_",
+        &vnode_str,
+        ".manta_bucket_deleted_object ( \
+         id, owner, bucket_id, name, created, modified, \
+         content_length, content_md5, \
+         content_type, headers, sharks, properties) \
+         SELECT id, owner, bucket_id, name, created, \
+         modified, content_length, \
+         content_md5, content_type, headers, sharks, \
+         properties FROM manta_bucket_",
+        &vnode_str,
+        ".manta_bucket_object \
+         WHERE owner = $1 \
+         AND bucket_id = $2 \
+         AND name = ANY($3) \
+         AND content_length > 0",
+    ]
+    .concat()
+}
+
+fn delete_sql(vnode: u64) -> String {
+    [
+        "DELETE FROM manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_object \
+         WHERE owner = $1 \
+         AND bucket_id = $2 \
+         AND name = ANY($3) \
+         RETURNING id, name, content_length",
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use quickcheck_helpers::random;
+
+    impl Arbitrary for DeleteByTagPayload {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let bucket_id = Uuid::new_v4();
+            DeleteByTagPayload {
+                owner: Uuid::new_v4(),
+                bucket_id,
+                vnode: u64::arbitrary(g),
+                request_id: Uuid::new_v4(),
+                key: random::string(g, 16),
+                value: IndexedValue::arbitrary(g),
+                limit: u64::arbitrary(g),
+                marker: Option::<String>::arbitrary(g),
+                confirm: Some(bucket_id),
+                bucket_vnode: Option::<u64>::arbitrary(g),
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_delete_by_tag_payload_roundtrip(
+            msg: DeleteByTagPayload
+        ) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(delete_str) => {
+                    let decode_result: Result<DeleteByTagPayload, _> =
+                        serde_json::from_str(&delete_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_delete_by_tag_requires_confirm(
+            msg: DeleteByTagPayload
+        ) -> bool {
+            let unconfirmed = DeleteByTagPayload {
+                confirm: None,
+                ..msg.clone()
+            };
+            let wrong_bucket = DeleteByTagPayload {
+                confirm: Some(Uuid::new_v4()),
+                ..msg.clone()
+            };
+            check_confirmed(&msg).is_ok()
+                && check_confirmed(&unconfirmed).is_err()
+                && check_confirmed(&wrong_bucket).is_err()
+        }
+    }
+}
@@ -3,21 +3,24 @@
 
 use std::vec::Vec;
 
+use chrono::Utc;
 use serde_json::Error as SerdeError;
 use serde_json::Value;
 use slog::{debug, error, Logger};
 
 use cueball_postgres_connection::PostgresConnection;
 use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
 
 use crate::error::BucketsMdapiError;
 use crate::metrics::RegisteredMetrics;
 use crate::object::{
-    get_sql, response, to_json, GetObjectPayload, ObjectResponse,
+    check_content_md5, fold_name, properties, response, to_json,
+    GetObjectPayload, ObjectResponse,
 };
-use crate::sql;
-use crate::types::HandlerResponse;
-use crate::util::array_wrap;
+use crate::sql::{self, StatementCache};
+use crate::types::{HandlerResponse, Timestamptz};
+use crate::util::{array_wrap, check_name_length, duration_to_seconds};
 
 pub(crate) fn decode_msg(
     value: &Value,
@@ -25,20 +28,31 @@ pub(crate) fn decode_msg(
     serde_json::from_value::<Vec<GetObjectPayload>>(value.clone())
 }
 
-#[allow(clippy::needless_pass_by_value)]
+#[allow(clippy::needless_pass_by_value, clippy::too_many_arguments)]
 pub(crate) fn action(
     msg_id: u32,
     method: &str,
+    config: &Config,
     metrics: &RegisteredMetrics,
     log: &Logger,
     payload: GetObjectPayload,
     conn: &mut PostgresConnection,
+    statements: &StatementCache,
 ) -> Result<HandlerResponse, String> {
+    let payload = GetObjectPayload {
+        name: fold_name(&payload.name, config),
+        ..payload
+    };
+
     // Make database request
-    do_get(method, &payload, conn, metrics, log)
+    let statements = Some(statements);
+    do_get(method, &payload, statements, config, conn, metrics, log)
         .and_then(|object_resp| {
             // Handle the successful database response
             debug!(log, "operation successful");
+            metrics
+                .object_age_at_read
+                .observe(age_secs(&object_resp.created));
             let value = array_wrap(to_json(object_resp));
             let msg_data = FastMessageData::new(method.into(), value);
             let msg: HandlerResponse =
@@ -46,7 +60,7 @@ pub(crate) fn action(
             Ok(msg)
         })
         .or_else(|e| {
-            if let BucketsMdapiError::PostgresError(_) = &e {
+            if let BucketsMdapiError::PostgresError { .. } = &e {
                 error!(log, "operation failed"; "error" => e.message());
             }
 
@@ -65,36 +79,85 @@ pub(crate) fn action(
         })
 }
 
-fn do_get(
+// The time in seconds since an object was created. A creation time in the
+// future, which is only possible through clock skew, is treated as an age of
+// zero.
+fn age_secs(created: &Timestamptz) -> f64 {
+    Utc::now()
+        .signed_duration_since(*created)
+        .to_std()
+        .map(duration_to_seconds)
+        .unwrap_or(0.0)
+}
+
+/// Get the object described by `payload`, with a statement prepared once per
+/// connection when the connection's `statements` are given.
+pub(crate) fn do_get(
     method: &str,
     payload: &GetObjectPayload,
+    statements: Option<&StatementCache>,
+    config: &Config,
     mut conn: &mut PostgresConnection,
     metrics: &RegisteredMetrics,
     log: &Logger,
 ) -> Result<ObjectResponse, BucketsMdapiError> {
-    let sql = get_sql(payload.vnode);
+    check_name_length(&payload.name, config)?;
+
+    let sql = [
+        properties::select_sql(
+            payload.vnode,
+            payload.include_properties,
+            config.names.case_insensitive_names,
+            true,
+        )
+        .as_str(),
+        " WHERE owner = $1 AND bucket_id = $2 AND name = $3",
+    ]
+    .concat();
 
     /*
      * This request is conditional, but the contitional::request method will run
      * the same SQL as this, so we can save a roundtrip by running the query
      * ourselves and just pass the result to the conditional check.
      */
-    sql::query(
+    sql::cached_query(
         sql::Method::ObjectGet,
+        payload.vnode,
+        statements,
         &mut conn,
         sql.as_str(),
         &[&payload.owner, &payload.bucket_id, &payload.name],
         metrics,
         log,
     )
-    .map_err(|e| BucketsMdapiError::PostgresError(e.to_string()))
-    .and_then(|rows| response(method, &rows))
+    .map_err(BucketsMdapiError::from)
+    .and_then(|rows| {
+        response(method, payload.vnode, &rows, config, metrics, log)
+    })
     .and_then(|maybe_resp| match maybe_resp {
         None => Err(BucketsMdapiError::ObjectNotFound),
         Some(object) => {
+            check_content_md5(method, &object, config, metrics, log)?;
             payload.conditions.check(Some(&object))?;
 
             Ok(object)
         }
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::Duration;
+
+    #[test]
+    fn object_age() {
+        let created = Utc::now() - Duration::seconds(90);
+        let age = age_secs(&created);
+        assert!(age >= 90.0 && age < 100.0);
+
+        let skewed = Utc::now() + Duration::seconds(90);
+        assert_eq!(age_secs(&skewed), 0.0);
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! The `headobject` RPC, which checks that an object exists.
+//!
+//! A `getobject` response includes every field of the object, among them its
+//! `sharks`, `headers`, and `properties`, which a client only checking that an
+//! object exists has no use for. A `headobject` request takes the same payload
+//! as `getobject` and honors its `conditions`, but selects only the fields
+//! that identify the version of the object and describe its content.
+
+use std::vec::Vec;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::object::{fold_name, GetObjectPayload};
+use crate::sql;
+use crate::types::{HandlerResponse, Timestamptz};
+use crate::util::{array_wrap, check_name_length};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct HeadObjectResponse {
+    pub id: Uuid,
+    pub content_length: i64,
+    pub content_md5: String,
+    pub modified: Timestamptz,
+    pub content_type: String,
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<GetObjectPayload>, SerdeError> {
+    serde_json::from_value::<Vec<GetObjectPayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: GetObjectPayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    let payload = GetObjectPayload {
+        name: fold_name(&payload.name, config),
+        ..payload
+    };
+
+    // Make database request
+    do_head(&payload, config, conn, metrics, log)
+        .and_then(|resp| {
+            // Handle the successful database response
+            debug!(log, "operation successful");
+            // The HeadObjectResponse type contains no maps, so the conversion
+            // to JSON is not expected to fail.
+            let value = serde_json::to_value(resp)
+                .expect("failed to serialize HeadObjectResponse");
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(value));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+        .or_else(|e| {
+            if let BucketsMdapiError::PostgresError { .. } = &e {
+                error!(log, "operation failed"; "error" => e.message());
+            }
+
+            // Missing objects and failed conditions are returned as regular
+            // Fast messages to be handled by the calling application
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+fn do_head(
+    payload: &GetObjectPayload,
+    config: &Config,
+    mut conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<HeadObjectResponse, BucketsMdapiError> {
+    check_name_length(&payload.name, config)?;
+
+    let rows = sql::query(
+        sql::Method::ObjectHead,
+        &mut conn,
+        head_sql(payload.vnode).as_str(),
+        &[&payload.owner, &payload.bucket_id, &payload.name],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    let row = rows.first().ok_or(BucketsMdapiError::ObjectNotFound)?;
+    let content_md5_bytes: Vec<u8> = row.get("content_md5");
+    let resp = HeadObjectResponse {
+        id: row.get("id"),
+        content_length: row.get("content_length"),
+        content_md5: base64::encode(&content_md5_bytes),
+        modified: row.get("modified"),
+        content_type: row.get("content_type"),
+    };
+
+    payload
+        .conditions
+        .check_version(Some((resp.id, resp.modified)))?;
+
+    Ok(resp)
+}
+
+fn head_sql(vnode: u64) -> String {
+    format!(
+        "SELECT id, content_length, content_md5, modified, content_type \
+         FROM manta_bucket_{}.manta_bucket_object \
+         WHERE owner = $1 AND bucket_id = $2 AND name = $3",
+        vnode
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::Utc;
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use quickcheck_helpers::random;
+
+    impl Arbitrary for HeadObjectResponse {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            HeadObjectResponse {
+                id: Uuid::new_v4(),
+                content_length: i64::arbitrary(g),
+                content_md5: random::string(g, 32),
+                modified: Utc::now(),
+                content_type: random::string(g, 32),
+            }
+        }
+    }
+
+    #[test]
+    fn head_selects_no_sharks_headers_or_properties() {
+        let sql = head_sql(3);
+        assert!(sql.contains("FROM manta_bucket_3.manta_bucket_object"));
+        for column in &["sharks", "headers", "properties"] {
+            assert!(!sql.contains(column));
+        }
+    }
+
+    quickcheck! {
+        fn prop_head_object_response_roundtrip(msg: HeadObjectResponse) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(head_str) => {
+                    let decode_result: Result<HeadObjectResponse, _> =
+                        serde_json::from_str(&head_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+}
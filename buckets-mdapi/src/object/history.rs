@@ -0,0 +1,209 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+use std::vec::Vec;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::sql;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes, Timestamptz};
+use crate::util::{array_wrap, limit_constraint_error};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ObjectHistoryPayload {
+    pub owner: Uuid,
+    pub bucket_id: Uuid,
+    pub name: String,
+    pub vnode: u64,
+    pub limit: u64,
+    /// The `seq` of the last event returned by a previous request. Only
+    /// events recorded after it are returned.
+    pub marker: Option<i64>,
+    pub request_id: Uuid,
+}
+
+impl HasRequestId for ObjectHistoryPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for ObjectHistoryPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
+/// A single recorded mutation of an object. Events are returned in the order
+/// they were recorded, which is given by `seq`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ObjectHistoryEvent {
+    pub seq: i64,
+    pub object_id: Uuid,
+    pub action: String,
+    pub request_id: Uuid,
+    pub occurred_at: Timestamptz,
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<ObjectHistoryPayload>, SerdeError> {
+    serde_json::from_value::<Vec<ObjectHistoryPayload>>(value.clone())
+}
+
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: ObjectHistoryPayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    if !config.audit.enabled {
+        let value = BucketsMdapiError::AuditDisabled.into_fast();
+        let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+        let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+        return Ok(msg);
+    }
+
+    // Make database request
+    if payload.limit > 0 && payload.limit <= 1024 {
+        do_history(msg_id, method, payload, conn, metrics, log)
+            .and_then(|resp| {
+                // Handle the successful database response
+                debug!(log, "operation successful");
+                Ok(HandlerResponse::from(resp))
+            })
+            .or_else(|e| {
+                // Handle database error response
+                error!(log, "operation failed"; "error" => e.message());
+
+                // Database errors are returned to as regular Fast messages
+                // to be handled by the calling application
+                let msg_data = FastMessageData::new(
+                    method.into(),
+                    array_wrap(e.into_fast()),
+                );
+                let msg: HandlerResponse =
+                    FastMessage::data(msg_id, msg_data).into();
+                Ok(msg)
+            })
+    } else {
+        // Limit constraint violations are returned to as regular
+        // Fast messages to be handled by the calling application
+        let e = format!(
+            "the {} limit option must be a value between 1 \
+             and 1024. the requested limit was {}",
+            &method, &payload.limit
+        );
+        let value = limit_constraint_error(e);
+        let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+        let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+        Ok(msg)
+    }
+}
+
+fn do_history(
+    msg_id: u32,
+    method: &str,
+    payload: ObjectHistoryPayload,
+    mut conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<Vec<FastMessage>, BucketsMdapiError> {
+    let sql = history_sql(payload.vnode, payload.limit);
+    let marker = payload.marker.unwrap_or(0);
+
+    sql::query(
+        sql::Method::ObjectHistory,
+        &mut conn,
+        sql.as_str(),
+        &[&payload.owner, &payload.bucket_id, &payload.name, &marker],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)
+    .and_then(|rows| {
+        let mut msgs: Vec<FastMessage> = Vec::with_capacity(rows.len());
+
+        for row in &rows {
+            let event = ObjectHistoryEvent {
+                seq: row.get("seq"),
+                object_id: row.get("object_id"),
+                action: row.get("action"),
+                request_id: row.get("request_id"),
+                occurred_at: row.get("occurred_at"),
+            };
+
+            // The ObjectHistoryEvent type contains no maps and so the
+            // conversion to JSON is not expected to fail.
+            let value = serde_json::to_value(event)
+                .expect("failed to serialize ObjectHistoryEvent");
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(value));
+            msgs.push(FastMessage::data(msg_id, msg_data));
+        }
+
+        Ok(msgs)
+    })
+}
+
+fn history_sql(vnode: u64, limit: u64) -> String {
+    format!(
+        "SELECT seq, object_id, action, request_id, occurred_at \
+        FROM manta_bucket_{}.manta_bucket_object_audit
+        WHERE owner = $1 AND bucket_id = $2 AND name = $3 AND seq > $4
+        ORDER BY seq ASC
+        LIMIT {}",
+        vnode, limit
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use quickcheck_helpers::random;
+    use serde_json;
+
+    impl Arbitrary for ObjectHistoryPayload {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            ObjectHistoryPayload {
+                owner: Uuid::new_v4(),
+                bucket_id: Uuid::new_v4(),
+                name: random::string(g, 32),
+                vnode: u64::arbitrary(g),
+                limit: u64::arbitrary(g),
+                marker: Option::<i64>::arbitrary(g),
+                request_id: Uuid::new_v4(),
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_object_history_payload_roundtrip(msg: ObjectHistoryPayload) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(history_str) => {
+                    let decode_result: Result<ObjectHistoryPayload, _> =
+                        serde_json::from_str(&history_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+}
@@ -0,0 +1,354 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Indexed object metadata.
+//!
+//! Unlike the opaque `properties` of an object, indexed metadata is a set of
+//! typed key-value pairs that `listobjects` can filter on. The entries are kept
+//! in the `manta_bucket_object_index` table of the object's vnode, one row per
+//! key, with the value stored in the column for its type (`str_value`,
+//! `num_value`, or `bool_value`). Each column has a partial index on
+//! `(owner, bucket_id, key, <column>)` so that both equality and range filters
+//! on a key are served by an index scan.
+//!
+//! A `listobjects` filter such as `{ "key": "size", "op": "gt", "value": 10 }`
+//! is translated into an `EXISTS` subquery against the index table that
+//! compares the column matching the type of the filter value:
+//!
+//! ```sql
+//! AND EXISTS (SELECT 1 FROM manta_bucket_<vnode>.manta_bucket_object_index i
+//!     WHERE i.owner = o.owner AND i.bucket_id = o.bucket_id
+//!     AND i.name = o.name AND i.key = $n AND i.num_value > $n+1)
+//! ```
+//!
+//! The index entries of an object are replaced by every `createobject`, by an
+//! `updateobject` that includes `indexed_metadata`, and are removed by
+//! `deleteobject`, in the same transaction as the change to the object.
+
+use std::collections::HashMap;
+use std::marker::Sync;
+
+use postgres::types::ToSql;
+use postgres::Transaction;
+use serde_derive::{Deserialize, Serialize};
+use slog::Logger;
+use uuid::Uuid;
+
+use crate::conditional::ComparisonOp;
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::sql;
+
+pub type IndexedMetadata = HashMap<String, IndexedValue>;
+
+/// A typed value of an indexed metadata entry.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum IndexedValue {
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+impl IndexedValue {
+    fn column(&self) -> &'static str {
+        match self {
+            IndexedValue::Bool(_) => "bool_value",
+            IndexedValue::Number(_) => "num_value",
+            IndexedValue::String(_) => "str_value",
+        }
+    }
+
+    fn as_sql(&self) -> &(dyn ToSql + Sync) {
+        match self {
+            IndexedValue::Bool(b) => b,
+            IndexedValue::Number(n) => n,
+            IndexedValue::String(s) => s,
+        }
+    }
+}
+
+/// A `listobjects` filter on an indexed metadata key. Only objects with an
+/// entry for `key` whose value has the same type as `value` and compares to
+/// it as described by `op` are listed.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct IndexFilter {
+    pub key: String,
+    pub op: ComparisonOp,
+    pub value: IndexedValue,
+}
+
+impl IndexFilter {
+    /// The SQL parameters of the clause returned by `filter_sql`.
+    pub(crate) fn items(&self) -> [&(dyn ToSql + Sync); 2] {
+        [&self.key, self.value.as_sql()]
+    }
+}
+
+fn sql_operator(op: ComparisonOp) -> &'static str {
+    match op {
+        ComparisonOp::Lt => "<",
+        ComparisonOp::Le => "<=",
+        ComparisonOp::Eq => "=",
+        ComparisonOp::Ge => ">=",
+        ComparisonOp::Gt => ">",
+    }
+}
+
+/// Build the clause restricting a listing of the `manta_bucket_object` table,
+/// aliased as `o`, to the objects matching `filter`. The key and value of the
+/// filter are bound to the parameters `$first_param` and `$first_param + 1`.
+pub(crate) fn filter_sql(
+    vnode: u64,
+    filter: &IndexFilter,
+    first_param: usize,
+) -> String {
+    format!(
+        "AND EXISTS (SELECT 1 \
+         FROM manta_bucket_{}.manta_bucket_object_index i \
+         WHERE i.owner = o.owner AND i.bucket_id = o.bucket_id \
+         AND i.name = o.name AND i.key = ${} AND i.{} {} ${})",
+        vnode,
+        first_param,
+        filter.value.column(),
+        sql_operator(filter.op),
+        first_param + 1
+    )
+}
+
+/// Replace the indexed metadata entries of an object with `entries`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn replace_entries(
+    mut txn: &mut Transaction,
+    vnode: u64,
+    owner: &Uuid,
+    bucket_id: &Uuid,
+    name: &str,
+    entries: &IndexedMetadata,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<(), BucketsMdapiError> {
+    delete_entries(&mut txn, vnode, owner, bucket_id, name, metrics, log)?;
+
+    let insert_sql = insert_sql(vnode);
+    for (key, value) in entries {
+        let (str_value, num_value, bool_value) = match value {
+            IndexedValue::String(s) => (Some(s.as_str()), None, None),
+            IndexedValue::Number(n) => (None, Some(*n), None),
+            IndexedValue::Bool(b) => (None, None, Some(*b)),
+        };
+
+        sql::txn_execute(
+            sql::Method::ObjectIndexInsert,
+            &mut txn,
+            insert_sql.as_str(),
+            &[
+                owner,
+                bucket_id,
+                &name,
+                key,
+                &str_value,
+                &num_value,
+                &bool_value,
+            ],
+            metrics,
+            log,
+        )
+        .map_err(BucketsMdapiError::from)?;
+    }
+
+    Ok(())
+}
+
+/// Remove all indexed metadata entries of an object.
+pub(crate) fn delete_entries(
+    mut txn: &mut Transaction,
+    vnode: u64,
+    owner: &Uuid,
+    bucket_id: &Uuid,
+    name: &str,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<(), BucketsMdapiError> {
+    sql::txn_execute(
+        sql::Method::ObjectIndexDelete,
+        &mut txn,
+        delete_sql(vnode).as_str(),
+        &[owner, bucket_id, &name],
+        metrics,
+        log,
+    )
+    .map(|_| ())
+    .map_err(BucketsMdapiError::from)
+}
+
+/// Read the indexed metadata entries of an object.
+pub(crate) fn entries(
+    mut txn: &mut Transaction,
+    vnode: u64,
+    owner: &Uuid,
+    bucket_id: &Uuid,
+    name: &str,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<IndexedMetadata, BucketsMdapiError> {
+    let rows = sql::txn_query(
+        sql::Method::ObjectIndexRead,
+        &mut txn,
+        select_sql(vnode).as_str(),
+        &[owner, bucket_id, &name],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    let mut entries = IndexedMetadata::with_capacity(rows.len());
+    for row in &rows {
+        let str_value: Option<String> = row.get("str_value");
+        let num_value: Option<f64> = row.get("num_value");
+        let bool_value: Option<bool> = row.get("bool_value");
+        let value = match (str_value, num_value, bool_value) {
+            (Some(s), _, _) => IndexedValue::String(s),
+            (_, Some(n), _) => IndexedValue::Number(n),
+            (_, _, Some(b)) => IndexedValue::Bool(b),
+            (None, None, None) => continue,
+        };
+        let _ = entries.insert(row.get("key"), value);
+    }
+
+    Ok(entries)
+}
+
+fn insert_sql(vnode: u64) -> String {
+    [
+        "INSERT INTO manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_object_index \
+         (owner, bucket_id, name, key, str_value, num_value, bool_value) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7)",
+    ]
+    .concat()
+}
+
+fn select_sql(vnode: u64) -> String {
+    [
+        "SELECT key, str_value, num_value, bool_value FROM manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_object_index \
+         WHERE owner = $1 AND bucket_id = $2 AND name = $3",
+    ]
+    .concat()
+}
+
+fn delete_sql(vnode: u64) -> String {
+    [
+        "DELETE FROM manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_object_index \
+         WHERE owner = $1 AND bucket_id = $2 AND name = $3",
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use quickcheck_helpers::random;
+    use serde_json::json;
+
+    impl Arbitrary for IndexedValue {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            match u8::arbitrary(g) % 3 {
+                0 => IndexedValue::Bool(bool::arbitrary(g)),
+                // Whole numbers avoid any loss of precision in the JSON
+                // roundtrip of an arbitrary float
+                1 => IndexedValue::Number(f64::from(i32::arbitrary(g))),
+                _ => IndexedValue::String(random::string(g, 16)),
+            }
+        }
+    }
+
+    impl Arbitrary for IndexFilter {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            IndexFilter {
+                key: random::string(g, 16),
+                op: ComparisonOp::arbitrary(g),
+                value: IndexedValue::arbitrary(g),
+            }
+        }
+    }
+
+    #[test]
+    fn indexed_value_types() {
+        let metadata: IndexedMetadata = serde_json::from_value(json!({
+            "color": "red",
+            "size": 10,
+            "ratio": 0.5,
+            "archived": false,
+        }))
+        .unwrap();
+
+        assert_eq!(metadata["color"], IndexedValue::String("red".into()));
+        assert_eq!(metadata["size"], IndexedValue::Number(10.0));
+        assert_eq!(metadata["ratio"], IndexedValue::Number(0.5));
+        assert_eq!(metadata["archived"], IndexedValue::Bool(false));
+
+        let nested = serde_json::from_value::<IndexedMetadata>(json!({
+            "nested": { "a": 1 },
+        }));
+        assert!(nested.is_err());
+    }
+
+    #[test]
+    fn filter_sql_equality() {
+        let filter: IndexFilter = serde_json::from_value(json!({
+            "key": "color",
+            "op": "eq",
+            "value": "red",
+        }))
+        .unwrap();
+
+        assert_eq!(
+            filter_sql(3, &filter, 3),
+            "AND EXISTS (SELECT 1 \
+             FROM manta_bucket_3.manta_bucket_object_index i \
+             WHERE i.owner = o.owner AND i.bucket_id = o.bucket_id \
+             AND i.name = o.name AND i.key = $3 AND i.str_value = $4)"
+        );
+    }
+
+    #[test]
+    fn filter_sql_range() {
+        let filter: IndexFilter = serde_json::from_value(json!({
+            "key": "size",
+            "op": "ge",
+            "value": 10,
+        }))
+        .unwrap();
+
+        assert_eq!(
+            filter_sql(0, &filter, 5),
+            "AND EXISTS (SELECT 1 \
+             FROM manta_bucket_0.manta_bucket_object_index i \
+             WHERE i.owner = o.owner AND i.bucket_id = o.bucket_id \
+             AND i.name = o.name AND i.key = $5 AND i.num_value >= $6)"
+        );
+    }
+
+    quickcheck! {
+        fn prop_index_filter_roundtrip(filter: IndexFilter) -> bool {
+            match serde_json::to_string(&filter) {
+                Ok(filter_str) => {
+                    let decode_result: Result<IndexFilter, _> =
+                        serde_json::from_str(&filter_str);
+                    match decode_result {
+                        Ok(decoded) => decoded == filter,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+}
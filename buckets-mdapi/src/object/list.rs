@@ -1,22 +1,36 @@
 // Copyright 2020 Joyent, Inc.
 
+use std::collections::HashSet;
+use std::marker::Sync;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 use base64;
+use hmac::{Hmac, Mac};
+use postgres::types::ToSql;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
 use serde_json::Value;
+use sha2::Sha256;
 use slog::{debug, error, Logger};
 use uuid::Uuid;
 
 use cueball_postgres_connection::PostgresConnection;
 use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
 
 use crate::error::BucketsMdapiError;
-use crate::metrics::RegisteredMetrics;
-use crate::object::{to_json, ObjectResponse};
-use crate::sql;
-use crate::types::{HandlerResponse, HasRequestId};
+use crate::metrics::{RegisteredMetrics, MAX_LIST_LIMIT};
+use crate::object::index::{self, IndexFilter};
+use crate::object::{
+    check_content_md5, fold_name, properties, row_headers, to_json,
+    ObjectResponse,
+};
+use crate::sql::{self, StatementCache};
+use crate::types::{
+    HandlerResponse, HasRequestId, HasVnodes, ListCount, ListOrder,
+    PostgresResult, Rows, Timestamptz,
+};
 use crate::util::{array_wrap, limit_constraint_error};
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -28,6 +42,200 @@ pub struct ListObjectsPayload {
     pub limit: u64,
     pub marker: Option<String>,
     pub request_id: Uuid,
+
+    /// The creation time of the object named by the `marker`, which a listing
+    /// ordered by creation time continues after along with the marker.
+    #[serde(default)]
+    pub marker_created: Option<Timestamptz>,
+
+    /// Only list objects whose indexed metadata matches this filter.
+    #[serde(default)]
+    pub index_filter: Option<IndexFilter>,
+
+    /// Only list the objects directly under this parent prefix, such as
+    /// `photos/2019/`, and not those nested further below it. The empty
+    /// string lists the objects whose names do not contain a `/`.
+    #[serde(default)]
+    pub parent_prefix: Option<String>,
+
+    /// Include the `properties` of objects that are stored outside of the
+    /// object row for exceeding the configured `max_inline_bytes`.
+    #[serde(default)]
+    pub include_properties: bool,
+
+    /// Follow the listed objects with a final `ListStatus` message reporting
+    /// whether the listing ran to completion.
+    #[serde(default)]
+    pub report_status: bool,
+
+    /// Only list objects last modified after this time.
+    #[serde(default)]
+    pub modified_after: Option<Timestamptz>,
+
+    /// Only list objects last modified before this time.
+    #[serde(default)]
+    pub modified_before: Option<Timestamptz>,
+
+    /// Neither read nor decode the `sharks` of the listed objects, returning
+    /// them empty, for listings that only need the names and sizes of objects.
+    #[serde(default)]
+    pub skip_sharks: bool,
+
+    /// Collapse the objects whose names continue past the `prefix` with this
+    /// delimiter into a single `CommonPrefix` message for each name up to and
+    /// including the first delimiter after the prefix.
+    #[serde(default)]
+    pub delimiter: Option<String>,
+
+    /// Respond with a single `ListCount` message counting the objects that
+    /// match the listing, regardless of its limit, instead of listing them.
+    #[serde(default)]
+    pub count_only: bool,
+
+    /// The order in which to list objects, and in which the `marker`
+    /// continues the listing. The default is `name_asc`.
+    #[serde(default)]
+    pub order_by: ListOrder,
+
+    /// Continue the listing from the `next_marker` of the `ListContinuation`
+    /// message of a previous listing rather than from a `marker`. The token
+    /// only continues a listing in the order it was issued for.
+    #[serde(default)]
+    pub continuation_token: Option<String>,
+
+    /// Follow the listed objects with a final `ListContinuation` message
+    /// carrying the token from which to continue the listing.
+    #[serde(default)]
+    pub report_continuation: bool,
+}
+
+/// A message of a delimited listing standing for every listed object whose
+/// name begins with `common_prefix`. Objects are still counted toward the
+/// listing limit when collapsed, so a common prefix that spans pages is
+/// repeated on each page.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CommonPrefix {
+    pub common_prefix: String,
+}
+
+/// The final message of a listing that requested `report_status`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ListStatus {
+    /// `false` when the listing was cut short by the configured
+    /// `deadline_ms`, in which case more objects may remain before the limit
+    /// was reached, rather than running out of matching objects.
+    pub complete: bool,
+    /// The marker from which to continue the listing: the name of the last
+    /// object listed, or the marker of the request if no object was listed.
+    pub next_marker: Option<String>,
+    /// The creation time of the object named by `next_marker`, to give as the
+    /// `marker_created` of a listing ordered by creation time.
+    #[serde(default)]
+    pub next_marker_created: Option<Timestamptz>,
+}
+
+/// The final message of a listing that requested `report_continuation`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ListContinuation {
+    /// An opaque token to give as the `continuation_token` of the listing
+    /// continuing after the last object listed, or `None` when the listing ran
+    /// out of matching objects.
+    pub next_marker: Option<String>,
+}
+
+// The contents of a continuation token, encoded as base64 JSON. The checksum
+// is an HMAC of the marker and the order keyed with the configured
+// `continuation_secret`, so a token altered by a client is rejected rather than
+// continuing the listing from an arbitrary name.
+#[derive(Deserialize, Serialize)]
+struct ContinuationToken {
+    name: String,
+    #[serde(default)]
+    created: Option<Timestamptz>,
+    order_by: ListOrder,
+    checksum: String,
+}
+
+/// The marker after which a listing continues: the name of an object, and its
+/// creation time when the listing is ordered by creation time.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Marker {
+    pub name: String,
+    pub created: Option<Timestamptz>,
+}
+
+fn continuation_mac(
+    marker: &Marker,
+    order_by: ListOrder,
+    secret: &str,
+) -> Hmac<Sha256> {
+    // An HMAC accepts a key of any length
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes())
+        .expect("failed to key continuation HMAC");
+    let created = marker
+        .created
+        .map(|created| created.to_rfc3339())
+        .unwrap_or_default();
+    mac.input(
+        format!("{}\n{}\n{}", order_by.order_sql(), created, marker.name)
+            .as_bytes(),
+    );
+    mac
+}
+
+/// Encode the token continuing a listing in `order_by` after `marker`, signed
+/// with `secret`.
+pub(crate) fn encode_continuation(
+    marker: &Marker,
+    order_by: ListOrder,
+    secret: &str,
+) -> String {
+    let checksum = continuation_mac(marker, order_by, secret).result().code();
+    let token = ContinuationToken {
+        name: marker.name.clone(),
+        created: marker.created,
+        order_by,
+        checksum: base64::encode(&checksum),
+    };
+    let json = serde_json::to_vec(&token)
+        .expect("failed to serialize ContinuationToken");
+    base64::encode(&json)
+}
+
+/// Decode a continuation token into the marker after which a listing in
+/// `order_by` continues. A token that has been altered, that was not signed
+/// with `secret`, or that was issued for a listing in another order, is a
+/// BadRequestError.
+pub(crate) fn decode_continuation(
+    token: &str,
+    order_by: ListOrder,
+    secret: &str,
+) -> Result<Marker, BucketsMdapiError> {
+    let invalid = || {
+        BucketsMdapiError::BadRequestError(
+            "the continuation_token is not valid".to_string(),
+        )
+    };
+    let json = base64::decode(token).map_err(|_| invalid())?;
+    let token: ContinuationToken =
+        serde_json::from_slice(&json).map_err(|_| invalid())?;
+    let marker = Marker {
+        name: token.name,
+        created: token.created,
+    };
+    let checksum = base64::decode(&token.checksum).map_err(|_| invalid())?;
+    continuation_mac(&marker, token.order_by, secret)
+        .verify(&checksum)
+        .map_err(|_| invalid())?;
+    if token.order_by != order_by {
+        return Err(BucketsMdapiError::BadRequestError(format!(
+            "the continuation_token was issued for a listing ordered by {}, \
+             not {}",
+            token.order_by.order_sql(),
+            order_by.order_sql()
+        )));
+    }
+    Ok(marker)
 }
 
 impl HasRequestId for ListObjectsPayload {
@@ -36,43 +244,64 @@ impl HasRequestId for ListObjectsPayload {
     }
 }
 
+impl HasVnodes for ListObjectsPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
 pub(crate) fn decode_msg(
     value: &Value,
 ) -> Result<Vec<ListObjectsPayload>, SerdeError> {
     serde_json::from_value::<Vec<ListObjectsPayload>>(value.clone())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn action(
     msg_id: u32,
     method: &str,
+    config: &Config,
     metrics: &RegisteredMetrics,
     log: &Logger,
     payload: ListObjectsPayload,
     conn: &mut PostgresConnection,
+    statements: &StatementCache,
 ) -> Result<HandlerResponse, String> {
+    // Objects are listed in the order of their folded names, so the prefix and
+    // marker are folded in the same way
+    let fold = |name: &String| fold_name(name, config);
+    let payload = ListObjectsPayload {
+        prefix: payload.prefix.as_ref().map(fold),
+        marker: payload.marker.as_ref().map(fold),
+        parent_prefix: payload.parent_prefix.as_ref().map(fold),
+        ..payload
+    };
+
     // Make database request
-    if payload.limit > 0 && payload.limit <= 1024 {
-        do_list(msg_id, method, payload, conn, metrics, log)
-            .and_then(|resp| {
-                // Handle the successful database response
-                debug!(log, "operation successful");
-                Ok(HandlerResponse::from(resp))
-            })
-            .or_else(|e| {
-                // Handle database error response
-                error!(log, "operation failed"; "error" => &e);
-
-                // Database errors are returned to as regular Fast messages
-                // to be handled by the calling application
-                let err = BucketsMdapiError::PostgresError(e);
-                let msg_data = FastMessageData::new(
-                    method.into(),
-                    array_wrap(err.into_fast()),
-                );
-                let msg: HandlerResponse =
-                    FastMessage::data(msg_id, msg_data).into();
-                Ok(msg)
-            })
+    // The limit does not apply to a count
+    if payload.count_only
+        || (payload.limit > 0 && payload.limit <= MAX_LIST_LIMIT)
+    {
+        do_list(
+            msg_id, method, payload, statements, config, conn, metrics, log,
+        )
+        .and_then(|resp| {
+            // Handle the successful database response
+            debug!(log, "operation successful");
+            Ok(HandlerResponse::from(resp))
+        })
+        .or_else(|e| {
+            // Handle database error response
+            error!(log, "operation failed"; "error" => e.message());
+
+            // Database errors are returned to as regular Fast messages
+            // to be handled by the calling application
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
     } else {
         // Limit constraint violations are returned to as regular
         // Fast messages to be handled by the calling application
@@ -88,144 +317,406 @@ pub(crate) fn action(
     }
 }
 
+// The listing query is run with a statement prepared once per connection,
+// except for a listing with a deadline, which fetches its rows in batches from
+// a portal.
+#[allow(clippy::too_many_arguments)]
 fn do_list(
     msg_id: u32,
     method: &str,
     payload: ListObjectsPayload,
+    statements: &StatementCache,
+    config: &Config,
     mut conn: &mut PostgresConnection,
     metrics: &RegisteredMetrics,
     log: &Logger,
-) -> Result<Vec<FastMessage>, String> {
-    let query_result = match (payload.marker, payload.prefix) {
-        (Some(marker), Some(prefix)) => {
-            let sql = list_sql_prefix_marker(payload.vnode, payload.limit);
-            let prefix = format!("{}%", prefix);
-            sql::query(
-                sql::Method::ObjectList,
-                &mut conn,
-                sql.as_str(),
-                &[&payload.owner, &payload.bucket_id, &prefix, &marker],
-                metrics,
-                log,
-            )
+) -> Result<Vec<FastMessage>, BucketsMdapiError> {
+    // Continuation tokens are signed with the configured secret, and without
+    // one can neither be issued nor accepted
+    let secret = match &config.listing.continuation_secret {
+        None if payload.continuation_token.is_some()
+            || payload.report_continuation =>
+        {
+            return Err(BucketsMdapiError::BadRequestError(
+                "continuation tokens are not enabled".to_string(),
+            ));
         }
-        (Some(marker), None) => {
-            let sql = list_sql_marker(payload.vnode, payload.limit);
-            sql::query(
-                sql::Method::ObjectList,
-                &mut conn,
-                sql.as_str(),
-                &[&payload.owner, &payload.bucket_id, &marker],
-                metrics,
-                log,
-            )
-        }
-        (None, Some(prefix)) => {
-            let sql = list_sql_prefix(payload.vnode, payload.limit);
-            let prefix = format!("{}%", prefix);
-            sql::query(
-                sql::Method::ObjectList,
-                &mut conn,
-                sql.as_str(),
-                &[&payload.owner, &payload.bucket_id, &prefix],
-                metrics,
-                log,
-            )
+        secret => secret.as_ref().map(String::as_str).unwrap_or_default(),
+    };
+
+    // A continuation token takes the place of the marker
+    let marker = match &payload.continuation_token {
+        Some(_) if payload.marker.is_some() => {
+            return Err(BucketsMdapiError::BadRequestError(
+                "a listing may not give both a marker and a \
+                 continuation_token"
+                    .to_string(),
+            ));
         }
-        (None, None) => {
-            let sql = list_sql(payload.vnode, payload.limit);
-            sql::query(
-                sql::Method::ObjectList,
-                &mut conn,
-                sql.as_str(),
-                &[&payload.owner, &payload.bucket_id],
-                metrics,
-                log,
-            )
+        Some(token) => {
+            Some(decode_continuation(token, payload.order_by, secret)?)
         }
+        None => payload.marker.clone().map(|name| Marker {
+            name,
+            created: payload.marker_created,
+        }),
+    };
+    let marker_created = payload
+        .order_by
+        .marker_created(
+            marker.is_some(),
+            marker.as_ref().and_then(|marker| marker.created),
+        )
+        .map_err(BucketsMdapiError::BadRequestError)?;
+
+    let prefix = payload.prefix.as_ref().map(|prefix| format!("{}%", prefix));
+    let mut items: Vec<&(dyn ToSql + Sync)> =
+        vec![&payload.owner, &payload.bucket_id];
+
+    if let Some(prefix) = &prefix {
+        items.push(prefix);
+    }
+    if let Some(marker) = &marker {
+        items.push(&marker.name);
+    }
+    if let Some(marker_created) = &marker_created {
+        items.push(marker_created);
+    }
+    let names = names_sql(prefix.is_some(), marker.is_some(), payload.order_by);
+
+    // The parent prefix, index filter, and modification time parameters
+    // follow those of the listing query
+    let mut filters = Vec::new();
+    if let Some(parent_prefix) = &payload.parent_prefix {
+        filters.push(parent_prefix_sql(
+            config.listing.parent_prefix,
+            items.len() + 1,
+        ));
+        items.push(parent_prefix);
+    }
+    if let Some(index_filter) = &payload.index_filter {
+        filters.push(index::filter_sql(
+            payload.vnode,
+            index_filter,
+            items.len() + 1,
+        ));
+        items.extend_from_slice(&index_filter.items());
+    }
+    if let Some(modified_after) = &payload.modified_after {
+        filters.push(modified_sql(">", items.len() + 1));
+        items.push(modified_after);
+    }
+    if let Some(modified_before) = &payload.modified_before {
+        filters.push(modified_sql("<", items.len() + 1));
+        items.push(modified_before);
+    }
+
+    if payload.count_only {
+        let sql = count_sql(payload.vnode, &names, &filters.join(" "));
+        return count(msg_id, method, &sql, &items, conn, metrics, log);
+    }
+
+    let select = properties::select_sql(
+        payload.vnode,
+        payload.include_properties,
+        config.names.case_insensitive_names,
+        !payload.skip_sharks,
+    );
+    let sql = list_sql(
+        &select,
+        &names,
+        &filters.join(" "),
+        payload.order_by,
+        payload.limit,
+    );
+    let query_result = match config.listing.deadline_ms {
+        Some(deadline_ms) => query_with_deadline(
+            &mut conn,
+            sql.as_str(),
+            &items,
+            Duration::from_millis(deadline_ms),
+            metrics,
+            log,
+        ),
+        None => sql::cached_query(
+            sql::Method::ObjectList,
+            payload.vnode,
+            Some(statements),
+            &mut conn,
+            sql.as_str(),
+            &items,
+            metrics,
+            log,
+        )
+        .map(|rows| (rows, true)),
     };
 
     let mut msgs: Vec<FastMessage> = Vec::with_capacity(1024);
+    let delimiter = payload.delimiter.as_ref().filter(|d| !d.is_empty());
+    let mut common_prefixes: HashSet<String> = HashSet::new();
 
-    query_result.map_err(|e| e.to_string()).and_then(|rows| {
-        for row in &rows {
-            let content_md5_bytes: Vec<u8> = row.get(7);
-            let content_md5 = base64::encode(&content_md5_bytes);
-            let resp = ObjectResponse {
-                id: row.get("id"),
-                owner: row.get("owner"),
-                bucket_id: row.get("bucket_id"),
-                name: row.get("name"),
-                created: row.get("created"),
-                modified: row.get("modified"),
-                content_length: row.get("content_length"),
-                content_md5,
-                content_type: row.get("content_type"),
-                headers: row.get("headers"),
-                sharks: row.get("sharks"),
-                properties: row.get("properties"),
-            };
+    query_result.map_err(BucketsMdapiError::from).and_then(
+        |(rows, complete)| {
+            metrics.record_list_rows(method, rows.len(), payload.limit);
+            for row in &rows {
+                // The objects sharing a common prefix are only adjacent when
+                // listed in name order, so each common prefix is sent once
+                // for the first of its objects
+                if let Some(delimiter) = delimiter {
+                    let name: String = row.get("name");
+                    let prefix = payload.prefix.as_ref().map(String::as_str);
+                    if let Some(common_prefix) =
+                        common_prefix(&name, prefix, delimiter)
+                    {
+                        if !common_prefixes.contains(&common_prefix) {
+                            let value = serde_json::to_value(CommonPrefix {
+                                common_prefix: common_prefix.clone(),
+                            })
+                            .expect("failed to serialize CommonPrefix");
+                            let msg_data = FastMessageData::new(
+                                method.into(),
+                                array_wrap(value),
+                            );
+                            msgs.push(FastMessage::data(msg_id, msg_data));
+                            let _ = common_prefixes.insert(common_prefix);
+                        }
+                        continue;
+                    }
+                }
 
-            let value = to_json(resp);
-            let msg_data =
-                FastMessageData::new(method.into(), array_wrap(value));
-            let msg = FastMessage::data(msg_id, msg_data);
+                let content_md5_bytes: Vec<u8> = row.get(7);
+                let content_md5 = base64::encode(&content_md5_bytes);
+                let resp = ObjectResponse {
+                    id: row.get("id"),
+                    owner: row.get("owner"),
+                    bucket_id: row.get("bucket_id"),
+                    name: row.get("name"),
+                    created: row.get("created"),
+                    modified: row.get("modified"),
+                    content_length: row.get("content_length"),
+                    content_md5,
+                    content_type: row.get("content_type"),
+                    headers: row_headers(row, config, metrics, log),
+                    sharks: if payload.skip_sharks {
+                        vec![]
+                    } else {
+                        row.get("sharks")
+                    },
+                    properties: row.get("properties"),
+                    vnode: payload.vnode,
+                    display_name: row.try_get("display_name").unwrap_or(None),
+                };
+                check_content_md5(method, &resp, config, metrics, log)?;
 
-            msgs.push(msg);
-        }
-        Ok(msgs)
-    })
-}
+                let value = to_json(resp);
+                let msg_data =
+                    FastMessageData::new(method.into(), array_wrap(value));
+                let msg = FastMessage::data(msg_id, msg_data);
 
-fn list_sql_prefix_marker(vnode: u64, limit: u64) -> String {
-    format!(
-        "SELECT id, owner, bucket_id, name, created, modified, \
-        content_length, content_md5, content_type, headers, sharks, \
-        properties \
-        FROM manta_bucket_{}.manta_bucket_object
-        WHERE owner = $1 AND bucket_id = $2 AND name like $3 AND name > $4
-        ORDER BY name ASC
-        LIMIT {}",
-        vnode, limit
+                msgs.push(msg);
+            }
+
+            // The marker continuing the listing after the last object listed
+            let next_marker = rows
+                .last()
+                .map(|row| Marker {
+                    name: row.get("name"),
+                    created: Some(row.get("created")),
+                })
+                .or_else(|| marker.clone());
+
+            if payload.report_status {
+                let status = ListStatus {
+                    complete,
+                    next_marker: next_marker
+                        .as_ref()
+                        .map(|marker| marker.name.clone()),
+                    next_marker_created: next_marker
+                        .as_ref()
+                        .and_then(|marker| marker.created),
+                };
+                let value = serde_json::to_value(status)
+                    .expect("failed to serialize ListStatus");
+                let msg_data =
+                    FastMessageData::new(method.into(), array_wrap(value));
+                msgs.push(FastMessage::data(msg_id, msg_data));
+            }
+
+            if payload.report_continuation {
+                // More objects likely remain when the listing was cut short or
+                // filled its limit
+                let more = !complete || rows.len() as u64 >= payload.limit;
+                let next_marker = next_marker.filter(|_| more).map(|marker| {
+                    encode_continuation(&marker, payload.order_by, secret)
+                });
+                let value =
+                    serde_json::to_value(ListContinuation { next_marker })
+                        .expect("failed to serialize ListContinuation");
+                let msg_data =
+                    FastMessageData::new(method.into(), array_wrap(value));
+                msgs.push(FastMessage::data(msg_id, msg_data));
+            }
+
+            Ok(msgs)
+        },
     )
 }
 
-fn list_sql_prefix(vnode: u64, limit: u64) -> String {
-    format!(
-        "SELECT id, owner, bucket_id, name, created, modified, \
-        content_length, content_md5, content_type, headers, sharks, \
-        properties \
-        FROM manta_bucket_{}.manta_bucket_object
-        WHERE owner = $1 AND bucket_id = $2 AND name like $3
-        ORDER BY name ASC
-        LIMIT {}",
-        vnode, limit
+// Count the objects matching a listing rather than listing them. The limit
+// and delimiter of the listing do not apply.
+fn count(
+    msg_id: u32,
+    method: &str,
+    sql: &str,
+    items: &[&(dyn ToSql + Sync)],
+    mut conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<Vec<FastMessage>, BucketsMdapiError> {
+    let rows = sql::query(
+        sql::Method::ObjectCount,
+        &mut conn,
+        sql,
+        items,
+        metrics,
+        log,
     )
+    .map_err(BucketsMdapiError::from)?;
+
+    let count = ListCount {
+        count: rows.first().map_or(0, |row| row.get(0)),
+    };
+    let value =
+        serde_json::to_value(count).expect("failed to serialize ListCount");
+    let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+    Ok(vec![FastMessage::data(msg_id, msg_data)])
+}
+
+/// Fetch the rows of a listing `LIST_FETCH_ROWS` at a time, stopping once
+/// `deadline` has passed since the listing started. Returns the rows fetched
+/// and whether the listing ran to completion before the deadline.
+fn query_with_deadline(
+    conn: &mut PostgresConnection,
+    sql: &str,
+    items: &[&(dyn ToSql + Sync)],
+    deadline: Duration,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> PostgresResult<(Rows, bool)> {
+    let start = Instant::now();
+    let mut txn = conn.transaction()?;
+    let portal = txn.bind(sql, items)?;
+    let mut rows = Vec::new();
+
+    loop {
+        if start.elapsed() >= deadline {
+            debug!(log, "listing stopped at deadline";
+                "rows" => rows.len(),
+                "deadline_ms" => deadline.as_millis());
+            return Ok((rows, false));
+        }
+
+        let fetched = sql::txn_query_portal(
+            sql::Method::ObjectList,
+            &mut txn,
+            &portal,
+            LIST_FETCH_ROWS,
+            metrics,
+            log,
+        )?;
+        let exhausted = fetched.len() < LIST_FETCH_ROWS as usize;
+        rows.extend(fetched);
+
+        if exhausted {
+            return Ok((rows, true));
+        }
+    }
+}
+
+// The number of rows fetched at a time by a listing with a deadline
+const LIST_FETCH_ROWS: i32 = 100;
+
+/// Build the clause restricting a listing of the `manta_bucket_object` table,
+/// aliased as `o`, to the objects directly under the parent prefix bound to
+/// `$param`. The indexed `parent_prefix` column is used when it is stored,
+/// otherwise the object names under the prefix are compared.
+fn parent_prefix_sql(stored: bool, param: usize) -> String {
+    if stored {
+        format!("AND o.parent_prefix = ${}", param)
+    } else {
+        format!(
+            "AND left(o.name, length(${0}::text)) = ${0}::text \
+             AND strpos(substr(o.name, length(${0}::text) + 1), '/') = 0",
+            param
+        )
+    }
 }
 
-fn list_sql_marker(vnode: u64, limit: u64) -> String {
+/// The name of the object up to and including the first `delimiter` after the
+/// listing `prefix`, or `None` if the name has no delimiter after the prefix.
+fn common_prefix(
+    name: &str,
+    prefix: Option<&str>,
+    delimiter: &str,
+) -> Option<String> {
+    let start = prefix
+        .filter(|prefix| name.starts_with(prefix))
+        .map_or(0, str::len);
+    name[start..]
+        .find(delimiter)
+        .map(|pos| name[..start + pos + delimiter.len()].to_string())
+}
+
+/// Build the clause restricting a listing to the objects whose `modified` time
+/// compares to the time bound to `$param` with `op`.
+fn modified_sql(op: &str, param: usize) -> String {
+    format!("AND o.modified {} ${}", op, param)
+}
+
+/// Build the conditions of a listing on the names of objects, whose prefix and
+/// marker parameters follow the owner and bucket parameters. The marker
+/// continues the listing in the given order, and is followed by its creation
+/// time when the listing is ordered by creation time.
+fn names_sql(prefix: bool, marker: bool, order: ListOrder) -> String {
+    let mut sql = String::new();
+    if prefix {
+        sql.push_str(" AND name like $3");
+    }
+    if marker {
+        let param = if prefix { 4 } else { 3 };
+        sql.push_str(" AND ");
+        sql.push_str(&order.marker_sql(param));
+    }
+    sql
+}
+
+// Each listing query selects from the objects as given by
+// `properties::select_sql`
+fn list_sql(
+    select: &str,
+    names: &str,
+    filter: &str,
+    order: ListOrder,
+    limit: u64,
+) -> String {
     format!(
-        "SELECT id, owner, bucket_id, name, created, modified, \
-        content_length, content_md5, content_type, headers, sharks, \
-        properties \
-        FROM manta_bucket_{}.manta_bucket_object
-        WHERE owner = $1 AND bucket_id = $2 AND name > $3
-        ORDER BY name ASC
+        "{}
+        WHERE owner = $1 AND bucket_id = $2{} {}
+        ORDER BY {}
         LIMIT {}",
-        vnode, limit
+        select,
+        names,
+        filter,
+        order.order_sql(),
+        limit
     )
 }
 
-fn list_sql(vnode: u64, limit: u64) -> String {
+fn count_sql(vnode: u64, names: &str, filter: &str) -> String {
     format!(
-        "SELECT id, owner, bucket_id, name, created, modified, \
-        content_length, content_md5, content_type, headers, sharks, \
-        properties \
-        FROM manta_bucket_{}.manta_bucket_object
-        WHERE owner = $1 AND bucket_id = $2
-        ORDER BY name ASC
-        LIMIT {}",
-        vnode, limit
+        "SELECT count(*) FROM manta_bucket_{}.manta_bucket_object o
+        WHERE owner = $1 AND bucket_id = $2{} {}",
+        vnode, names, filter
     )
 }
 
@@ -233,6 +724,7 @@ fn list_sql(vnode: u64, limit: u64) -> String {
 mod test {
     use super::*;
 
+    use chrono::{TimeZone, Utc};
     use quickcheck::{quickcheck, Arbitrary, Gen};
     use quickcheck_helpers::random;
     use serde_json;
@@ -270,6 +762,14 @@ mod test {
         }
     }
 
+    fn arbitrary_timestamp<G: Gen>(g: &mut G) -> Option<Timestamptz> {
+        if bool::arbitrary(g) {
+            Some(Utc.timestamp(i64::from(u32::arbitrary(g)), 0))
+        } else {
+            None
+        }
+    }
+
     impl Arbitrary for ListObjectsPayload {
         fn arbitrary<G: Gen>(g: &mut G) -> Self {
             let owner = Uuid::new_v4();
@@ -279,6 +779,19 @@ mod test {
             let limit = u64::arbitrary(g);
             let marker = Some(random::string(g, 32));
             let request_id = Uuid::new_v4();
+            let marker_created = arbitrary_timestamp(g);
+            let index_filter = Option::<IndexFilter>::arbitrary(g);
+            let parent_prefix = Option::<String>::arbitrary(g);
+            let include_properties = bool::arbitrary(g);
+            let report_status = bool::arbitrary(g);
+            let modified_after = arbitrary_timestamp(g);
+            let modified_before = arbitrary_timestamp(g);
+            let skip_sharks = bool::arbitrary(g);
+            let delimiter = Option::<String>::arbitrary(g);
+            let count_only = bool::arbitrary(g);
+            let order_by = ListOrder::arbitrary(g);
+            let continuation_token = Option::<String>::arbitrary(g);
+            let report_continuation = bool::arbitrary(g);
 
             ListObjectsPayload {
                 owner,
@@ -288,10 +801,228 @@ mod test {
                 limit,
                 marker,
                 request_id,
+                marker_created,
+                index_filter,
+                parent_prefix,
+                include_properties,
+                report_status,
+                modified_after,
+                modified_before,
+                skip_sharks,
+                delimiter,
+                count_only,
+                order_by,
+                continuation_token,
+                report_continuation,
             }
         }
     }
 
+    impl Arbitrary for ListOrder {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            match u8::arbitrary(g) % 4 {
+                0 => ListOrder::NameAsc,
+                1 => ListOrder::NameDesc,
+                2 => ListOrder::CreatedAsc,
+                _ => ListOrder::CreatedDesc,
+            }
+        }
+    }
+
+    #[test]
+    fn parent_prefix_clause() {
+        assert_eq!(parent_prefix_sql(true, 3), "AND o.parent_prefix = $3");
+        assert_eq!(
+            parent_prefix_sql(false, 4),
+            "AND left(o.name, length($4::text)) = $4::text \
+             AND strpos(substr(o.name, length($4::text) + 1), '/') = 0"
+        );
+    }
+
+    #[test]
+    fn modified_window_clauses() {
+        // An open-ended window follows any other filters
+        let filters = vec![parent_prefix_sql(true, 4), modified_sql(">", 5)];
+        let names = names_sql(false, true, ListOrder::NameAsc);
+        let sql = list_sql(
+            "SELECT",
+            &names,
+            &filters.join(" "),
+            ListOrder::NameAsc,
+            10,
+        );
+        assert!(sql.contains(
+            "name > $3 AND o.parent_prefix = $4 AND o.modified > $5\n"
+        ));
+
+        // A bounded window takes a parameter for each end
+        let filters = vec![modified_sql(">", 5), modified_sql("<", 6)];
+        let names = names_sql(true, true, ListOrder::NameAsc);
+        let sql = list_sql(
+            "SELECT",
+            &names,
+            &filters.join(" "),
+            ListOrder::NameAsc,
+            10,
+        );
+        assert!(
+            sql.contains("name > $4 AND o.modified > $5 AND o.modified < $6\n")
+        );
+    }
+
+    #[test]
+    fn count_clauses() {
+        let filters = vec![modified_sql(">", 4)];
+        let names = names_sql(true, false, ListOrder::NameAsc);
+        let sql = count_sql(3, &names, &filters.join(" "));
+        assert_eq!(
+            sql,
+            "SELECT count(*) FROM manta_bucket_3.manta_bucket_object o
+        WHERE owner = $1 AND bucket_id = $2 AND name like $3 \
+             AND o.modified > $4"
+        );
+        assert!(!sql.contains("LIMIT"));
+    }
+
+    #[test]
+    fn ordered_marker_clauses() {
+        let sql = list_sql(
+            "SELECT",
+            &names_sql(false, true, ListOrder::NameDesc),
+            "",
+            ListOrder::NameDesc,
+            10,
+        );
+        assert!(sql.contains("AND name < $3 \n"));
+        assert!(sql.contains("ORDER BY name DESC\n"));
+
+        let sql = list_sql(
+            "SELECT",
+            &names_sql(true, true, ListOrder::CreatedAsc),
+            "",
+            ListOrder::CreatedAsc,
+            10,
+        );
+        assert!(sql.contains(
+            "AND name like $3 AND (created, name) > ($5::timestamptz, $4)"
+        ));
+        assert!(sql.contains("ORDER BY created ASC, name ASC\n"));
+
+        let names = names_sql(false, true, ListOrder::CreatedDesc);
+        assert_eq!(names, " AND (created, name) < ($4::timestamptz, $3)");
+    }
+
+    #[test]
+    fn created_markers_require_creation_time() {
+        let created = Utc.timestamp(1_500_000_000, 0);
+        assert!(ListOrder::CreatedAsc.marker_created(true, None).is_err());
+        assert_eq!(
+            ListOrder::CreatedDesc.marker_created(true, Some(created)),
+            Ok(Some(created))
+        );
+        assert_eq!(ListOrder::CreatedAsc.marker_created(false, None), Ok(None));
+        assert_eq!(
+            ListOrder::NameAsc.marker_created(true, Some(created)),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn delimited_common_prefixes() {
+        let common_prefixes: Vec<Option<String>> = ["a/b", "a/c", "d"]
+            .iter()
+            .map(|name| common_prefix(name, None, "/"))
+            .collect();
+        assert_eq!(
+            common_prefixes,
+            vec![Some("a/".to_string()), Some("a/".to_string()), None]
+        );
+
+        // The delimiter is only sought after the prefix
+        assert_eq!(
+            common_prefix("photos/2019/cat.jpg", Some("photos/"), "/"),
+            Some("photos/2019/".to_string())
+        );
+        assert_eq!(common_prefix("photos/dog.jpg", Some("photos/"), "/"), None);
+        assert_eq!(
+            common_prefix("photos--2019--cat.jpg", Some("pho"), "--"),
+            Some("photos--".to_string())
+        );
+    }
+
+    #[test]
+    fn continuation_tokens() {
+        let marker = Marker {
+            name: "photos/cat.jpg".to_string(),
+            created: Some(Utc.timestamp(1_500_000_000, 0)),
+        };
+        let secret = "continuation secret";
+        let token = encode_continuation(&marker, ListOrder::NameDesc, secret);
+        assert_eq!(
+            decode_continuation(&token, ListOrder::NameDesc, secret).ok(),
+            Some(marker)
+        );
+
+        // A token only continues a listing in the order it was issued for
+        let bad_request =
+            |result: Result<Marker, BucketsMdapiError>| match result {
+                Err(BucketsMdapiError::BadRequestError(_)) => true,
+                _ => false,
+            };
+        assert!(bad_request(decode_continuation(
+            &token,
+            ListOrder::NameAsc,
+            secret
+        )));
+
+        // A token signed with another secret is rejected
+        assert!(bad_request(decode_continuation(
+            &token,
+            ListOrder::NameDesc,
+            "another secret"
+        )));
+
+        // A token whose name or order was altered is rejected
+        let json = base64::decode(&token).unwrap();
+        let mut altered: ContinuationToken =
+            serde_json::from_slice(&json).unwrap();
+        altered.name = "photos/dog.jpg".to_string();
+        let altered_token =
+            base64::encode(&serde_json::to_vec(&altered).unwrap());
+        assert!(bad_request(decode_continuation(
+            &altered_token,
+            ListOrder::NameDesc,
+            secret
+        )));
+        let mut reordered: ContinuationToken =
+            serde_json::from_slice(&json).unwrap();
+        reordered.order_by = ListOrder::NameAsc;
+        let reordered_token =
+            base64::encode(&serde_json::to_vec(&reordered).unwrap());
+        assert!(bad_request(decode_continuation(
+            &reordered_token,
+            ListOrder::NameAsc,
+            secret
+        )));
+
+        assert!(bad_request(decode_continuation(
+            "photos/cat.jpg",
+            ListOrder::NameAsc,
+            secret
+        )));
+    }
+
+    quickcheck! {
+        fn prop_continuation_token_roundtrip(name: String, created: u32) -> bool {
+            let marker = Marker {
+                name,
+                created: Some(Utc.timestamp(i64::from(created), 0)),
+            };
+            let token = encode_continuation(&marker, ListOrder::CreatedAsc, "secret");
+            decode_continuation(&token, ListOrder::CreatedAsc, "secret").ok() == Some(marker)
+        }
+    }
+
     quickcheck! {
         fn prop_list_object_payload_roundtrip(msg: ListObjectsPayload) -> bool {
             match serde_json::to_string(&msg) {
@@ -0,0 +1,255 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Fetch several objects of a bucket with a single Fast request.
+//!
+//! The `getobjects` RPC reads the objects named by the request from a single
+//! vnode with one query. The response has one message for each object found,
+//! in the order of their names, followed by a `GetObjectsStatus` message
+//! listing the requested names for which no object exists.
+
+use std::collections::HashSet;
+use std::slice;
+use std::vec::Vec;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::object::{
+    check_content_md5, fold_name, properties, response, to_json,
+};
+use crate::sql;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
+use crate::util::{array_wrap, limit_constraint_error};
+
+/// The maximum number of objects that may be requested at once.
+pub const MAX_GET_OBJECTS: usize = 1024;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GetObjectsPayload {
+    pub owner: Uuid,
+    pub bucket_id: Uuid,
+    pub vnode: u64,
+    pub request_id: Uuid,
+    pub names: Vec<String>,
+
+    /// Include the `properties` of objects that are stored outside of the
+    /// object row for exceeding the configured `max_inline_bytes`.
+    #[serde(default)]
+    pub include_properties: bool,
+}
+
+impl HasRequestId for GetObjectsPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for GetObjectsPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
+/// The final message of a `getobjects` response.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GetObjectsStatus {
+    /// The requested names for which no object exists, as they were given in
+    /// the request.
+    pub missing: Vec<String>,
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<GetObjectsPayload>, SerdeError> {
+    serde_json::from_value::<Vec<GetObjectsPayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: GetObjectsPayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    let count = payload.names.len();
+    if count == 0 || count > MAX_GET_OBJECTS {
+        // Limit constraint violations are returned to as regular
+        // Fast messages to be handled by the calling application
+        let e = format!(
+            "the {} names option must contain between 1 and {} names. \
+             the request contained {}",
+            &method, MAX_GET_OBJECTS, count
+        );
+        let value = limit_constraint_error(e);
+        let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+        let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+        return Ok(msg);
+    }
+
+    // Make database request
+    do_get_many(msg_id, method, &payload, config, conn, metrics, log)
+        .and_then(|msgs| {
+            // Handle the successful database response
+            debug!(log, "operation successful");
+            Ok(HandlerResponse::from(msgs))
+        })
+        .or_else(|e| {
+            if let BucketsMdapiError::PostgresError { .. } = &e {
+                error!(log, "operation failed"; "error" => e.message());
+            }
+
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+fn do_get_many(
+    msg_id: u32,
+    method: &str,
+    payload: &GetObjectsPayload,
+    config: &Config,
+    mut conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<Vec<FastMessage>, BucketsMdapiError> {
+    let names: Vec<String> = payload
+        .names
+        .iter()
+        .map(|name| fold_name(name, config))
+        .collect();
+    let sql = [
+        properties::select_sql(
+            payload.vnode,
+            payload.include_properties,
+            config.names.case_insensitive_names,
+            true,
+        )
+        .as_str(),
+        " WHERE owner = $1 AND bucket_id = $2 AND name = ANY($3) \
+         ORDER BY name",
+    ]
+    .concat();
+
+    let rows = sql::query(
+        sql::Method::ObjectGetMany,
+        &mut conn,
+        sql.as_str(),
+        &[&payload.owner, &payload.bucket_id, &names],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    let mut msgs: Vec<FastMessage> = Vec::with_capacity(rows.len() + 1);
+    let mut found = HashSet::with_capacity(rows.len());
+    for row in &rows {
+        let object = response(
+            method,
+            payload.vnode,
+            slice::from_ref(row),
+            config,
+            metrics,
+            log,
+        )?
+        .ok_or(BucketsMdapiError::ObjectNotFound)?;
+        check_content_md5(method, &object, config, metrics, log)?;
+        let _ = found.insert(object.name.clone());
+
+        let msg_data =
+            FastMessageData::new(method.into(), array_wrap(to_json(object)));
+        msgs.push(FastMessage::data(msg_id, msg_data));
+    }
+
+    let status = GetObjectsStatus {
+        missing: missing_names(&payload.names, &names, &found),
+    };
+    let value = serde_json::to_value(status)
+        .expect("failed to serialize GetObjectsStatus");
+    let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+    msgs.push(FastMessage::data(msg_id, msg_data));
+
+    Ok(msgs)
+}
+
+// The requested names, in request order, whose folded name is not among the
+// names of the objects found.
+fn missing_names(
+    requested: &[String],
+    folded: &[String],
+    found: &HashSet<String>,
+) -> Vec<String> {
+    requested
+        .iter()
+        .zip(folded)
+        .filter(|(_, name)| !found.contains(*name))
+        .map(|(requested, _)| requested.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use quickcheck_helpers::random;
+
+    impl Arbitrary for GetObjectsPayload {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let len = usize::arbitrary(g) % 8;
+            GetObjectsPayload {
+                owner: Uuid::new_v4(),
+                bucket_id: Uuid::new_v4(),
+                vnode: u64::arbitrary(g),
+                request_id: Uuid::new_v4(),
+                names: (0..len).map(|_| random::string(g, 32)).collect(),
+                include_properties: bool::arbitrary(g),
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_get_objects_payload_roundtrip(msg: GetObjectsPayload) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(get_str) => {
+                    let decode_result: Result<GetObjectsPayload, _> =
+                        serde_json::from_str(&get_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+
+    #[test]
+    fn missing_names_in_request_order() {
+        let requested: Vec<String> =
+            vec!["Cat.jpg".into(), "dog.jpg".into(), "Owl.jpg".into()];
+        let folded: Vec<String> =
+            requested.iter().map(|name| name.to_lowercase()).collect();
+        let found: HashSet<String> =
+            vec!["dog.jpg".to_string()].into_iter().collect();
+
+        assert_eq!(
+            missing_names(&requested, &folded, &found),
+            vec!["Cat.jpg".to_string(), "Owl.jpg".to_string()]
+        );
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Protect critical objects from accidental deletion.
+//!
+//! The `pinobject` RPC sets or clears the pin of an object. A pinned object
+//! cannot be deleted, and is not replaced by a create or rename of another
+//! object with its name; such requests fail with an `ObjectPinned` error. A
+//! `deleteobject` request may set `unpin_first` to delete a pinned object in
+//! one step. Because a pinned object is never moved to the deleted object
+//! table, garbage collection never reclaims its data, and the orphan scan
+//! leaves it in place when its bucket is deleted.
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::object::{fold_name, object_not_found};
+use crate::sql;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
+use crate::util::array_wrap;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PinObjectPayload {
+    pub owner: Uuid,
+    pub bucket_id: Uuid,
+    pub name: String,
+    pub vnode: u64,
+    pub request_id: Uuid,
+    /// Whether the object is pinned or its pin cleared
+    pub pinned: bool,
+}
+
+impl HasRequestId for PinObjectPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for PinObjectPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PinObjectResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub pinned: bool,
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<PinObjectPayload>, SerdeError> {
+    serde_json::from_value::<Vec<PinObjectPayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: PinObjectPayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    let payload = PinObjectPayload {
+        name: fold_name(&payload.name, config),
+        ..payload
+    };
+
+    // Make database request
+    do_pin(&payload, conn, metrics, log)
+        .and_then(|maybe_resp| {
+            // Handle the successful database response
+            debug!(log, "operation successful");
+            let value = match maybe_resp {
+                // The PinObjectResponse type contains no maps, so the
+                // conversion to JSON is not expected to fail.
+                Some(resp) => serde_json::to_value(resp)
+                    .expect("failed to serialize PinObjectResponse"),
+                None => object_not_found(),
+            };
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(value));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+        .or_else(|e| {
+            // Handle database error response
+            error!(log, "operation failed"; "error" => e.message());
+
+            // Database errors are returned to as regular Fast messages
+            // to be handled by the calling application
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+fn do_pin(
+    payload: &PinObjectPayload,
+    mut conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<Option<PinObjectResponse>, BucketsMdapiError> {
+    let sql = pin_sql(payload.vnode);
+
+    let rows = sql::query(
+        sql::Method::ObjectPin,
+        &mut conn,
+        sql.as_str(),
+        &[
+            &payload.owner,
+            &payload.bucket_id,
+            &payload.name,
+            &payload.pinned,
+        ],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    Ok(rows.first().map(|row| PinObjectResponse {
+        id: row.get("id"),
+        name: row.get("name"),
+        pinned: row.get("pinned"),
+    }))
+}
+
+fn pin_sql(vnode: u64) -> String {
+    [
+        "UPDATE manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_object SET pinned = $4 \
+         WHERE owner = $1 AND bucket_id = $2 AND name = $3 \
+         RETURNING id, name, pinned",
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use quickcheck_helpers::random;
+
+    impl Arbitrary for PinObjectPayload {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            PinObjectPayload {
+                owner: Uuid::new_v4(),
+                bucket_id: Uuid::new_v4(),
+                name: random::string(g, 32),
+                vnode: u64::arbitrary(g),
+                request_id: Uuid::new_v4(),
+                pinned: bool::arbitrary(g),
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_pin_object_payload_roundtrip(msg: PinObjectPayload) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(pin_str) => {
+                    let decode_result: Result<PinObjectPayload, _> =
+                        serde_json::from_str(&pin_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Object properties stored outside of the object row.
+//!
+//! The `properties` of an object are returned by every read of the object, so
+//! very large properties make every `getobject` and `listobjects` costly. When
+//! `max_inline_bytes` is configured, properties whose JSON encoding is larger
+//! than that are stored in the `manta_bucket_object_properties` table of the
+//! object's vnode, keyed by the object id, and the `properties` column of the
+//! object row is left `NULL`. Reads only join the external properties when the
+//! request sets `include_properties`:
+//!
+//! ```sql
+//! SELECT o.id, ..., COALESCE(o.properties, p.properties) AS properties
+//! FROM manta_bucket_<vnode>.manta_bucket_object o
+//! LEFT JOIN manta_bucket_<vnode>.manta_bucket_object_properties p
+//!     ON p.id = o.id
+//! ```
+//!
+//! The external properties of an object are removed and rewritten by every
+//! `createobject` and `updateobject`, and removed by `deleteobject`, in the
+//! same transaction as the change to the object row, so a reader never sees
+//! the properties of one version of an object with the row of another.
+
+use postgres::Transaction;
+use serde_json::Value;
+use slog::Logger;
+use uuid::Uuid;
+
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::sql;
+
+/// Split the properties of an object into those to store in the object row
+/// and those to store externally. Properties are only stored externally when
+/// their JSON encoding is larger than `max_inline_bytes`.
+pub(crate) fn split(
+    properties: &Option<Value>,
+    max_inline_bytes: Option<usize>,
+) -> (Option<&Value>, Option<&Value>) {
+    match (properties, max_inline_bytes) {
+        (Some(value), Some(max)) if encoded_len(value) > max => {
+            (None, Some(value))
+        }
+        (value, _) => (value.as_ref(), None),
+    }
+}
+
+fn encoded_len(value: &Value) -> usize {
+    serde_json::to_vec(value).map(|v| v.len()).unwrap_or(0)
+}
+
+/// Store the external properties of the object `id`.
+pub(crate) fn insert(
+    mut txn: &mut Transaction,
+    vnode: u64,
+    id: &Uuid,
+    properties: &Value,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<(), BucketsMdapiError> {
+    sql::txn_execute(
+        sql::Method::ObjectPropertiesInsert,
+        &mut txn,
+        insert_sql(vnode).as_str(),
+        &[id, properties],
+        metrics,
+        log,
+    )
+    .map(|_| ())
+    .map_err(BucketsMdapiError::from)
+}
+
+/// Remove the external properties, if any, of the object currently stored
+/// under `name`.
+pub(crate) fn delete(
+    mut txn: &mut Transaction,
+    vnode: u64,
+    owner: &Uuid,
+    bucket_id: &Uuid,
+    name: &str,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<(), BucketsMdapiError> {
+    sql::txn_execute(
+        sql::Method::ObjectPropertiesDelete,
+        &mut txn,
+        delete_sql(vnode).as_str(),
+        &[owner, bucket_id, &name],
+        metrics,
+        log,
+    )
+    .map(|_| ())
+    .map_err(BucketsMdapiError::from)
+}
+
+/// The select list and source of a query for whole objects from the
+/// `manta_bucket_object` table, aliased as `o`. The external properties of the
+/// objects are joined when `include_properties` is set, the display names of
+/// the objects are selected when `display_name` is set, and the sharks of the
+/// objects are selected unless `sharks` is unset.
+pub(crate) fn select_sql(
+    vnode: u64,
+    include_properties: bool,
+    display_name: bool,
+    sharks: bool,
+) -> String {
+    let vnode_str = vnode.to_string();
+    let display_name_column = if display_name { ", display_name" } else { "" };
+    let sharks_column = if sharks { ", sharks" } else { "" };
+    if include_properties {
+        [
+            "SELECT o.id, owner, bucket_id, name, created, modified, \
+             content_length, content_md5, content_type, headers",
+            sharks_column,
+            ", COALESCE(o.properties, p.properties) AS properties",
+            display_name_column,
+            " FROM manta_bucket_",
+            &vnode_str,
+            ".manta_bucket_object o \
+             LEFT JOIN manta_bucket_",
+            &vnode_str,
+            ".manta_bucket_object_properties p ON p.id = o.id",
+        ]
+        .concat()
+    } else {
+        [
+            "SELECT id, owner, bucket_id, name, created, modified, \
+             content_length, content_md5, content_type, headers",
+            sharks_column,
+            ", properties",
+            display_name_column,
+            " FROM manta_bucket_",
+            &vnode_str,
+            ".manta_bucket_object o",
+        ]
+        .concat()
+    }
+}
+
+fn insert_sql(vnode: u64) -> String {
+    [
+        "INSERT INTO manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_object_properties (id, properties) VALUES ($1, $2)",
+    ]
+    .concat()
+}
+
+fn delete_sql(vnode: u64) -> String {
+    let vnode_str = vnode.to_string();
+    [
+        "DELETE FROM manta_bucket_",
+        &vnode_str,
+        ".manta_bucket_object_properties \
+         WHERE id IN (SELECT id FROM manta_bucket_",
+        &vnode_str,
+        ".manta_bucket_object \
+         WHERE owner = $1 AND bucket_id = $2 AND name = $3)",
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn split_properties() {
+        let small = Some(json!({ "color": "blue" }));
+        let large = Some(json!({ "notes": "x".repeat(128) }));
+
+        assert_eq!(split(&small, Some(64)), (small.as_ref(), None));
+        assert_eq!(split(&large, Some(64)), (None, large.as_ref()));
+        assert_eq!(split(&None, Some(64)), (None, None));
+
+        // Every property is stored inline unless a maximum is configured
+        assert_eq!(split(&large, None), (large.as_ref(), None));
+    }
+
+    #[test]
+    fn select_without_sharks() {
+        let sql = select_sql(1, false, false, true);
+        assert!(sql.contains("content_type, headers, sharks, properties FROM"));
+
+        let sql = select_sql(1, true, true, false);
+        assert!(!sql.contains("sharks"));
+        assert!(sql.contains(
+            "headers, COALESCE(o.properties, p.properties) AS properties, \
+             display_name FROM"
+        ));
+    }
+}
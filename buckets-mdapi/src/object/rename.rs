@@ -0,0 +1,397 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Rename an object within its bucket.
+//!
+//! The `renameobject` RPC changes the name of an object in a single
+//! transaction. The object keeps its id, its `created` and `modified` times,
+//! its sharks and all of its other metadata, and its indexed metadata entries
+//! move with it. An existing object with the new name is replaced and, if it
+//! has content, moved to the deleted object table as for `createobject`. The
+//! `conditions` of the request apply to that existing object, so a client
+//! passing `if-none-match: *` never replaces an object. When bucket quotas
+//! are enabled the maintained bucket totals are lowered by a replaced object.
+
+use std::marker::Sync;
+use std::vec::Vec;
+
+use postgres::types::ToSql;
+use postgres::Transaction;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::conditional;
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::object::index;
+use crate::object::properties;
+use crate::object::{
+    check_bucket_vnode, check_not_pinned, fold_name, insert_delete_table_sql,
+    parent_prefix, record_audit_event, rename_index_sql, rename_sql, response,
+    to_json, update_bucket_totals, ObjectResponse,
+};
+use crate::sql;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
+use crate::util::{array_wrap, limit_constraint_error};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RenameObjectPayload {
+    pub owner: Uuid,
+    pub bucket_id: Uuid,
+    pub old_name: String,
+    pub new_name: String,
+    pub vnode: u64,
+    pub request_id: Uuid,
+
+    /// Conditions on any existing object named `new_name`.
+    #[serde(default)]
+    pub conditions: conditional::Conditions,
+
+    /// The vnode holding the metadata of the object's bucket. When bucket
+    /// quotas are enabled the bucket's maintained totals are lowered by any
+    /// object replaced by the rename.
+    #[serde(default)]
+    pub bucket_vnode: Option<u64>,
+}
+
+impl HasRequestId for RenameObjectPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for RenameObjectPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        std::iter::once(self.vnode)
+            .chain(self.bucket_vnode)
+            .collect()
+    }
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<RenameObjectPayload>, SerdeError> {
+    serde_json::from_value::<Vec<RenameObjectPayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: RenameObjectPayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    // Renaming an object to its own name would replace the object with
+    // itself, removing it
+    if fold_name(&payload.old_name, config)
+        == fold_name(&payload.new_name, config)
+    {
+        // Limit constraint violations are returned to as regular
+        // Fast messages to be handled by the calling application
+        let e = format!(
+            "the {} names must name two different objects. both names \
+             were '{}'",
+            &method, &payload.old_name
+        );
+        let value = limit_constraint_error(e);
+        let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+        let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+        return Ok(msg);
+    }
+
+    // Make database request
+    check_bucket_vnode(payload.bucket_vnode, config)
+        .and_then(|_| do_rename(method, &payload, config, conn, metrics, log))
+        .and_then(|resp| {
+            // Handle the successful database response
+            debug!(log, "operation successful");
+            let value = array_wrap(to_json(resp));
+            let msg_data = FastMessageData::new(method.into(), value);
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+        .or_else(|e| {
+            if let BucketsMdapiError::PostgresError { .. } = &e {
+                error!(log, "operation failed"; "error" => e.message());
+            }
+
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+fn do_rename(
+    method: &str,
+    payload: &RenameObjectPayload,
+    config: &Config,
+    conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<ObjectResponse, BucketsMdapiError> {
+    let old_name = fold_name(&payload.old_name, config);
+    let new_name = fold_name(&payload.new_name, config);
+    let store_parent_prefix = config.listing.parent_prefix;
+    let store_display_name = config.names.case_insensitive_names;
+
+    let mut txn = (*conn).transaction().map_err(BucketsMdapiError::from)?;
+
+    conditional::request(
+        &mut txn,
+        &[&payload.owner, &payload.bucket_id, &new_name],
+        payload.vnode,
+        &payload.conditions,
+        config,
+        metrics,
+        log,
+    )?;
+
+    // Returning early drops the transaction, which restores any replaced
+    // object when the object being renamed does not exist
+    replace_object(&mut txn, payload, &new_name, config, metrics, log)?;
+
+    let prefix = parent_prefix(&new_name);
+    let mut items: Vec<&(dyn ToSql + Sync)> =
+        vec![&payload.owner, &payload.bucket_id, &old_name, &new_name];
+    if store_parent_prefix {
+        items.push(&prefix);
+    }
+    if store_display_name {
+        items.push(&payload.new_name);
+    }
+
+    let rows = sql::txn_query(
+        sql::Method::ObjectRename,
+        &mut txn,
+        rename_sql(payload.vnode, store_parent_prefix, store_display_name)
+            .as_str(),
+        &items,
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+    if rows.is_empty() {
+        return Err(BucketsMdapiError::ObjectNotFound);
+    }
+
+    sql::txn_execute(
+        sql::Method::ObjectRename,
+        &mut txn,
+        rename_index_sql(payload.vnode).as_str(),
+        &[&payload.owner, &payload.bucket_id, &old_name, &new_name],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    if config.audit.enabled {
+        record_audit_event(
+            &mut txn,
+            payload.vnode,
+            &payload.owner,
+            &payload.bucket_id,
+            &new_name,
+            &rows[0].get("id"),
+            "rename",
+            &payload.request_id,
+            metrics,
+            log,
+        )?;
+    }
+
+    txn.commit().map_err(BucketsMdapiError::from)?;
+
+    response(method, payload.vnode, &rows, config, metrics, log)?
+        .ok_or(BucketsMdapiError::ObjectNotFound)
+}
+
+// Remove any existing object named `new_name`, along with its external
+// properties and indexed metadata entries, moving it to the deleted object
+// table if it has content and lowering the bucket's maintained totals when
+// quotas are enabled. A pinned object is never replaced.
+fn replace_object(
+    mut txn: &mut Transaction,
+    payload: &RenameObjectPayload,
+    new_name: &str,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<(), BucketsMdapiError> {
+    check_not_pinned(
+        &mut txn,
+        payload.vnode,
+        &payload.owner,
+        &payload.bucket_id,
+        new_name,
+        metrics,
+        log,
+    )?;
+
+    if config.properties.max_inline_bytes.is_some() {
+        properties::delete(
+            &mut txn,
+            payload.vnode,
+            &payload.owner,
+            &payload.bucket_id,
+            new_name,
+            metrics,
+            log,
+        )?;
+    }
+
+    index::delete_entries(
+        &mut txn,
+        payload.vnode,
+        &payload.owner,
+        &payload.bucket_id,
+        new_name,
+        metrics,
+        log,
+    )?;
+
+    sql::txn_execute(
+        sql::Method::ObjectDeleteMove,
+        &mut txn,
+        insert_delete_table_sql(payload.vnode).as_str(),
+        &[&payload.owner, &payload.bucket_id, &new_name],
+        metrics,
+        log,
+    )
+    .and_then(|_| {
+        sql::txn_query(
+            sql::Method::ObjectDelete,
+            &mut txn,
+            replace_sql(payload.vnode).as_str(),
+            &[&payload.owner, &payload.bucket_id, &new_name],
+            metrics,
+            log,
+        )
+    })
+    .map_err(BucketsMdapiError::from)
+    .and_then(|rows| match payload.bucket_vnode {
+        Some(bucket_vnode) if config.quota.enabled && !rows.is_empty() => {
+            let replaced_bytes: i64 = rows[0].get("content_length");
+            update_bucket_totals(
+                &mut txn,
+                &payload.owner,
+                &payload.bucket_id,
+                bucket_vnode,
+                -replaced_bytes,
+                -1,
+                metrics,
+                log,
+            )
+        }
+        _ => Ok(()),
+    })
+}
+
+fn replace_sql(vnode: u64) -> String {
+    [
+        "DELETE FROM manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_object \
+         WHERE owner = $1 AND bucket_id = $2 AND name = $3 \
+         RETURNING content_length",
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use quickcheck_helpers::random;
+    use serde_json::Map;
+
+    #[derive(Clone, Debug)]
+    struct RenameObjectJson(Value);
+
+    impl Arbitrary for RenameObjectJson {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let owner = serde_json::to_value(Uuid::new_v4())
+                .expect("failed to convert owner field to Value");
+            let bucket_id = serde_json::to_value(Uuid::new_v4())
+                .expect("failed to convert bucket_id field to Value");
+            let old_name = serde_json::to_value(random::string(g, 32))
+                .expect("failed to convert old_name field to Value");
+            let new_name = serde_json::to_value(random::string(g, 32))
+                .expect("failed to convert new_name field to Value");
+            let vnode = serde_json::to_value(u64::arbitrary(g))
+                .expect("failed to convert vnode field to Value");
+            let request_id = serde_json::to_value(Uuid::new_v4())
+                .expect("failed to convert request_id field to Value");
+
+            let mut obj = Map::new();
+            obj.insert("owner".into(), owner);
+            obj.insert("bucket_id".into(), bucket_id);
+            obj.insert("old_name".into(), old_name);
+            obj.insert("new_name".into(), new_name);
+            obj.insert("vnode".into(), vnode);
+            obj.insert("request_id".into(), request_id);
+            RenameObjectJson(Value::Object(obj))
+        }
+    }
+
+    impl Arbitrary for RenameObjectPayload {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            RenameObjectPayload {
+                owner: Uuid::new_v4(),
+                bucket_id: Uuid::new_v4(),
+                old_name: random::string(g, 32),
+                new_name: random::string(g, 32),
+                vnode: u64::arbitrary(g),
+                request_id: Uuid::new_v4(),
+                conditions: Default::default(),
+                bucket_vnode: Option::<u64>::arbitrary(g),
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_rename_object_payload_roundtrip(
+            msg: RenameObjectPayload
+        ) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(rename_str) => {
+                    let decode_result: Result<RenameObjectPayload, _> =
+                        serde_json::from_str(&rename_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_renameobject_payload_from_json(
+            json: RenameObjectJson
+        ) -> bool {
+            let decode_result1: Result<RenameObjectPayload, _> =
+                serde_json::from_value(json.0.clone());
+            let res1 = decode_result1.is_ok();
+
+            let decode_result2: Result<Vec<RenameObjectPayload>, _> =
+                serde_json::from_value(Value::Array(vec![json.0]));
+            let res2 = decode_result2.is_ok();
+
+            res1 && res2
+        }
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Exchange the names of two objects of a bucket.
+//!
+//! A client publishing a new version of an object under a well-known name
+//! (swapping `current` and `staging`, say) cannot do so atomically with a pair
+//! of `createobject` requests. The `swapobjects` RPC renames both objects in a
+//! single transaction, so a reader sees either both objects under their old
+//! names or both under their new names. Each object keeps its id and all of
+//! its other metadata, and its indexed metadata entries move with it.
+//!
+//! The unique constraint on object names is checked as each row is updated, so
+//! the first object is moved aside to a temporary name while the second is
+//! renamed. The temporary name is never visible outside of the transaction.
+
+use std::vec::Vec;
+
+use postgres::types::ToSql;
+use postgres::Transaction;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::object::{
+    fold_name, parent_prefix, record_audit_event, rename_index_sql, rename_sql,
+    response, ObjectResponse,
+};
+use crate::sql;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes, Rows};
+use crate::util::{array_wrap, limit_constraint_error};
+
+/// Swap the names of the objects `name_a` and `name_b`. Both objects must
+/// reside in `vnode`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SwapObjectsPayload {
+    pub owner: Uuid,
+    pub bucket_id: Uuid,
+    pub vnode: u64,
+    pub name_a: String,
+    pub name_b: String,
+    pub request_id: Uuid,
+}
+
+impl HasRequestId for SwapObjectsPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for SwapObjectsPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
+/// The objects after the swap: `a` is the object now named `name_a`, which
+/// was previously named `name_b`, and `b` is the object now named `name_b`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SwapObjectsResponse {
+    pub a: ObjectResponse,
+    pub b: ObjectResponse,
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<SwapObjectsPayload>, SerdeError> {
+    serde_json::from_value::<Vec<SwapObjectsPayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: SwapObjectsPayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    if fold_name(&payload.name_a, config) == fold_name(&payload.name_b, config)
+    {
+        // Limit constraint violations are returned to as regular
+        // Fast messages to be handled by the calling application
+        let e = format!(
+            "the {} names must name two different objects. both names \
+             were '{}'",
+            &method, &payload.name_a
+        );
+        let value = limit_constraint_error(e);
+        let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+        let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+        return Ok(msg);
+    }
+
+    // Make database request
+    do_swap(method, &payload, config, conn, metrics, log)
+        .and_then(|resp| {
+            // Handle the successful database response
+            debug!(log, "operation successful");
+            let value = serde_json::to_value(resp)
+                .expect("failed to serialize SwapObjectsResponse");
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(value));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+        .or_else(|e| {
+            if let BucketsMdapiError::PostgresError { .. } = &e {
+                error!(log, "operation failed"; "error" => e.message());
+            }
+
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+fn do_swap(
+    method: &str,
+    payload: &SwapObjectsPayload,
+    config: &Config,
+    conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<SwapObjectsResponse, BucketsMdapiError> {
+    let name_a = fold_name(&payload.name_a, config);
+    let name_b = fold_name(&payload.name_b, config);
+    let temp_name = ["\u{1}swap-", &payload.request_id.to_string()].concat();
+    let renamer = Renamer {
+        payload,
+        config,
+        metrics,
+        log,
+    };
+
+    let mut txn = (*conn).transaction().map_err(BucketsMdapiError::from)?;
+
+    // Returning early drops the transaction, which rolls back any rename
+    // already made when the second object does not exist
+    renamer
+        .rename(&mut txn, &name_a, &temp_name, &payload.name_a)?
+        .ok_or(BucketsMdapiError::ObjectNotFound)?;
+    let rows_a = renamer
+        .rename(&mut txn, &name_b, &name_a, &payload.name_a)?
+        .ok_or(BucketsMdapiError::ObjectNotFound)?;
+    let rows_b = renamer
+        .rename(&mut txn, &temp_name, &name_b, &payload.name_b)?
+        .ok_or(BucketsMdapiError::ObjectNotFound)?;
+
+    renamer.rename_index_entries(&mut txn, &name_a, &temp_name)?;
+    renamer.rename_index_entries(&mut txn, &name_b, &name_a)?;
+    renamer.rename_index_entries(&mut txn, &temp_name, &name_b)?;
+
+    if config.audit.enabled {
+        for (name, rows) in &[(&name_a, &rows_a), (&name_b, &rows_b)] {
+            record_audit_event(
+                &mut txn,
+                payload.vnode,
+                &payload.owner,
+                &payload.bucket_id,
+                name,
+                &rows[0].get("id"),
+                "swap",
+                &payload.request_id,
+                metrics,
+                log,
+            )?;
+        }
+    }
+
+    txn.commit().map_err(BucketsMdapiError::from)?;
+
+    let a = response(method, payload.vnode, &rows_a, config, metrics, log)?
+        .ok_or(BucketsMdapiError::ObjectNotFound)?;
+    let b = response(method, payload.vnode, &rows_b, config, metrics, log)?
+        .ok_or(BucketsMdapiError::ObjectNotFound)?;
+
+    Ok(SwapObjectsResponse { a, b })
+}
+
+/// Renames the objects of a single swap request.
+struct Renamer<'a> {
+    payload: &'a SwapObjectsPayload,
+    config: &'a Config,
+    metrics: &'a RegisteredMetrics,
+    log: &'a Logger,
+}
+
+impl<'a> Renamer<'a> {
+    /// Rename the object `from` to `to`, returning the renamed object row or
+    /// `None` if there is no object named `from`. The display name of the
+    /// object, when display names are stored, becomes `display_name`.
+    fn rename(
+        &self,
+        mut txn: &mut Transaction,
+        from: &str,
+        to: &str,
+        display_name: &str,
+    ) -> Result<Option<Rows>, BucketsMdapiError> {
+        let store_parent_prefix = self.config.listing.parent_prefix;
+        let store_display_name = self.config.names.case_insensitive_names;
+        let prefix = parent_prefix(to);
+
+        let mut items: Vec<&(dyn ToSql + Sync)> =
+            vec![&self.payload.owner, &self.payload.bucket_id, &from, &to];
+        if store_parent_prefix {
+            items.push(&prefix);
+        }
+        if store_display_name {
+            items.push(&display_name);
+        }
+
+        let rows = sql::txn_query(
+            sql::Method::ObjectSwap,
+            &mut txn,
+            rename_sql(
+                self.payload.vnode,
+                store_parent_prefix,
+                store_display_name,
+            )
+            .as_str(),
+            &items,
+            self.metrics,
+            self.log,
+        )
+        .map_err(BucketsMdapiError::from)?;
+
+        if rows.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(rows))
+        }
+    }
+
+    fn rename_index_entries(
+        &self,
+        mut txn: &mut Transaction,
+        from: &str,
+        to: &str,
+    ) -> Result<(), BucketsMdapiError> {
+        sql::txn_execute(
+            sql::Method::ObjectSwap,
+            &mut txn,
+            rename_index_sql(self.payload.vnode).as_str(),
+            &[&self.payload.owner, &self.payload.bucket_id, &from, &to],
+            self.metrics,
+            self.log,
+        )
+        .map(|_| ())
+        .map_err(BucketsMdapiError::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use quickcheck_helpers::random;
+
+    impl Arbitrary for SwapObjectsPayload {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            SwapObjectsPayload {
+                owner: Uuid::new_v4(),
+                bucket_id: Uuid::new_v4(),
+                vnode: u64::arbitrary(g),
+                name_a: random::string(g, 32),
+                name_b: random::string(g, 32),
+                request_id: Uuid::new_v4(),
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_swap_objects_payload_roundtrip(
+            msg: SwapObjectsPayload
+        ) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(swap_str) => {
+                    let decode_result: Result<SwapObjectsPayload, _> =
+                        serde_json::from_str(&swap_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+}
@@ -3,6 +3,8 @@
 
 use std::vec::Vec;
 
+use postgres::types::ToSql;
+use postgres::Transaction;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
 use serde_json::Value;
@@ -11,14 +13,20 @@ use uuid::Uuid;
 
 use cueball_postgres_connection::PostgresConnection;
 use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
 
 use crate::conditional;
 use crate::error::BucketsMdapiError;
 use crate::metrics::RegisteredMetrics;
-use crate::object::{object_not_found, response, to_json, ObjectResponse};
+use crate::object::index::{self, IndexedMetadata};
+use crate::object::properties;
+use crate::object::{
+    check_content_type, fold_name, modified_sql, object_not_found,
+    record_audit_event, response, row_headers, ObjectResponse,
+};
 use crate::sql;
-use crate::types::{HandlerResponse, HasRequestId, Hstore};
-use crate::util::array_wrap;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes, Hstore, Rows};
+use crate::util::{array_wrap, check_name_length};
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct UpdateObjectPayload {
@@ -34,6 +42,78 @@ pub struct UpdateObjectPayload {
 
     #[serde(default)]
     pub conditions: conditional::Conditions,
+
+    /// When present, replaces all of the object's indexed metadata entries.
+    #[serde(default)]
+    pub indexed_metadata: Option<IndexedMetadata>,
+
+    /// The etag the client expects the object to have, typically taken from a
+    /// recent read. The update is guarded by the etag in the same statement
+    /// rather than by a separate read of the object, and fails with a
+    /// `PreconditionFailedError` if the object does not have this etag.
+    #[serde(default)]
+    pub known_etag: Option<Uuid>,
+
+    /// Report whether the update changed the object's content type, headers,
+    /// properties, or indexed metadata as the `changed` field of the response.
+    /// This reads the object before it is updated, so it is only done when
+    /// requested.
+    #[serde(default)]
+    pub report_changed: bool,
+
+    /// How `headers` are applied to the object's existing headers.
+    #[serde(default)]
+    pub update_mode: UpdateMode,
+}
+
+/// How the headers of an `updateobject` request are applied to the object.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateMode {
+    /// The request headers replace all of the object's headers.
+    Replace,
+    /// The request headers are merged into the object's headers, so that
+    /// individual headers may be set without resending the others. A header
+    /// with a `null` value is removed from the object.
+    Merge,
+}
+
+impl Default for UpdateMode {
+    fn default() -> Self {
+        UpdateMode::Replace
+    }
+}
+
+/// The response to an `updateobject` request: the updated object, along with
+/// whether the update changed it, which has no meaning for the other requests
+/// that return an object.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct UpdateObjectResponse {
+    #[serde(flatten)]
+    pub object: ObjectResponse,
+
+    /// Whether the update changed any of the object's metadata, when the
+    /// request set `report_changed`, and `None` otherwise.
+    #[serde(default)]
+    pub changed: Option<bool>,
+}
+
+impl UpdateObjectResponse {
+    pub fn to_json(&self) -> Value {
+        // As for ObjectResponse, the only maps this type contains have string
+        // keys, and the JSON roundtrip quickcheck test verifies it serializes
+        serde_json::to_value(self)
+            .expect("failed to serialize UpdateObjectResponse")
+    }
+}
+
+// The metadata of an object that an update may change, as stored before the
+// update.
+struct CurrentMetadata {
+    content_type: String,
+    headers: Hstore,
+    properties: Option<Value>,
+    indexed_metadata: Option<IndexedMetadata>,
 }
 
 impl HasRequestId for UpdateObjectPayload {
@@ -42,6 +122,12 @@ impl HasRequestId for UpdateObjectPayload {
     }
 }
 
+impl HasVnodes for UpdateObjectPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
 pub(crate) fn decode_msg(
     value: &Value,
 ) -> Result<Vec<UpdateObjectPayload>, SerdeError> {
@@ -52,18 +138,24 @@ pub(crate) fn decode_msg(
 pub(crate) fn action(
     msg_id: u32,
     method: &str,
+    config: &Config,
     metrics: &RegisteredMetrics,
     log: &Logger,
     payload: UpdateObjectPayload,
     conn: &mut PostgresConnection,
 ) -> Result<HandlerResponse, String> {
+    let payload = UpdateObjectPayload {
+        name: fold_name(&payload.name, config),
+        ..payload
+    };
+
     // Make database request
-    do_update(method, &payload, conn, metrics, log)
+    do_update(method, &payload, config, conn, metrics, log)
         .and_then(|maybe_resp| {
             // Handle the successful database response
             debug!(log, "operation successful");
             let value = match maybe_resp {
-                Some(resp) => to_json(resp),
+                Some(resp) => resp.to_json(),
                 None => object_not_found(),
             };
             let msg_data =
@@ -73,7 +165,7 @@ pub(crate) fn action(
             Ok(msg)
         })
         .or_else(|e| {
-            if let BucketsMdapiError::PostgresError(_) = &e {
+            if let BucketsMdapiError::PostgresError { .. } = &e {
                 error!(log, "operation failed"; "error" => e.message());
             }
 
@@ -88,64 +180,294 @@ pub(crate) fn action(
 fn do_update(
     method: &str,
     payload: &UpdateObjectPayload,
+    config: &Config,
     conn: &mut PostgresConnection,
     metrics: &RegisteredMetrics,
     log: &Logger,
-) -> Result<Option<ObjectResponse>, BucketsMdapiError> {
-    let mut txn = (*conn)
-        .transaction()
-        .map_err(|e| BucketsMdapiError::PostgresError(e.to_string()))?;
-    let update_sql = update_sql(payload.vnode);
+) -> Result<Option<UpdateObjectResponse>, BucketsMdapiError> {
+    let (changed, rows) = sql::txn_with_retries(
+        sql::Method::ObjectUpdate,
+        conn,
+        &config.database,
+        metrics,
+        log,
+        |txn| update_in_txn(txn, payload, config, metrics, log),
+    )?;
+
+    response(method, payload.vnode, &rows, config, metrics, log).map(
+        |maybe_resp| {
+            maybe_resp.map(|object| UpdateObjectResponse { object, changed })
+        },
+    )
+}
+
+// Update the object described by `payload` as part of `txn`, returning whether
+// the update changed the object, when that is requested, and the updated rows.
+fn update_in_txn(
+    mut txn: &mut Transaction,
+    payload: &UpdateObjectPayload,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<(Option<bool>, Rows), BucketsMdapiError> {
+    check_content_type(&payload.content_type, config)?;
+    check_name_length(&payload.name, config)?;
+
+    let changed = if payload.report_changed {
+        current_metadata(&mut txn, payload, config, metrics, log)?
+            .map(|current| is_change(payload, &current))
+    } else {
+        None
+    };
+    let update_sql = update_sql(
+        payload.vnode,
+        config.timestamps.monotonic_modified,
+        payload.known_etag.is_some(),
+        config.names.case_insensitive_names,
+        payload.update_mode,
+    );
+    let max_inline_bytes = config.properties.max_inline_bytes;
+    let (inline_properties, external_properties) =
+        properties::split(&payload.properties, max_inline_bytes);
 
     conditional::request(
         &mut txn,
         &[&payload.owner, &payload.bucket_id, &payload.name],
         payload.vnode,
         &payload.conditions,
+        config,
         metrics,
         log,
     )
     .and_then(|_| {
+        if max_inline_bytes.is_some() {
+            properties::delete(
+                &mut txn,
+                payload.vnode,
+                &payload.owner,
+                &payload.bucket_id,
+                &payload.name,
+                metrics,
+                log,
+            )?;
+        }
+        Ok(())
+    })
+    .and_then(|_| {
+        let mut items: Vec<&(dyn ToSql + Sync)> = vec![
+            &payload.content_type,
+            &payload.headers,
+            &inline_properties,
+            &payload.owner,
+            &payload.bucket_id,
+            &payload.name,
+        ];
+        if let Some(known_etag) = &payload.known_etag {
+            items.push(known_etag);
+        }
+
         sql::txn_query(
             sql::Method::ObjectUpdate,
             &mut txn,
             update_sql.as_str(),
-            &[
-                &payload.content_type,
-                &payload.headers,
-                &payload.properties,
-                &payload.owner,
-                &payload.bucket_id,
-                &payload.name,
-            ],
+            &items,
             metrics,
             log,
         )
-        .map_err(|e| BucketsMdapiError::PostgresError(e.to_string()))
+        .map_err(BucketsMdapiError::from)
+    })
+    .map(|updated_rows| {
+        sql::check_mutation_width(
+            sql::Method::ObjectUpdate,
+            updated_rows.len(),
+            config.server.wide_mutation_rows,
+            metrics,
+            log,
+        );
+        updated_rows
+    })
+    .and_then(|updated_rows| match &payload.known_etag {
+        Some(known_etag) if updated_rows.is_empty() => {
+            Err(BucketsMdapiError::PreconditionFailedError(format!(
+                "known etag '{}' didn't match the object",
+                known_etag
+            )))
+        }
+        _ => Ok(updated_rows),
     })
     .and_then(|updated_rows| {
-        txn.commit()
-            .map_err(|e| BucketsMdapiError::PostgresError(e.to_string()))?;
-        Ok(updated_rows)
+        match external_properties {
+            Some(value) if !updated_rows.is_empty() => {
+                properties::insert(
+                    &mut txn,
+                    payload.vnode,
+                    &updated_rows[0].get("id"),
+                    value,
+                    metrics,
+                    log,
+                )?;
+            }
+            _ => (),
+        }
+
+        match &payload.indexed_metadata {
+            Some(entries) if !updated_rows.is_empty() => {
+                index::replace_entries(
+                    &mut txn,
+                    payload.vnode,
+                    &payload.owner,
+                    &payload.bucket_id,
+                    &payload.name,
+                    entries,
+                    metrics,
+                    log,
+                )?;
+            }
+            _ => (),
+        }
+
+        if config.audit.enabled && !updated_rows.is_empty() {
+            record_audit_event(
+                &mut txn,
+                payload.vnode,
+                &payload.owner,
+                &payload.bucket_id,
+                &payload.name,
+                &updated_rows[0].get("id"),
+                "update",
+                &payload.request_id,
+                metrics,
+                log,
+            )?;
+        }
+        Ok((changed, updated_rows))
     })
-    .and_then(|rows| response(method, &rows))
 }
 
-fn update_sql(vnode: u64) -> String {
+// Read the metadata of the object being updated, or `None` if there is no such
+// object. The indexed metadata entries are only read when the update replaces
+// them.
+fn current_metadata(
+    mut txn: &mut Transaction,
+    payload: &UpdateObjectPayload,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<Option<CurrentMetadata>, BucketsMdapiError> {
+    let sql = [
+        properties::select_sql(payload.vnode, true, false, false).as_str(),
+        " WHERE owner = $1 AND bucket_id = $2 AND name = $3",
+    ]
+    .concat();
+    let rows = sql::txn_query(
+        sql::Method::ObjectGet,
+        &mut txn,
+        sql.as_str(),
+        &[&payload.owner, &payload.bucket_id, &payload.name],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    let row = match rows.first() {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    let indexed_metadata = match payload.indexed_metadata {
+        Some(_) => Some(index::entries(
+            &mut txn,
+            payload.vnode,
+            &payload.owner,
+            &payload.bucket_id,
+            &payload.name,
+            metrics,
+            log,
+        )?),
+        None => None,
+    };
+
+    Ok(Some(CurrentMetadata {
+        content_type: row.get("content_type"),
+        headers: row_headers(row, config, metrics, log),
+        properties: row.get("properties"),
+        indexed_metadata,
+    }))
+}
+
+// Whether the update would change any of the current metadata of the object.
+fn is_change(payload: &UpdateObjectPayload, current: &CurrentMetadata) -> bool {
+    let headers_changed = match payload.update_mode {
+        UpdateMode::Replace => payload.headers != current.headers,
+        UpdateMode::Merge => {
+            merge_headers(&current.headers, &payload.headers) != current.headers
+        }
+    };
+
+    payload.content_type != current.content_type
+        || headers_changed
+        || payload.properties != current.properties
+        || (payload.indexed_metadata.is_some()
+            && payload.indexed_metadata != current.indexed_metadata)
+}
+
+// The headers of an object after `update` is merged into its `current` headers,
+// as done by the `headers_sql` of a merge.
+fn merge_headers(current: &Hstore, update: &Hstore) -> Hstore {
+    let mut merged = current.clone();
+    for (key, value) in update {
+        match value {
+            Some(_) => {
+                let _ = merged.insert(key.clone(), value.clone());
+            }
+            None => {
+                let _ = merged.remove(key);
+            }
+        }
+    }
+    merged
+}
+
+// The new value of the headers column, given the request headers bound to $2.
+// A merge concatenates the request headers onto the existing headers and then
+// removes the keys whose request value is null.
+fn headers_sql(update_mode: UpdateMode) -> &'static str {
+    match update_mode {
+        UpdateMode::Replace => "$2",
+        UpdateMode::Merge => {
+            "(COALESCE(headers, ''::hstore) || $2::hstore) - \
+             ARRAY(SELECT key FROM each($2::hstore) WHERE value IS NULL)"
+        }
+    }
+}
+
+fn update_sql(
+    vnode: u64,
+    monotonic_modified: bool,
+    known_etag: bool,
+    display_name: bool,
+    update_mode: UpdateMode,
+) -> String {
+    let etag_condition = if known_etag { " AND id = $7" } else { "" };
+    let returning = if display_name { ", display_name" } else { "" };
     [
         "UPDATE manta_bucket_",
         &vnode.to_string(),
-        &".manta_bucket_object \
-       SET content_type = $1,
-       headers = $2, \
-       properties = $3, \
-       modified = current_timestamp \
-       WHERE owner = $4 \
-       AND bucket_id = $5 \
-       AND name = $6 \
-       RETURNING id, owner, bucket_id, name, created, modified, \
-       content_length, content_md5, content_type, headers, \
-       sharks, properties",
+        ".manta_bucket_object \
+         SET content_type = $1, \
+         headers = ",
+        headers_sql(update_mode),
+        ", \
+         properties = $3, \
+         modified = ",
+        &modified_sql("modified", monotonic_modified),
+        " WHERE owner = $4 \
+         AND bucket_id = $5 \
+         AND name = $6",
+        etag_condition,
+        " RETURNING id, owner, bucket_id, name, created, modified, \
+         content_length, content_md5, content_type, headers, \
+         sharks, properties",
+        returning,
     ]
     .concat()
 }
@@ -218,6 +540,14 @@ mod test {
             let properties = None;
             let request_id = Uuid::new_v4();
             let conditions: conditional::Conditions = Default::default();
+            let indexed_metadata = Option::<IndexedMetadata>::arbitrary(g);
+            let known_etag = if bool::arbitrary(g) {
+                Some(Uuid::new_v4())
+            } else {
+                None
+            };
+            let report_changed = bool::arbitrary(g);
+            let update_mode = UpdateMode::arbitrary(g);
 
             UpdateObjectPayload {
                 owner,
@@ -230,10 +560,116 @@ mod test {
                 properties,
                 request_id,
                 conditions,
+                indexed_metadata,
+                known_etag,
+                report_changed,
+                update_mode,
+            }
+        }
+    }
+
+    impl Arbitrary for UpdateMode {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            if bool::arbitrary(g) {
+                UpdateMode::Replace
+            } else {
+                UpdateMode::Merge
+            }
+        }
+    }
+
+    #[test]
+    fn update_change_detection() {
+        let mut headers = HashMap::new();
+        let _ = headers.insert("m-color".to_string(), Some("blue".to_string()));
+        let mut indexed_metadata = IndexedMetadata::new();
+        let _ = indexed_metadata
+            .insert("size".into(), index::IndexedValue::Number(10.0));
+
+        let mut payload = UpdateObjectPayload {
+            owner: Uuid::new_v4(),
+            bucket_id: Uuid::new_v4(),
+            name: "photos/cat.jpg".into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_type: "image/jpeg".into(),
+            headers: headers.clone(),
+            properties: Some(serde_json::json!({ "rating": 5 })),
+            request_id: Uuid::new_v4(),
+            conditions: Default::default(),
+            indexed_metadata: None,
+            known_etag: None,
+            report_changed: true,
+            update_mode: UpdateMode::Replace,
+        };
+        let current = CurrentMetadata {
+            content_type: "image/jpeg".into(),
+            headers,
+            properties: Some(serde_json::json!({ "rating": 5 })),
+            indexed_metadata: Some(indexed_metadata.clone()),
+        };
+
+        // Setting every field to its current value is not a change
+        assert!(!is_change(&payload, &current));
+        payload.indexed_metadata = Some(indexed_metadata);
+        assert!(!is_change(&payload, &current));
+
+        payload.content_type = "image/png".into();
+        assert!(is_change(&payload, &current));
+        payload.content_type = "image/jpeg".into();
+
+        payload.indexed_metadata = Some(IndexedMetadata::new());
+        assert!(is_change(&payload, &current));
+        payload.indexed_metadata = None;
+
+        let _ = payload.headers.insert("m-size".into(), None);
+        assert!(is_change(&payload, &current));
+
+        // Merging a subset of the current headers, or removing a header the
+        // object does not have, is not a change
+        payload.update_mode = UpdateMode::Merge;
+        assert!(!is_change(&payload, &current));
+        payload.headers.clear();
+        assert!(!is_change(&payload, &current));
+
+        let _ = payload.headers.insert("m-color".into(), None);
+        assert!(is_change(&payload, &current));
+    }
+
+    #[test]
+    fn update_merge_headers() {
+        let mut current = HashMap::new();
+        let _ = current.insert("m-color".to_string(), Some("blue".to_string()));
+        let _ = current.insert("m-size".to_string(), Some("10".to_string()));
+
+        let mut update = HashMap::new();
+        let _ = update.insert("m-shape".to_string(), Some("round".to_string()));
+        let _ = update.insert("m-size".to_string(), None);
+
+        let mut expected = HashMap::new();
+        let _ =
+            expected.insert("m-color".to_string(), Some("blue".to_string()));
+        let _ =
+            expected.insert("m-shape".to_string(), Some("round".to_string()));
+        assert_eq!(merge_headers(&current, &update), expected);
+    }
+
+    impl Arbitrary for UpdateObjectResponse {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            UpdateObjectResponse {
+                object: ObjectResponse::arbitrary(g),
+                changed: Option::<bool>::arbitrary(g),
             }
         }
     }
 
+    quickcheck! {
+        fn prop_update_object_response_roundtrip(resp: UpdateObjectResponse) -> bool {
+            serde_json::from_value::<UpdateObjectResponse>(resp.to_json())
+                .map_or(false, |decoded_resp| decoded_resp == resp)
+        }
+    }
+
     quickcheck! {
         fn prop_update_object_payload_roundtrip(msg: UpdateObjectPayload) -> bool {
             match serde_json::to_string(&msg) {
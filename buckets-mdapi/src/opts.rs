@@ -1,9 +1,25 @@
 // Copyright 2019 Joyent, Inc.
 // Copyright 2023 MNX Cloud, Inc.
 
+use std::ffi::OsString;
+
 use clap::{crate_version, App, Arg, ArgMatches};
 
 pub fn parse<'a>(app: &str) -> ArgMatches<'a> {
+    app_args(app).get_matches()
+}
+
+/// Parse the command line arguments in `args` rather than those of the
+/// process. The first argument is taken to be the program name.
+pub fn parse_from<'a, I, T>(app: &str, args: I) -> ArgMatches<'a>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    app_args(app).get_matches_from(args)
+}
+
+fn app_args<'a, 'b>(app: &str) -> App<'a, 'b> {
     App::new(app)
         .about("Tool to test different hierarchy options offered by PostgreSQL")
         .version(crate_version!())
@@ -77,5 +93,4 @@ pub fn parse<'a>(app: &str) -> ArgMatches<'a> {
                 .takes_value(true)
                 .required(false),
         )
-        .get_matches()
 }
@@ -0,0 +1,301 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! A background scan for objects whose bucket has been deleted.
+//!
+//! Deleting a bucket does not delete its objects, which are held in the
+//! vnodes of their own names rather than the vnode of the bucket. An object
+//! left behind by a bucket deleted while it still had objects can no longer
+//! be listed or deleted through the bucket, and its data is never collected.
+//! When `orphan_scan.enabled` is set the scan periodically examines a batch of
+//! the objects of each vnode in turn and moves the objects of deleted buckets
+//! to the deleted object table, from which they are garbage collected as
+//! though they had been deleted by a `deleteobject` request.
+//!
+//! A bucket is considered deleted when its id is found in the deleted bucket
+//! table of any vnode on this database, so the deletion of a bucket is only
+//! recognized for objects stored on the same database as the bucket's vnode.
+
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+
+use slog::{debug, error, info, warn, Logger};
+use uuid::Uuid;
+
+use cueball::backend::Backend;
+use cueball::connection_pool::ConnectionPool;
+use cueball::resolver::Resolver;
+use cueball_postgres_connection::PostgresConnection;
+use utils::config::Config;
+
+use crate::connection::AgedConnection;
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::object::{self, GetObjectPayload};
+use crate::sql;
+
+/// The outcome of scanning one batch of objects.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScanResult {
+    pub vnode: u64,
+    /// The number of objects examined
+    pub scanned: usize,
+    /// The number of objects moved to the deleted object table
+    pub tombstoned: usize,
+}
+
+/// The position of an orphan scan. Each batch resumes after the last object
+/// examined by the previous batch, and moves on to the next vnode once every
+/// object of the current vnode has been examined. After the last vnode the
+/// scan starts again from the first.
+pub struct OrphanScanner {
+    batch_size: i64,
+    vnodes: VecDeque<u64>,
+    vnode: Option<u64>,
+    cursor: Option<(Uuid, Uuid, String)>,
+}
+
+impl OrphanScanner {
+    pub fn new(batch_size: i64) -> Self {
+        OrphanScanner {
+            batch_size,
+            vnodes: VecDeque::new(),
+            vnode: None,
+            cursor: None,
+        }
+    }
+
+    /// Examine the next batch of objects, moving those whose bucket has been
+    /// deleted to the deleted object table. Returns `None` when there are no
+    /// vnodes on the database.
+    pub fn scan_batch(
+        &mut self,
+        config: &Config,
+        mut conn: &mut PostgresConnection,
+        metrics: &RegisteredMetrics,
+        log: &Logger,
+    ) -> Result<Option<ScanResult>, BucketsMdapiError> {
+        let vnode = match self.next_vnode(&mut conn, metrics, log)? {
+            Some(vnode) => vnode,
+            None => return Ok(None),
+        };
+
+        let rows = match &self.cursor {
+            Some((owner, bucket_id, name)) => sql::query(
+                sql::Method::ObjectOrphanScan,
+                &mut conn,
+                scan_sql(vnode, true).as_str(),
+                &[&self.batch_size, owner, bucket_id, name],
+                metrics,
+                log,
+            ),
+            None => sql::query(
+                sql::Method::ObjectOrphanScan,
+                &mut conn,
+                scan_sql(vnode, false).as_str(),
+                &[&self.batch_size],
+                metrics,
+                log,
+            ),
+        }
+        .map_err(BucketsMdapiError::from)?;
+
+        let objects: Vec<(Uuid, Uuid, String)> = rows
+            .iter()
+            .map(|row| {
+                (row.get("owner"), row.get("bucket_id"), row.get("name"))
+            })
+            .collect();
+
+        let mut bucket_ids: Vec<Uuid> =
+            objects.iter().map(|(_, bucket_id, _)| *bucket_id).collect();
+        bucket_ids.sort();
+        bucket_ids.dedup();
+
+        let deleted: Vec<Uuid> = if bucket_ids.is_empty() {
+            vec![]
+        } else {
+            sql::query(
+                sql::Method::BucketDeletedLookup,
+                &mut conn,
+                "SELECT id FROM deleted_bucket_ids($1)",
+                &[&bucket_ids],
+                metrics,
+                log,
+            )
+            .map_err(BucketsMdapiError::from)?
+            .iter()
+            .map(|row| row.get("id"))
+            .collect()
+        };
+
+        let mut tombstoned = 0;
+        for (owner, bucket_id, name) in &objects {
+            if !deleted.contains(bucket_id) {
+                continue;
+            }
+
+            let payload = GetObjectPayload {
+                owner: *owner,
+                bucket_id: *bucket_id,
+                name: name.clone(),
+                vnode,
+                request_id: Uuid::new_v4(),
+                conditions: Default::default(),
+                bucket_vnode: None,
+                if_content_length: None,
+                include_properties: false,
+                unpin_first: false,
+            };
+
+            match object::delete::do_delete(
+                &payload, config, conn, metrics, log,
+            ) {
+                Ok(_) => {
+                    debug!(log, "tombstoned orphaned object";
+                        "owner" => %owner,
+                        "bucket_id" => %bucket_id,
+                        "name" => name,
+                        "vnode" => vnode);
+                    tombstoned += 1;
+                }
+                // The object was deleted since it was scanned
+                Err(BucketsMdapiError::ObjectNotFound) => (),
+                // Pinned objects are kept even when their bucket is deleted
+                Err(BucketsMdapiError::ObjectPinned(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        metrics
+            .orphaned_objects_tombstoned
+            .inc_by(tombstoned as f64);
+
+        if (objects.len() as i64) < self.batch_size {
+            self.vnode = None;
+            self.cursor = None;
+        } else {
+            self.cursor = objects.last().cloned();
+        }
+
+        Ok(Some(ScanResult {
+            vnode,
+            scanned: objects.len(),
+            tombstoned,
+        }))
+    }
+
+    fn next_vnode(
+        &mut self,
+        mut conn: &mut PostgresConnection,
+        metrics: &RegisteredMetrics,
+        log: &Logger,
+    ) -> Result<Option<u64>, BucketsMdapiError> {
+        if self.vnode.is_none() {
+            if self.vnodes.is_empty() {
+                self.vnodes = sql::query(
+                    sql::Method::ObjectOrphanScan,
+                    &mut conn,
+                    VNODES_SQL,
+                    &[],
+                    metrics,
+                    log,
+                )
+                .map_err(BucketsMdapiError::from)?
+                .iter()
+                .map(|row| row.get::<_, i64>("vnode") as u64)
+                .collect();
+            }
+            self.vnode = self.vnodes.pop_front();
+        }
+        Ok(self.vnode)
+    }
+}
+
+/// Scan for orphaned objects every `orphan_scan.interval_secs` seconds until
+/// the process exits.
+pub fn run<R, F>(
+    pool: ConnectionPool<AgedConnection, R, F>,
+    config: Config,
+    metrics: RegisteredMetrics,
+    log: Logger,
+) where
+    R: Resolver,
+    F: FnMut(&Backend) -> AgedConnection + Send + 'static,
+{
+    let interval = Duration::from_secs(config.orphan_scan.interval_secs);
+    let mut scanner = OrphanScanner::new(config.orphan_scan.batch_size);
+
+    loop {
+        thread::sleep(interval);
+
+        let mut conn = match pool.claim() {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!(log, "failed to claim connection for orphan scan";
+                    "error" => %e);
+                continue;
+            }
+        };
+
+        match scanner.scan_batch(&config, &mut conn, &metrics, &log) {
+            Ok(Some(result)) if result.tombstoned > 0 => {
+                info!(log, "orphan scan tombstoned objects";
+                    "vnode" => result.vnode,
+                    "scanned" => result.scanned,
+                    "tombstoned" => result.tombstoned);
+            }
+            Ok(Some(result)) => {
+                debug!(log, "orphan scan found no orphaned objects";
+                    "vnode" => result.vnode,
+                    "scanned" => result.scanned);
+            }
+            Ok(None) => debug!(log, "orphan scan found no vnodes"),
+            Err(e) => {
+                error!(log, "orphan scan failed"; "error" => e.message());
+            }
+        }
+    }
+}
+
+const VNODES_SQL: &str =
+    "SELECT substring(schema_name FROM 14)::bigint AS vnode \
+     FROM information_schema.schemata \
+     WHERE schema_name ~ '^manta_bucket_[0-9]+$' \
+     ORDER BY vnode";
+
+fn scan_sql(vnode: u64, resume: bool) -> String {
+    let condition = if resume {
+        " WHERE (owner, bucket_id, name) > ($2, $3, $4)"
+    } else {
+        ""
+    };
+    [
+        "SELECT owner, bucket_id, name FROM manta_bucket_",
+        &vnode.to_string(),
+        ".manta_bucket_object",
+        condition,
+        " ORDER BY owner, bucket_id, name LIMIT $1",
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resumed_scan_sql() {
+        assert_eq!(
+            scan_sql(3, false),
+            "SELECT owner, bucket_id, name FROM manta_bucket_3.\
+             manta_bucket_object ORDER BY owner, bucket_id, name LIMIT $1"
+        );
+        assert_eq!(
+            scan_sql(3, true),
+            "SELECT owner, bucket_id, name FROM manta_bucket_3.\
+             manta_bucket_object WHERE (owner, bucket_id, name) > \
+             ($2, $3, $4) ORDER BY owner, bucket_id, name LIMIT $1"
+        );
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Per-owner usage metrics with bounded label cardinality.
+//!
+//! Exporting a counter for every owner would give the metrics one label value
+//! for each tenant that ever made a request, which Prometheus cannot store.
+//! Instead the requests and the object bytes of each owner are counted by a
+//! space-saving heavy hitter tracker that holds a fixed number of counters.
+//! When an owner that is not tracked is seen and every counter is taken, the
+//! owner with the smallest count is replaced and the new owner starts from the
+//! count it replaced. An owner's count may so overestimate its usage, by at
+//! most that starting count, but any owner whose usage exceeds the total usage
+//! divided by the number of counters is always tracked. Only the `top_n`
+//! heaviest owners are exported, as the `owner_top_requests` and
+//! `owner_top_bytes` gauges labelled by owner, so the label cardinality never
+//! exceeds `top_n` for each gauge.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use prometheus::GaugeVec;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+/// The number of counters of each tracker for every exported owner. Tracking
+/// more owners than are exported keeps the exported counts accurate when many
+/// owners have similar usage.
+const COUNTERS_PER_EXPORTED_OWNER: usize = 10;
+
+/// The fields of a request payload attributed to its owner.
+#[derive(Debug, Default, Deserialize)]
+struct OwnerPayload {
+    #[serde(default)]
+    owner: Option<Uuid>,
+    #[serde(default)]
+    content_length: Option<i64>,
+}
+
+/// A space-saving tracker of the heaviest keys of a stream.
+#[derive(Debug)]
+struct SpaceSaving {
+    capacity: usize,
+    counts: HashMap<String, u64>,
+}
+
+impl SpaceSaving {
+    fn new(capacity: usize) -> Self {
+        SpaceSaving {
+            capacity,
+            counts: HashMap::with_capacity(capacity),
+        }
+    }
+
+    fn observe(&mut self, key: &str, weight: u64) {
+        if let Some(count) = self.counts.get_mut(key) {
+            *count += weight;
+            return;
+        }
+
+        let start = if self.counts.len() < self.capacity {
+            0
+        } else {
+            // Replace the key with the smallest count
+            let (min_key, min_count) = self
+                .counts
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, count)| (key.clone(), *count))
+                .expect("a full tracker has counters");
+            let _ = self.counts.remove(&min_key);
+            min_count
+        };
+        let _ = self.counts.insert(key.to_string(), start + weight);
+    }
+
+    // The `n` keys with the largest counts, largest first.
+    fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let mut entries: Vec<(String, u64)> = self
+            .counts
+            .iter()
+            .map(|(key, count)| (key.clone(), *count))
+            .collect();
+        entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        entries.truncate(n);
+        entries
+    }
+}
+
+/// The request and object byte counts of the heaviest owners.
+pub struct OwnerUsage {
+    top_n: usize,
+    requests: Mutex<SpaceSaving>,
+    bytes: Mutex<SpaceSaving>,
+    top_requests: GaugeVec,
+    top_bytes: GaugeVec,
+}
+
+impl OwnerUsage {
+    /// Track the heaviest owners, exporting the `top_n` heaviest through the
+    /// given gauges, which must be labelled by `owner`.
+    pub fn new(
+        top_n: usize,
+        top_requests: GaugeVec,
+        top_bytes: GaugeVec,
+    ) -> Self {
+        let capacity = (top_n * COUNTERS_PER_EXPORTED_OWNER).max(1);
+        OwnerUsage {
+            top_n,
+            requests: Mutex::new(SpaceSaving::new(capacity)),
+            bytes: Mutex::new(SpaceSaving::new(capacity)),
+            top_requests,
+            top_bytes,
+        }
+    }
+
+    /// Count a request against the owner of its payload, and the
+    /// `content_length` of each object of the request against the owner of
+    /// that object. Requests without an owner, such as those for garbage
+    /// collection, are not counted.
+    pub fn record(&self, data: &Value) {
+        let payloads: Vec<OwnerPayload> = data
+            .as_array()
+            .map(|payloads| {
+                payloads
+                    .iter()
+                    .filter_map(|payload| {
+                        serde_json::from_value(payload.clone()).ok()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if let Some(owner) = payloads.first().and_then(|p| p.owner) {
+            if let Ok(mut requests) = self.requests.lock() {
+                requests.observe(&owner.to_string(), 1);
+            }
+        }
+
+        if let Ok(mut bytes) = self.bytes.lock() {
+            for payload in &payloads {
+                if let (Some(owner), Some(length)) =
+                    (payload.owner, payload.content_length)
+                {
+                    if length > 0 {
+                        bytes.observe(&owner.to_string(), length as u64);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Update the exported gauges to the current heaviest owners, removing
+    /// owners that are no longer among them.
+    pub fn refresh(&self) {
+        if let Ok(requests) = self.requests.lock() {
+            export(&self.top_requests, &requests.top(self.top_n));
+        }
+        if let Ok(bytes) = self.bytes.lock() {
+            export(&self.top_bytes, &bytes.top(self.top_n));
+        }
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn export(gauges: &GaugeVec, top: &[(String, u64)]) {
+    gauges.reset();
+    for (owner, count) in top {
+        gauges
+            .with_label_values(&[owner.as_str()])
+            .set(*count as f64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use prometheus::core::Collector;
+    use prometheus::Opts;
+    use serde_json::json;
+
+    fn gauges(name: &str) -> GaugeVec {
+        GaugeVec::new(Opts::new(name, "test gauge"), &["owner"])
+            .expect("failed to create gauge")
+    }
+
+    #[test]
+    fn space_saving_keeps_heavy_hitter() {
+        let mut tracker = SpaceSaving::new(4);
+        for i in 0..100 {
+            tracker.observe("heavy", 1);
+            tracker.observe(&format!("light{}", i), 1);
+        }
+
+        assert_eq!(tracker.counts.len(), 4);
+        let top = tracker.top(1);
+        assert_eq!(top[0].0, "heavy");
+        assert!(top[0].1 >= 100);
+    }
+
+    #[test]
+    fn heaviest_owner_exported() {
+        let top_requests = gauges("test_owner_top_requests");
+        let top_bytes = gauges("test_owner_top_bytes");
+        let usage = OwnerUsage::new(2, top_requests.clone(), top_bytes.clone());
+
+        let heavy = Uuid::new_v4();
+        for _ in 0..50 {
+            usage.record(&json!([{ "owner": heavy, "content_length": 10 }]));
+            usage.record(&json!([{
+                "owner": Uuid::new_v4(),
+                "content_length": 1
+            }]));
+        }
+        // Requests without an owner are not counted
+        usage.record(&json!([{ "batch_id": Uuid::new_v4() }]));
+        usage.refresh();
+
+        let heavy = heavy.to_string();
+        assert_eq!(
+            top_requests.with_label_values(&[heavy.as_str()]).get(),
+            50.0
+        );
+        assert_eq!(top_bytes.with_label_values(&[heavy.as_str()]).get(), 500.0);
+
+        let exported = top_requests
+            .collect()
+            .iter()
+            .map(|family| family.get_metric().len())
+            .sum::<usize>();
+        assert_eq!(exported, 2);
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Routing of read-only requests to a read replica of the database.
+//!
+//! When `replica.host` is set, `getobject`, `getobjects`, `headobject`,
+//! `listobjects`, `getobjecthistory`, `getbucket`, and `listbuckets` requests
+//! are served by connections to the replica and every other request by the
+//! primary.
+//!
+//! The replica applies the writes of the primary asynchronously, so a read
+//! served by the replica may not yet see a write that has completed on the
+//! primary. So that a client reads its own writes, the reads of a bucket are
+//! served by the primary for `read_your_writes_ms` milliseconds after this
+//! process completes a write to an object of the bucket, and the `getbucket`
+//! and `listbuckets` reads of an owner for as long after this process creates
+//! or deletes a bucket of the owner. Writes served by other buckets-mdapi
+//! processes do not hold reads on the primary, and may be seen late by reads
+//! served by the replica.
+//!
+//! `replica.host` may be an IP address or a host name. A host name is resolved
+//! with the system resolver once, as the replica pool is created, and every
+//! address it resolves to becomes a backend of the pool. When DNS returns
+//! several addresses, as for several A records, connections are spread across
+//! all of them, so each must be a replica of the same primary. Later changes
+//! to the DNS records are not followed until the process is restarted.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use prometheus::Counter;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+use cueball::backend::Backend;
+use cueball::connection_pool::ConnectionPool;
+use cueball_static_resolver::StaticIpResolver;
+use fast_rpc::protocol::FastMessage;
+use utils::config::ConfigReplica;
+
+use crate::connection::AgedConnection;
+use crate::limiter::is_write_method;
+use crate::metrics::RegisteredMetrics;
+
+/// Writes and reads are matched by owner and, for object requests, bucket id.
+/// Bucket requests carry no bucket id.
+type RoutingKey = (Uuid, Option<Uuid>);
+
+/// The fields of a request payload used to route it.
+#[derive(Debug, Deserialize)]
+struct RoutingPayload {
+    owner: Uuid,
+    #[serde(default)]
+    bucket_id: Option<Uuid>,
+}
+
+/// Returns `true` if the RPC method only reads and may be served by a read
+/// replica.
+pub fn is_read_method(method: &str) -> bool {
+    match method {
+        "getobject" | "getobjects" | "headobject" | "listobjects"
+        | "getobjecthistory" | "getbucket" | "listbuckets" => true,
+        _ => false,
+    }
+}
+
+/// The backends of the read replica at `host`, which is either an IP address
+/// or a host name to resolve. Every distinct address a host name resolves to
+/// is a backend.
+pub fn resolve_backends(
+    host: &str,
+    port: u16,
+) -> io::Result<Vec<(IpAddr, u16)>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![(ip, port)]);
+    }
+
+    let mut backends: Vec<(IpAddr, u16)> = vec![];
+    for addr in (host, port).to_socket_addrs()? {
+        let backend = (addr.ip(), addr.port());
+        if !backends.contains(&backend) {
+            backends.push(backend);
+        }
+    }
+
+    if backends.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no addresses found for host {}", host),
+        ));
+    }
+    Ok(backends)
+}
+
+// The routing keys of the payloads of a request. Payloads without an owner,
+// such as those of garbage collection requests, have no routing key.
+fn routing_keys(data: &Value) -> Vec<RoutingKey> {
+    data.as_array()
+        .map(|payloads| {
+            payloads
+                .iter()
+                .filter_map(|payload| {
+                    serde_json::from_value::<RoutingPayload>(payload.clone())
+                        .ok()
+                })
+                .map(|payload| (payload.owner, payload.bucket_id))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The writes completed by this process within the read-your-writes window.
+struct RecentWrites {
+    window: Duration,
+    writes: Mutex<HashMap<RoutingKey, Instant>>,
+}
+
+impl RecentWrites {
+    fn new(window: Duration) -> Self {
+        RecentWrites {
+            window,
+            writes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn record_at(&self, keys: Vec<RoutingKey>, now: Instant) {
+        let mut writes = self.writes.lock().expect("replica lock poisoned");
+        let window = self.window;
+        writes.retain(|_, written| now.duration_since(*written) < window);
+        for key in keys {
+            let _ = writes.insert(key, now);
+        }
+    }
+
+    fn is_recent_at(&self, keys: &[RoutingKey], now: Instant) -> bool {
+        let writes = self.writes.lock().expect("replica lock poisoned");
+        keys.iter().any(|key| {
+            writes.get(key).map_or(false, |written| {
+                now.duration_since(*written) < self.window
+            })
+        })
+    }
+}
+
+/// An optional pool of connections to a read replica, shared by every
+/// connection. The replica is cheap to clone and all clones share the same
+/// pool and recent writes.
+pub struct ReadReplica<F>
+where
+    F: FnMut(&Backend) -> AgedConnection + Send + 'static,
+{
+    pool: Option<ConnectionPool<AgedConnection, StaticIpResolver, F>>,
+    recent_writes: Arc<RecentWrites>,
+    requests: Counter,
+}
+
+impl<F> Clone for ReadReplica<F>
+where
+    F: FnMut(&Backend) -> AgedConnection + Send + 'static,
+{
+    fn clone(&self) -> Self {
+        ReadReplica {
+            pool: self.pool.clone(),
+            recent_writes: self.recent_writes.clone(),
+            requests: self.requests.clone(),
+        }
+    }
+}
+
+impl<F> ReadReplica<F>
+where
+    F: FnMut(&Backend) -> AgedConnection + Send + 'static,
+{
+    /// Route reads to the replica served by `pool`. When `pool` is `None`
+    /// every request is served by the primary.
+    pub fn new(
+        pool: Option<ConnectionPool<AgedConnection, StaticIpResolver, F>>,
+        config: &ConfigReplica,
+        metrics: &RegisteredMetrics,
+    ) -> Self {
+        ReadReplica {
+            pool,
+            recent_writes: Arc::new(RecentWrites::new(Duration::from_millis(
+                config.read_your_writes_ms,
+            ))),
+            requests: metrics.replica_requests.clone(),
+        }
+    }
+
+    /// The replica pool to serve `msg` from, or `None` if the request must be
+    /// served by the primary.
+    pub fn route(
+        &self,
+        msg: &FastMessage,
+    ) -> Option<&ConnectionPool<AgedConnection, StaticIpResolver, F>> {
+        let pool = self.pool.as_ref()?;
+        let method = msg.data.m.name.as_str();
+        if !is_read_method(method) {
+            return None;
+        }
+
+        let keys = routing_keys(&msg.data.d);
+        if self.recent_writes.is_recent_at(&keys, Instant::now()) {
+            return None;
+        }
+
+        self.requests.inc();
+        Some(pool)
+    }
+
+    /// Record the completion of a request served by the primary so that
+    /// reads of the buckets it wrote are served by the primary for the
+    /// read-your-writes window.
+    pub fn record(&self, msg: &FastMessage) {
+        if self.pool.is_none() || !is_write_method(msg.data.m.name.as_str()) {
+            return;
+        }
+        self.recent_writes
+            .record_at(routing_keys(&msg.data.d), Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn read_methods_routed() {
+        assert!(is_read_method("getobject"));
+        assert!(is_read_method("listbuckets"));
+        assert!(!is_read_method("createobject"));
+        assert!(!is_read_method("deletegcbatch"));
+        assert!(!is_read_method("selftest"));
+    }
+
+    #[test]
+    fn replica_backends_resolved() {
+        assert_eq!(
+            resolve_backends("10.0.0.5", 5432).unwrap(),
+            vec![("10.0.0.5".parse::<IpAddr>().unwrap(), 5432)]
+        );
+        assert_eq!(
+            resolve_backends("::1", 5433).unwrap(),
+            vec![("::1".parse::<IpAddr>().unwrap(), 5433)]
+        );
+
+        let local = resolve_backends("localhost", 5432).unwrap();
+        assert!(!local.is_empty());
+        assert!(local
+            .iter()
+            .all(|(ip, port)| ip.is_loopback() && *port == 5432));
+
+        assert!(resolve_backends("replica.invalid", 5432).is_err());
+    }
+
+    #[test]
+    fn reads_own_writes_within_window() {
+        let owner = Uuid::new_v4();
+        let bucket_id = Uuid::new_v4();
+        let recent = RecentWrites::new(Duration::from_millis(1000));
+        let start = Instant::now();
+
+        let write = json!([{ "owner": owner, "bucket_id": bucket_id }]);
+        recent.record_at(routing_keys(&write), start);
+
+        let read = routing_keys(&json!([{
+            "owner": owner,
+            "bucket_id": bucket_id,
+            "name": "cat.jpg"
+        }]));
+        assert!(recent.is_recent_at(&read, start));
+        assert!(recent.is_recent_at(&read, start + Duration::from_millis(999)));
+        assert!(
+            !recent.is_recent_at(&read, start + Duration::from_millis(1000))
+        );
+
+        // Reads of other buckets and of the owner's buckets are not held
+        let other = routing_keys(&json!([{
+            "owner": owner,
+            "bucket_id": Uuid::new_v4()
+        }]));
+        assert!(!recent.is_recent_at(&other, start));
+        let buckets = routing_keys(&json!([{ "owner": owner, "name": "b" }]));
+        assert!(!recent.is_recent_at(&buckets, start));
+
+        // Payloads without an owner are not recorded
+        assert!(
+            routing_keys(&json!([{ "batch_id": Uuid::new_v4() }])).is_empty()
+        );
+    }
+}
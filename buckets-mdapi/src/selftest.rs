@@ -0,0 +1,259 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, warn, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::metrics::RegisteredMetrics;
+use crate::object;
+use crate::object::create::CreateObjectPayload;
+use crate::object::GetObjectPayload;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
+use crate::util::{array_wrap, duration_to_seconds};
+
+/// The base64 encoded MD5 digest of zero bytes of data. Self-test objects have
+/// no data so that deleting them never produces garbage to be collected.
+const EMPTY_CONTENT_MD5: &str = "1B2M2Y8AsgTpgAmY7PhCfg==";
+
+/// The owner and bucket id reserved for self-test objects. The nil UUID is
+/// never generated for a real owner or bucket.
+pub fn selftest_owner() -> Uuid {
+    Uuid::nil()
+}
+
+pub fn selftest_bucket_id() -> Uuid {
+    Uuid::nil()
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SelfTestPayload {
+    pub vnode: u64,
+    pub request_id: Uuid,
+}
+
+impl HasRequestId for SelfTestPayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for SelfTestPayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SelfTestStep {
+    pub name: String,
+    pub success: bool,
+    /// The time taken by the step in seconds
+    pub duration: f64,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SelfTestResponse {
+    pub success: bool,
+    pub steps: Vec<SelfTestStep>,
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<SelfTestPayload>, SerdeError> {
+    serde_json::from_value::<Vec<SelfTestPayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: SelfTestPayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    if !config.selftest.enabled {
+        return Err(format!("Unsupported functon: {}", method));
+    }
+
+    let resp = do_selftest(&payload, config, conn, metrics, log);
+
+    if resp.success {
+        debug!(log, "operation successful");
+    } else {
+        warn!(log, "self-test failed"; "steps" => format!("{:?}", resp.steps));
+    }
+
+    // The SelfTestResponse type contains no maps and so the conversion to JSON
+    // is not expected to fail.
+    let value = serde_json::to_value(resp)
+        .expect("failed to serialize SelfTestResponse");
+    let msg_data = FastMessageData::new(method.into(), array_wrap(value));
+    let msg: HandlerResponse = FastMessage::data(msg_id, msg_data).into();
+    Ok(msg)
+}
+
+fn do_selftest(
+    payload: &SelfTestPayload,
+    config: &Config,
+    conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> SelfTestResponse {
+    let owner = selftest_owner();
+    let bucket_id = selftest_bucket_id();
+    let name = ["selftest-", &payload.request_id.to_string()].concat();
+    let id = Uuid::new_v4();
+
+    let create_payload = CreateObjectPayload {
+        owner,
+        bucket_id,
+        name: name.clone(),
+        id,
+        vnode: payload.vnode,
+        content_length: 0,
+        content_md5: EMPTY_CONTENT_MD5.into(),
+        content_type: "application/octet-stream".into(),
+        headers: HashMap::new(),
+        sharks: vec![],
+        properties: None,
+        request_id: payload.request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        indexed_metadata: HashMap::new(),
+        dry_run: false,
+    };
+
+    let get_payload = GetObjectPayload {
+        owner,
+        bucket_id,
+        name,
+        vnode: payload.vnode,
+        request_id: payload.request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
+    };
+
+    let mut steps = Vec::with_capacity(3);
+
+    steps.push(run_step("createobject", || {
+        object::create::do_create(
+            "selftest",
+            &create_payload,
+            &None,
+            config,
+            conn,
+            metrics,
+            log,
+        )
+        .map_err(|e| e.message())
+        .map(|_| ())
+    }));
+
+    if steps[0].success {
+        steps.push(run_step("getobject", || {
+            object::get::do_get(
+                "selftest",
+                &get_payload,
+                None,
+                config,
+                conn,
+                metrics,
+                log,
+            )
+            .map_err(|e| e.message())
+            .and_then(|resp| {
+                if resp.id == id {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "read object id {} but expected {}",
+                        resp.id, id
+                    ))
+                }
+            })
+        }));
+    }
+
+    // The delete is always attempted so that the scratch object is cleaned up
+    // even if an earlier step failed after the object was written.
+    steps.push(run_step("deleteobject", || {
+        object::delete::do_delete(&get_payload, config, conn, metrics, log)
+            .map_err(|e| e.message())
+            .and_then(|deleted| {
+                if deleted.is_empty() {
+                    Err(String::from("no object was deleted"))
+                } else {
+                    Ok(())
+                }
+            })
+    }));
+
+    SelfTestResponse {
+        success: steps.iter().all(|step| step.success),
+        steps,
+    }
+}
+
+fn run_step<F>(name: &str, f: F) -> SelfTestStep
+where
+    F: FnOnce() -> Result<(), String>,
+{
+    let now = Instant::now();
+    let result = f();
+    let duration = duration_to_seconds(now.elapsed());
+
+    SelfTestStep {
+        name: name.into(),
+        success: result.is_ok(),
+        duration,
+        error: result.err(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use serde_json;
+
+    impl Arbitrary for SelfTestPayload {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            SelfTestPayload {
+                vnode: u64::arbitrary(g),
+                request_id: Uuid::new_v4(),
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_selftest_payload_roundtrip(msg: SelfTestPayload) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(selftest_str) => {
+                    let decode_result: Result<SelfTestPayload, _> =
+                        serde_json::from_str(&selftest_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+}
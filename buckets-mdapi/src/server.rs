@@ -0,0 +1,96 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! The task serving the requests received on a Fast connection.
+//!
+//! This takes the place of `fast_rpc::server::make_task`, which hands the
+//! response of a request to be sent and moves on without reporting when it
+//! has been written. Here the reservation of a request's bytes from the
+//! in-flight byte budget is returned along with its response, and is only
+//! released once the response has been written to the connection, so that
+//! the budget bounds the responses waiting to be sent to slow clients as well
+//! as the requests in progress.
+
+use std::io::{Error as IOError, ErrorKind};
+
+use serde_json::json;
+use slog::{debug, error, Logger};
+use tokio::codec::Decoder;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+use fast_rpc::protocol::{FastMessage, FastMessageData, FastRpc};
+
+use crate::limiter::BytePermit;
+
+/// The response to a request, along with the reservation of the bytes of the
+/// request and the response from the in-flight byte budget, if any.
+pub type Response = (Vec<FastMessage>, Option<BytePermit>);
+
+/// Create the task serving the requests received on `socket` with `handler`.
+/// Each request is answered in turn, and the next request is not handled
+/// until the response to the previous one has been written.
+pub fn make_task<F>(
+    socket: TcpStream,
+    mut handler: F,
+    log: &Logger,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    F: FnMut(&FastMessage, &Logger) -> Result<Response, IOError> + Send,
+{
+    let (tx, rx) = FastRpc.framed(socket).split();
+    let rx_log = log.clone();
+    let tx_log = log.clone();
+
+    rx.fold(tx, move |tx, msgs| {
+        debug!(rx_log, "processing fast message");
+        respond(&msgs, &mut handler, &rx_log)
+            .into_future()
+            .and_then(move |(response, byte_permit)| {
+                // The sent future resolves once the response has been
+                // flushed to the connection
+                tx.send(response).map(move |tx| {
+                    drop(byte_permit);
+                    tx
+                })
+            })
+    })
+    .then(move |res| {
+        match res {
+            Ok(_) => debug!(tx_log, "connection closed"),
+            Err(e) => error!(tx_log, "failed to process connection";
+                "err" => %e),
+        }
+        Ok(())
+    })
+}
+
+// Answer the request, ending the response with the end message the client
+// waits for, or with an error message if the request could not be handled
+fn respond<F>(
+    msgs: &[FastMessage],
+    handler: &mut F,
+    log: &Logger,
+) -> Result<Response, IOError>
+where
+    F: FnMut(&FastMessage, &Logger) -> Result<Response, IOError>,
+{
+    let msg = msgs.first().ok_or_else(|| {
+        IOError::new(ErrorKind::Other, "received an empty Fast request")
+    })?;
+    let method = msg.data.m.name.clone();
+
+    match handler(msg, log) {
+        Ok((mut response, byte_permit)) => {
+            response.push(FastMessage::end(msg.id, method));
+            Ok((response, byte_permit))
+        }
+        Err(err) => {
+            let value = json!({
+                "name": "FastError",
+                "message": err.to_string()
+            });
+            let data = FastMessageData::new(method, value);
+            Ok((vec![FastMessage::error(msg.id, data)], None))
+        }
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Graceful shutdown of the Fast server on SIGTERM.
+//!
+//! Once SIGTERM is received the server stops accepting connections and waits
+//! for the requests in progress to complete, for at most
+//! `server.shutdown_drain_timeout_secs`, then flushes the final metric values
+//! to StatsD, if configured, before the process exits. Requests received
+//! during the drain on connections accepted before the signal are still
+//! served, and are waited for like any other request in progress. Requests
+//! still in progress when the timeout elapses are abandoned: the process is
+//! forced to exit without waiting for them, and their clients see the
+//! connection close without a response.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the shutdown flag and the requests in progress are checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigterm(_signal: libc::c_int) {
+    request(&SHUTDOWN_REQUESTED);
+}
+
+// Only an atomic store is async-signal-safe in the signal handler
+fn request(flag: &AtomicBool) {
+    flag.store(true, Ordering::SeqCst);
+}
+
+/// Install the SIGTERM handler that requests a graceful shutdown.
+pub fn install_handler() -> io::Result<()> {
+    // The handler only stores to an atomic, and the sigaction struct is fully
+    // initialized before it is passed to sigaction(2)
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigterm as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGTERM, &action, std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Returns `true` once SIGTERM has been received.
+pub fn requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Block the calling thread until SIGTERM is received.
+pub fn wait_for_signal() {
+    wait_for(&SHUTDOWN_REQUESTED);
+}
+
+fn wait_for(flag: &AtomicBool) {
+    while !flag.load(Ordering::SeqCst) {
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// How the drain of the requests in progress on shutdown ended.
+#[derive(Debug, PartialEq)]
+pub enum Drain {
+    /// Every request in progress completed.
+    Completed,
+    /// The drain timeout elapsed with this many requests still in progress.
+    Abandoned(usize),
+}
+
+/// The number of requests in progress, shared by every connection.
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+// Decrements the count of requests in progress when dropped, so that a request
+// that panics is not waited for.
+struct InFlightGuard<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for InFlightGuard<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count the request handled by `handle` as in progress until it returns.
+    pub fn track<T, H: FnOnce() -> T>(&self, handle: H) -> T {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        let _guard = InFlightGuard(&self.0);
+        handle()
+    }
+
+    /// The number of requests in progress.
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Wait for every request in progress to complete, for at most `timeout`.
+    /// Returns `false` if requests were still in progress at the timeout.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.count() == 0 {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    /// Wait for every request in progress to complete, for at most `timeout`,
+    /// and report the requests abandoned if the timeout elapsed first.
+    pub fn drain(&self, timeout: Duration) -> Drain {
+        if self.wait_idle(timeout) {
+            return Drain::Completed;
+        }
+        match self.count() {
+            0 => Drain::Completed,
+            abandoned => Drain::Abandoned(abandoned),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    #[test]
+    fn in_flight_requests_drained() {
+        let in_flight = InFlight::new();
+        assert!(in_flight.wait_idle(Duration::from_millis(0)));
+
+        let (started_tx, started_rx) = mpsc::channel();
+        let (finish_tx, finish_rx) = mpsc::channel::<()>();
+        let request_in_flight = in_flight.clone();
+        let request = thread::spawn(move || {
+            request_in_flight.track(|| {
+                started_tx.send(()).unwrap();
+                finish_rx.recv().unwrap();
+            })
+        });
+
+        started_rx.recv().unwrap();
+        assert_eq!(in_flight.count(), 1);
+        assert!(!in_flight.wait_idle(Duration::from_millis(100)));
+
+        finish_tx.send(()).unwrap();
+        assert!(in_flight.wait_idle(Duration::from_secs(10)));
+        request.join().unwrap();
+    }
+
+    #[test]
+    fn stuck_request_abandoned_at_timeout() {
+        let in_flight = InFlight::new();
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let request_in_flight = in_flight.clone();
+        let request = thread::spawn(move || {
+            request_in_flight.track(|| {
+                started_tx.send(()).unwrap();
+                // Stuck until released after the drain gives up on it
+                release_rx.recv().unwrap();
+            })
+        });
+        started_rx.recv().unwrap();
+
+        let start = Instant::now();
+        assert_eq!(
+            in_flight.drain(Duration::from_millis(100)),
+            Drain::Abandoned(1)
+        );
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_secs(10));
+
+        release_tx.send(()).unwrap();
+        request.join().unwrap();
+        assert_eq!(in_flight.drain(Duration::from_millis(0)), Drain::Completed);
+    }
+
+    #[test]
+    fn panicked_request_not_waited_for() {
+        let in_flight = InFlight::new();
+        let request_in_flight = in_flight.clone();
+        let request = thread::spawn(move || {
+            request_in_flight.track(|| panic!("request failed"))
+        });
+        assert!(request.join().is_err());
+        assert_eq!(in_flight.count(), 0);
+    }
+
+    #[test]
+    fn shutdown_request_ends_wait() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let (done_tx, done_rx) = mpsc::channel();
+        let waiter_flag = Arc::clone(&flag);
+        let waiter = thread::spawn(move || {
+            wait_for(&waiter_flag);
+            done_tx.send(()).unwrap();
+        });
+
+        assert!(done_rx.recv_timeout(Duration::from_millis(200)).is_err());
+        request(&flag);
+        done_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        waiter.join().unwrap();
+    }
+}
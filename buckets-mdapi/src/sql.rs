@@ -1,34 +1,66 @@
 // Copyright 2020 Joyent, Inc.
 
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::marker::Sync;
-use std::time::Instant;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 use postgres::types::ToSql;
-use postgres::{Client, ToStatement, Transaction};
+use postgres::{Client, Statement, ToStatement, Transaction};
 use tokio_postgres::Error as PGError;
+use tokio_postgres::Portal;
 use tokio_postgres::Row as PGRow;
 
-use slog::{o, trace, Logger};
+use slog::{o, trace, warn, Logger};
 
+use utils::config::ConfigDatabase;
+
+use crate::error::BucketsMdapiError;
 use crate::metrics;
 use crate::util;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Method {
     BucketCreate,
     BucketGet,
     BucketList,
+    BucketCount,
+    BucketCounts,
+    BucketObjectCount,
+    VnodeUsage,
+    Time,
+    Ping,
     BucketDeleteMove,
     BucketDelete,
+    BucketTotalBytesUpdate,
+    BucketDeletedLookup,
     ObjectCreate,
     ObjectCreateMove,
     ObjectGet,
+    ObjectGetMany,
+    ObjectHead,
     ObjectList,
+    ObjectCount,
     ObjectDelete,
+    ObjectDeleteByTagSelect,
+    ObjectDeleteByTag,
     ObjectDeleteMove,
     ObjectUpdate,
+    ObjectAuditRecord,
+    ObjectHistory,
+    ObjectIndexInsert,
+    ObjectIndexDelete,
+    ObjectIndexRead,
+    ObjectPropertiesInsert,
+    ObjectPropertiesDelete,
+    ObjectOrphanScan,
+    ObjectSwap,
+    ObjectRename,
+    ObjectPin,
+    ObjectPinCheck,
     GarbageGet,
     GarbageDelete,
     GarbageRecordDelete,
@@ -43,15 +75,40 @@ impl Method {
             Method::BucketCreate => "BucketCreate",
             Method::BucketGet => "BucketGet",
             Method::BucketList => "BucketList",
+            Method::BucketCount => "BucketCount",
+            Method::BucketCounts => "BucketCounts",
+            Method::BucketObjectCount => "BucketObjectCount",
+            Method::VnodeUsage => "VnodeUsage",
+            Method::Time => "Time",
+            Method::Ping => "Ping",
             Method::BucketDeleteMove => "BucketDeleteMove",
             Method::BucketDelete => "BucketDelete",
+            Method::BucketTotalBytesUpdate => "BucketTotalBytesUpdate",
+            Method::BucketDeletedLookup => "BucketDeletedLookup",
             Method::ObjectCreate => "ObjectCreate",
             Method::ObjectCreateMove => "ObjectCreateMove",
             Method::ObjectGet => "ObjectGet",
+            Method::ObjectGetMany => "ObjectGetMany",
+            Method::ObjectHead => "ObjectHead",
             Method::ObjectList => "ObjectList",
+            Method::ObjectCount => "ObjectCount",
             Method::ObjectDelete => "ObjectDelete",
+            Method::ObjectDeleteByTagSelect => "ObjectDeleteByTagSelect",
+            Method::ObjectDeleteByTag => "ObjectDeleteByTag",
             Method::ObjectDeleteMove => "ObjectDeleteMove",
             Method::ObjectUpdate => "ObjectUpdate",
+            Method::ObjectAuditRecord => "ObjectAuditRecord",
+            Method::ObjectHistory => "ObjectHistory",
+            Method::ObjectIndexInsert => "ObjectIndexInsert",
+            Method::ObjectIndexDelete => "ObjectIndexDelete",
+            Method::ObjectIndexRead => "ObjectIndexRead",
+            Method::ObjectPropertiesInsert => "ObjectPropertiesInsert",
+            Method::ObjectPropertiesDelete => "ObjectPropertiesDelete",
+            Method::ObjectOrphanScan => "ObjectOrphanScan",
+            Method::ObjectSwap => "ObjectSwap",
+            Method::ObjectRename => "ObjectRename",
+            Method::ObjectPin => "ObjectPin",
+            Method::ObjectPinCheck => "ObjectPinCheck",
             Method::GarbageGet => "GarbageGet",
             Method::GarbageDelete => "GarbageDelete",
             Method::GarbageRecordDelete => "GarbageRecordDelete",
@@ -142,6 +199,238 @@ where
     sql_with_metrics(method, metrics, &q_log, || txn.query(sql, items))
 }
 
+/// The number of statements kept for each method and vnode. A method builds
+/// different SQL for a vnode depending on the options of a request, so a few of
+/// its most recently used statements are kept.
+const STATEMENTS_PER_KEY: usize = 4;
+
+/// The most statements kept on a connection before the cache is emptied, which
+/// bounds the memory held by the prepared statements of the database session.
+const MAX_CACHED_STATEMENTS: usize = 1024;
+
+/// The statements prepared on a single connection, keyed by the method and the
+/// vnode the SQL of the statement was built for. Preparing the SQL of a query
+/// once and reusing the statement saves the database from parsing and planning
+/// the query on every request. The statements belong to the database session,
+/// so the cache must be cleared whenever the connection is reestablished.
+/// Clones of the cache share the same statements.
+#[derive(Clone, Default)]
+pub struct StatementCache {
+    statements: Arc<Mutex<CachedStatements>>,
+}
+
+// The SQL and statement of each cached statement, most recently used first
+type CachedStatements = HashMap<(Method, u64), Vec<(String, Statement)>>;
+
+impl StatementCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget every statement, as when the connection the statements were
+    /// prepared on is reestablished.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// The number of statements kept.
+    pub fn len(&self) -> usize {
+        self.lock().values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // A panic while the cache is locked cannot leave it inconsistent, so a
+    // poisoned lock is still used
+    fn lock(&self) -> MutexGuard<CachedStatements> {
+        self.statements
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The statement prepared on `conn` for `sql`, preparing it if it is not
+    /// already cached for `method` and `vnode`. Each reuse of a statement is
+    /// counted by the `prepared_statement_cache_hits_total` metric.
+    pub fn prepare(
+        &self,
+        method: Method,
+        vnode: u64,
+        conn: &mut Client,
+        sql: &str,
+        metrics: &metrics::RegisteredMetrics,
+    ) -> Result<Statement, PGError> {
+        let mut statements = self.lock();
+        let key = (method, vnode);
+
+        if let Some(cached) = statements.get_mut(&key) {
+            if let Some(pos) = cached.iter().position(|(s, _)| s == sql) {
+                // Keep the most recently used statement first
+                let entry = cached.remove(pos);
+                let statement = entry.1.clone();
+                cached.insert(0, entry);
+                metrics
+                    .prepared_statement_cache_hits
+                    .with_label_values(&[method.as_str()])
+                    .inc();
+                return Ok(statement);
+            }
+        }
+
+        let statement = conn.prepare(sql)?;
+        let total: usize = statements.values().map(Vec::len).sum();
+        if total >= MAX_CACHED_STATEMENTS {
+            statements.clear();
+        }
+        let cached = statements.entry(key).or_insert_with(Vec::new);
+        cached.insert(0, (sql.to_string(), statement.clone()));
+        cached.truncate(STATEMENTS_PER_KEY);
+        Ok(statement)
+    }
+}
+
+/// Run the query `sql` for `vnode` with a statement prepared once per
+/// connection and kept in `statements`. Without a cache the query is run as an
+/// ad hoc query.
+#[allow(clippy::too_many_arguments)]
+pub fn cached_query(
+    method: Method,
+    vnode: u64,
+    statements: Option<&StatementCache>,
+    conn: &mut Client,
+    sql: &str,
+    items: &[&(dyn ToSql + Sync)],
+    metrics: &metrics::RegisteredMetrics,
+    log: &Logger,
+) -> Result<Vec<PGRow>, PGError> {
+    let statements = match statements {
+        Some(statements) => statements,
+        None => return query(method, conn, sql, items, metrics, log),
+    };
+
+    let q_log = log.new(o!("op" => "sql::cached_query"));
+    trace!(q_log, "begin";
+        "sql" => sql.to_string(),
+        "items" => format!("{:?}", items),
+    );
+    sql_with_metrics(method, metrics, &q_log, || {
+        let statement =
+            statements.prepare(method, vnode, conn, sql, metrics)?;
+        conn.query(&statement, items)
+    })
+}
+
+// txn.query_portal wrapper that posts metrics
+pub fn txn_query_portal(
+    method: Method,
+    txn: &mut Transaction,
+    portal: &Portal,
+    max_rows: i32,
+    metrics: &metrics::RegisteredMetrics,
+    log: &Logger,
+) -> Result<Vec<PGRow>, PGError> {
+    let q_log = log.new(o!("op" => "sql::txn_query_portal"));
+    trace!(q_log, "begin"; "max_rows" => max_rows);
+    sql_with_metrics(method, metrics, &q_log, || {
+        txn.query_portal(portal, max_rows)
+    })
+}
+
+/// Check the number of rows affected by a mutation of a single object, which
+/// should be at most `max_rows`. A mutation affecting more rows than that is
+/// the sign of an unexpectedly broad `WHERE` clause, so it is logged and
+/// counted by the `wide_mutation_total` metric. Returns whether the mutation
+/// was wider than expected.
+pub fn check_mutation_width(
+    method: Method,
+    affected_rows: usize,
+    max_rows: u64,
+    metrics: &metrics::RegisteredMetrics,
+    log: &Logger,
+) -> bool {
+    if affected_rows as u64 <= max_rows {
+        return false;
+    }
+
+    warn!(log, "mutation affected more rows than expected";
+        "method" => method.as_str(),
+        "affected_rows" => affected_rows,
+        "max_rows" => max_rows,
+    );
+    metrics
+        .wide_mutations
+        .with_label_values(&[method.as_str()])
+        .inc();
+    true
+}
+
+/// Run `f` in a new transaction of `conn` and commit it. When the transaction
+/// is rolled back by the database with one of the configured
+/// `retryable_sql_states`, such as for a serialization failure or a deadlock,
+/// the whole transaction is run again after an exponential backoff, up to
+/// `max_retries` times. Each retry is counted by the `postgres_retry_total`
+/// metric for `method`.
+pub fn txn_with_retries<F, T>(
+    method: Method,
+    conn: &mut Client,
+    config: &ConfigDatabase,
+    metrics: &metrics::RegisteredMetrics,
+    log: &Logger,
+    mut f: F,
+) -> Result<T, BucketsMdapiError>
+where
+    F: FnMut(&mut Transaction) -> Result<T, BucketsMdapiError>,
+{
+    let mut attempt = 0;
+    loop {
+        let result = conn
+            .transaction()
+            .map_err(BucketsMdapiError::from)
+            .and_then(|mut txn| {
+                let value = f(&mut txn)?;
+                txn.commit().map_err(BucketsMdapiError::from)?;
+                Ok(value)
+            });
+
+        match result {
+            Err(e)
+                if attempt < config.max_retries
+                    && is_retryable(&e, &config.retryable_sql_states) =>
+            {
+                let backoff = retry_backoff(config.base_backoff_ms, attempt);
+                warn!(log, "retrying transaction";
+                    "method" => method.as_str(),
+                    "attempt" => attempt + 1,
+                    "backoff_ms" => backoff.as_millis(),
+                    "error" => e.message(),
+                );
+                metrics
+                    .postgres_retries
+                    .with_label_values(&[method.as_str()])
+                    .inc();
+                thread::sleep(backoff);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_retryable(
+    e: &BucketsMdapiError,
+    retryable_sql_states: &[String],
+) -> bool {
+    e.sql_state()
+        .map_or(false, |code| retryable_sql_states.iter().any(|s| s == code))
+}
+
+// The wait before retry `attempt`, counting from zero, which doubles with each
+// attempt.
+fn retry_backoff(base_backoff_ms: u64, attempt: u32) -> Duration {
+    Duration::from_millis(base_backoff_ms.saturating_mul(1 << attempt.min(16)))
+}
+
 fn sql_with_metrics<F, T>(
     method: Method,
     metrics: &metrics::RegisteredMetrics,
@@ -182,4 +471,53 @@ fn post_timer_metrics<T>(
         .postgres_requests
         .with_label_values(&[&method.as_str(), success])
         .observe(t);
+
+    // The vnodes of the request are part of the log context
+    if let Some(threshold) = metrics.slow_query_threshold {
+        if duration > threshold {
+            warn!(log, "slow query";
+                "method" => method.as_str(),
+                "duration_ms" => duration.as_millis(),
+                "threshold_ms" => threshold.as_millis(),
+            );
+            metrics
+                .slow_queries
+                .with_label_values(&[method.as_str()])
+                .inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retries_configured_sql_states() {
+        let retryable = vec!["40001".to_string(), "40P01".to_string()];
+        let rollback = |code: &str| BucketsMdapiError::PostgresError {
+            code: Some(code.into()),
+            message: "msg".into(),
+        };
+
+        assert!(is_retryable(&rollback("40001"), &retryable));
+        assert!(is_retryable(&rollback("40P01"), &retryable));
+        assert!(!is_retryable(&rollback("40002"), &retryable));
+        assert!(!is_retryable(
+            &BucketsMdapiError::postgres_error("40001".into()),
+            &retryable
+        ));
+        assert_eq!(rollback("40001").to_string(), "PostgresError");
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        assert_eq!(retry_backoff(10, 0), Duration::from_millis(10));
+        assert_eq!(retry_backoff(10, 1), Duration::from_millis(20));
+        assert_eq!(retry_backoff(10, 3), Duration::from_millis(80));
+        assert_eq!(
+            retry_backoff(u64::max_value(), 2),
+            Duration::from_millis(u64::max_value())
+        );
+    }
 }
@@ -0,0 +1,239 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+use std::io::Error as IOError;
+use std::net::UdpSocket;
+
+use prometheus::proto::{MetricFamily, MetricType};
+
+use utils::config::{ConfigStatsd, StatsdFormat};
+
+/// Sends metrics to a StatsD server over UDP as they are recorded.
+///
+/// Delivery is best effort: a metric that cannot be sent is dropped rather than
+/// failing or delaying the request that recorded it.
+pub struct StatsdEmitter {
+    socket: UdpSocket,
+    prefix: String,
+    format: StatsdFormat,
+    const_tags: Vec<(String, String)>,
+}
+
+impl StatsdEmitter {
+    /// Create an emitter sending to the StatsD server in `config`. The
+    /// `const_tags` are added to every metric sent in the DogStatsD format.
+    pub fn new(
+        config: &ConfigStatsd,
+        const_tags: Vec<(String, String)>,
+    ) -> Result<Self, IOError> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect((config.host.as_str(), config.port))?;
+        socket.set_nonblocking(true)?;
+
+        Ok(StatsdEmitter {
+            socket,
+            prefix: config.prefix.clone(),
+            format: config.format,
+            const_tags,
+        })
+    }
+
+    /// Increment the counter `name` by one.
+    pub fn count(&self, name: &str, tags: &[(&str, &str)]) {
+        self.send(name, "1", "c", tags)
+    }
+
+    /// Record a duration, given in seconds, to the timer `name`. StatsD timers
+    /// are measured in milliseconds.
+    pub fn timing(&self, name: &str, seconds: f64, tags: &[(&str, &str)]) {
+        self.send(name, &(seconds * 1000.0).to_string(), "ms", tags)
+    }
+
+    /// Set the gauge `name` to `value`.
+    pub fn gauge(&self, name: &str, value: f64, tags: &[(&str, &str)]) {
+        self.send(name, &value.to_string(), "g", tags)
+    }
+
+    /// Send the current value of every counter and gauge in `families` as a
+    /// gauge. Histograms are skipped, their observations having already been
+    /// sent as timers. Labels that are also constant tags are not repeated.
+    pub fn flush(&self, families: &[MetricFamily]) {
+        for family in families {
+            for metric in family.get_metric() {
+                let value = match family.get_field_type() {
+                    MetricType::COUNTER => metric.get_counter().get_value(),
+                    MetricType::GAUGE => metric.get_gauge().get_value(),
+                    _ => continue,
+                };
+                let tags: Vec<(&str, &str)> = metric
+                    .get_label()
+                    .iter()
+                    .filter(|label| !self.is_const_tag(label.get_name()))
+                    .map(|label| (label.get_name(), label.get_value()))
+                    .collect();
+                self.gauge(family.get_name(), value, &tags);
+            }
+        }
+    }
+
+    fn is_const_tag(&self, name: &str) -> bool {
+        self.const_tags.iter().any(|(k, _)| k == name)
+    }
+
+    fn send(&self, name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) {
+        let line = format_line(
+            self.format,
+            &self.prefix,
+            name,
+            value,
+            kind,
+            &self.const_tags,
+            tags,
+        );
+        let _ = self.socket.send(line.as_bytes());
+    }
+}
+
+fn format_line(
+    format: StatsdFormat,
+    prefix: &str,
+    name: &str,
+    value: &str,
+    kind: &str,
+    const_tags: &[(String, String)],
+    tags: &[(&str, &str)],
+) -> String {
+    let mut line = String::new();
+    if !prefix.is_empty() {
+        line.push_str(prefix);
+        line.push('.');
+    }
+    line.push_str(name);
+
+    match format {
+        StatsdFormat::StatsD => {
+            for (_, tag_value) in tags {
+                line.push('.');
+                line.push_str(tag_value);
+            }
+            [&line, ":", value, "|", kind].concat()
+        }
+        StatsdFormat::DogStatsD => {
+            let all_tags: Vec<String> = const_tags
+                .iter()
+                .map(|(k, v)| [k.as_str(), ":", v.as_str()].concat())
+                .chain(tags.iter().map(|(k, v)| [*k, ":", *v].concat()))
+                .collect();
+            if all_tags.is_empty() {
+                [&line, ":", value, "|", kind].concat()
+            } else {
+                [&line, ":", value, "|", kind, "|#", &all_tags.join(",")]
+                    .concat()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn receiver() -> (UdpSocket, ConfigStatsd) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let config = ConfigStatsd {
+            enabled: true,
+            port: socket.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        (socket, config)
+    }
+
+    fn recv_line(socket: &UdpSocket) -> String {
+        let mut buf = [0; 512];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn statsd_packets() {
+        let (socket, config) = receiver();
+        let emitter = StatsdEmitter::new(&config, vec![]).unwrap();
+
+        emitter.count("incoming_request_count", &[]);
+        assert_eq!(
+            recv_line(&socket),
+            "buckets_mdapi.incoming_request_count:1|c"
+        );
+
+        emitter.timing(
+            "fast_requests",
+            0.25,
+            &[("method", "getobject"), ("success", "true")],
+        );
+        assert_eq!(
+            recv_line(&socket),
+            "buckets_mdapi.fast_requests.getobject.true:250|ms"
+        );
+    }
+
+    #[test]
+    fn dogstatsd_packets() {
+        let (socket, mut config) = receiver();
+        config.format = StatsdFormat::DogStatsD;
+        let const_tags = vec![("datacenter".into(), "us-east-1".into())];
+        let emitter = StatsdEmitter::new(&config, const_tags).unwrap();
+
+        emitter.timing(
+            "fast_requests",
+            0.25,
+            &[("method", "getobject"), ("success", "true")],
+        );
+        assert_eq!(
+            recv_line(&socket),
+            "buckets_mdapi.fast_requests:250|ms\
+             |#datacenter:us-east-1,method:getobject,success:true"
+        );
+    }
+
+    #[test]
+    fn flushed_counters_and_gauges() {
+        let (socket, config) = receiver();
+        let const_tags = vec![("datacenter".into(), "us-east-1".into())];
+        let emitter = StatsdEmitter::new(&config, const_tags).unwrap();
+
+        let registry = prometheus::Registry::new();
+        let counter = prometheus::CounterVec::new(
+            prometheus::Opts::new("requests", "Requests.")
+                .const_label("datacenter", "us-east-1"),
+            &["method"],
+        )
+        .unwrap();
+        let histogram = prometheus::Histogram::with_opts(
+            prometheus::HistogramOpts::new("latency", "Latency."),
+        )
+        .unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        counter.with_label_values(&["getobject"]).inc_by(3.0);
+        histogram.observe(0.5);
+
+        emitter.flush(&registry.gather());
+        assert_eq!(recv_line(&socket), "buckets_mdapi.requests.getobject:3|g");
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buf = [0; 512];
+        assert!(socket.recv(&mut buf).is_err());
+    }
+
+    #[test]
+    fn unprefixed_line() {
+        let line =
+            format_line(StatsdFormat::StatsD, "", "count", "1", "c", &[], &[]);
+        assert_eq!(line, "count:1|c");
+    }
+}
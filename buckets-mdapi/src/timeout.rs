@@ -0,0 +1,152 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Per-method statement timeouts.
+//!
+//! The timeout of a request is the timeout configured for its method in
+//! `method_timeouts_ms`, or `default_timeout_ms` for any other method. A
+//! request may also carry its own `timeout_ms` in its payload, in which case
+//! the smaller of the two timeouts applies. The timeout is set as the
+//! `statement_timeout` of the connection for the duration of the request, and
+//! the connection is then returned to the `database.statement_timeout_ms` set
+//! on every session when it is established.
+
+use serde_derive::Deserialize;
+use serde_json::Value;
+use slog::{warn, Logger};
+
+use cueball_postgres_connection::PostgresConnection;
+use utils::config::Config;
+
+/// The timeout optionally requested by the client of a request.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+struct ClientTimeout {
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+impl ClientTimeout {
+    // Read the client timeout from the Fast message data of a request, which
+    // is an array holding the request payload.
+    fn from_msg_data(data: &Value) -> Self {
+        data.get(0)
+            .and_then(|payload| {
+                serde_json::from_value::<ClientTimeout>(payload.clone()).ok()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// The statement timeout in milliseconds of a request for `method`, or `None`
+/// if neither the configuration nor the client sets one.
+pub fn request_timeout_ms(
+    method: &str,
+    data: &Value,
+    config: &Config,
+) -> Option<u64> {
+    let configured = config
+        .server
+        .method_timeouts_ms
+        .get(method)
+        .cloned()
+        .or(config.server.default_timeout_ms);
+    let client = ClientTimeout::from_msg_data(data).timeout_ms;
+
+    match (configured, client) {
+        (Some(configured), Some(client)) => Some(configured.min(client)),
+        (configured, client) => configured.or(client),
+    }
+}
+
+/// Set the statement timeout of the connection for a request. Nothing is done
+/// for a request without a timeout.
+pub fn set(
+    conn: &mut PostgresConnection,
+    timeout_ms: Option<u64>,
+) -> Result<(), String> {
+    match timeout_ms {
+        Some(ms) => conn
+            .batch_execute(&format!("SET statement_timeout = {}", ms))
+            .map_err(|e| e.to_string()),
+        None => Ok(()),
+    }
+}
+
+/// Restore the statement timeout of the database session once a request with
+/// a timeout is complete, so the connection does not carry the timeout to the
+/// next request it serves.
+pub fn reset(
+    conn: &mut PostgresConnection,
+    timeout_ms: Option<u64>,
+    config: &Config,
+    log: &Logger,
+) {
+    if timeout_ms.is_none() {
+        return;
+    }
+
+    // A timeout set on the session since it was established is lost to a
+    // RESET, so the configured session timeout is set again instead
+    let sql = match config.database.statement_timeout_ms {
+        Some(ms) => format!("SET statement_timeout = {}", ms),
+        None => "RESET statement_timeout".to_string(),
+    };
+    if let Err(e) = conn.batch_execute(&sql) {
+        warn!(log, "failed to reset statement timeout";
+            "error" => e.to_string());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.server.default_timeout_ms = Some(30000);
+        let _ = config
+            .server
+            .method_timeouts_ms
+            .insert("listobjects".into(), 5000);
+        config
+    }
+
+    #[test]
+    fn method_timeout_applied() {
+        let config = config();
+        let data = json!([{ "name": "photos/cat.jpg" }]);
+        assert_eq!(
+            request_timeout_ms("listobjects", &data, &config),
+            Some(5000)
+        );
+        assert_eq!(
+            request_timeout_ms("getobject", &data, &config),
+            Some(30000)
+        );
+        assert_eq!(
+            request_timeout_ms("getobject", &data, &Config::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn smaller_client_timeout_wins() {
+        let config = config();
+        let data = json!([{ "name": "photos/cat.jpg", "timeout_ms": 250 }]);
+        assert_eq!(
+            request_timeout_ms("listobjects", &data, &config),
+            Some(250)
+        );
+        assert_eq!(
+            request_timeout_ms("listobjects", &data, &Config::default()),
+            Some(250)
+        );
+
+        let data = json!([{ "name": "photos/cat.jpg", "timeout_ms": 60000 }]);
+        assert_eq!(
+            request_timeout_ms("listobjects", &data, &config),
+            Some(5000)
+        );
+    }
+}
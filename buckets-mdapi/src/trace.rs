@@ -0,0 +1,164 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+use serde_derive::Deserialize;
+use serde_json::Value;
+use slog::{o, Logger};
+
+/// The distributed trace context optionally carried by a request payload.
+///
+/// Callers that sampled a request for tracing include the `trace_id` of the
+/// trace and their own `span_id` alongside the other payload fields. Requests
+/// without a `trace_id` were not sampled and are logged as before.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct TraceContext {
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    #[serde(default)]
+    pub span_id: Option<String>,
+}
+
+impl TraceContext {
+    /// Read the trace context from the Fast message data of a request, which
+    /// is an array holding the request payload.
+    pub fn from_msg_data(data: &Value) -> Self {
+        data.get(0)
+            .and_then(|payload| {
+                serde_json::from_value::<TraceContext>(payload.clone()).ok()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Create a child logger that adds the trace id, the caller's span id as
+    /// the parent span id, and a newly generated span id for the handling of
+    /// this request to every log entry. The logger is returned unchanged for
+    /// requests that are not traced.
+    pub fn logger(&self, log: &Logger) -> Logger {
+        match &self.trace_id {
+            Some(trace_id) => log.new(o!(
+                "trace_id" => trace_id.clone(),
+                "parent_span_id" => self.span_id.clone(),
+                "span_id" => new_span_id(),
+            )),
+            None => log.clone(),
+        }
+    }
+}
+
+fn new_span_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::json;
+    use slog::{info, Drain, Key, OwnedKVList, Record, Serializer, KV};
+
+    type Captured = Arc<Mutex<Vec<(String, String)>>>;
+
+    struct CaptureSerializer(Vec<(String, String)>);
+
+    impl Serializer for CaptureSerializer {
+        fn emit_arguments(
+            &mut self,
+            key: Key,
+            val: &fmt::Arguments,
+        ) -> slog::Result {
+            self.0.push((key.to_string(), val.to_string()));
+            Ok(())
+        }
+    }
+
+    struct CaptureDrain(Captured);
+
+    impl Drain for CaptureDrain {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(
+            &self,
+            record: &Record,
+            values: &OwnedKVList,
+        ) -> Result<(), slog::Never> {
+            let mut ser = CaptureSerializer(vec![]);
+            let _ = values.serialize(record, &mut ser);
+            let _ = record.kv().serialize(record, &mut ser);
+            self.0.lock().unwrap().extend(ser.0);
+            Ok(())
+        }
+    }
+
+    fn capture_logger() -> (Logger, Captured) {
+        let captured = Arc::new(Mutex::new(vec![]));
+        let log = Logger::root(CaptureDrain(captured.clone()), o!());
+        (log, captured)
+    }
+
+    fn value_of(captured: &Captured, key: &str) -> Option<String> {
+        captured
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    }
+
+    #[test]
+    fn trace_from_msg_data() {
+        let data = json!([{
+            "owner": "a6c0c3b6-7e9a-4d0e-8d9a-2b6f1f6c7a61",
+            "trace_id": "4bf92f3577b34da6a3ce929d0e0e4736",
+            "span_id": "00f067aa0ba902b7",
+        }]);
+        let trace = TraceContext::from_msg_data(&data);
+        assert_eq!(
+            trace.trace_id,
+            Some("4bf92f3577b34da6a3ce929d0e0e4736".into())
+        );
+        assert_eq!(trace.span_id, Some("00f067aa0ba902b7".into()));
+
+        let untraced = TraceContext::from_msg_data(&json!([{ "vnode": 0 }]));
+        assert_eq!(untraced, TraceContext::default());
+
+        let malformed = TraceContext::from_msg_data(&json!({}));
+        assert_eq!(malformed, TraceContext::default());
+    }
+
+    #[test]
+    fn trace_ids_logged() {
+        let (log, captured) = capture_logger();
+        let trace = TraceContext {
+            trace_id: Some("4bf92f3577b34da6a3ce929d0e0e4736".into()),
+            span_id: Some("00f067aa0ba902b7".into()),
+        };
+
+        info!(trace.logger(&log), "handling request");
+
+        assert_eq!(
+            value_of(&captured, "trace_id"),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736".into())
+        );
+        assert_eq!(
+            value_of(&captured, "parent_span_id"),
+            Some("00f067aa0ba902b7".into())
+        );
+
+        let span_id = value_of(&captured, "span_id").unwrap();
+        assert_eq!(span_id.len(), 16);
+        assert_ne!(span_id, "00f067aa0ba902b7");
+    }
+
+    #[test]
+    fn untraced_not_logged() {
+        let (log, captured) = capture_logger();
+
+        info!(TraceContext::default().logger(&log), "handling request");
+
+        assert!(value_of(&captured, "trace_id").is_none());
+        assert!(value_of(&captured, "span_id").is_none());
+    }
+}
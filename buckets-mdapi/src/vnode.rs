@@ -0,0 +1,204 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+//! Report how loaded a vnode is so that rebalancing tooling can decide which
+//! vnodes to move. The `getvnodeusage` RPC counts the bucket and object rows
+//! of the schema of a single vnode and sums the content length of its objects
+//! with one aggregate query.
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+use serde_json::Value;
+use slog::{debug, error, Logger};
+use uuid::Uuid;
+
+use cueball_postgres_connection::PostgresConnection;
+use fast_rpc::protocol::{FastMessage, FastMessageData};
+use utils::config::Config;
+
+use crate::error::BucketsMdapiError;
+use crate::metrics::RegisteredMetrics;
+use crate::sql;
+use crate::types::{HandlerResponse, HasRequestId, HasVnodes};
+use crate::util::array_wrap;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct VnodeUsagePayload {
+    pub vnode: u64,
+    pub request_id: Uuid,
+}
+
+impl HasRequestId for VnodeUsagePayload {
+    fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+}
+
+impl HasVnodes for VnodeUsagePayload {
+    fn vnodes(&self) -> Vec<u64> {
+        vec![self.vnode]
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct VnodeUsageResponse {
+    pub vnode: u64,
+    /// The number of buckets in the vnode
+    pub buckets: i64,
+    /// The number of objects in the vnode
+    pub objects: i64,
+    /// The sum of the content length of the objects in the vnode
+    pub bytes: i64,
+}
+
+pub(crate) fn decode_msg(
+    value: &Value,
+) -> Result<Vec<VnodeUsagePayload>, SerdeError> {
+    serde_json::from_value::<Vec<VnodeUsagePayload>>(value.clone())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+pub(crate) fn action(
+    msg_id: u32,
+    method: &str,
+    _config: &Config,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+    payload: VnodeUsagePayload,
+    conn: &mut PostgresConnection,
+) -> Result<HandlerResponse, String> {
+    // Make database request
+    do_usage(&payload, conn, metrics, log)
+        .and_then(|resp| {
+            // Handle the successful database response
+            debug!(log, "operation successful");
+            // The VnodeUsageResponse type contains only integers, so the
+            // conversion to JSON is not expected to fail.
+            let value = serde_json::to_value(resp)
+                .expect("failed to serialize VnodeUsageResponse");
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(value));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+        .or_else(|e| {
+            // Handle database error response
+            error!(log, "operation failed"; "error" => e.message());
+
+            // Database errors are returned to as regular Fast messages
+            // to be handled by the calling application
+            let msg_data =
+                FastMessageData::new(method.into(), array_wrap(e.into_fast()));
+            let msg: HandlerResponse =
+                FastMessage::data(msg_id, msg_data).into();
+            Ok(msg)
+        })
+}
+
+fn do_usage(
+    payload: &VnodeUsagePayload,
+    mut conn: &mut PostgresConnection,
+    metrics: &RegisteredMetrics,
+    log: &Logger,
+) -> Result<VnodeUsageResponse, BucketsMdapiError> {
+    let sql = usage_sql(payload.vnode);
+
+    let rows = sql::query(
+        sql::Method::VnodeUsage,
+        &mut conn,
+        sql.as_str(),
+        &[],
+        metrics,
+        log,
+    )
+    .map_err(BucketsMdapiError::from)?;
+
+    // An aggregate query without a GROUP BY clause always returns one row
+    let row = rows.first().ok_or_else(|| {
+        BucketsMdapiError::postgres_error(
+            "vnode usage query returned no rows".to_string(),
+        )
+    })?;
+    Ok(VnodeUsageResponse {
+        vnode: payload.vnode,
+        buckets: row.get("buckets"),
+        objects: row.get("objects"),
+        bytes: row.get("bytes"),
+    })
+}
+
+fn usage_sql(vnode: u64) -> String {
+    let schema = ["manta_bucket_", &vnode.to_string()].concat();
+    [
+        "SELECT (SELECT count(*) FROM ",
+        &schema,
+        ".manta_bucket) AS buckets, \
+         count(*) AS objects, \
+         COALESCE(sum(content_length), 0)::int8 AS bytes \
+         FROM ",
+        &schema,
+        ".manta_bucket_object",
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+
+    impl Arbitrary for VnodeUsagePayload {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            VnodeUsagePayload {
+                vnode: u64::arbitrary(g),
+                request_id: Uuid::new_v4(),
+            }
+        }
+    }
+
+    impl Arbitrary for VnodeUsageResponse {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            VnodeUsageResponse {
+                vnode: u64::arbitrary(g),
+                buckets: i64::arbitrary(g),
+                objects: i64::arbitrary(g),
+                bytes: i64::arbitrary(g),
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_vnode_usage_payload_roundtrip(msg: VnodeUsagePayload) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(usage_str) => {
+                    let decode_result: Result<VnodeUsagePayload, _> =
+                        serde_json::from_str(&usage_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+
+    quickcheck! {
+        fn prop_vnode_usage_response_roundtrip(
+            msg: VnodeUsageResponse
+        ) -> bool {
+            match serde_json::to_string(&msg) {
+                Ok(usage_str) => {
+                    let decode_result: Result<VnodeUsageResponse, _> =
+                        serde_json::from_str(&usage_str);
+                    match decode_result {
+                        Ok(decoded_msg) => decoded_msg == msg,
+                        Err(_) => false
+                    }
+                },
+                Err(_) => false
+            }
+        }
+    }
+}
@@ -2,31 +2,49 @@
 // Copyright 2023 MNX Cloud, Inc.
 
 use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
 use std::net::{IpAddr, Ipv4Addr};
 use std::path::Path;
 use std::process::Command;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
+use prometheus::core::Collector;
 use serde_json::json;
-use slog::{error, info, o, Drain, Level, LevelFilter, Logger};
+use slog::{
+    error, info, o, Drain, Key, Level, LevelFilter, Logger, OwnedKVList,
+    Record, Serializer, KV,
+};
 use url::Url;
 use uuid::Uuid;
 
 use cueball::connection_pool::types::ConnectionPoolOptions;
 use cueball::connection_pool::ConnectionPool;
-use cueball_postgres_connection::{
-    PostgresConnection, PostgresConnectionConfig, TlsConfig,
-};
+use cueball_postgres_connection::{PostgresConnectionConfig, TlsConfig};
 use cueball_static_resolver::StaticIpResolver;
 use fast_rpc::protocol::{FastMessage, FastMessageData};
 
+use buckets_mdapi::admin;
+use buckets_mdapi::admission;
+use buckets_mdapi::batch;
 use buckets_mdapi::bucket;
+use buckets_mdapi::cache;
+use buckets_mdapi::clock;
 use buckets_mdapi::conditional;
+use buckets_mdapi::connection::AgedConnection;
 use buckets_mdapi::error::{BucketsMdapiError, BucketsMdapiWrappedError};
 use buckets_mdapi::gc;
+use buckets_mdapi::health;
+use buckets_mdapi::limiter;
 use buckets_mdapi::metrics;
 use buckets_mdapi::object;
+use buckets_mdapi::orphan;
+use buckets_mdapi::replica;
+use buckets_mdapi::selftest;
+use buckets_mdapi::sql;
+use buckets_mdapi::types;
 use buckets_mdapi::util;
+use buckets_mdapi::vnode;
 use utils::{config, schema};
 
 // This test suite requires PostgreSQL and pg_tmp
@@ -46,6 +64,13 @@ fn verify_rpc_handlers() {
 
     let metrics_config = config::ConfigMetrics::default();
     let metrics = metrics::register_metrics(&metrics_config);
+    let mdapi_config = config::Config::default();
+    let write_limiter = limiter::WriteLimiter::new(None, &metrics);
+    let byte_budget = limiter::ByteBudget::new(None, &metrics);
+    let admission_control = admission::AdmissionControl::new(
+        &config::ConfigAdmission::default(),
+        &metrics,
+    );
 
     ////////////////////////////////////////////////////////////////////////////
     // Check for pg_tmp on the system
@@ -96,7 +121,8 @@ fn verify_rpc_handlers() {
         tls_config: TlsConfig::disable(),
     };
 
-    let connection_creator = PostgresConnection::connection_creator(pg_config);
+    let connection_creator =
+        AgedConnection::connection_creator(pg_config, None);
     let pool_opts = ConnectionPoolOptions {
         max_connections: Some(5),
         claim_timeout: None,
@@ -111,6 +137,62 @@ fn verify_rpc_handlers() {
 
     let pool = ConnectionPool::new(pool_opts, resolver, connection_creator);
 
+    // Reads are routed to a replica pool connected to the same database, so
+    // that the routing of requests is visible in the replica metrics only
+    let replica_pool_opts = ConnectionPoolOptions {
+        max_connections: Some(5),
+        claim_timeout: None,
+        log: Some(log.clone()),
+        rebalancer_action_delay: None,
+        decoherence_interval: None,
+        connection_check_interval: None,
+    };
+    let replica_pg_config = PostgresConnectionConfig {
+        user: Some(user.into()),
+        password: None,
+        host: None,
+        port: Some(pg_port),
+        database: Some(pg_db.into()),
+        application_name: Some(application_name.into()),
+        tls_config: TlsConfig::disable(),
+    };
+    let replica_pool = ConnectionPool::new(
+        replica_pool_opts,
+        StaticIpResolver::new(vec![primary_backend]),
+        AgedConnection::connection_creator(replica_pg_config, None),
+    );
+    let replica = replica::ReadReplica::new(
+        Some(replica_pool),
+        &mdapi_config.replica,
+        &metrics,
+    );
+
+    // Health checks are served by a pool of a single connection to the same
+    // database, kept apart from the main pool
+    let health_pool_opts = ConnectionPoolOptions {
+        max_connections: Some(1),
+        claim_timeout: None,
+        log: Some(log.clone()),
+        rebalancer_action_delay: None,
+        decoherence_interval: None,
+        connection_check_interval: None,
+    };
+    let health_pg_config = PostgresConnectionConfig {
+        user: Some(user.into()),
+        password: None,
+        host: None,
+        port: Some(pg_port),
+        database: Some(pg_db.into()),
+        application_name: Some(application_name.into()),
+        tls_config: TlsConfig::disable(),
+    };
+    let health_pool = ConnectionPool::new(
+        health_pool_opts,
+        StaticIpResolver::new(vec![primary_backend]),
+        AgedConnection::connection_creator(health_pg_config, None),
+    );
+    let health = health::HealthCheck::new(Some(health_pool));
+
     ////////////////////////////////////////////////////////////////////////////
     // Setup the vnode schemas
     //
@@ -128,6 +210,7 @@ fn verify_rpc_handlers() {
     let config = config::ConfigDatabase {
         port: pg_port,
         database: pg_db.to_owned(),
+        max_concurrent_migrations: Some(1),
         ..Default::default()
     };
 
@@ -168,8 +251,14 @@ fn verify_rpc_handlers() {
         FastMessageData::new("getbucket".into(), get_bucket_json);
     let get_bucket_fast_msg =
         FastMessage::data(msg_id, get_bucket_fast_msg_data);
-    let mut get_bucket_result =
-        util::handle_msg(&get_bucket_fast_msg, &pool, &metrics, &log);
+    let mut get_bucket_result = util::handle_msg(
+        &get_bucket_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(get_bucket_result.is_ok());
     let get_bucket_response = get_bucket_result.unwrap();
@@ -202,6 +291,7 @@ fn verify_rpc_handlers() {
         name: bucket.clone(),
         vnode: 0,
         request_id,
+        max_total_bytes: None,
     };
 
     let create_bucket_json =
@@ -210,8 +300,14 @@ fn verify_rpc_handlers() {
         FastMessageData::new("createbucket".into(), create_bucket_json);
     let create_bucket_fast_msg =
         FastMessage::data(msg_id, create_bucket_fast_msg_data);
-    let mut create_bucket_result =
-        util::handle_msg(&create_bucket_fast_msg, &pool, &metrics, &log);
+    let mut create_bucket_result = util::handle_msg(
+        &create_bucket_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(create_bucket_result.is_ok());
     let create_bucket_response = create_bucket_result.unwrap();
@@ -223,8 +319,14 @@ fn verify_rpc_handlers() {
     assert_eq!(create_bucket_response_result.unwrap().name, bucket);
 
     // Read bucket again and make sure the resonse is returned successfully
-    get_bucket_result =
-        util::handle_msg(&get_bucket_fast_msg, &pool, &metrics, &log);
+    get_bucket_result = util::handle_msg(
+        &get_bucket_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(get_bucket_result.is_ok());
     let get_bucket_response = get_bucket_result.unwrap();
@@ -237,8 +339,14 @@ fn verify_rpc_handlers() {
 
     // Try to create same bucket again and verify a BucketAlreadyExists error is
     // returned
-    create_bucket_result =
-        util::handle_msg(&create_bucket_fast_msg, &pool, &metrics, &log);
+    create_bucket_result = util::handle_msg(
+        &create_bucket_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(create_bucket_result.is_ok());
     let create_bucket_response = create_bucket_result.unwrap();
@@ -259,6 +367,7 @@ fn verify_rpc_handlers() {
         name: bucket.clone(),
         vnode: 0,
         request_id,
+        max_objects: None,
     };
     let delete_bucket_json =
         serde_json::to_value(vec![delete_bucket_payload]).unwrap();
@@ -266,8 +375,14 @@ fn verify_rpc_handlers() {
         FastMessageData::new("deletebucket".into(), delete_bucket_json);
     let delete_bucket_fast_msg =
         FastMessage::data(msg_id, delete_bucket_fast_msg_data);
-    let mut delete_bucket_result =
-        util::handle_msg(&delete_bucket_fast_msg, &pool, &metrics, &log);
+    let mut delete_bucket_result = util::handle_msg(
+        &delete_bucket_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(delete_bucket_result.is_ok());
     let delete_bucket_response = delete_bucket_result.unwrap();
@@ -279,8 +394,14 @@ fn verify_rpc_handlers() {
     assert_eq!(delete_bucket_response_result.unwrap(), 1);
 
     // Read bucket again and verify it's gone
-    get_bucket_result =
-        util::handle_msg(&get_bucket_fast_msg, &pool, &metrics, &log);
+    get_bucket_result = util::handle_msg(
+        &get_bucket_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(get_bucket_result.is_ok());
     let get_bucket_response = get_bucket_result.unwrap();
@@ -295,8 +416,14 @@ fn verify_rpc_handlers() {
     );
 
     // Attempt to delete a nonexistent bucket and verify an error is returned
-    delete_bucket_result =
-        util::handle_msg(&delete_bucket_fast_msg, &pool, &metrics, &log);
+    delete_bucket_result = util::handle_msg(
+        &delete_bucket_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(delete_bucket_result.is_ok());
     let delete_bucket_response = delete_bucket_result.unwrap();
@@ -321,6 +448,10 @@ fn verify_rpc_handlers() {
         vnode: 1,
         request_id,
         conditions,
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
     };
 
     let get_object_json =
@@ -329,8 +460,14 @@ fn verify_rpc_handlers() {
         FastMessageData::new("getobject".into(), get_object_json);
     let get_object_fast_msg =
         FastMessage::data(msg_id, get_object_fast_msg_data);
-    let mut get_object_result =
-        util::handle_msg(&get_object_fast_msg, &pool, &metrics, &log);
+    let mut get_object_result = util::handle_msg(
+        &get_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(get_object_result.is_ok());
     let get_object_response = get_object_result.unwrap();
@@ -369,6 +506,10 @@ fn verify_rpc_handlers() {
         properties: None,
         request_id,
         conditions,
+        indexed_metadata: None,
+        known_etag: None,
+        report_changed: false,
+        update_mode: object::update::UpdateMode::Replace,
     };
 
     let update_object_json =
@@ -377,8 +518,14 @@ fn verify_rpc_handlers() {
         FastMessageData::new("updateobject".into(), update_object_json);
     let update_object_fast_msg =
         FastMessage::data(msg_id, update_object_fast_msg_data);
-    let mut update_object_result =
-        util::handle_msg(&update_object_fast_msg, &pool, &metrics, &log);
+    let mut update_object_result = util::handle_msg(
+        &update_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(update_object_result.is_ok());
     let mut update_object_response = update_object_result.unwrap();
@@ -420,6 +567,9 @@ fn verify_rpc_handlers() {
         properties: None,
         request_id,
         conditions,
+        bucket_vnode: None,
+        indexed_metadata: HashMap::new(),
+        dry_run: false,
     };
 
     let create_object_json =
@@ -428,8 +578,14 @@ fn verify_rpc_handlers() {
         FastMessageData::new("createobject".into(), create_object_json);
     let create_object_fast_msg =
         FastMessage::data(msg_id, create_object_fast_msg_data);
-    let create_object_result =
-        util::handle_msg(&create_object_fast_msg, &pool, &metrics, &log);
+    let create_object_result = util::handle_msg(
+        &create_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(create_object_result.is_ok());
     let create_object_response = create_object_result.unwrap();
@@ -475,6 +631,9 @@ fn verify_rpc_handlers() {
         properties: None,
         request_id,
         conditions,
+        bucket_vnode: None,
+        indexed_metadata: HashMap::new(),
+        dry_run: false,
     };
 
     let create_object_json =
@@ -483,8 +642,14 @@ fn verify_rpc_handlers() {
         FastMessageData::new("createobject".into(), create_object_json);
     let create_object_fast_msg =
         FastMessage::data(msg_id, create_object_fast_msg_data);
-    let create_object_result =
-        util::handle_msg(&create_object_fast_msg, &pool, &metrics, &log);
+    let create_object_result = util::handle_msg(
+        &create_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(create_object_result.is_ok());
     let create_object_response = create_object_result.unwrap();
@@ -523,6 +688,9 @@ fn verify_rpc_handlers() {
         properties: None,
         request_id,
         conditions,
+        bucket_vnode: None,
+        indexed_metadata: HashMap::new(),
+        dry_run: false,
     };
 
     let create_object_json =
@@ -531,8 +699,14 @@ fn verify_rpc_handlers() {
         FastMessageData::new("createobject".into(), create_object_json);
     let create_object_fast_msg =
         FastMessage::data(msg_id, create_object_fast_msg_data);
-    let create_object_result =
-        util::handle_msg(&create_object_fast_msg, &pool, &metrics, &log);
+    let create_object_result = util::handle_msg(
+        &create_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(create_object_result.is_ok());
     let create_object_response = create_object_result.unwrap();
@@ -552,8 +726,14 @@ fn verify_rpc_handlers() {
     );
 
     // Read object again and verify a successful response is returned
-    get_object_result =
-        util::handle_msg(&get_object_fast_msg, &pool, &metrics, &log);
+    get_object_result = util::handle_msg(
+        &get_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(get_object_result.is_ok());
     let get_object_response = get_object_result.unwrap();
@@ -567,8 +747,14 @@ fn verify_rpc_handlers() {
     assert_eq!(&get_object_unwrapped_result.content_type, "text/plain");
 
     // Update the object's metadata and verify it is successful
-    update_object_result =
-        util::handle_msg(&update_object_fast_msg, &pool, &metrics, &log);
+    update_object_result = util::handle_msg(
+        &update_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(update_object_result.is_ok());
     update_object_response = update_object_result.unwrap();
@@ -582,8 +768,14 @@ fn verify_rpc_handlers() {
     assert_eq!(&update_object_unwrapped_result.content_type, "text/html");
 
     // Read object again and verify the metadata update
-    get_object_result =
-        util::handle_msg(&get_object_fast_msg, &pool, &metrics, &log);
+    get_object_result = util::handle_msg(
+        &get_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
     assert!(get_object_result.is_ok());
     let get_object_response = get_object_result.unwrap();
     assert_eq!(get_object_response.len(), 1);
@@ -610,6 +802,10 @@ fn verify_rpc_handlers() {
         vnode: 1,
         request_id,
         conditions,
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
     };
 
     let get_object_json =
@@ -618,8 +814,14 @@ fn verify_rpc_handlers() {
         FastMessageData::new("getobject".into(), get_object_json);
     let get_object_fast_msg =
         FastMessage::data(msg_id, get_object_fast_msg_data);
-    get_object_result =
-        util::handle_msg(&get_object_fast_msg, &pool, &metrics, &log);
+    get_object_result = util::handle_msg(
+        &get_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(get_object_result.is_ok());
     let get_object_response = get_object_result.unwrap();
@@ -648,6 +850,10 @@ fn verify_rpc_handlers() {
         vnode: 1,
         request_id,
         conditions,
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
     };
 
     let get_object_json =
@@ -656,8 +862,14 @@ fn verify_rpc_handlers() {
         FastMessageData::new("getobject".into(), get_object_json);
     let get_object_fast_msg =
         FastMessage::data(msg_id, get_object_fast_msg_data);
-    get_object_result =
-        util::handle_msg(&get_object_fast_msg, &pool, &metrics, &log);
+    get_object_result = util::handle_msg(
+        &get_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(get_object_result.is_ok());
     let get_object_response = get_object_result.unwrap();
@@ -676,19 +888,136 @@ fn verify_rpc_handlers() {
         ),
     );
 
+    // Try to delete the object with "if-match: wrongETag" and verify the
+    // request fails without removing the object
+    let wrong_etag_delete_json =
+        serde_json::to_value(vec![&get_object_payload]).unwrap();
+    let wrong_etag_delete_fast_msg = FastMessage::data(
+        msg_id,
+        FastMessageData::new("deleteobject".into(), wrong_etag_delete_json),
+    );
+    let wrong_etag_delete_result = util::handle_msg(
+        &wrong_etag_delete_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+
+    assert!(wrong_etag_delete_result.is_ok());
+    let wrong_etag_delete_response = wrong_etag_delete_result.unwrap();
+    assert_eq!(wrong_etag_delete_response.len(), 1);
+
+    let wrong_etag_delete_response_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(wrong_etag_delete_response[0].data.d[0].clone());
+    assert!(wrong_etag_delete_response_result.is_ok());
+    assert_eq!(
+        wrong_etag_delete_response_result.unwrap(),
+        BucketsMdapiWrappedError::new(
+            BucketsMdapiError::PreconditionFailedError(format!(
+                "if-match '\"{}\"' didn't match etag '{}'",
+                if_match_etag, object_id
+            ))
+        ),
+    );
+
+    let unconditional_get_json =
+        serde_json::to_value(vec![object::GetObjectPayload {
+            conditions: Default::default(),
+            ..get_object_payload.clone()
+        }])
+        .unwrap();
+    let unconditional_get_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("getobject".into(), unconditional_get_json),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    let unconditional_get_response: Result<object::ObjectResponse, _> =
+        serde_json::from_value(
+            unconditional_get_result.unwrap()[0].data.d[0].clone(),
+        );
+    assert!(unconditional_get_response.is_ok());
+    assert_eq!(unconditional_get_response.unwrap().id, object_id);
+
     // Delete object
 
     // The get and delete object args are the same so we can reuse
     // get_object_json here.  Just lets empty the conditions first.
     get_object_payload.conditions = Default::default();
+
+    // Try to delete the object only if it is larger than 1000 bytes and
+    // verify the request fails without removing the object
+    get_object_payload.if_content_length = Some(
+        serde_json::from_value::<conditional::ContentLengthCondition>(json!({
+            "op": "gt",
+            "value": 1000,
+        }))
+        .unwrap(),
+    );
+    let conditional_delete_json =
+        serde_json::to_value(vec![&get_object_payload]).unwrap();
+    let conditional_delete_fast_msg = FastMessage::data(
+        msg_id,
+        FastMessageData::new("deleteobject".into(), conditional_delete_json),
+    );
+    let conditional_delete_result = util::handle_msg(
+        &conditional_delete_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+
+    assert!(conditional_delete_result.is_ok());
+    let conditional_delete_response = conditional_delete_result.unwrap();
+    assert_eq!(conditional_delete_response.len(), 1);
+
+    let conditional_delete_response_result: Result<
+        BucketsMdapiWrappedError,
+        _,
+    > = serde_json::from_value(
+        conditional_delete_response[0].data.d[0].clone(),
+    );
+    assert!(conditional_delete_response_result.is_ok());
+    assert_eq!(
+        conditional_delete_response_result.unwrap(),
+        BucketsMdapiWrappedError::new(
+            BucketsMdapiError::PreconditionFailedError(
+                "content_length 5 is not gt 1000".into()
+            )
+        ),
+    );
+
+    // Now delete the object only if it is smaller than 1000 bytes, which it is
+    get_object_payload.if_content_length = Some(
+        serde_json::from_value::<conditional::ContentLengthCondition>(json!({
+            "op": "lt",
+            "value": 1000,
+        }))
+        .unwrap(),
+    );
     let delete_object_json =
         serde_json::to_value(vec![get_object_payload]).unwrap();
     let delete_object_fast_msg_data =
         FastMessageData::new("deleteobject".into(), delete_object_json);
     let delete_object_fast_msg =
         FastMessage::data(msg_id, delete_object_fast_msg_data);
-    let mut delete_object_result =
-        util::handle_msg(&delete_object_fast_msg, &pool, &metrics, &log);
+    let mut delete_object_result = util::handle_msg(
+        &delete_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(delete_object_result.is_ok());
     let delete_object_response = delete_object_result.unwrap();
@@ -704,10 +1033,21 @@ fn verify_rpc_handlers() {
     assert_eq!(&delete_object_response[0].owner, &owner_id);
     assert_eq!(&delete_object_response[0].bucket_id, &bucket_id);
     assert_eq!(&delete_object_response[0].name, &object);
+    assert!(!delete_object_response[0].sharks.is_empty());
+    assert_eq!(
+        delete_object_response[0].sharks.len() as i32,
+        delete_object_response[0].shark_count
+    );
 
     // Read object again and verify it is not found
-    get_object_result =
-        util::handle_msg(&get_object_fast_msg, &pool, &metrics, &log);
+    get_object_result = util::handle_msg(
+        &get_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(get_object_result.is_ok());
     let get_object_response = get_object_result.unwrap();
@@ -722,8 +1062,14 @@ fn verify_rpc_handlers() {
     );
 
     // Delete the object again and verify it is not found
-    delete_object_result =
-        util::handle_msg(&delete_object_fast_msg, &pool, &metrics, &log);
+    delete_object_result = util::handle_msg(
+        &delete_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(delete_object_result.is_ok());
     let delete_object_response = delete_object_result.unwrap();
@@ -745,7 +1091,10 @@ fn verify_rpc_handlers() {
         prefix: Some("testbucket".into()),
         limit: 1000,
         marker: None,
+        marker_created: None,
         request_id,
+        count_only: false,
+        order_by: Default::default(),
     };
 
     let list_buckets_json =
@@ -754,16 +1103,28 @@ fn verify_rpc_handlers() {
         FastMessageData::new("listbuckets".into(), list_buckets_json);
     let list_buckets_fast_msg =
         FastMessage::data(msg_id, list_buckets_fast_msg_data);
-    let mut list_buckets_result =
-        util::handle_msg(&list_buckets_fast_msg, &pool, &metrics, &log);
+    let mut list_buckets_result = util::handle_msg(
+        &list_buckets_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(list_buckets_result.is_ok());
     let list_buckets_response = list_buckets_result.unwrap();
     assert_eq!(list_buckets_response.len(), 0);
 
     // Create a bucket and list buckets again
-    create_bucket_result =
-        util::handle_msg(&create_bucket_fast_msg, &pool, &metrics, &log);
+    create_bucket_result = util::handle_msg(
+        &create_bucket_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(create_bucket_result.is_ok());
     let create_bucket_response = create_bucket_result.unwrap();
@@ -774,8 +1135,14 @@ fn verify_rpc_handlers() {
     assert!(create_bucket_response_result.is_ok());
     assert_eq!(create_bucket_response_result.unwrap().name, bucket);
 
-    list_buckets_result =
-        util::handle_msg(&list_buckets_fast_msg, &pool, &metrics, &log);
+    list_buckets_result = util::handle_msg(
+        &list_buckets_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(list_buckets_result.is_ok());
     let list_buckets_response = list_buckets_result.unwrap();
@@ -790,7 +1157,20 @@ fn verify_rpc_handlers() {
         prefix: Some("testobject".into()),
         limit: 1000,
         marker: None,
+        marker_created: None,
         request_id,
+        index_filter: None,
+        parent_prefix: None,
+        include_properties: false,
+        report_status: false,
+        modified_after: None,
+        modified_before: None,
+        skip_sharks: false,
+        delimiter: None,
+        count_only: false,
+        order_by: Default::default(),
+        continuation_token: None,
+        report_continuation: false,
     };
 
     let list_objects_json =
@@ -799,16 +1179,28 @@ fn verify_rpc_handlers() {
         FastMessageData::new("listobjects".into(), list_objects_json);
     let list_objects_fast_msg =
         FastMessage::data(msg_id, list_objects_fast_msg_data);
-    let mut list_objects_result =
-        util::handle_msg(&list_objects_fast_msg, &pool, &metrics, &log);
+    let mut list_objects_result = util::handle_msg(
+        &list_objects_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(list_objects_result.is_ok());
     let list_objects_response = list_objects_result.unwrap();
     assert_eq!(list_objects_response.len(), 0);
 
     // Create an object and list objects again
-    let create_object_result =
-        util::handle_msg(&create_object_fast_msg, &pool, &metrics, &log);
+    let create_object_result = util::handle_msg(
+        &create_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(create_object_result.is_ok());
     let create_object_response = create_object_result.unwrap();
@@ -819,8 +1211,14 @@ fn verify_rpc_handlers() {
     assert!(create_object_response_result.is_ok());
     assert_eq!(create_object_response_result.unwrap().name, object);
 
-    list_objects_result =
-        util::handle_msg(&list_objects_fast_msg, &pool, &metrics, &log);
+    list_objects_result = util::handle_msg(
+        &list_objects_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(list_objects_result.is_ok());
     let list_objects_response = list_objects_result.unwrap();
@@ -830,7 +1228,12 @@ fn verify_rpc_handlers() {
 
     // First request a batch of garbage
     let request_id = Uuid::new_v4();
-    let mut get_garbage_payload = gc::get::GetGarbagePayload { request_id };
+    let mut get_garbage_payload = gc::get::GetGarbagePayload {
+        request_id,
+        vnode: None,
+        after: None,
+        limit: 1000,
+    };
 
     let mut get_garbage_json =
         serde_json::to_value(vec![&get_garbage_payload]).unwrap();
@@ -838,8 +1241,14 @@ fn verify_rpc_handlers() {
         FastMessageData::new("getgcbatch".into(), get_garbage_json);
     let mut get_garbage_fast_msg =
         FastMessage::data(msg_id, get_garbage_fast_msg_data);
-    let mut get_garbage_result =
-        util::handle_msg(&get_garbage_fast_msg, &pool, &metrics, &log);
+    let mut get_garbage_result = util::handle_msg(
+        &get_garbage_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(get_garbage_result.is_ok());
     let mut get_garbage_response = get_garbage_result.unwrap();
@@ -861,15 +1270,26 @@ fn verify_rpc_handlers() {
     // one from the previous request.
 
     let request_id = Uuid::new_v4();
-    get_garbage_payload = gc::get::GetGarbagePayload { request_id };
+    get_garbage_payload = gc::get::GetGarbagePayload {
+        request_id,
+        vnode: None,
+        after: None,
+        limit: 1000,
+    };
 
     get_garbage_json =
         serde_json::to_value(vec![&get_garbage_payload]).unwrap();
     get_garbage_fast_msg_data =
         FastMessageData::new("getgcbatch".into(), get_garbage_json);
     get_garbage_fast_msg = FastMessage::data(msg_id, get_garbage_fast_msg_data);
-    get_garbage_result =
-        util::handle_msg(&get_garbage_fast_msg, &pool, &metrics, &log);
+    get_garbage_result = util::handle_msg(
+        &get_garbage_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(get_garbage_result.is_ok());
     get_garbage_response = get_garbage_result.unwrap();
@@ -885,12 +1305,90 @@ fn verify_rpc_handlers() {
 
     assert_eq!(batch_id, get_garbage_unwrapped_result.batch_id.unwrap());
 
+    // A limit beyond the largest gc batch is rejected, while the largest limit
+    // and a limit that truncates the batch are served. A truncated batch keeps
+    // the batch id of the whole batch.
+    for (limit, valid) in
+        &[(0, false), (10001, false), (10000, true), (1, true)]
+    {
+        let limited_garbage_payload = gc::get::GetGarbagePayload {
+            request_id: Uuid::new_v4(),
+            vnode: None,
+            after: None,
+            limit: *limit,
+        };
+        let limited_garbage_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new(
+                    "getgcbatch".into(),
+                    serde_json::to_value(vec![&limited_garbage_payload])
+                        .unwrap(),
+                ),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(limited_garbage_result.is_ok());
+        let limited_garbage_value =
+            limited_garbage_result.unwrap()[0].data.d[0].clone();
+
+        if *valid {
+            let limited_garbage: gc::get::GetGarbageResponse =
+                serde_json::from_value(limited_garbage_value).unwrap();
+            assert_eq!(limited_garbage.batch_id, Some(batch_id));
+            assert!(!limited_garbage.garbage.is_empty());
+            assert!(limited_garbage.garbage.len() as u64 <= *limit);
+        } else {
+            let limited_garbage_error: BucketsMdapiError =
+                serde_json::from_value(limited_garbage_value).unwrap();
+            match limited_garbage_error {
+                BucketsMdapiError::LimitConstraintError(_) => (),
+                e => panic!("unexpected getgcbatch limit error {:?}", e),
+            }
+        }
+    }
+
+    // The deleted object rows of the batch, which a successful deletegcbatch
+    // request removes from the vnode tables themselves rather than only from
+    // the materialized view
+    let garbage_ids: Vec<Uuid> = get_garbage_unwrapped_result
+        .garbage
+        .iter()
+        .map(|garbage| garbage.id)
+        .collect();
+    let count_garbage_rows = || -> i64 {
+        let mut conn = pool.claim().unwrap();
+        (0..2)
+            .map(|vnode| {
+                let rows = conn
+                    .query(
+                        format!(
+                            "SELECT count(*) FROM manta_bucket_{}.\
+                             manta_bucket_deleted_object WHERE id = ANY($1)",
+                            vnode
+                        )
+                        .as_str(),
+                        &[&garbage_ids],
+                    )
+                    .unwrap();
+                rows[0].get::<_, i64>(0)
+            })
+            .sum()
+    };
+    assert_eq!(count_garbage_rows(), garbage_ids.len() as i64);
+
     // Indicate that the batch of garbage is processed and request for it to be
     // deleted, but use a batch_id that does not match the id of the current
     // batch.
     let mut delete_garbage_payload = gc::delete::DeleteGarbagePayload {
-        batch_id: Uuid::new_v4(),
+        batch_id: Some(Uuid::new_v4()),
         request_id,
+        vnode: None,
+        ids: vec![],
     };
     let mut delete_garbage_json =
         serde_json::to_value(vec![delete_garbage_payload]).unwrap();
@@ -898,8 +1396,14 @@ fn verify_rpc_handlers() {
         FastMessageData::new("deletegcbatch".into(), delete_garbage_json);
     let mut delete_garbage_fast_msg =
         FastMessage::data(msg_id, delete_garbage_fast_msg_data);
-    let mut delete_garbage_result =
-        util::handle_msg(&delete_garbage_fast_msg, &pool, &metrics, &log);
+    let mut delete_garbage_result = util::handle_msg(
+        &delete_garbage_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(delete_garbage_result.is_ok());
     let mut delete_garbage_responses = delete_garbage_result.unwrap();
@@ -911,13 +1415,18 @@ fn verify_rpc_handlers() {
     let mut delete_garbage_response = delete_garbage_response_result.unwrap();
     assert_eq!(&delete_garbage_response, "ok");
 
+    // A mismatched batch id removes no rows
+    assert_eq!(count_garbage_rows(), garbage_ids.len() as i64);
+
     // Now indicate that the batch of garbage is processed and request for it to
     // be deleted. This also verifies the logic that ensures the previous
     // request to delete the gc batch using an invalid batch id does not
     // actually result in the garbage batch being removed.
     delete_garbage_payload = gc::delete::DeleteGarbagePayload {
-        batch_id,
+        batch_id: Some(batch_id),
         request_id,
+        vnode: None,
+        ids: vec![],
     };
     delete_garbage_json =
         serde_json::to_value(vec![delete_garbage_payload]).unwrap();
@@ -925,8 +1434,14 @@ fn verify_rpc_handlers() {
         FastMessageData::new("deletegcbatch".into(), delete_garbage_json);
     delete_garbage_fast_msg =
         FastMessage::data(msg_id, delete_garbage_fast_msg_data);
-    delete_garbage_result =
-        util::handle_msg(&delete_garbage_fast_msg, &pool, &metrics, &log);
+    delete_garbage_result = util::handle_msg(
+        &delete_garbage_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(delete_garbage_result.is_ok());
     // let mut delete_garbage_responses = delete_garbage_result.unwrap();
@@ -941,10 +1456,19 @@ fn verify_rpc_handlers() {
     delete_garbage_response = delete_garbage_response_result.unwrap();
     assert_eq!(&delete_garbage_response, "ok");
 
+    // The matching batch id removes every row of the batch
+    assert_eq!(count_garbage_rows(), 0);
+
     // Request another batch of garbage and this time it should return an empty
     // list and a NULL batch_id
-    get_garbage_result =
-        util::handle_msg(&get_garbage_fast_msg, &pool, &metrics, &log);
+    get_garbage_result = util::handle_msg(
+        &get_garbage_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
 
     assert!(get_garbage_result.is_ok());
     let get_garbage_response = get_garbage_result.unwrap();
@@ -958,4 +1482,5795 @@ fn verify_rpc_handlers() {
     get_garbage_unwrapped_result = get_garbage_response_result.unwrap();
     assert!(get_garbage_unwrapped_result.batch_id.is_none());
     assert!(get_garbage_unwrapped_result.garbage.is_empty());
+
+    // Exercise the keyset garbage scan that bypasses the materialized view
+
+    let keyset_config = config::Config {
+        gc: config::ConfigGc {
+            use_materialized_view: false,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    // Delete the object to produce a garbage record in vnode 1
+    delete_object_result = util::handle_msg(
+        &delete_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_object_result.is_ok());
+
+    get_garbage_payload = gc::get::GetGarbagePayload {
+        request_id,
+        vnode: Some(1),
+        after: None,
+        limit: 1000,
+    };
+    get_garbage_json = serde_json::to_value(vec![get_garbage_payload]).unwrap();
+    get_garbage_fast_msg_data =
+        FastMessageData::new("getgcbatch".into(), get_garbage_json);
+    get_garbage_fast_msg = FastMessage::data(msg_id, get_garbage_fast_msg_data);
+    get_garbage_result = util::handle_msg(
+        &get_garbage_fast_msg,
+        &pool,
+        &keyset_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+
+    assert!(get_garbage_result.is_ok());
+    let get_garbage_response = get_garbage_result.unwrap();
+    assert_eq!(get_garbage_response.len(), 1);
+
+    let keyset_page: gc::get::GetGarbageResponse =
+        serde_json::from_value(get_garbage_response[0].data.d[0].clone())
+            .unwrap();
+    assert!(keyset_page.batch_id.is_none());
+    assert_eq!(keyset_page.garbage.len(), 1);
+    assert_eq!(keyset_page.garbage[0].name, object);
+    assert!(keyset_page.cursor.is_some());
+
+    // Recreate and delete the object again. The new garbage record must be
+    // returned when resuming the scan from the previous cursor.
+    let create_object_result = util::handle_msg(
+        &create_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+    delete_object_result = util::handle_msg(
+        &delete_object_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_object_result.is_ok());
+
+    get_garbage_payload = gc::get::GetGarbagePayload {
+        request_id,
+        vnode: Some(1),
+        after: keyset_page.cursor,
+        limit: 1000,
+    };
+    get_garbage_json = serde_json::to_value(vec![get_garbage_payload]).unwrap();
+    get_garbage_fast_msg_data =
+        FastMessageData::new("getgcbatch".into(), get_garbage_json);
+    get_garbage_fast_msg = FastMessage::data(msg_id, get_garbage_fast_msg_data);
+    get_garbage_result = util::handle_msg(
+        &get_garbage_fast_msg,
+        &pool,
+        &keyset_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+
+    assert!(get_garbage_result.is_ok());
+    let keyset_page: gc::get::GetGarbageResponse = serde_json::from_value(
+        get_garbage_result.unwrap()[0].data.d[0].clone(),
+    )
+    .unwrap();
+    assert_eq!(keyset_page.garbage.len(), 1);
+    assert!(keyset_page.cursor.is_some());
+
+    // Resuming after the last record yields an empty page and no cursor
+    get_garbage_payload = gc::get::GetGarbagePayload {
+        request_id,
+        vnode: Some(1),
+        after: keyset_page.cursor,
+        limit: 1000,
+    };
+    get_garbage_json = serde_json::to_value(vec![get_garbage_payload]).unwrap();
+    get_garbage_fast_msg_data =
+        FastMessageData::new("getgcbatch".into(), get_garbage_json);
+    get_garbage_fast_msg = FastMessage::data(msg_id, get_garbage_fast_msg_data);
+    get_garbage_result = util::handle_msg(
+        &get_garbage_fast_msg,
+        &pool,
+        &keyset_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+
+    assert!(get_garbage_result.is_ok());
+    let keyset_page: gc::get::GetGarbageResponse = serde_json::from_value(
+        get_garbage_result.unwrap()[0].data.d[0].clone(),
+    )
+    .unwrap();
+    assert!(keyset_page.garbage.is_empty());
+    assert!(keyset_page.cursor.is_none());
+
+    // Delete the processed records and verify a new scan finds nothing
+    delete_garbage_payload = gc::delete::DeleteGarbagePayload {
+        batch_id: None,
+        request_id,
+        vnode: Some(1),
+        ids: vec![object_id],
+    };
+    delete_garbage_json =
+        serde_json::to_value(vec![delete_garbage_payload]).unwrap();
+    delete_garbage_fast_msg_data =
+        FastMessageData::new("deletegcbatch".into(), delete_garbage_json);
+    delete_garbage_fast_msg =
+        FastMessage::data(msg_id, delete_garbage_fast_msg_data);
+    delete_garbage_result = util::handle_msg(
+        &delete_garbage_fast_msg,
+        &pool,
+        &keyset_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+
+    assert!(delete_garbage_result.is_ok());
+    delete_garbage_response = serde_json::from_value(
+        delete_garbage_result.unwrap()[0].data.d[0].clone(),
+    )
+    .unwrap();
+    assert_eq!(&delete_garbage_response, "ok");
+
+    get_garbage_payload = gc::get::GetGarbagePayload {
+        request_id,
+        vnode: Some(1),
+        after: None,
+        limit: 1000,
+    };
+    get_garbage_json = serde_json::to_value(vec![get_garbage_payload]).unwrap();
+    get_garbage_fast_msg_data =
+        FastMessageData::new("getgcbatch".into(), get_garbage_json);
+    get_garbage_fast_msg = FastMessage::data(msg_id, get_garbage_fast_msg_data);
+    get_garbage_result = util::handle_msg(
+        &get_garbage_fast_msg,
+        &pool,
+        &keyset_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+
+    assert!(get_garbage_result.is_ok());
+    let keyset_page: gc::get::GetGarbageResponse = serde_json::from_value(
+        get_garbage_result.unwrap()[0].data.d[0].clone(),
+    )
+    .unwrap();
+    assert!(keyset_page.garbage.is_empty());
+
+    // Exercise bucket quota enforcement. The quota bucket's metadata resides
+    // in vnode 0 while its objects are created in vnode 1.
+
+    let quota_config = config::Config {
+        quota: config::ConfigQuota { enabled: true },
+        ..Default::default()
+    };
+
+    let create_bucket_payload = bucket::create::CreateBucketPayload {
+        owner: owner_id,
+        name: "quotabucket".into(),
+        vnode: 0,
+        request_id,
+        max_total_bytes: Some(10),
+    };
+    let create_bucket_json =
+        serde_json::to_value(vec![create_bucket_payload]).unwrap();
+    let create_bucket_fast_msg_data =
+        FastMessageData::new("createbucket".into(), create_bucket_json);
+    let create_bucket_fast_msg =
+        FastMessage::data(msg_id, create_bucket_fast_msg_data);
+    create_bucket_result = util::handle_msg(
+        &create_bucket_fast_msg,
+        &pool,
+        &quota_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+
+    assert!(create_bucket_result.is_ok());
+    let quota_bucket: bucket::BucketResponse = serde_json::from_value(
+        create_bucket_result.unwrap()[0].data.d[0].clone(),
+    )
+    .unwrap();
+
+    let quota_object_fast_msg = |name: &str, content_length: i64| {
+        let payload = object::create::CreateObjectPayload {
+            owner: owner_id,
+            bucket_id: quota_bucket.id,
+            name: name.into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_length,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers: HashMap::new(),
+            sharks: vec![],
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: Some(0),
+            indexed_metadata: HashMap::new(),
+            dry_run: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let msg_data = FastMessageData::new("createobject".into(), json);
+        FastMessage::data(msg_id, msg_data)
+    };
+
+    // Fill the bucket to its quota
+    for name in &["quotaobject1", "quotaobject2"] {
+        let create_object_result = util::handle_msg(
+            &quota_object_fast_msg(name, 5),
+            &pool,
+            &quota_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(create_object_result.is_ok());
+        let create_object_response_result: Result<object::ObjectResponse, _> =
+            serde_json::from_value(
+                create_object_result.unwrap()[0].data.d[0].clone(),
+            );
+        assert!(create_object_response_result.is_ok());
+    }
+
+    // The next create must be rejected
+    let create_object_result = util::handle_msg(
+        &quota_object_fast_msg("quotaobject3", 1),
+        &pool,
+        &quota_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+    let create_object_response_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(
+            create_object_result.unwrap()[0].data.d[0].clone(),
+        );
+    assert!(create_object_response_result.is_ok());
+    assert_eq!(
+        create_object_response_result.unwrap().error.name,
+        "QuotaExceeded"
+    );
+
+    // Deleting an object frees its bytes for use by another create
+    let delete_quota_object_payload = object::GetObjectPayload {
+        owner: owner_id,
+        bucket_id: quota_bucket.id,
+        name: "quotaobject1".into(),
+        vnode: 1,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: Some(0),
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
+    };
+    let delete_quota_object_json =
+        serde_json::to_value(vec![delete_quota_object_payload]).unwrap();
+    let delete_quota_object_fast_msg = FastMessage::data(
+        msg_id,
+        FastMessageData::new("deleteobject".into(), delete_quota_object_json),
+    );
+    delete_object_result = util::handle_msg(
+        &delete_quota_object_fast_msg,
+        &pool,
+        &quota_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_object_result.is_ok());
+
+    let create_object_result = util::handle_msg(
+        &quota_object_fast_msg("quotaobject3", 1),
+        &pool,
+        &quota_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+    let create_object_response_result: Result<object::ObjectResponse, _> =
+        serde_json::from_value(
+            create_object_result.unwrap()[0].data.d[0].clone(),
+        );
+    assert!(create_object_response_result.is_ok());
+
+    // A copy into the bucket is checked against its quota like a create
+    let copy_quota_object_payload = object::copy::CopyObjectPayload {
+        owner: owner_id,
+        source_bucket_id: quota_bucket.id,
+        source_name: "quotaobject2".into(),
+        dest_bucket_id: quota_bucket.id,
+        dest_name: "quotacopy".into(),
+        vnode: 1,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: Some(0),
+    };
+    let copy_quota_object_json =
+        serde_json::to_value(vec![copy_quota_object_payload]).unwrap();
+    let copy_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("copyobject".into(), copy_quota_object_json),
+        ),
+        &pool,
+        &quota_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(copy_object_result.is_ok());
+    let copy_object_response_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(
+            copy_object_result.unwrap()[0].data.d[0].clone(),
+        );
+    assert!(copy_object_response_result.is_ok());
+    assert_eq!(
+        copy_object_response_result.unwrap().error.name,
+        "QuotaExceeded"
+    );
+
+    // A write that does not name the bucket's vnode is rejected rather than
+    // leaving the maintained totals behind
+    let unscoped_object_json =
+        serde_json::to_value(vec![object::create::CreateObjectPayload {
+            owner: owner_id,
+            bucket_id: quota_bucket.id,
+            name: "quotaunscoped".into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_length: 1,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers: HashMap::new(),
+            sharks: vec![],
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            indexed_metadata: HashMap::new(),
+            dry_run: false,
+        }])
+        .unwrap();
+    let create_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("createobject".into(), unscoped_object_json),
+        ),
+        &pool,
+        &quota_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+    let create_object_response_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(
+            create_object_result.unwrap()[0].data.d[0].clone(),
+        );
+    assert!(create_object_response_result.is_ok());
+    assert_eq!(
+        create_object_response_result.unwrap().error.name,
+        "BadRequestError"
+    );
+
+    // Renaming an object over another lowers the totals by the replaced
+    // object, leaving the 5 byte quotaobject2 and the 1 byte quotaobject3
+    let create_object_result = util::handle_msg(
+        &quota_object_fast_msg("quotarename", 1),
+        &pool,
+        &quota_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+
+    let rename_quota_object_payload = object::rename::RenameObjectPayload {
+        owner: owner_id,
+        bucket_id: quota_bucket.id,
+        old_name: "quotarename".into(),
+        new_name: "quotaobject3".into(),
+        vnode: 1,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: Some(0),
+    };
+    let rename_quota_object_json =
+        serde_json::to_value(vec![rename_quota_object_payload]).unwrap();
+    let rename_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "renameobject".into(),
+                rename_quota_object_json,
+            ),
+        ),
+        &pool,
+        &quota_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(rename_object_result.is_ok());
+    let rename_object_response_result: Result<object::ObjectResponse, _> =
+        serde_json::from_value(
+            rename_object_result.unwrap()[0].data.d[0].clone(),
+        );
+    assert!(rename_object_response_result.is_ok());
+
+    let quota_bucket_totals = || {
+        let mut conn = pool.claim().unwrap();
+        let rows = conn
+            .query(
+                "SELECT total_bytes, total_objects \
+                 FROM manta_bucket_0.manta_bucket WHERE id = $1",
+                &[&quota_bucket.id],
+            )
+            .unwrap();
+        let total_bytes: i64 = rows[0].get("total_bytes");
+        let total_objects: i64 = rows[0].get("total_objects");
+        (total_bytes, total_objects)
+    };
+    assert_eq!(quota_bucket_totals(), (6, 2));
+
+    // Recomputing the totals from the stored objects finds the same values
+    let mut conn = pool.claim().unwrap();
+    conn.execute(
+        "UPDATE manta_bucket_0.manta_bucket \
+         SET total_bytes = 0, total_objects = 0 WHERE id = $1",
+        &[&quota_bucket.id],
+    )
+    .unwrap();
+    conn.execute("SELECT recompute_bucket_totals('manta_bucket_0')", &[])
+        .unwrap();
+    drop(conn);
+    assert_eq!(quota_bucket_totals(), (6, 2));
+
+    // Saturate the write transaction limit and verify that writes are rejected
+    // while reads continue to be served
+
+    let saturated_limiter = limiter::WriteLimiter::new(Some(1), &metrics);
+    let write_permit = saturated_limiter.try_acquire();
+    assert!(write_permit.is_some());
+    assert!(saturated_limiter.try_acquire().is_none());
+
+    let create_object_result = util::handle_msg(
+        &quota_object_fast_msg("quotaobject4", 1),
+        &pool,
+        &quota_config,
+        &saturated_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+    let create_object_response_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(
+            create_object_result.unwrap()[0].data.d[0].clone(),
+        );
+    assert!(create_object_response_result.is_ok());
+    assert_eq!(
+        create_object_response_result.unwrap().error.name,
+        "OverloadedError"
+    );
+
+    let get_quota_bucket_payload = bucket::GetBucketPayload {
+        owner: owner_id,
+        name: "quotabucket".into(),
+        vnode: 0,
+        request_id,
+    };
+    let get_quota_bucket_json =
+        serde_json::to_value(vec![get_quota_bucket_payload]).unwrap();
+    let get_quota_bucket_fast_msg = FastMessage::data(
+        msg_id,
+        FastMessageData::new("getbucket".into(), get_quota_bucket_json),
+    );
+    let get_bucket_result = util::handle_msg(
+        &get_quota_bucket_fast_msg,
+        &pool,
+        &mdapi_config,
+        &saturated_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(get_bucket_result.is_ok());
+    let get_bucket_response_result: Result<bucket::BucketResponse, _> =
+        serde_json::from_value(get_bucket_result.unwrap()[0].data.d[0].clone());
+    assert!(get_bucket_response_result.is_ok());
+
+    // Releasing the permit allows writes to proceed again
+    drop(write_permit);
+
+    let create_object_result = util::handle_msg(
+        &quota_object_fast_msg("quotaobject4", 1),
+        &pool,
+        &quota_config,
+        &saturated_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+    let create_object_response_result: Result<object::ObjectResponse, _> =
+        serde_json::from_value(
+            create_object_result.unwrap()[0].data.d[0].clone(),
+        );
+    assert!(create_object_response_result.is_ok());
+
+    // Run the self-test RPC and verify the full cycle reports success
+
+    let selftest_config = config::Config {
+        selftest: config::ConfigSelfTest { enabled: true },
+        ..Default::default()
+    };
+
+    let selftest_payload = selftest::SelfTestPayload {
+        vnode: 1,
+        request_id,
+    };
+    let selftest_json = serde_json::to_value(vec![selftest_payload]).unwrap();
+    let selftest_fast_msg = FastMessage::data(
+        msg_id,
+        FastMessageData::new("selftest".into(), selftest_json),
+    );
+
+    // The RPC is rejected unless it is enabled
+    let selftest_result = util::handle_msg(
+        &selftest_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(selftest_result.is_err());
+
+    let selftest_result = util::handle_msg(
+        &selftest_fast_msg,
+        &pool,
+        &selftest_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(selftest_result.is_ok());
+    let selftest_response: selftest::SelfTestResponse =
+        serde_json::from_value(selftest_result.unwrap()[0].data.d[0].clone())
+            .unwrap();
+    assert!(selftest_response.success);
+    let step_names: Vec<&str> = selftest_response
+        .steps
+        .iter()
+        .map(|step| step.name.as_str())
+        .collect();
+    assert_eq!(
+        step_names,
+        vec!["createobject", "getobject", "deleteobject"]
+    );
+
+    // Create and update an object with auditing enabled and read back its
+    // history
+
+    let audit_config = config::Config {
+        audit: config::ConfigAudit { enabled: true },
+        ..Default::default()
+    };
+    let audit_object_id = Uuid::new_v4();
+
+    let create_object_payload = object::create::CreateObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "auditobject".into(),
+        id: audit_object_id,
+        vnode: 1,
+        content_length: 5,
+        content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+        content_type: "text/plain".into(),
+        headers: HashMap::new(),
+        sharks: vec![],
+        properties: None,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        indexed_metadata: HashMap::new(),
+        dry_run: false,
+    };
+    let create_object_json =
+        serde_json::to_value(vec![create_object_payload]).unwrap();
+    let create_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("createobject".into(), create_object_json),
+        ),
+        &pool,
+        &audit_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+
+    let update_object_payload = object::update::UpdateObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "auditobject".into(),
+        id: audit_object_id,
+        vnode: 1,
+        content_type: "text/html".into(),
+        headers: HashMap::new(),
+        properties: None,
+        request_id,
+        conditions: Default::default(),
+        indexed_metadata: None,
+        known_etag: None,
+        report_changed: false,
+        update_mode: object::update::UpdateMode::Replace,
+    };
+    let update_object_json =
+        serde_json::to_value(vec![update_object_payload]).unwrap();
+    let update_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("updateobject".into(), update_object_json),
+        ),
+        &pool,
+        &audit_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(update_object_result.is_ok());
+
+    let history_payload = object::history::ObjectHistoryPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "auditobject".into(),
+        vnode: 1,
+        limit: 10,
+        marker: None,
+        request_id,
+    };
+    let history_json = serde_json::to_value(vec![history_payload]).unwrap();
+    let history_fast_msg = FastMessage::data(
+        msg_id,
+        FastMessageData::new("getobjecthistory".into(), history_json),
+    );
+
+    // History is unavailable when auditing is disabled
+    let history_result = util::handle_msg(
+        &history_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(history_result.is_ok());
+    let history_response_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(history_result.unwrap()[0].data.d[0].clone());
+    assert_eq!(
+        history_response_result.unwrap(),
+        BucketsMdapiWrappedError::new(BucketsMdapiError::AuditDisabled),
+    );
+
+    let history_result = util::handle_msg(
+        &history_fast_msg,
+        &pool,
+        &audit_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(history_result.is_ok());
+    let history_response = history_result.unwrap();
+    assert_eq!(history_response.len(), 2);
+
+    let history: Vec<object::history::ObjectHistoryEvent> = history_response
+        .iter()
+        .map(|msg| serde_json::from_value(msg.data.d[0].clone()).unwrap())
+        .collect();
+    assert_eq!(history[0].action, "create");
+    assert_eq!(history[1].action, "update");
+    assert!(history.iter().all(|e| e.object_id == audit_object_id));
+    assert!(history[0].seq < history[1].seq);
+
+    // Verify that a getobject miss is counted as not_found rather than as an
+    // error
+
+    let outcome_count = |result: &str| {
+        metrics
+            .fast_request_outcomes
+            .with_label_values(&["getobject", result])
+            .get() as u64
+    };
+    let not_found_before = outcome_count("not_found");
+    let error_before = outcome_count("error");
+
+    let missing_object_payload = object::GetObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "missingobject".into(),
+        vnode: 1,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
+    };
+    let missing_object_json =
+        serde_json::to_value(vec![missing_object_payload]).unwrap();
+    let get_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("getobject".into(), missing_object_json),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(get_object_result.is_ok());
+
+    assert_eq!(outcome_count("not_found"), not_found_before + 1);
+    assert_eq!(outcome_count("error"), error_before);
+
+    // Verify that requests for vnodes beyond the configured maximum are
+    // rejected while those in range are handled
+
+    let vnode_config = config::Config {
+        server: config::ConfigServer {
+            max_vnode: 0,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let vnode_bucket_fast_msg = |vnode: u64| {
+        let payload = bucket::GetBucketPayload {
+            owner: owner_id,
+            name: "quotabucket".into(),
+            vnode,
+            request_id,
+        };
+        FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "getbucket".into(),
+                serde_json::to_value(vec![payload]).unwrap(),
+            ),
+        )
+    };
+
+    let in_range_result = util::handle_msg(
+        &vnode_bucket_fast_msg(0),
+        &pool,
+        &vnode_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(in_range_result.is_ok());
+    let in_range_response = in_range_result.unwrap();
+    assert_eq!(in_range_response.len(), 1);
+    let in_range_bucket: Result<bucket::BucketResponse, _> =
+        serde_json::from_value(in_range_response[0].data.d[0].clone());
+    assert!(in_range_bucket.is_ok());
+
+    let out_of_range_result = util::handle_msg(
+        &vnode_bucket_fast_msg(1),
+        &pool,
+        &vnode_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(out_of_range_result.is_ok());
+    let out_of_range_response = out_of_range_result.unwrap();
+    assert_eq!(out_of_range_response.len(), 1);
+    let out_of_range_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(out_of_range_response[0].data.d[0].clone());
+    assert!(out_of_range_result.is_ok());
+    assert_eq!(
+        out_of_range_result.unwrap(),
+        BucketsMdapiWrappedError::new(BucketsMdapiError::InvalidVnode(
+            "vnode 1 exceeds the maximum vnode 0".into()
+        )),
+    );
+
+    let max_vnode_result = util::handle_msg(
+        &vnode_bucket_fast_msg(u64::max_value()),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(max_vnode_result.is_ok());
+    let max_vnode_response = max_vnode_result.unwrap();
+    let max_vnode_error: BucketsMdapiWrappedError =
+        serde_json::from_value(max_vnode_response[0].data.d[0].clone())
+            .unwrap();
+    assert_eq!(max_vnode_error.error.name, "InvalidVnode");
+
+    // Verify getgcbatch errors use the wrapped error envelope by default and
+    // the legacy unwrapped envelope when configured to
+
+    let legacy_error_config = config::Config {
+        gc: config::ConfigGc {
+            use_materialized_view: false,
+            legacy_error_envelope: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    // A keyset scan without a vnode fails
+    let vnodeless_garbage_payload = gc::get::GetGarbagePayload {
+        request_id,
+        vnode: None,
+        after: None,
+        limit: 1000,
+    };
+    let vnodeless_garbage_fast_msg = FastMessage::data(
+        msg_id,
+        FastMessageData::new(
+            "getgcbatch".into(),
+            serde_json::to_value(vec![vnodeless_garbage_payload]).unwrap(),
+        ),
+    );
+    let vnodeless_garbage_message = "a vnode is required to read garbage when \
+                                     the materialized view is disabled";
+
+    let wrapped_result = util::handle_msg(
+        &vnodeless_garbage_fast_msg,
+        &pool,
+        &keyset_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(wrapped_result.is_ok());
+    let wrapped_response = wrapped_result.unwrap();
+    assert_eq!(wrapped_response.len(), 1);
+    assert_eq!(
+        wrapped_response[0].data.d[0],
+        json!({
+            "error": {
+                "name": "PostgresError",
+                "message": vnodeless_garbage_message
+            }
+        })
+    );
+
+    let legacy_result = util::handle_msg(
+        &vnodeless_garbage_fast_msg,
+        &pool,
+        &legacy_error_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(legacy_result.is_ok());
+    let legacy_response = legacy_result.unwrap();
+    assert_eq!(legacy_response.len(), 1);
+    assert_eq!(
+        legacy_response[0].data.d[0],
+        json!({
+            "name": "PostgresError",
+            "message": vnodeless_garbage_message
+        })
+    );
+
+    // Verify that garbage within the gc grace period is neither returned by a
+    // keyset scan nor removed by deletegcbatch
+
+    let grace_config = config::Config {
+        gc: config::ConfigGc {
+            use_materialized_view: false,
+            grace_period_seconds: 3600,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let grace_object_id = Uuid::new_v4();
+
+    let create_object_payload = object::create::CreateObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "graceobject".into(),
+        id: grace_object_id,
+        vnode: 1,
+        content_length: 5,
+        content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+        content_type: "text/plain".into(),
+        headers: HashMap::new(),
+        sharks: vec![],
+        properties: None,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        indexed_metadata: HashMap::new(),
+        dry_run: false,
+    };
+    let create_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "createobject".into(),
+                serde_json::to_value(vec![create_object_payload]).unwrap(),
+            ),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+
+    let delete_object_payload = object::GetObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "graceobject".into(),
+        vnode: 1,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
+    };
+    let delete_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "deleteobject".into(),
+                serde_json::to_value(vec![delete_object_payload]).unwrap(),
+            ),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_object_result.is_ok());
+
+    let grace_garbage_fast_msg = FastMessage::data(
+        msg_id,
+        FastMessageData::new(
+            "getgcbatch".into(),
+            serde_json::to_value(vec![gc::get::GetGarbagePayload {
+                request_id,
+                vnode: Some(1),
+                after: None,
+                limit: 1000,
+            }])
+            .unwrap(),
+        ),
+    );
+    let scan_garbage_ids = |gc_config: &config::Config| {
+        let get_garbage_result = util::handle_msg(
+            &grace_garbage_fast_msg,
+            &pool,
+            gc_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(get_garbage_result.is_ok());
+        let page: gc::get::GetGarbageResponse = serde_json::from_value(
+            get_garbage_result.unwrap()[0].data.d[0].clone(),
+        )
+        .unwrap();
+        page.garbage.iter().map(|g| g.id).collect::<Vec<Uuid>>()
+    };
+
+    assert!(!scan_garbage_ids(&grace_config).contains(&grace_object_id));
+    assert!(scan_garbage_ids(&keyset_config).contains(&grace_object_id));
+
+    let grace_delete_fast_msg = FastMessage::data(
+        msg_id,
+        FastMessageData::new(
+            "deletegcbatch".into(),
+            serde_json::to_value(vec![gc::delete::DeleteGarbagePayload {
+                batch_id: None,
+                request_id,
+                vnode: Some(1),
+                ids: vec![grace_object_id],
+            }])
+            .unwrap(),
+        ),
+    );
+    let delete_garbage_result = util::handle_msg(
+        &grace_delete_fast_msg,
+        &pool,
+        &grace_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_garbage_result.is_ok());
+
+    // The record survived the delete made within the grace period
+    assert!(scan_garbage_ids(&keyset_config).contains(&grace_object_id));
+
+    // Without a grace period the record is removed
+    let delete_garbage_result = util::handle_msg(
+        &grace_delete_fast_msg,
+        &pool,
+        &keyset_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_garbage_result.is_ok());
+    assert!(!scan_garbage_ids(&keyset_config).contains(&grace_object_id));
+
+    // Verify listobjects equality and range filters on indexed metadata
+
+    let indexed_object_fast_msg =
+        |name: &str, indexed_metadata: serde_json::Value| {
+            let payload = object::create::CreateObjectPayload {
+                owner: owner_id,
+                bucket_id,
+                name: name.into(),
+                id: Uuid::new_v4(),
+                vnode: 1,
+                content_length: 5,
+                content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+                content_type: "text/plain".into(),
+                headers: HashMap::new(),
+                sharks: vec![],
+                properties: None,
+                request_id,
+                conditions: Default::default(),
+                bucket_vnode: None,
+                indexed_metadata: serde_json::from_value(indexed_metadata)
+                    .unwrap(),
+                dry_run: false,
+            };
+            FastMessage::data(
+                msg_id,
+                FastMessageData::new(
+                    "createobject".into(),
+                    serde_json::to_value(vec![payload]).unwrap(),
+                ),
+            )
+        };
+
+    for (name, indexed_metadata) in vec![
+        ("indexed-a", json!({ "color": "red", "size": 5 })),
+        ("indexed-b", json!({ "color": "blue", "size": 50 })),
+        ("indexed-c", json!({})),
+    ] {
+        let create_object_result = util::handle_msg(
+            &indexed_object_fast_msg(name, indexed_metadata),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(create_object_result.is_ok());
+        let create_object_response: Result<object::ObjectResponse, _> =
+            serde_json::from_value(
+                create_object_result.unwrap()[0].data.d[0].clone(),
+            );
+        assert!(create_object_response.is_ok());
+    }
+
+    let filtered_names = |index_filter: serde_json::Value| {
+        let payload = object::list::ListObjectsPayload {
+            owner: owner_id,
+            bucket_id,
+            vnode: 1,
+            prefix: Some("indexed-".into()),
+            limit: 1000,
+            marker: None,
+            marker_created: None,
+            request_id,
+            index_filter: Some(serde_json::from_value(index_filter).unwrap()),
+            parent_prefix: None,
+            include_properties: false,
+            report_status: false,
+            modified_after: None,
+            modified_before: None,
+            skip_sharks: false,
+            delimiter: None,
+            count_only: false,
+            order_by: Default::default(),
+            continuation_token: None,
+            report_continuation: false,
+        };
+        let list_objects_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new(
+                    "listobjects".into(),
+                    serde_json::to_value(vec![payload]).unwrap(),
+                ),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(list_objects_result.is_ok());
+        list_objects_result
+            .unwrap()
+            .iter()
+            .map(|msg| {
+                let obj: object::ObjectResponse =
+                    serde_json::from_value(msg.data.d[0].clone()).unwrap();
+                obj.name
+            })
+            .collect::<Vec<String>>()
+    };
+
+    assert_eq!(
+        filtered_names(json!({ "key": "color", "op": "eq", "value": "red" })),
+        vec!["indexed-a"]
+    );
+    assert_eq!(
+        filtered_names(json!({ "key": "size", "op": "gt", "value": 10 })),
+        vec!["indexed-b"]
+    );
+    assert_eq!(
+        filtered_names(json!({ "key": "size", "op": "le", "value": 50 })),
+        vec!["indexed-a", "indexed-b"]
+    );
+    // A value of a different type than the stored values matches nothing
+    assert!(
+        filtered_names(json!({ "key": "size", "op": "eq", "value": "5" }))
+            .is_empty()
+    );
+
+    // Updating the indexed metadata of an object replaces its entries
+    let update_object_payload = object::update::UpdateObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "indexed-a".into(),
+        id: Uuid::new_v4(),
+        vnode: 1,
+        content_type: "text/plain".into(),
+        headers: HashMap::new(),
+        properties: None,
+        request_id,
+        conditions: Default::default(),
+        indexed_metadata: Some(
+            serde_json::from_value(json!({ "color": "blue" })).unwrap(),
+        ),
+        known_etag: None,
+        report_changed: false,
+        update_mode: object::update::UpdateMode::Replace,
+    };
+    let update_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "updateobject".into(),
+                serde_json::to_value(vec![update_object_payload]).unwrap(),
+            ),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(update_object_result.is_ok());
+
+    assert_eq!(
+        filtered_names(json!({ "key": "color", "op": "eq", "value": "blue" })),
+        vec!["indexed-a", "indexed-b"]
+    );
+    assert!(
+        filtered_names(json!({ "key": "size", "op": "lt", "value": 10 }))
+            .is_empty()
+    );
+
+    // An object recreated without indexed metadata no longer matches the
+    // filters its previous incarnation did
+    let delete_indexed_objects = |names: &[&str]| {
+        for name in names {
+            let delete_object_payload = object::GetObjectPayload {
+                owner: owner_id,
+                bucket_id,
+                name: (*name).into(),
+                vnode: 1,
+                request_id,
+                conditions: Default::default(),
+                bucket_vnode: None,
+                if_content_length: None,
+                include_properties: false,
+                unpin_first: false,
+            };
+            let delete_object_result = util::handle_msg(
+                &FastMessage::data(
+                    msg_id,
+                    FastMessageData::new(
+                        "deleteobject".into(),
+                        serde_json::to_value(vec![delete_object_payload])
+                            .unwrap(),
+                    ),
+                ),
+                &pool,
+                &mdapi_config,
+                &write_limiter,
+                &metrics,
+                &log,
+            );
+            assert!(delete_object_result.is_ok());
+        }
+    };
+
+    delete_indexed_objects(&["indexed-a", "indexed-b"]);
+
+    let create_object_result = util::handle_msg(
+        &indexed_object_fast_msg("indexed-b", json!({})),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+
+    assert!(filtered_names(
+        json!({ "key": "color", "op": "eq", "value": "blue" })
+    )
+    .is_empty());
+
+    delete_indexed_objects(&["indexed-b", "indexed-c"]);
+
+    // Verify createobject rejects sharks in datacenters that are not known
+    // when a datacenter allowlist is configured
+
+    let placement_config = config::Config {
+        placement: config::ConfigPlacement {
+            known_datacenters: Some(vec!["us-east-1".into()]),
+        },
+        ..Default::default()
+    };
+
+    let placement_object_fast_msg = |datacenter: &str| {
+        let payload = object::create::CreateObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "placementobject".into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_length: 5,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers: HashMap::new(),
+            sharks: vec![object::StorageNodeIdentifier {
+                datacenter: datacenter.into(),
+                manta_storage_id: "1.stor.joyent.us".into(),
+            }],
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            indexed_metadata: HashMap::new(),
+            dry_run: false,
+        };
+        FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "createobject".into(),
+                serde_json::to_value(vec![payload]).unwrap(),
+            ),
+        )
+    };
+
+    let unknown_dc_result = util::handle_msg(
+        &placement_object_fast_msg("us-west-1"),
+        &pool,
+        &placement_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(unknown_dc_result.is_ok());
+    let unknown_dc_response: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(unknown_dc_result.unwrap()[0].data.d[0].clone());
+    assert_eq!(
+        unknown_dc_response.unwrap(),
+        BucketsMdapiWrappedError::new(BucketsMdapiError::UnknownDatacenter(
+            "us-west-1".into()
+        )),
+    );
+
+    let known_dc_result = util::handle_msg(
+        &placement_object_fast_msg("us-east-1"),
+        &pool,
+        &placement_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(known_dc_result.is_ok());
+    let known_dc_response: Result<object::ObjectResponse, _> =
+        serde_json::from_value(known_dc_result.unwrap()[0].data.d[0].clone());
+    assert!(known_dc_response.is_ok());
+    assert_eq!(known_dc_response.unwrap().sharks[0].datacenter, "us-east-1");
+
+    let delete_object_payload = object::GetObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "placementobject".into(),
+        vnode: 1,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
+    };
+    let delete_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "deleteobject".into(),
+                serde_json::to_value(vec![delete_object_payload]).unwrap(),
+            ),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_object_result.is_ok());
+
+    // Verify batchupdateobjects merges header patches into the objects that
+    // exist and reports the objects that do not as failed items
+
+    for name in &["batch-a", "batch-b"] {
+        let mut headers = HashMap::new();
+        let _ = headers.insert("m-brand".into(), Some("old".into()));
+        let _ = headers.insert("m-legacy".into(), Some("yes".into()));
+        let payload = object::create::CreateObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: (*name).into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_length: 5,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers,
+            sharks: vec![],
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            indexed_metadata: HashMap::new(),
+            dry_run: false,
+        };
+        let create_object_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new(
+                    "createobject".into(),
+                    serde_json::to_value(vec![payload]).unwrap(),
+                ),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(create_object_result.is_ok());
+    }
+
+    let batch_update_fast_msg = |names: Vec<String>| {
+        let updates = names
+            .into_iter()
+            .map(|name| {
+                let mut headers = HashMap::new();
+                let _ = headers.insert("m-brand".into(), Some("new".into()));
+                let _ = headers.insert("m-legacy".into(), None);
+                object::batch_update::HeaderUpdate { name, headers }
+            })
+            .collect();
+        let payload = object::batch_update::BatchUpdateObjectsPayload {
+            owner: owner_id,
+            bucket_id,
+            vnode: 1,
+            request_id,
+            updates,
+        };
+        FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "batchupdateobjects".into(),
+                serde_json::to_value(vec![payload]).unwrap(),
+            ),
+        )
+    };
+
+    let batch_update_result = util::handle_msg(
+        &batch_update_fast_msg(vec![
+            "batch-a".into(),
+            "batch-missing".into(),
+            "batch-b".into(),
+        ]),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(batch_update_result.is_ok());
+    let batch_update_response: batch::BatchResponse<object::ObjectResponse> =
+        serde_json::from_value(
+            batch_update_result.unwrap()[0].data.d[0].clone(),
+        )
+        .unwrap();
+    assert!(batch_update_response.is_partial());
+    let updated_names: Vec<&str> = batch_update_response
+        .succeeded
+        .iter()
+        .map(|o| o.name.as_str())
+        .collect();
+    assert_eq!(updated_names, vec!["batch-a", "batch-b"]);
+    for updated in &batch_update_response.succeeded {
+        assert_eq!(updated.headers.len(), 1);
+        assert_eq!(updated.headers["m-brand"], Some("new".into()));
+    }
+    assert_eq!(batch_update_response.failed.len(), 1);
+    assert_eq!(batch_update_response.failed[0].index, 1);
+    assert_eq!(batch_update_response.failed[0].error.name, "ObjectNotFound");
+
+    // A batch larger than the maximum batch size is rejected
+    let oversized_names = (0..=object::batch_update::MAX_BATCH_UPDATE_SIZE)
+        .map(|i| format!("batch-{}", i))
+        .collect();
+    let oversized_result = util::handle_msg(
+        &batch_update_fast_msg(oversized_names),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(oversized_result.is_ok());
+    let oversized_response: BucketsMdapiError =
+        serde_json::from_value(oversized_result.unwrap()[0].data.d[0].clone())
+            .unwrap();
+    match oversized_response {
+        BucketsMdapiError::LimitConstraintError(_) => (),
+        e => panic!("unexpected batch size error {:?}", e),
+    }
+
+    for name in &["batch-a", "batch-b"] {
+        let delete_object_payload = object::GetObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: (*name).into(),
+            vnode: 1,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            if_content_length: None,
+            include_properties: false,
+            unpin_first: false,
+        };
+        let delete_object_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new(
+                    "deleteobject".into(),
+                    serde_json::to_value(vec![delete_object_payload]).unwrap(),
+                ),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(delete_object_result.is_ok());
+    }
+
+    // Verify a connection older than the maximum connection age is
+    // reestablished when it is claimed
+
+    let recycle_config = config::Config {
+        cueball: config::ConfigCueball {
+            max_connection_age_secs: Some(0),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let recycled_before = metrics.connections_recycled.get();
+
+    let recycle_bucket_payload = bucket::GetBucketPayload {
+        owner: owner_id,
+        name: "quotabucket".into(),
+        vnode: 0,
+        request_id,
+    };
+    let recycle_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "getbucket".into(),
+                serde_json::to_value(vec![recycle_bucket_payload]).unwrap(),
+            ),
+        ),
+        &pool,
+        &recycle_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(recycle_result.is_ok());
+    let recycle_response: Result<bucket::BucketResponse, _> =
+        serde_json::from_value(recycle_result.unwrap()[0].data.d[0].clone());
+    assert!(recycle_response.is_ok());
+    assert!(metrics.connections_recycled.get() > recycled_before);
+
+    // Connections are not recycled when no maximum age is configured
+    let recycled_before = metrics.connections_recycled.get();
+    let get_bucket_payload = bucket::GetBucketPayload {
+        owner: owner_id,
+        name: "quotabucket".into(),
+        vnode: 0,
+        request_id,
+    };
+    let get_bucket_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "getbucket".into(),
+                serde_json::to_value(vec![get_bucket_payload]).unwrap(),
+            ),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(get_bucket_result.is_ok());
+    assert_eq!(metrics.connections_recycled.get(), recycled_before);
+
+    // Verify listobjects lists only the objects directly under a parent
+    // prefix, both from the stored parent_prefix column and by comparing names
+
+    let listing_config = config::Config {
+        listing: config::ConfigListing {
+            parent_prefix: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let prefix_bucket_id = Uuid::new_v4();
+    let prefix_object_names =
+        vec!["top", "dir/a", "dir/b", "dir/sub/c", "dirx/d"];
+
+    for name in &prefix_object_names {
+        let payload = object::create::CreateObjectPayload {
+            owner: owner_id,
+            bucket_id: prefix_bucket_id,
+            name: (*name).into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_length: 5,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers: HashMap::new(),
+            sharks: vec![],
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            indexed_metadata: HashMap::new(),
+            dry_run: false,
+        };
+        let create_object_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new(
+                    "createobject".into(),
+                    serde_json::to_value(vec![payload]).unwrap(),
+                ),
+            ),
+            &pool,
+            &listing_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(create_object_result.is_ok());
+        let create_object_response: Result<object::ObjectResponse, _> =
+            serde_json::from_value(
+                create_object_result.unwrap()[0].data.d[0].clone(),
+            );
+        assert!(create_object_response.is_ok());
+    }
+
+    let parent_prefix_names =
+        |parent_prefix: &str, list_config: &config::Config| {
+            let payload = object::list::ListObjectsPayload {
+                owner: owner_id,
+                bucket_id: prefix_bucket_id,
+                vnode: 1,
+                prefix: None,
+                limit: 1000,
+                marker: None,
+                marker_created: None,
+                request_id,
+                index_filter: None,
+                parent_prefix: Some(parent_prefix.into()),
+                include_properties: false,
+                report_status: false,
+                modified_after: None,
+                modified_before: None,
+                skip_sharks: false,
+                delimiter: None,
+                count_only: false,
+                order_by: Default::default(),
+                continuation_token: None,
+                report_continuation: false,
+            };
+            let list_objects_result = util::handle_msg(
+                &FastMessage::data(
+                    msg_id,
+                    FastMessageData::new(
+                        "listobjects".into(),
+                        serde_json::to_value(vec![payload]).unwrap(),
+                    ),
+                ),
+                &pool,
+                list_config,
+                &write_limiter,
+                &metrics,
+                &log,
+            );
+            assert!(list_objects_result.is_ok());
+            list_objects_result
+                .unwrap()
+                .iter()
+                .map(|msg| {
+                    let object: object::ObjectResponse =
+                        serde_json::from_value(msg.data.d[0].clone()).unwrap();
+                    object.name
+                })
+                .collect::<Vec<String>>()
+        };
+
+    for list_config in &[&listing_config, &mdapi_config] {
+        assert_eq!(
+            parent_prefix_names("dir/", list_config),
+            ["dir/a", "dir/b"]
+        );
+        assert_eq!(parent_prefix_names("dir/sub/", list_config), ["dir/sub/c"]);
+        assert_eq!(parent_prefix_names("", list_config), ["top"]);
+        assert!(parent_prefix_names("missing/", list_config).is_empty());
+    }
+
+    for name in &prefix_object_names {
+        let delete_object_payload = object::GetObjectPayload {
+            owner: owner_id,
+            bucket_id: prefix_bucket_id,
+            name: (*name).into(),
+            vnode: 1,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            if_content_length: None,
+            include_properties: false,
+            unpin_first: false,
+        };
+        let delete_object_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new(
+                    "deleteobject".into(),
+                    serde_json::to_value(vec![delete_object_payload]).unwrap(),
+                ),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(delete_object_result.is_ok());
+    }
+
+    // Verify a successful getobject records the age of the object read
+
+    let age_reads = || {
+        metrics.object_age_at_read.collect()[0].get_metric()[0]
+            .get_histogram()
+            .get_sample_count()
+    };
+    let age_object_payload = object::create::CreateObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "ageobject".into(),
+        id: Uuid::new_v4(),
+        vnode: 1,
+        content_length: 5,
+        content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+        content_type: "text/plain".into(),
+        headers: HashMap::new(),
+        sharks: vec![],
+        properties: None,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        indexed_metadata: HashMap::new(),
+        dry_run: false,
+    };
+    let create_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "createobject".into(),
+                serde_json::to_value(vec![age_object_payload]).unwrap(),
+            ),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+
+    let age_reads_before = age_reads();
+    let age_get_payload = |name: &str| object::GetObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: name.into(),
+        vnode: 1,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
+    };
+    let age_get_fast_msg = |method: &str, name: &str| {
+        FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                method.into(),
+                serde_json::to_value(vec![age_get_payload(name)]).unwrap(),
+            ),
+        )
+    };
+
+    let get_object_result = util::handle_msg(
+        &age_get_fast_msg("getobject", "ageobject"),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(get_object_result.is_ok());
+    let get_object_response: Result<object::ObjectResponse, _> =
+        serde_json::from_value(get_object_result.unwrap()[0].data.d[0].clone());
+    assert!(get_object_response.is_ok());
+    assert_eq!(age_reads(), age_reads_before + 1);
+
+    // A getobject for a missing object is not observed
+    let get_object_result = util::handle_msg(
+        &age_get_fast_msg("getobject", "agemissing"),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(get_object_result.is_ok());
+    assert_eq!(age_reads(), age_reads_before + 1);
+
+    let delete_object_result = util::handle_msg(
+        &age_get_fast_msg("deleteobject", "ageobject"),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_object_result.is_ok());
+    // Conditional bucket deletion relies on the object count maintained while
+    // quotas are enabled
+
+    let create_bucket_payload = bucket::create::CreateBucketPayload {
+        owner: owner_id,
+        name: "countbucket".into(),
+        vnode: 0,
+        request_id,
+        max_total_bytes: None,
+    };
+    let create_bucket_json =
+        serde_json::to_value(vec![create_bucket_payload]).unwrap();
+    let create_bucket_fast_msg = FastMessage::data(
+        msg_id,
+        FastMessageData::new("createbucket".into(), create_bucket_json),
+    );
+    let create_bucket_result = util::handle_msg(
+        &create_bucket_fast_msg,
+        &pool,
+        &quota_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_bucket_result.is_ok());
+    let count_bucket: bucket::BucketResponse = serde_json::from_value(
+        create_bucket_result.unwrap()[0].data.d[0].clone(),
+    )
+    .unwrap();
+
+    for name in &["countobject1", "countobject2"] {
+        let payload = object::create::CreateObjectPayload {
+            owner: owner_id,
+            bucket_id: count_bucket.id,
+            name: (*name).into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_length: 5,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers: HashMap::new(),
+            sharks: vec![],
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: Some(0),
+            indexed_metadata: HashMap::new(),
+            dry_run: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let create_object_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("createobject".into(), json),
+            ),
+            &pool,
+            &quota_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(create_object_result.is_ok());
+        let create_object_response_result: Result<object::ObjectResponse, _> =
+            serde_json::from_value(
+                create_object_result.unwrap()[0].data.d[0].clone(),
+            );
+        assert!(create_object_response_result.is_ok());
+    }
+
+    let delete_count_bucket_fast_msg = |max_objects: Option<i64>| {
+        let payload = bucket::DeleteBucketPayload {
+            owner: owner_id,
+            name: "countbucket".into(),
+            vnode: 0,
+            request_id,
+            max_objects,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        FastMessage::data(
+            msg_id,
+            FastMessageData::new("deletebucket".into(), json),
+        )
+    };
+
+    // The threshold is refused when quotas are disabled, as the object count
+    // is then not maintained
+    let delete_bucket_result = util::handle_msg(
+        &delete_count_bucket_fast_msg(Some(2)),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_bucket_result.is_ok());
+    let delete_bucket_response_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(
+            delete_bucket_result.unwrap()[0].data.d[0].clone(),
+        );
+    assert!(delete_bucket_response_result.is_ok());
+    assert_eq!(
+        delete_bucket_response_result.unwrap().error.name,
+        "BadRequestError"
+    );
+
+    // A bucket holding more objects than the threshold is not deleted
+    let delete_bucket_result = util::handle_msg(
+        &delete_count_bucket_fast_msg(Some(1)),
+        &pool,
+        &quota_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_bucket_result.is_ok());
+    let delete_bucket_response_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(
+            delete_bucket_result.unwrap()[0].data.d[0].clone(),
+        );
+    assert!(delete_bucket_response_result.is_ok());
+    assert_eq!(
+        delete_bucket_response_result.unwrap().error.name,
+        "BucketNotEmpty"
+    );
+
+    // A bucket holding no more objects than the threshold is deleted
+    let delete_bucket_result = util::handle_msg(
+        &delete_count_bucket_fast_msg(Some(2)),
+        &pool,
+        &quota_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_bucket_result.is_ok());
+    let delete_bucket_response_result: Result<u64, _> = serde_json::from_value(
+        delete_bucket_result.unwrap()[0].data.d[0].clone(),
+    );
+    assert!(delete_bucket_response_result.is_ok());
+    assert_eq!(delete_bucket_response_result.unwrap(), 1);
+
+    // Force the clock of an object's previous write ahead of the database
+    // clock and verify an update still moves its modified time forward
+
+    let monotonic_config = config::Config {
+        timestamps: config::ConfigTimestamps {
+            monotonic_modified: true,
+        },
+        ..Default::default()
+    };
+
+    let create_monotonic_payload = object::create::CreateObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "monotonicobject".into(),
+        id: Uuid::new_v4(),
+        vnode: 1,
+        content_length: 5,
+        content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+        content_type: "text/plain".into(),
+        headers: HashMap::new(),
+        sharks: vec![],
+        properties: None,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        indexed_metadata: HashMap::new(),
+        dry_run: false,
+    };
+    let create_monotonic_json =
+        serde_json::to_value(vec![create_monotonic_payload]).unwrap();
+    let create_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("createobject".into(), create_monotonic_json),
+        ),
+        &pool,
+        &monotonic_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+    let monotonic_object: object::ObjectResponse = serde_json::from_value(
+        create_object_result.unwrap()[0].data.d[0].clone(),
+    )
+    .unwrap();
+
+    let future_modified: buckets_mdapi::types::Timestamptz = {
+        let mut conn = pool.claim().unwrap();
+        let rows = conn
+            .query(
+                "UPDATE manta_bucket_1.manta_bucket_object \
+                 SET modified = current_timestamp + interval '1 day' \
+                 WHERE owner = $1 AND bucket_id = $2 AND name = $3 \
+                 RETURNING modified",
+                &[&owner_id, &bucket_id, &monotonic_object.name],
+            )
+            .unwrap();
+        rows[0].get("modified")
+    };
+
+    let update_monotonic_object = |config: &config::Config| {
+        let payload = object::update::UpdateObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "monotonicobject".into(),
+            id: monotonic_object.id,
+            vnode: 1,
+            content_type: "text/html".into(),
+            headers: HashMap::new(),
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            indexed_metadata: None,
+            known_etag: None,
+            report_changed: false,
+            update_mode: object::update::UpdateMode::Replace,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("updateobject".into(), json),
+            ),
+            &pool,
+            config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        let updated: object::ObjectResponse =
+            serde_json::from_value(result.unwrap()[0].data.d[0].clone())
+                .unwrap();
+        updated.modified
+    };
+
+    let modified = update_monotonic_object(&monotonic_config);
+    assert!(modified > future_modified);
+    assert_eq!(
+        modified - future_modified,
+        chrono::Duration::microseconds(1)
+    );
+
+    // Without the option the update takes the database time, which would
+    // regress
+    let modified = update_monotonic_object(&mdapi_config);
+    assert!(modified < future_modified);
+
+    // Store oversized properties outside of the object row and verify they
+    // are only read when requested
+
+    let properties_config = config::Config {
+        properties: config::ConfigProperties {
+            max_inline_bytes: Some(64),
+        },
+        ..Default::default()
+    };
+    let small_properties = json!({ "color": "blue" });
+    let large_properties = json!({ "notes": "x".repeat(128) });
+
+    for (name, properties) in &[
+        ("propsobject-small", &small_properties),
+        ("propsobject-large", &large_properties),
+    ] {
+        let payload = object::create::CreateObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: (*name).into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_length: 5,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers: HashMap::new(),
+            sharks: vec![],
+            properties: Some((*properties).clone()),
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            indexed_metadata: HashMap::new(),
+            dry_run: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let create_object_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("createobject".into(), json),
+            ),
+            &pool,
+            &properties_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(create_object_result.is_ok());
+        let create_object_response_result: Result<object::ObjectResponse, _> =
+            serde_json::from_value(
+                create_object_result.unwrap()[0].data.d[0].clone(),
+            );
+        assert!(create_object_response_result.is_ok());
+    }
+
+    let get_properties = |name: &str, include_properties: bool| {
+        let payload = object::GetObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: name.into(),
+            vnode: 1,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            if_content_length: None,
+            include_properties,
+            unpin_first: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("getobject".into(), json),
+            ),
+            &pool,
+            &properties_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        let object: object::ObjectResponse =
+            serde_json::from_value(result.unwrap()[0].data.d[0].clone())
+                .unwrap();
+        object.properties
+    };
+
+    // Small properties are stored inline and always returned
+    assert_eq!(
+        get_properties("propsobject-small", false),
+        Some(small_properties.clone())
+    );
+    assert_eq!(
+        get_properties("propsobject-small", true),
+        Some(small_properties.clone())
+    );
+
+    // Large properties are stored externally and only returned on request
+    assert_eq!(get_properties("propsobject-large", false), None);
+    assert_eq!(
+        get_properties("propsobject-large", true),
+        Some(large_properties.clone())
+    );
+
+    let list_properties_payload = object::list::ListObjectsPayload {
+        owner: owner_id,
+        bucket_id,
+        vnode: 1,
+        prefix: Some("propsobject-".into()),
+        limit: 10,
+        marker: None,
+        marker_created: None,
+        request_id,
+        index_filter: None,
+        parent_prefix: None,
+        include_properties: true,
+        report_status: false,
+        modified_after: None,
+        modified_before: None,
+        skip_sharks: false,
+        delimiter: None,
+        count_only: false,
+        order_by: Default::default(),
+        continuation_token: None,
+        report_continuation: false,
+    };
+    let list_properties_json =
+        serde_json::to_value(vec![list_properties_payload]).unwrap();
+    let list_objects_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("listobjects".into(), list_properties_json),
+        ),
+        &pool,
+        &properties_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(list_objects_result.is_ok());
+    let listed: Vec<object::ObjectResponse> = list_objects_result
+        .unwrap()
+        .iter()
+        .map(|msg| serde_json::from_value(msg.data.d[0].clone()).unwrap())
+        .collect();
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0].properties, Some(large_properties.clone()));
+    assert_eq!(listed[1].properties, Some(small_properties.clone()));
+
+    // Deleting the object removes its external properties
+    let delete_properties_payload = object::GetObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "propsobject-large".into(),
+        vnode: 1,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
+    };
+    let delete_properties_json =
+        serde_json::to_value(vec![delete_properties_payload]).unwrap();
+    let delete_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("deleteobject".into(), delete_properties_json),
+        ),
+        &pool,
+        &properties_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_object_result.is_ok());
+
+    let external_properties_count: i64 = {
+        let mut conn = pool.claim().unwrap();
+        let rows = conn
+            .query(
+                "SELECT count(*) AS count \
+                 FROM manta_bucket_1.manta_bucket_object_properties",
+                &[],
+            )
+            .unwrap();
+        rows[0].get("count")
+    };
+    assert_eq!(external_properties_count, 0);
+
+    // Page through the objects of the quota bucket on a single connection and
+    // verify that list requests are throttled once the consecutive list limit
+    // is reached
+
+    let mut list_pacer =
+        limiter::ListPacer::new(Some(3), std::time::Duration::from_secs(60));
+    let object_cache =
+        cache::ObjectCache::new(&mdapi_config.object_cache, &metrics);
+    let list_page_fast_msg = |marker: Option<String>| {
+        let payload = object::list::ListObjectsPayload {
+            owner: owner_id,
+            bucket_id: quota_bucket.id,
+            vnode: 1,
+            prefix: None,
+            limit: 1,
+            marker,
+            marker_created: None,
+            request_id,
+            index_filter: None,
+            parent_prefix: None,
+            include_properties: false,
+            report_status: false,
+            modified_after: None,
+            modified_before: None,
+            skip_sharks: false,
+            delimiter: None,
+            count_only: false,
+            order_by: Default::default(),
+            continuation_token: None,
+            report_continuation: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        FastMessage::data(
+            msg_id,
+            FastMessageData::new("listobjects".into(), json),
+        )
+    };
+
+    let mut marker = None;
+    for _ in 0..3 {
+        let list_result = util::handle_connection_msg(
+            &list_page_fast_msg(marker.clone()),
+            &pool,
+            &replica,
+            &health,
+            &mdapi_config,
+            &write_limiter,
+            &byte_budget,
+            &admission_control,
+            &mut list_pacer,
+            &object_cache,
+            &metrics,
+            &log,
+        );
+        assert!(list_result.is_ok());
+        let page: object::ObjectResponse =
+            serde_json::from_value(list_result.unwrap().0[0].data.d[0].clone())
+                .unwrap();
+        marker = Some(page.name);
+    }
+
+    let list_result = util::handle_connection_msg(
+        &list_page_fast_msg(marker),
+        &pool,
+        &replica,
+        &health,
+        &mdapi_config,
+        &write_limiter,
+        &byte_budget,
+        &admission_control,
+        &mut list_pacer,
+        &object_cache,
+        &metrics,
+        &log,
+    );
+    assert!(list_result.is_ok());
+    let list_response_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(list_result.unwrap().0[0].data.d[0].clone());
+    assert!(list_response_result.is_ok());
+    assert_eq!(list_response_result.unwrap().error.name, "OverloadedError");
+
+    // Object responses echo the vnode they were served from
+    let get_served_payload = object::GetObjectPayload {
+        owner: owner_id,
+        bucket_id: quota_bucket.id,
+        name: "quotaobject2".into(),
+        vnode: 1,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
+    };
+    let get_served_json =
+        serde_json::to_value(vec![get_served_payload]).unwrap();
+    let get_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("getobject".into(), get_served_json),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(get_object_result.is_ok());
+    let served_object: object::ObjectResponse =
+        serde_json::from_value(get_object_result.unwrap()[0].data.d[0].clone())
+            .unwrap();
+    assert_eq!(served_object.vnode, 1);
+
+    // The orphan scan tombstones the objects of a deleted bucket
+    let create_bucket_payload = bucket::create::CreateBucketPayload {
+        owner: owner_id,
+        name: "orphanbucket".into(),
+        vnode: 0,
+        request_id,
+        max_total_bytes: None,
+    };
+    let create_bucket_json =
+        serde_json::to_value(vec![create_bucket_payload]).unwrap();
+    let create_bucket_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("createbucket".into(), create_bucket_json),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_bucket_result.is_ok());
+    let orphan_bucket: bucket::BucketResponse = serde_json::from_value(
+        create_bucket_result.unwrap()[0].data.d[0].clone(),
+    )
+    .unwrap();
+
+    let create_orphan_payload = object::create::CreateObjectPayload {
+        owner: owner_id,
+        bucket_id: orphan_bucket.id,
+        name: "orphanobject".into(),
+        id: Uuid::new_v4(),
+        vnode: 1,
+        content_length: 5,
+        content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+        content_type: "text/plain".into(),
+        headers: HashMap::new(),
+        sharks: vec![],
+        properties: None,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        indexed_metadata: HashMap::new(),
+        dry_run: false,
+    };
+    let create_orphan_json =
+        serde_json::to_value(vec![create_orphan_payload]).unwrap();
+    let create_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("createobject".into(), create_orphan_json),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+
+    let delete_orphan_bucket_payload = bucket::DeleteBucketPayload {
+        owner: owner_id,
+        name: "orphanbucket".into(),
+        vnode: 0,
+        request_id,
+        max_objects: None,
+    };
+    let delete_orphan_bucket_json =
+        serde_json::to_value(vec![delete_orphan_bucket_payload]).unwrap();
+    let delete_bucket_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "deletebucket".into(),
+                delete_orphan_bucket_json,
+            ),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_bucket_result.is_ok());
+
+    // Scan until every vnode has been examined once
+    let mut scanner = orphan::OrphanScanner::new(2);
+    let mut conn = pool.claim().unwrap();
+    let mut tombstoned = 0;
+    let mut vnodes_scanned = vec![];
+    while vnodes_scanned.len() < 2 {
+        let result = scanner
+            .scan_batch(&mdapi_config, &mut conn, &metrics, &log)
+            .unwrap()
+            .unwrap();
+        tombstoned += result.tombstoned;
+        if result.scanned < 2 {
+            vnodes_scanned.push(result.vnode);
+        }
+    }
+    drop(conn);
+    // Objects of buckets deleted earlier in the test may also be tombstoned
+    assert!(tombstoned >= 1);
+
+    let get_orphan_payload = object::GetObjectPayload {
+        owner: owner_id,
+        bucket_id: orphan_bucket.id,
+        name: "orphanobject".into(),
+        vnode: 1,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
+    };
+    let get_orphan_json =
+        serde_json::to_value(vec![get_orphan_payload]).unwrap();
+    let get_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("getobject".into(), get_orphan_json),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(get_object_result.is_ok());
+    let get_object_response_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(get_object_result.unwrap()[0].data.d[0].clone());
+    assert!(get_object_response_result.is_ok());
+    assert_eq!(
+        get_object_response_result.unwrap().error.name,
+        "ObjectNotFound"
+    );
+
+    // An update guarded by a known etag only applies to the object with that
+    // etag
+    let known_etag_update_msg = |known_etag: Uuid| {
+        let payload = object::update::UpdateObjectPayload {
+            owner: owner_id,
+            bucket_id: quota_bucket.id,
+            name: "quotaobject2".into(),
+            id: served_object.id,
+            vnode: 1,
+            content_type: "text/csv".into(),
+            headers: HashMap::new(),
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            indexed_metadata: None,
+            known_etag: Some(known_etag),
+            report_changed: false,
+            update_mode: object::update::UpdateMode::Replace,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        FastMessage::data(
+            msg_id,
+            FastMessageData::new("updateobject".into(), json),
+        )
+    };
+
+    let update_object_result = util::handle_msg(
+        &known_etag_update_msg(Uuid::new_v4()),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(update_object_result.is_ok());
+    let update_object_response_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(
+            update_object_result.unwrap()[0].data.d[0].clone(),
+        );
+    assert!(update_object_response_result.is_ok());
+    assert_eq!(
+        update_object_response_result.unwrap().error.name,
+        "PreconditionFailedError"
+    );
+
+    let update_object_result = util::handle_msg(
+        &known_etag_update_msg(served_object.id),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(update_object_result.is_ok());
+    let updated_object: object::ObjectResponse = serde_json::from_value(
+        update_object_result.unwrap()[0].data.d[0].clone(),
+    )
+    .unwrap();
+    assert_eq!(updated_object.id, served_object.id);
+    assert_eq!(updated_object.content_type, "text/csv");
+
+    // Object names differing only in case collide when names are
+    // case-insensitive, and the name as given is kept as the display name
+    let mut names_config = mdapi_config.clone();
+    names_config.names.case_insensitive_names = true;
+
+    let create_named_object = |name: &str, id: Uuid| {
+        let payload = object::create::CreateObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: name.into(),
+            id,
+            vnode: 1,
+            content_length: 5,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers: HashMap::new(),
+            sharks: vec![],
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            indexed_metadata: HashMap::new(),
+            dry_run: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let create_object_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("createobject".into(), json),
+            ),
+            &pool,
+            &names_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(create_object_result.is_ok());
+        serde_json::from_value::<object::ObjectResponse>(
+            create_object_result.unwrap()[0].data.d[0].clone(),
+        )
+        .unwrap()
+    };
+
+    let first_object = create_named_object("Names/Foo.txt", Uuid::new_v4());
+    assert_eq!(first_object.name, "names/foo.txt");
+    assert_eq!(first_object.display_name, Some("Names/Foo.txt".into()));
+
+    let second_id = Uuid::new_v4();
+    let second_object = create_named_object("NAMES/foo.TXT", second_id);
+    assert_eq!(second_object.name, "names/foo.txt");
+    assert_eq!(second_object.display_name, Some("NAMES/foo.TXT".into()));
+
+    let get_named_payload = object::GetObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "names/FOO.txt".into(),
+        vnode: 1,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
+    };
+    let get_named_json =
+        serde_json::to_value(vec![get_named_payload.clone()]).unwrap();
+    let get_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("getobject".into(), get_named_json),
+        ),
+        &pool,
+        &names_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(get_object_result.is_ok());
+    let named_object: object::ObjectResponse =
+        serde_json::from_value(get_object_result.unwrap()[0].data.d[0].clone())
+            .unwrap();
+    assert_eq!(named_object.id, second_id);
+    assert_eq!(named_object.display_name, Some("NAMES/foo.TXT".into()));
+
+    let list_named_payload = object::list::ListObjectsPayload {
+        owner: owner_id,
+        bucket_id,
+        vnode: 1,
+        prefix: Some("Names/".into()),
+        limit: 10,
+        marker: None,
+        marker_created: None,
+        request_id,
+        index_filter: None,
+        parent_prefix: None,
+        include_properties: false,
+        report_status: false,
+        modified_after: None,
+        modified_before: None,
+        skip_sharks: false,
+        delimiter: None,
+        count_only: false,
+        order_by: Default::default(),
+        continuation_token: None,
+        report_continuation: false,
+    };
+    let list_named_json =
+        serde_json::to_value(vec![list_named_payload]).unwrap();
+    let list_objects_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("listobjects".into(), list_named_json),
+        ),
+        &pool,
+        &names_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(list_objects_result.is_ok());
+    let list_objects_response = list_objects_result.unwrap();
+    assert_eq!(list_objects_response.len(), 1);
+    let listed_object: object::ObjectResponse =
+        serde_json::from_value(list_objects_response[0].data.d[0].clone())
+            .unwrap();
+    assert_eq!(listed_object.id, second_id);
+
+    let delete_named_json =
+        serde_json::to_value(vec![get_named_payload]).unwrap();
+    let delete_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("deleteobject".into(), delete_named_json),
+        ),
+        &pool,
+        &names_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(delete_object_result.is_ok());
+    let delete_object_response: Vec<object::DeleteObjectResponse> =
+        serde_json::from_value(
+            delete_object_result.unwrap()[0].data.d[0].clone(),
+        )
+        .unwrap();
+    assert_eq!(delete_object_response.len(), 1);
+
+    // A mutation affecting more rows than expected is counted as wide
+    let wide_before = metrics
+        .wide_mutations
+        .with_label_values(&["ObjectUpdate"])
+        .get();
+    let mut conn = pool.claim().unwrap();
+    let mut txn = conn.transaction().unwrap();
+    let broad_update_rows = sql::txn_query(
+        sql::Method::ObjectUpdate,
+        &mut txn,
+        "UPDATE manta_bucket_1.manta_bucket_object \
+         SET content_type = content_type \
+         WHERE owner = $1 AND bucket_id = $2 \
+         RETURNING id",
+        &[&owner_id, &quota_bucket.id],
+        &metrics,
+        &log,
+    )
+    .unwrap();
+    assert!(broad_update_rows.len() > 1);
+    assert!(sql::check_mutation_width(
+        sql::Method::ObjectUpdate,
+        broad_update_rows.len(),
+        mdapi_config.server.wide_mutation_rows,
+        &metrics,
+        &log,
+    ));
+    assert!(!sql::check_mutation_width(
+        sql::Method::ObjectUpdate,
+        1,
+        mdapi_config.server.wide_mutation_rows,
+        &metrics,
+        &log,
+    ));
+    txn.rollback().unwrap();
+    drop(conn);
+    assert_eq!(
+        metrics
+            .wide_mutations
+            .with_label_values(&["ObjectUpdate"])
+            .get(),
+        wide_before + 1.0
+    );
+
+    // The create response reports whether a replaced object was moved to the
+    // deleted object table
+    let create_garbage_object = || {
+        let payload = object::create::CreateObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "garbageobject".into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_length: 5,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers: HashMap::new(),
+            sharks: vec![],
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            indexed_metadata: HashMap::new(),
+            dry_run: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let create_object_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("createobject".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(create_object_result.is_ok());
+        serde_json::from_value::<object::create::CreateObjectResponse>(
+            create_object_result.unwrap()[0].data.d[0].clone(),
+        )
+        .unwrap()
+    };
+
+    assert!(!create_garbage_object().moved_to_garbage);
+    assert!(create_garbage_object().moved_to_garbage);
+
+    // No more runners than the migration slot ceiling migrate at once
+    let mut first_runner = pool.claim().unwrap();
+    let mut second_runner = pool.claim().unwrap();
+    let first_slot =
+        schema::migrations::try_acquire_migration_slot(&mut first_runner, 1)
+            .unwrap();
+    assert!(first_slot.is_some());
+    assert_eq!(
+        schema::migrations::try_acquire_migration_slot(&mut second_runner, 1)
+            .unwrap(),
+        None
+    );
+
+    // A runner waiting for a slot takes it once the slot is released
+    schema::migrations::release_migration_slot(
+        &mut first_runner,
+        first_slot.unwrap(),
+    )
+    .unwrap();
+    let second_slot = schema::migrations::acquire_migration_slot(
+        &mut second_runner,
+        1,
+        std::time::Duration::from_millis(10),
+    )
+    .unwrap();
+    assert_eq!(
+        schema::migrations::try_acquire_migration_slot(&mut first_runner, 1)
+            .unwrap(),
+        None
+    );
+
+    // A higher ceiling admits another runner alongside
+    let third_slot =
+        schema::migrations::try_acquire_migration_slot(&mut first_runner, 2)
+            .unwrap();
+    assert!(third_slot.is_some());
+    schema::migrations::release_migration_slot(
+        &mut first_runner,
+        third_slot.unwrap(),
+    )
+    .unwrap();
+    schema::migrations::release_migration_slot(&mut second_runner, second_slot)
+        .unwrap();
+    drop(first_runner);
+    drop(second_runner);
+
+    // A listing cut short by its deadline reports that it is not complete
+    let list_status = |deadline_ms: Option<u64>| {
+        let mut deadline_config = mdapi_config.clone();
+        deadline_config.listing.deadline_ms = deadline_ms;
+        let payload = object::list::ListObjectsPayload {
+            owner: owner_id,
+            bucket_id: quota_bucket.id,
+            vnode: 1,
+            prefix: None,
+            limit: 10,
+            marker: None,
+            marker_created: None,
+            request_id,
+            index_filter: None,
+            parent_prefix: None,
+            include_properties: false,
+            report_status: true,
+            modified_after: None,
+            modified_before: None,
+            skip_sharks: false,
+            delimiter: None,
+            count_only: false,
+            order_by: Default::default(),
+            continuation_token: None,
+            report_continuation: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let list_objects_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("listobjects".into(), json),
+            ),
+            &pool,
+            &deadline_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(list_objects_result.is_ok());
+        let list_objects_response = list_objects_result.unwrap();
+        let status: object::list::ListStatus = serde_json::from_value(
+            list_objects_response.last().unwrap().data.d[0].clone(),
+        )
+        .unwrap();
+        (list_objects_response.len() - 1, status)
+    };
+
+    let (listed, status) = list_status(Some(0));
+    assert_eq!(listed, 0);
+    assert!(!status.complete);
+    assert_eq!(status.next_marker, None);
+
+    let (listed, status) = list_status(Some(60_000));
+    assert!(listed > 0);
+    assert!(status.complete);
+    assert!(status.next_marker.is_some());
+
+    let (_, status) = list_status(None);
+    assert!(status.complete);
+
+    // Swapping two objects exchanges their names and keeps their ids
+    let create_swap_object = |name: &str| {
+        let payload = object::create::CreateObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: name.into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_length: 5,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers: HashMap::new(),
+            sharks: vec![],
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            indexed_metadata: HashMap::new(),
+            dry_run: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let create_object_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("createobject".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(create_object_result.is_ok());
+        serde_json::from_value::<object::ObjectResponse>(
+            create_object_result.unwrap()[0].data.d[0].clone(),
+        )
+        .unwrap()
+    };
+    let swap_objects = |name_a: &str, name_b: &str| {
+        let payload = object::swap::SwapObjectsPayload {
+            owner: owner_id,
+            bucket_id,
+            vnode: 1,
+            name_a: name_a.into(),
+            name_b: name_b.into(),
+            request_id,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let swap_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("swapobjects".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(swap_result.is_ok());
+        swap_result.unwrap()[0].data.d[0].clone()
+    };
+
+    let current = create_swap_object("swapcurrent");
+    let staging = create_swap_object("swapstaging");
+
+    let swap_response: object::swap::SwapObjectsResponse =
+        serde_json::from_value(swap_objects("swapcurrent", "swapstaging"))
+            .unwrap();
+    assert_eq!(swap_response.a.name, "swapcurrent");
+    assert_eq!(swap_response.a.id, staging.id);
+    assert_eq!(swap_response.a.created, staging.created);
+    assert_eq!(swap_response.b.name, "swapstaging");
+    assert_eq!(swap_response.b.id, current.id);
+
+    // A swap with a missing object fails and leaves the other object in place
+    let swap_missing_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(swap_objects("swapcurrent", "swapmissing"));
+    assert!(swap_missing_result.is_ok());
+    assert_eq!(
+        swap_missing_result.unwrap(),
+        BucketsMdapiWrappedError::new(BucketsMdapiError::ObjectNotFound),
+    );
+
+    let swap_response: object::swap::SwapObjectsResponse =
+        serde_json::from_value(swap_objects("swapcurrent", "swapstaging"))
+            .unwrap();
+    assert_eq!(swap_response.a.id, current.id);
+    assert_eq!(swap_response.b.id, staging.id);
+
+    // An object read with a content_md5 that is not an MD5 digest is returned,
+    // reported, or rejected according to the configured check
+    let corrupt_object = create_swap_object("corruptobject");
+    let mut conn = pool.claim().unwrap();
+    conn.execute(
+        "UPDATE manta_bucket_1.manta_bucket_object \
+         SET content_md5 = '\\x0102'::bytea WHERE id = $1",
+        &[&corrupt_object.id],
+    )
+    .unwrap();
+    drop(conn);
+
+    let get_corrupt_object = |check: config::ContentMd5Check| {
+        let check_config = config::Config {
+            server: config::ConfigServer {
+                content_md5_check: check,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let payload = object::GetObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "corruptobject".into(),
+            vnode: 1,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            if_content_length: None,
+            include_properties: false,
+            unpin_first: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let get_object_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("getobject".into(), json),
+            ),
+            &pool,
+            &check_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(get_object_result.is_ok());
+        get_object_result.unwrap()[0].data.d[0].clone()
+    };
+
+    let corruption_before = metrics.metadata_corruption.get();
+    let ignored: Result<object::ObjectResponse, _> = serde_json::from_value(
+        get_corrupt_object(config::ContentMd5Check::Ignore),
+    );
+    assert_eq!(ignored.unwrap().content_md5, "AQI=");
+    assert_eq!(metrics.metadata_corruption.get(), corruption_before);
+
+    let warned: Result<object::ObjectResponse, _> = serde_json::from_value(
+        get_corrupt_object(config::ContentMd5Check::Warn),
+    );
+    assert!(warned.is_ok());
+    assert_eq!(metrics.metadata_corruption.get(), corruption_before + 1.0);
+
+    let rejected: Result<BucketsMdapiWrappedError, _> = serde_json::from_value(
+        get_corrupt_object(config::ContentMd5Check::Reject),
+    );
+    assert_eq!(rejected.unwrap().error.name, "MetadataCorruption");
+    assert_eq!(metrics.metadata_corruption.get(), corruption_before + 2.0);
+
+    let mut conn = pool.claim().unwrap();
+    conn.execute(
+        "DELETE FROM manta_bucket_1.manta_bucket_object WHERE id = $1",
+        &[&corrupt_object.id],
+    )
+    .unwrap();
+    drop(conn);
+
+    // The getconfig RPC returns the effective configuration, including command
+    // line overrides, with sensitive entries redacted
+    let mut admin_config = config::Config {
+        database: config::ConfigDatabase {
+            certificate: Some("/opt/smartdc/buckets-mdapi/etc/ca.pem".into()),
+            ..Default::default()
+        },
+        admin: config::ConfigAdmin {
+            enabled: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let cli_matches = buckets_mdapi::opts::parse_from(
+        "buckets-mdapi",
+        vec!["buckets-mdapi", "--port", "2040"],
+    );
+    config::read_cli_args(&cli_matches, &mut admin_config);
+
+    let get_config_payload = admin::GetConfigPayload { request_id };
+    let get_config_fast_msg = FastMessage::data(
+        msg_id,
+        FastMessageData::new(
+            "getconfig".into(),
+            serde_json::to_value(vec![get_config_payload]).unwrap(),
+        ),
+    );
+
+    // The RPC is rejected unless it is enabled
+    let get_config_result = util::handle_msg(
+        &get_config_fast_msg,
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(get_config_result.is_err());
+
+    let get_config_result = util::handle_msg(
+        &get_config_fast_msg,
+        &pool,
+        &admin_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(get_config_result.is_ok());
+    let effective_config = get_config_result.unwrap()[0].data.d[0].clone();
+    assert_eq!(effective_config["server"]["port"], json!(2040));
+    assert_eq!(
+        effective_config["database"]["certificate"],
+        json!(config::REDACTED)
+    );
+    assert_eq!(effective_config["database"]["tls_mode"], json!("disable"));
+    assert_eq!(effective_config["admin"]["enabled"], json!(true));
+
+    // The object cache answers repeated reads of an object until the object
+    // is changed by a request served through the cache
+    let cache_config = config::Config {
+        object_cache: config::ConfigObjectCache {
+            enabled: true,
+            ttl_ms: 60_000,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let object_cache =
+        cache::ObjectCache::new(&cache_config.object_cache, &metrics);
+    let serve_cached = |method: &str, value: serde_json::Value| {
+        let result = util::handle_connection_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new(method.into(), value),
+            ),
+            &pool,
+            &replica,
+            &health,
+            &cache_config,
+            &write_limiter,
+            &byte_budget,
+            &admission_control,
+            &mut limiter::ListPacer::new(
+                None,
+                std::time::Duration::from_secs(60),
+            ),
+            &object_cache,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        serde_json::from_value::<object::ObjectResponse>(
+            result.unwrap().0[0].data.d[0].clone(),
+        )
+        .unwrap()
+    };
+    let get_cached_json =
+        serde_json::to_value(vec![object::GetObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "cacheobject".into(),
+            vnode: 1,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            if_content_length: None,
+            include_properties: false,
+            unpin_first: false,
+        }])
+        .unwrap();
+
+    let cached_object = create_swap_object("cacheobject");
+    let hits_before = metrics.object_cache_hits.get();
+    let misses_before = metrics.object_cache_misses.get();
+
+    let get_response = serve_cached("getobject", get_cached_json.clone());
+    assert_eq!(get_response.id, cached_object.id);
+    assert_eq!(metrics.object_cache_misses.get(), misses_before + 1.0);
+
+    // A change made behind the cache is not seen until the object expires or
+    // is invalidated
+    let mut conn = pool.claim().unwrap();
+    conn.execute(
+        "UPDATE manta_bucket_1.manta_bucket_object \
+         SET content_type = 'text/csv' WHERE id = $1",
+        &[&cached_object.id],
+    )
+    .unwrap();
+    drop(conn);
+
+    let get_response = serve_cached("getobject", get_cached_json.clone());
+    assert_eq!(get_response.content_type, "text/plain");
+    assert_eq!(metrics.object_cache_hits.get(), hits_before + 1.0);
+
+    // An update served through the cache invalidates the cached object
+    let update_cached_json =
+        serde_json::to_value(vec![object::update::UpdateObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "cacheobject".into(),
+            id: cached_object.id,
+            vnode: 1,
+            content_type: "application/json".into(),
+            headers: HashMap::new(),
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            indexed_metadata: None,
+            known_etag: None,
+            report_changed: false,
+            update_mode: object::update::UpdateMode::Replace,
+        }])
+        .unwrap();
+    let update_response = serve_cached("updateobject", update_cached_json);
+    assert_eq!(update_response.content_type, "application/json");
+
+    let get_response = serve_cached("getobject", get_cached_json);
+    assert_eq!(get_response.content_type, "application/json");
+    assert_eq!(metrics.object_cache_hits.get(), hits_before + 1.0);
+    assert_eq!(metrics.object_cache_misses.get(), misses_before + 2.0);
+
+    // Copying an object creates a new object with the source's metadata
+    let copy_object =
+        |source_name: &str, if_none_match: Option<Vec<String>>| {
+            let payload = object::copy::CopyObjectPayload {
+                owner: owner_id,
+                source_bucket_id: bucket_id,
+                source_name: source_name.into(),
+                dest_bucket_id: bucket_id,
+                dest_name: "copydest".into(),
+                vnode: 1,
+                request_id,
+                conditions: conditional::Conditions {
+                    if_none_match,
+                    ..Default::default()
+                },
+                bucket_vnode: None,
+            };
+            let json = serde_json::to_value(vec![payload]).unwrap();
+            let copy_result = util::handle_msg(
+                &FastMessage::data(
+                    msg_id,
+                    FastMessageData::new("copyobject".into(), json),
+                ),
+                &pool,
+                &mdapi_config,
+                &write_limiter,
+                &metrics,
+                &log,
+            );
+            assert!(copy_result.is_ok());
+            copy_result.unwrap()[0].data.d[0].clone()
+        };
+
+    let copy_response: object::ObjectResponse =
+        serde_json::from_value(copy_object("swapcurrent", None)).unwrap();
+    assert_eq!(copy_response.name, "copydest");
+    assert_ne!(copy_response.id, current.id);
+    assert_eq!(copy_response.content_length, current.content_length);
+    assert_eq!(copy_response.content_md5, current.content_md5);
+    assert_eq!(copy_response.sharks, current.sharks);
+    assert_eq!(copy_response.headers, current.headers);
+
+    // The destination exists, so a copy that requires it not to fails
+    let copy_exists_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(copy_object(
+            "swapcurrent",
+            Some(vec!["*".into()]),
+        ));
+    assert!(copy_exists_result.is_ok());
+    assert_eq!(
+        copy_exists_result.unwrap().error.name,
+        "PreconditionFailedError"
+    );
+
+    let copy_missing_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(copy_object("copymissing", None));
+    assert!(copy_missing_result.is_ok());
+    assert_eq!(
+        copy_missing_result.unwrap(),
+        BucketsMdapiWrappedError::new(BucketsMdapiError::ObjectNotFound),
+    );
+
+    // Renaming an object keeps its id, creation time, and sharks
+    let rename_object =
+        |old_name: &str, new_name: &str, if_none_match: Option<Vec<String>>| {
+            let payload = object::rename::RenameObjectPayload {
+                owner: owner_id,
+                bucket_id,
+                old_name: old_name.into(),
+                new_name: new_name.into(),
+                vnode: 1,
+                request_id,
+                conditions: conditional::Conditions {
+                    if_none_match,
+                    ..Default::default()
+                },
+                bucket_vnode: None,
+            };
+            let json = serde_json::to_value(vec![payload]).unwrap();
+            let rename_result = util::handle_msg(
+                &FastMessage::data(
+                    msg_id,
+                    FastMessageData::new("renameobject".into(), json),
+                ),
+                &pool,
+                &mdapi_config,
+                &write_limiter,
+                &metrics,
+                &log,
+            );
+            assert!(rename_result.is_ok());
+            rename_result.unwrap()[0].data.d[0].clone()
+        };
+
+    let renamed_object = create_swap_object("renamesource");
+    let rename_response: object::ObjectResponse = serde_json::from_value(
+        rename_object("renamesource", "renamedest", None),
+    )
+    .unwrap();
+    assert_eq!(rename_response.name, "renamedest");
+    assert_eq!(rename_response.id, renamed_object.id);
+    assert_eq!(rename_response.created, renamed_object.created);
+    assert_eq!(rename_response.sharks, renamed_object.sharks);
+
+    // The old name no longer exists
+    let rename_missing_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(rename_object(
+            "renamesource",
+            "renameother",
+            None,
+        ));
+    assert!(rename_missing_result.is_ok());
+    assert_eq!(
+        rename_missing_result.unwrap(),
+        BucketsMdapiWrappedError::new(BucketsMdapiError::ObjectNotFound),
+    );
+
+    // A rename onto an existing object that requires the new name to be
+    // unused fails and leaves both objects in place
+    let rename_exists_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(rename_object(
+            "renamedest",
+            "copydest",
+            Some(vec!["*".into()]),
+        ));
+    assert!(rename_exists_result.is_ok());
+    assert_eq!(
+        rename_exists_result.unwrap().error.name,
+        "PreconditionFailedError"
+    );
+
+    // Without the condition the existing object is replaced
+    let rename_response: object::ObjectResponse =
+        serde_json::from_value(rename_object("renamedest", "copydest", None))
+            .unwrap();
+    assert_eq!(rename_response.name, "copydest");
+    assert_eq!(rename_response.id, renamed_object.id);
+
+    // Listings can be restricted to the objects modified within a window
+    let window_first = create_swap_object("windowfirst");
+    let window_second = create_swap_object("windowsecond");
+    let list_window = |modified_after, modified_before| {
+        let payload = object::list::ListObjectsPayload {
+            owner: owner_id,
+            bucket_id,
+            vnode: 1,
+            prefix: Some("window".into()),
+            limit: 10,
+            marker: None,
+            marker_created: None,
+            request_id,
+            index_filter: None,
+            parent_prefix: None,
+            include_properties: false,
+            report_status: false,
+            modified_after,
+            modified_before,
+            skip_sharks: false,
+            delimiter: None,
+            count_only: false,
+            order_by: Default::default(),
+            continuation_token: None,
+            report_continuation: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let list_objects_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("listobjects".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(list_objects_result.is_ok());
+        list_objects_result
+            .unwrap()
+            .iter()
+            .map(|msg| {
+                serde_json::from_value::<object::ObjectResponse>(
+                    msg.data.d[0].clone(),
+                )
+                .unwrap()
+                .name
+            })
+            .collect::<Vec<String>>()
+    };
+
+    assert_eq!(
+        list_window(Some(window_first.modified), None),
+        vec!["windowsecond".to_string()]
+    );
+    assert_eq!(
+        list_window(
+            Some(window_first.modified - chrono::Duration::seconds(1)),
+            Some(window_second.modified)
+        ),
+        vec!["windowfirst".to_string()]
+    );
+
+    // Each object of a batch is created or reported in request order
+    let batch_object = |name: &str, if_match: Option<Vec<String>>| {
+        object::create::CreateObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: name.into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_length: 5,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers: HashMap::new(),
+            sharks: vec![],
+            properties: None,
+            request_id,
+            conditions: conditional::Conditions {
+                if_match,
+                ..Default::default()
+            },
+            bucket_vnode: None,
+            indexed_metadata: HashMap::new(),
+            dry_run: false,
+        }
+    };
+    let create_batch = |objects: Vec<object::create::CreateObjectPayload>,
+                        batch_config: &config::Config| {
+        let json = serde_json::to_value(objects).unwrap();
+        let create_batch_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("createobjectbatch".into(), json),
+            ),
+            &pool,
+            batch_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(create_batch_result.is_ok());
+        create_batch_result
+            .unwrap()
+            .iter()
+            .map(|msg| msg.data.d[0].clone())
+            .collect::<Vec<serde_json::Value>>()
+    };
+
+    let batch_response = create_batch(
+        vec![
+            batch_object("batchone", None),
+            batch_object("batchmissing", Some(vec!["*".into()])),
+            batch_object("batchtwo", None),
+        ],
+        &mdapi_config,
+    );
+    assert_eq!(batch_response.len(), 3);
+    let batch_first: object::ObjectResponse =
+        serde_json::from_value(batch_response[0].clone()).unwrap();
+    assert_eq!(batch_first.name, "batchone");
+    let batch_failed: BucketsMdapiWrappedError =
+        serde_json::from_value(batch_response[1].clone()).unwrap();
+    assert_eq!(batch_failed.error.name, "PreconditionFailedError");
+    let batch_second: object::ObjectResponse =
+        serde_json::from_value(batch_response[2].clone()).unwrap();
+    assert_eq!(batch_second.name, "batchtwo");
+
+    // An atomic batch with a failed object creates none of its objects
+    let mut atomic_config = mdapi_config.clone();
+    atomic_config.server.batch_create_atomic = true;
+    let batch_response = create_batch(
+        vec![
+            batch_object("batchthree", None),
+            batch_object("batchmissing", Some(vec!["*".into()])),
+        ],
+        &atomic_config,
+    );
+    assert_eq!(batch_response.len(), 1);
+    let batch_aborted: BucketsMdapiWrappedError =
+        serde_json::from_value(batch_response[0].clone()).unwrap();
+    assert_eq!(batch_aborted.error.name, "BatchAborted");
+
+    let batch_swap_result: Result<BucketsMdapiWrappedError, _> =
+        serde_json::from_value(swap_objects("batchone", "batchthree"));
+    assert!(batch_swap_result.is_ok());
+    assert_eq!(
+        batch_swap_result.unwrap(),
+        BucketsMdapiWrappedError::new(BucketsMdapiError::ObjectNotFound),
+    );
+
+    // Verify that getobjects returns the objects found, in name order,
+    // followed by the names of the objects not found
+
+    let get_objects = |names: Vec<String>| {
+        let payload = object::multiget::GetObjectsPayload {
+            owner: owner_id,
+            bucket_id,
+            vnode: 1,
+            request_id,
+            names,
+            include_properties: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let get_objects_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("getobjects".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(get_objects_result.is_ok());
+        get_objects_result
+            .unwrap()
+            .iter()
+            .map(|msg| msg.data.d[0].clone())
+            .collect::<Vec<serde_json::Value>>()
+    };
+
+    let get_objects_response = get_objects(vec![
+        "batchtwo".into(),
+        "batchmissing".into(),
+        "batchone".into(),
+    ]);
+    assert_eq!(get_objects_response.len(), 3);
+    let get_objects_first: object::ObjectResponse =
+        serde_json::from_value(get_objects_response[0].clone()).unwrap();
+    assert_eq!(get_objects_first.name, "batchone");
+    let get_objects_second: object::ObjectResponse =
+        serde_json::from_value(get_objects_response[1].clone()).unwrap();
+    assert_eq!(get_objects_second.name, "batchtwo");
+    let get_objects_status: object::multiget::GetObjectsStatus =
+        serde_json::from_value(get_objects_response[2].clone()).unwrap();
+    assert_eq!(get_objects_status.missing, vec!["batchmissing".to_string()]);
+
+    // Requests for more names than allowed are rejected
+    let too_many_names = (0..=object::multiget::MAX_GET_OBJECTS)
+        .map(|i| format!("object{}", i))
+        .collect();
+    let get_objects_response = get_objects(too_many_names);
+    assert_eq!(get_objects_response.len(), 1);
+    let get_objects_limit: BucketsMdapiError =
+        serde_json::from_value(get_objects_response[0].clone()).unwrap();
+    match get_objects_limit {
+        BucketsMdapiError::LimitConstraintError(_) => (),
+        e => panic!("unexpected getobjects limit error {:?}", e),
+    }
+
+    // Verify that listobjects returns the sharks of objects unless the
+    // request skips them
+
+    let listed_sharks = vec![
+        object::StorageNodeIdentifier {
+            datacenter: "us-east-1".into(),
+            manta_storage_id: "1.stor.us-east.joyent.com".into(),
+        },
+        object::StorageNodeIdentifier {
+            datacenter: "us-east-2".into(),
+            manta_storage_id: "3.stor.us-east.joyent.com".into(),
+        },
+    ];
+    let mut sharks_object = batch_object("sharksobject", None);
+    sharks_object.sharks = listed_sharks.clone();
+    let sharks_response = create_batch(vec![sharks_object], &mdapi_config);
+    assert_eq!(sharks_response.len(), 1);
+
+    let list_sharks = |skip_sharks| {
+        let payload = object::list::ListObjectsPayload {
+            owner: owner_id,
+            bucket_id,
+            vnode: 1,
+            prefix: Some("sharksobject".into()),
+            limit: 10,
+            marker: None,
+            marker_created: None,
+            request_id,
+            index_filter: None,
+            parent_prefix: None,
+            include_properties: false,
+            report_status: false,
+            modified_after: None,
+            modified_before: None,
+            skip_sharks,
+            delimiter: None,
+            count_only: false,
+            order_by: Default::default(),
+            continuation_token: None,
+            report_continuation: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let list_objects_result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("listobjects".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(list_objects_result.is_ok());
+        let list_objects_response = list_objects_result.unwrap();
+        assert_eq!(list_objects_response.len(), 1);
+        serde_json::from_value::<object::ObjectResponse>(
+            list_objects_response[0].data.d[0].clone(),
+        )
+        .unwrap()
+    };
+
+    let listed = list_sharks(false);
+    assert_eq!(listed.name, "sharksobject");
+    assert_eq!(listed.sharks, listed_sharks);
+
+    let listed = list_sharks(true);
+    assert_eq!(listed.name, "sharksobject");
+    assert_eq!(listed.content_length, 5);
+    assert!(listed.sharks.is_empty());
+
+    // Verify that a delimited listing collapses the objects sharing a common
+    // prefix into a single common prefix entry
+
+    let delimited_response = create_batch(
+        vec![
+            batch_object("delim/a/b", None),
+            batch_object("delim/a/c", None),
+            batch_object("delim/d", None),
+        ],
+        &mdapi_config,
+    );
+    assert_eq!(delimited_response.len(), 3);
+
+    let payload = object::list::ListObjectsPayload {
+        owner: owner_id,
+        bucket_id,
+        vnode: 1,
+        prefix: Some("delim/".into()),
+        limit: 10,
+        marker: None,
+        marker_created: None,
+        request_id,
+        index_filter: None,
+        parent_prefix: None,
+        include_properties: false,
+        report_status: false,
+        modified_after: None,
+        modified_before: None,
+        skip_sharks: false,
+        delimiter: Some("/".into()),
+        count_only: false,
+        order_by: Default::default(),
+        continuation_token: None,
+        report_continuation: false,
+    };
+    let json = serde_json::to_value(vec![payload]).unwrap();
+    let list_delimited_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("listobjects".into(), json),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(list_delimited_result.is_ok());
+    let list_delimited_response = list_delimited_result.unwrap();
+    assert_eq!(list_delimited_response.len(), 2);
+    let delimited_prefix: object::list::CommonPrefix =
+        serde_json::from_value(list_delimited_response[0].data.d[0].clone())
+            .unwrap();
+    assert_eq!(delimited_prefix.common_prefix, "delim/a/");
+    let delimited_object: object::ObjectResponse =
+        serde_json::from_value(list_delimited_response[1].data.d[0].clone())
+            .unwrap();
+    assert_eq!(delimited_object.name, "delim/d");
+
+    // Verify that an update reports whether it changed the object when asked
+
+    let update_changed = |content_type: &str| {
+        let payload = object::update::UpdateObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "batchtwo".into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_type: content_type.into(),
+            headers: HashMap::new(),
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            indexed_metadata: None,
+            known_etag: None,
+            report_changed: true,
+            update_mode: object::update::UpdateMode::Replace,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("updateobject".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        let updated: object::update::UpdateObjectResponse =
+            serde_json::from_value(result.unwrap()[0].data.d[0].clone())
+                .unwrap();
+        updated.changed
+    };
+
+    assert_eq!(update_changed("text/plain"), Some(false));
+    assert_eq!(update_changed("text/html"), Some(true));
+    assert_eq!(update_changed("text/html"), Some(false));
+
+    // Verify that an update in merge mode sets individual headers without
+    // resending the others, and removes the headers given a null value
+
+    let header = |name: &str, value: Option<&str>| {
+        (name.to_string(), value.map(String::from))
+    };
+    let merge_object = object::create::CreateObjectPayload {
+        headers: vec![
+            header("m-color", Some("blue")),
+            header("m-size", Some("10")),
+        ]
+        .into_iter()
+        .collect(),
+        ..batch_object("merge-headers", None)
+    };
+    assert_eq!(create_batch(vec![merge_object], &mdapi_config).len(), 1);
+
+    let update_merged = |headers: Vec<(String, Option<String>)>| {
+        let payload = object::update::UpdateObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "merge-headers".into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_type: "text/plain".into(),
+            headers: headers.into_iter().collect(),
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            indexed_metadata: None,
+            known_etag: None,
+            report_changed: false,
+            update_mode: object::update::UpdateMode::Merge,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("updateobject".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        let updated: object::ObjectResponse =
+            serde_json::from_value(result.unwrap()[0].data.d[0].clone())
+                .unwrap();
+        updated.headers
+    };
+
+    let merged_headers = update_merged(vec![header("m-shape", Some("round"))]);
+    assert_eq!(merged_headers.len(), 3);
+    assert_eq!(merged_headers["m-color"], Some("blue".into()));
+    assert_eq!(merged_headers["m-size"], Some("10".into()));
+    assert_eq!(merged_headers["m-shape"], Some("round".into()));
+
+    let merged_headers = update_merged(vec![
+        header("m-color", Some("green")),
+        header("m-size", None),
+    ]);
+    assert_eq!(merged_headers.len(), 2);
+    assert_eq!(merged_headers["m-color"], Some("green".into()));
+    assert_eq!(merged_headers["m-shape"], Some("round".into()));
+
+    // Verify that objects are only created and updated with the content types
+    // of a configured allowlist
+
+    let mut content_type_config = mdapi_config.clone();
+    content_type_config.server.allowed_content_types =
+        Some(vec!["text/plain".into(), "image/*".into()]);
+    let content_type_object =
+        |name: &str, content_type: &str| object::create::CreateObjectPayload {
+            content_type: content_type.into(),
+            ..batch_object(name, None)
+        };
+    let content_type_response = create_batch(
+        vec![
+            content_type_object("allowed-type", "text/plain"),
+            content_type_object("wildcard-type", "image/png"),
+            content_type_object("disallowed-type", "text/html"),
+        ],
+        &content_type_config,
+    );
+    assert_eq!(content_type_response.len(), 3);
+    for allowed_response in &content_type_response[..2] {
+        let created: Result<object::ObjectResponse, _> =
+            serde_json::from_value(allowed_response.clone());
+        assert!(created.is_ok());
+    }
+    let disallowed_error: BucketsMdapiWrappedError =
+        serde_json::from_value(content_type_response[2].clone()).unwrap();
+    assert_eq!(disallowed_error.error.name, "BadRequestError");
+
+    let update_content_type = |content_type: &str| {
+        let payload = object::update::UpdateObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "allowed-type".into(),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_type: content_type.into(),
+            headers: HashMap::new(),
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            indexed_metadata: None,
+            known_etag: None,
+            report_changed: false,
+            update_mode: object::update::UpdateMode::Merge,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("updateobject".into(), json),
+            ),
+            &pool,
+            &content_type_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        result.unwrap()[0].data.d[0].clone()
+    };
+
+    let updated: Result<object::ObjectResponse, _> =
+        serde_json::from_value(update_content_type("image/jpeg"));
+    assert!(updated.is_ok());
+    let update_error: BucketsMdapiWrappedError =
+        serde_json::from_value(update_content_type("application/json"))
+            .unwrap();
+    assert_eq!(update_error.error.name, "BadRequestError");
+
+    // Verify that object and bucket names up to the maximum name length are
+    // accepted and longer names are rejected
+
+    let longest_name = "n".repeat(mdapi_config.server.max_name_length);
+    let too_long_name = "n".repeat(mdapi_config.server.max_name_length + 1);
+    let name_length_response = create_batch(
+        vec![
+            batch_object(&longest_name, None),
+            batch_object(&too_long_name, None),
+        ],
+        &mdapi_config,
+    );
+    assert_eq!(name_length_response.len(), 2);
+    let created: Result<object::ObjectResponse, _> =
+        serde_json::from_value(name_length_response[0].clone());
+    assert!(created.is_ok());
+    let name_length_error: BucketsMdapiWrappedError =
+        serde_json::from_value(name_length_response[1].clone()).unwrap();
+    assert_eq!(name_length_error.error.name, "BadRequestError");
+
+    let get_named = |name: &str| {
+        let payload = object::GetObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: name.into(),
+            vnode: 1,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            if_content_length: None,
+            include_properties: false,
+            unpin_first: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("getobject".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        result.unwrap()[0].data.d[0].clone()
+    };
+
+    let got: Result<object::ObjectResponse, _> =
+        serde_json::from_value(get_named(&longest_name));
+    assert!(got.is_ok());
+    let get_error: BucketsMdapiWrappedError =
+        serde_json::from_value(get_named(&too_long_name)).unwrap();
+    assert_eq!(get_error.error.name, "BadRequestError");
+
+    let create_named_bucket = |name: &str| {
+        let payload = bucket::create::CreateBucketPayload {
+            owner: owner_id,
+            name: name.into(),
+            vnode: 0,
+            request_id,
+            max_total_bytes: None,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("createbucket".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        result.unwrap()[0].data.d[0].clone()
+    };
+
+    let created_bucket: Result<bucket::BucketResponse, _> =
+        serde_json::from_value(create_named_bucket(&longest_name));
+    assert!(created_bucket.is_ok());
+    let bucket_name_error: BucketsMdapiWrappedError =
+        serde_json::from_value(create_named_bucket(&too_long_name)).unwrap();
+    assert_eq!(bucket_name_error.error.name, "BadRequestError");
+
+    // Verify that a dry run create makes the checks of a create without
+    // creating the object
+
+    let dry_run_create = |name: &str, if_match: Option<Vec<String>>| {
+        let payload = object::create::CreateObjectPayload {
+            dry_run: true,
+            ..batch_object(name, if_match)
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("createobject".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        result.unwrap()[0].data.d[0].clone()
+    };
+
+    let dry_run_response: object::create::CreateObjectResponse =
+        serde_json::from_value(dry_run_create("dry-run-object", None)).unwrap();
+    assert!(dry_run_response.dry_run);
+    assert_eq!(dry_run_response.object.name, "dry-run-object");
+    let not_found: BucketsMdapiWrappedError =
+        serde_json::from_value(get_named("dry-run-object")).unwrap();
+    assert_eq!(not_found.error.name, "ObjectNotFound");
+
+    let dry_run_error: BucketsMdapiWrappedError = serde_json::from_value(
+        dry_run_create("dry-run-missing", Some(vec!["*".into()])),
+    )
+    .unwrap();
+    assert_eq!(dry_run_error.error.name, "PreconditionFailedError");
+
+    // Verify that headobject returns the identifying fields of an object
+    // without its sharks, headers, or properties, and honors conditions
+
+    let head_object = |name: &str, conditions: conditional::Conditions| {
+        let payload = object::GetObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: name.into(),
+            vnode: 1,
+            request_id,
+            conditions,
+            bucket_vnode: None,
+            if_content_length: None,
+            include_properties: false,
+            unpin_first: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("headobject".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        result.unwrap()[0].data.d[0].clone()
+    };
+
+    let full_object: object::ObjectResponse =
+        serde_json::from_value(get_named("merge-headers")).unwrap();
+    let head_value = head_object("merge-headers", Default::default());
+    for field in &["sharks", "headers", "properties"] {
+        assert!(head_value.get(field).is_none());
+    }
+    let head: object::head::HeadObjectResponse =
+        serde_json::from_value(head_value).unwrap();
+    assert_eq!(head.id, full_object.id);
+    assert_eq!(head.content_length, full_object.content_length);
+    assert_eq!(head.content_md5, full_object.content_md5);
+    assert_eq!(head.modified, full_object.modified);
+    assert_eq!(head.content_type, full_object.content_type);
+
+    let head_missing: BucketsMdapiWrappedError = serde_json::from_value(
+        head_object("no-such-object", Default::default()),
+    )
+    .unwrap();
+    assert_eq!(head_missing.error.name, "ObjectNotFound");
+
+    let head_matched: Result<object::head::HeadObjectResponse, _> =
+        serde_json::from_value(head_object(
+            "merge-headers",
+            conditional::Conditions {
+                if_match: Some(vec![full_object.id.to_string()]),
+                ..Default::default()
+            },
+        ));
+    assert!(head_matched.is_ok());
+    let head_unmatched: BucketsMdapiWrappedError =
+        serde_json::from_value(head_object(
+            "merge-headers",
+            conditional::Conditions {
+                if_match: Some(vec![Uuid::new_v4().to_string()]),
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+    assert_eq!(head_unmatched.error.name, "PreconditionFailedError");
+
+    // Verify that repeated gets reuse the statement prepared on each
+    // connection. Each of the pool's five connections prepares the statement
+    // at most once, so every other get is a cache hit.
+
+    let statement_hits = || {
+        metrics
+            .prepared_statement_cache_hits
+            .with_label_values(&["ObjectGet"])
+            .get()
+    };
+    let hits_before = statement_hits();
+    let repeated_gets = 50;
+    let gets_started = std::time::Instant::now();
+    for _ in 0..repeated_gets {
+        let payload = object::GetObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "merge-headers".into(),
+            vnode: 1,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            if_content_length: None,
+            include_properties: false,
+            unpin_first: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("getobject".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        let got: Result<object::ObjectResponse, _> =
+            serde_json::from_value(result.unwrap()[0].data.d[0].clone());
+        assert!(got.is_ok());
+    }
+    info!(log, "repeated gets complete";
+        "gets" => repeated_gets,
+        "elapsed_ms" => gets_started.elapsed().as_millis());
+    assert!(statement_hits() - hits_before >= f64::from(repeated_gets - 5));
+
+    // Verify that a count_only listing returns the number of matches
+
+    let payload = object::list::ListObjectsPayload {
+        owner: owner_id,
+        bucket_id,
+        vnode: 1,
+        prefix: Some("delim/".into()),
+        limit: 0,
+        marker: None,
+        marker_created: None,
+        request_id,
+        index_filter: None,
+        parent_prefix: None,
+        include_properties: false,
+        report_status: false,
+        modified_after: None,
+        modified_before: None,
+        skip_sharks: false,
+        delimiter: None,
+        count_only: true,
+        order_by: Default::default(),
+        continuation_token: None,
+        report_continuation: false,
+    };
+    let json = serde_json::to_value(vec![payload]).unwrap();
+    let count_objects_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("listobjects".into(), json),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(count_objects_result.is_ok());
+    let count_objects_response = count_objects_result.unwrap();
+    assert_eq!(count_objects_response.len(), 1);
+    let object_count: types::ListCount =
+        serde_json::from_value(count_objects_response[0].data.d[0].clone())
+            .unwrap();
+    assert_eq!(object_count.count, 3);
+
+    for name in &["countbucket1", "countbucket2"] {
+        let payload = bucket::create::CreateBucketPayload {
+            owner: owner_id,
+            name: (*name).into(),
+            vnode: 0,
+            request_id,
+            max_total_bytes: None,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("createbucket".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+    }
+
+    let payload = bucket::list::ListBucketsPayload {
+        owner: owner_id,
+        vnode: 0,
+        prefix: Some("countbucket".into()),
+        limit: 0,
+        marker: None,
+        marker_created: None,
+        request_id,
+        count_only: true,
+        order_by: Default::default(),
+    };
+    let json = serde_json::to_value(vec![payload]).unwrap();
+    let count_buckets_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("listbuckets".into(), json),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(count_buckets_result.is_ok());
+    let count_buckets_response = count_buckets_result.unwrap();
+    assert_eq!(count_buckets_response.len(), 1);
+    let bucket_count: types::ListCount =
+        serde_json::from_value(count_buckets_response[0].data.d[0].clone())
+            .unwrap();
+    assert_eq!(bucket_count.count, 2);
+
+    // Verify that reads are served by the read replica and writes by the
+    // primary, and that the reads of a bucket just written are served by the
+    // primary
+
+    let mut route_pacer =
+        limiter::ListPacer::new(None, std::time::Duration::from_secs(60));
+    let route_cache =
+        cache::ObjectCache::new(&mdapi_config.object_cache, &metrics);
+    let mut serve_routed = |method: &str, value: serde_json::Value| {
+        let result = util::handle_connection_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new(method.into(), value),
+            ),
+            &pool,
+            &replica,
+            &health,
+            &mdapi_config,
+            &write_limiter,
+            &byte_budget,
+            &admission_control,
+            &mut route_pacer,
+            &route_cache,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        result.unwrap().0
+    };
+    let get_bucket_json =
+        serde_json::to_value(vec![bucket::GetBucketPayload {
+            owner: owner_id,
+            name: "countbucket1".into(),
+            vnode: 0,
+            request_id,
+        }])
+        .unwrap();
+
+    let replica_before = metrics.replica_requests.get();
+    let _ = serve_routed("getbucket", get_bucket_json.clone());
+    assert_eq!(metrics.replica_requests.get(), replica_before + 1.0);
+
+    let routed_json =
+        serde_json::to_value(vec![batch_object("routedobject", None)]).unwrap();
+    let create_routed_response = serve_routed("createobject", routed_json);
+    assert_eq!(metrics.replica_requests.get(), replica_before + 1.0);
+    let created_routed: object::ObjectResponse =
+        serde_json::from_value(create_routed_response[0].data.d[0].clone())
+            .unwrap();
+
+    let get_routed_json =
+        serde_json::to_value(vec![object::GetObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "routedobject".into(),
+            vnode: 1,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            if_content_length: None,
+            include_properties: false,
+            unpin_first: false,
+        }])
+        .unwrap();
+    let get_routed_response = serve_routed("getobject", get_routed_json);
+    assert_eq!(metrics.replica_requests.get(), replica_before + 1.0);
+    let got_routed: object::ObjectResponse =
+        serde_json::from_value(get_routed_response[0].data.d[0].clone())
+            .unwrap();
+    assert_eq!(got_routed.id, created_routed.id);
+
+    // An object write does not hold the reads of the owner's buckets
+    let _ = serve_routed("getbucket", get_bucket_json);
+    assert_eq!(metrics.replica_requests.get(), replica_before + 2.0);
+
+    // Verify that listings can be ordered by name or creation time in either
+    // direction, and that markers continue in the order of the listing
+
+    for name in &["ordered/c", "ordered/a", "ordered/b"] {
+        let _ = create_swap_object(name);
+    }
+    let list_ordered =
+        |order_by: types::ListOrder,
+         marker: Option<(&str, types::Timestamptz)>| {
+            let payload = object::list::ListObjectsPayload {
+                owner: owner_id,
+                bucket_id,
+                vnode: 1,
+                prefix: Some("ordered/".into()),
+                limit: 2,
+                marker: marker.map(|(name, _)| String::from(name)),
+                marker_created: marker.map(|(_, created)| created),
+                request_id,
+                index_filter: None,
+                parent_prefix: None,
+                include_properties: false,
+                report_status: false,
+                modified_after: None,
+                modified_before: None,
+                skip_sharks: false,
+                delimiter: None,
+                count_only: false,
+                order_by,
+                continuation_token: None,
+                report_continuation: false,
+            };
+            let json = serde_json::to_value(vec![payload]).unwrap();
+            let list_ordered_result = util::handle_msg(
+                &FastMessage::data(
+                    msg_id,
+                    FastMessageData::new("listobjects".into(), json),
+                ),
+                &pool,
+                &mdapi_config,
+                &write_limiter,
+                &metrics,
+                &log,
+            );
+            assert!(list_ordered_result.is_ok());
+            list_ordered_result
+                .unwrap()
+                .iter()
+                .map(|msg| {
+                    serde_json::from_value::<object::ObjectResponse>(
+                        msg.data.d[0].clone(),
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<object::ObjectResponse>>()
+        };
+    let ordered_names = |objects: &[object::ObjectResponse]| {
+        objects
+            .iter()
+            .map(|object| object.name.clone())
+            .collect::<Vec<String>>()
+    };
+
+    let orders = vec![
+        (
+            types::ListOrder::NameAsc,
+            ["ordered/a", "ordered/b", "ordered/c"],
+        ),
+        (
+            types::ListOrder::NameDesc,
+            ["ordered/c", "ordered/b", "ordered/a"],
+        ),
+        (
+            types::ListOrder::CreatedAsc,
+            ["ordered/c", "ordered/a", "ordered/b"],
+        ),
+        (
+            types::ListOrder::CreatedDesc,
+            ["ordered/b", "ordered/a", "ordered/c"],
+        ),
+    ];
+    for (order_by, expected) in orders {
+        let first_page = list_ordered(order_by, None);
+        assert_eq!(ordered_names(&first_page), vec![expected[0], expected[1]]);
+        let marker = (first_page[1].name.as_str(), first_page[1].created);
+        let second_page = list_ordered(order_by, Some(marker));
+        assert_eq!(ordered_names(&second_page), vec![expected[2]]);
+    }
+
+    // A listing ordered by creation time continues from the creation time of
+    // its marker even when no object has the marker's name any longer
+    let first_page = list_ordered(types::ListOrder::CreatedAsc, None);
+    let second_page = list_ordered(
+        types::ListOrder::CreatedAsc,
+        Some(("ordered/deleted", first_page[1].created)),
+    );
+    assert_eq!(ordered_names(&second_page), vec!["ordered/b"]);
+
+    // Verify that a continuation token continues a listing in the order it
+    // was issued for, and is rejected by a listing in another order
+
+    let mut continuation_config = mdapi_config.clone();
+    continuation_config.listing.continuation_secret =
+        Some("continuation secret".to_string());
+    let list_continued =
+        |order_by: types::ListOrder,
+         continuation_token: Option<String>,
+         list_config: &config::Config| {
+            let payload = object::list::ListObjectsPayload {
+                owner: owner_id,
+                bucket_id,
+                vnode: 1,
+                prefix: Some("ordered/".into()),
+                limit: 2,
+                marker: None,
+                marker_created: None,
+                request_id,
+                index_filter: None,
+                parent_prefix: None,
+                include_properties: false,
+                report_status: false,
+                modified_after: None,
+                modified_before: None,
+                skip_sharks: false,
+                delimiter: None,
+                count_only: false,
+                order_by,
+                continuation_token,
+                report_continuation: true,
+            };
+            let json = serde_json::to_value(vec![payload]).unwrap();
+            let list_continued_result = util::handle_msg(
+                &FastMessage::data(
+                    msg_id,
+                    FastMessageData::new("listobjects".into(), json),
+                ),
+                &pool,
+                list_config,
+                &write_limiter,
+                &metrics,
+                &log,
+            );
+            assert!(list_continued_result.is_ok());
+            list_continued_result
+                .unwrap()
+                .iter()
+                .map(|msg| msg.data.d[0].clone())
+                .collect::<Vec<serde_json::Value>>()
+        };
+    let continuation = |msgs: &[serde_json::Value]| {
+        serde_json::from_value::<object::list::ListContinuation>(
+            msgs[msgs.len() - 1].clone(),
+        )
+        .unwrap()
+        .next_marker
+    };
+
+    let first_page = list_continued(
+        types::ListOrder::CreatedDesc,
+        None,
+        &continuation_config,
+    );
+    assert_eq!(first_page.len(), 3);
+    let token = continuation(&first_page);
+    assert!(token.is_some());
+    let second_page = list_continued(
+        types::ListOrder::CreatedDesc,
+        token.clone(),
+        &continuation_config,
+    );
+    assert_eq!(second_page.len(), 2);
+    let last_object: object::ObjectResponse =
+        serde_json::from_value(second_page[0].clone()).unwrap();
+    assert_eq!(last_object.name, "ordered/c");
+    assert_eq!(continuation(&second_page), None);
+
+    let reordered = list_continued(
+        types::ListOrder::NameDesc,
+        token.clone(),
+        &continuation_config,
+    );
+    assert_eq!(reordered.len(), 1);
+    let reordered_error: BucketsMdapiWrappedError =
+        serde_json::from_value(reordered[0].clone()).unwrap();
+    assert_eq!(reordered_error.error.name, "BadRequestError");
+
+    // Without a continuation_secret tokens are neither issued nor accepted
+    for continuation_token in &[None, token] {
+        let disabled = list_continued(
+            types::ListOrder::CreatedDesc,
+            continuation_token.clone(),
+            &mdapi_config,
+        );
+        assert_eq!(disabled.len(), 1);
+        let disabled_error: BucketsMdapiWrappedError =
+            serde_json::from_value(disabled[0].clone()).unwrap();
+        assert_eq!(disabled_error.error.name, "BadRequestError");
+    }
+
+    let bucket_page =
+        |order_by: types::ListOrder,
+         marker: Option<&bucket::BucketResponse>| {
+            let payload = bucket::list::ListBucketsPayload {
+                owner: owner_id,
+                vnode: 0,
+                prefix: Some("countbucket".into()),
+                limit: 1,
+                marker: marker.map(|bucket| bucket.name.clone()),
+                marker_created: marker.map(|bucket| bucket.created),
+                request_id,
+                count_only: false,
+                order_by,
+            };
+            let json = serde_json::to_value(vec![payload]).unwrap();
+            let bucket_page_result = util::handle_msg(
+                &FastMessage::data(
+                    msg_id,
+                    FastMessageData::new("listbuckets".into(), json),
+                ),
+                &pool,
+                &mdapi_config,
+                &write_limiter,
+                &metrics,
+                &log,
+            );
+            assert!(bucket_page_result.is_ok());
+            bucket_page_result
+                .unwrap()
+                .iter()
+                .map(|msg| {
+                    serde_json::from_value::<bucket::BucketResponse>(
+                        msg.data.d[0].clone(),
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<bucket::BucketResponse>>()
+        };
+
+    let first_bucket = bucket_page(types::ListOrder::NameDesc, None);
+    assert_eq!(first_bucket[0].name, "countbucket2");
+    assert_eq!(
+        bucket_page(types::ListOrder::NameDesc, Some(&first_bucket[0]))[0].name,
+        "countbucket1"
+    );
+    assert_eq!(
+        bucket_page(types::ListOrder::CreatedDesc, Some(&first_bucket[0]))[0]
+            .name,
+        "countbucket1"
+    );
+
+    // Verify that the repairvnode RPC applies the migrations a vnode schema is
+    // missing, and only those
+
+    let mut conn = pool.claim().unwrap();
+    conn.execute(
+        "DELETE FROM manta_bucket_1.migrations WHERE major = 1 AND minor = 7",
+        &[],
+    )
+    .unwrap();
+    drop(conn);
+
+    let repair_config = config::Config {
+        admin: config::ConfigAdmin {
+            enabled: true,
+            migrations_dir: "../migrations".into(),
+        },
+        ..Default::default()
+    };
+    let repair_vnode = || {
+        let payload = admin::RepairVnodePayload {
+            vnode: 1,
+            request_id,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("repairvnode".into(), json),
+            ),
+            &pool,
+            &repair_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        serde_json::from_value::<admin::RepairVnodeResponse>(
+            result.unwrap()[0].data.d[0].clone(),
+        )
+        .unwrap()
+    };
+
+    let repaired = repair_vnode();
+    assert_eq!(repaired.vnode, 1);
+    assert_eq!(repaired.applied, vec!["0001-0007-object-display-name"]);
+
+    let mut conn = pool.claim().unwrap();
+    let restored: bool = conn
+        .query("SELECT vnode_migration_exists('manta_bucket_1', 1, 7)", &[])
+        .unwrap()[0]
+        .get(0);
+    assert!(restored);
+    drop(conn);
+
+    assert!(repair_vnode().applied.is_empty());
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Statement timeouts
+    //
+    // A connection established with a statement timeout cancels a statement
+    // that runs longer, and the cancellation is reported as a TimeoutError.
+    ////////////////////////////////////////////////////////////////////////////
+    let timeout_pg_config = PostgresConnectionConfig {
+        user: Some(user.into()),
+        password: None,
+        host: None,
+        port: Some(pg_port),
+        database: Some(pg_db.into()),
+        application_name: Some(application_name.into()),
+        tls_config: TlsConfig::disable(),
+    };
+    let timeout_pool_opts = ConnectionPoolOptions {
+        max_connections: Some(1),
+        claim_timeout: None,
+        log: Some(log.clone()),
+        rebalancer_action_delay: None,
+        decoherence_interval: None,
+        connection_check_interval: None,
+    };
+    let timeout_pool = ConnectionPool::new(
+        timeout_pool_opts,
+        StaticIpResolver::new(vec![primary_backend]),
+        AgedConnection::connection_creator(timeout_pg_config, Some(100)),
+    );
+
+    let mut conn = timeout_pool.claim().unwrap();
+    let result = sql::query(
+        sql::Method::ObjectGet,
+        &mut conn,
+        "SELECT pg_sleep(1)",
+        &[],
+        &metrics,
+        &log,
+    );
+    let err = BucketsMdapiError::from(result.unwrap_err());
+    assert_eq!(err.to_string(), "TimeoutError");
+
+    // Statements that complete within the timeout are unaffected
+    let result = sql::query(
+        sql::Method::ObjectGet,
+        &mut conn,
+        "SELECT pg_sleep(0.01)",
+        &[],
+        &metrics,
+        &log,
+    );
+    assert!(result.is_ok());
+    drop(conn);
+
+    // Queries that take longer than the slow query threshold are logged and
+    // counted, and faster queries are not
+
+    let slow_metrics = metrics
+        .clone()
+        .with_slow_query_threshold(Some(std::time::Duration::from_millis(100)));
+    let slow_queries =
+        || metrics.slow_queries.with_label_values(&["Ping"]).get();
+    let slow_before = slow_queries();
+    let mut conn = pool.claim().unwrap();
+    let result = sql::query(
+        sql::Method::Ping,
+        &mut conn,
+        "SELECT pg_sleep(0.2)",
+        &[],
+        &slow_metrics,
+        &log,
+    );
+    assert!(result.is_ok());
+    assert_eq!(slow_queries(), slow_before + 1.0);
+
+    let result = sql::query(
+        sql::Method::Ping,
+        &mut conn,
+        "SELECT 1",
+        &[],
+        &slow_metrics,
+        &log,
+    );
+    assert!(result.is_ok());
+    assert_eq!(slow_queries(), slow_before + 1.0);
+    drop(conn);
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Admission control
+    //
+    // While a simulated replication lag exceeds the threshold creates are shed
+    // as overloaded and reads continue to be served.
+    ////////////////////////////////////////////////////////////////////////////
+    let admission_config = config::ConfigAdmission {
+        enabled: true,
+        max_replication_lag_ms: Some(1000),
+        ..Default::default()
+    };
+    let admission =
+        admission::AdmissionControl::new(&admission_config, &metrics);
+    let admission_cache =
+        cache::ObjectCache::new(&mdapi_config.object_cache, &metrics);
+    let serve_admitted = |method: &str, value: serde_json::Value| {
+        let result = util::handle_connection_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new(method.into(), value),
+            ),
+            &pool,
+            &replica,
+            &health,
+            &mdapi_config,
+            &write_limiter,
+            &byte_budget,
+            &admission,
+            &mut limiter::ListPacer::new(
+                None,
+                std::time::Duration::from_secs(60),
+            ),
+            &admission_cache,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        result.unwrap().0
+    };
+    admission.observe(5000, 0);
+    let shed_before = metrics.admission_shed.get();
+
+    let shed_json =
+        serde_json::to_value(vec![batch_object("shedobject", None)]).unwrap();
+    let shed_result = serve_admitted("createobject", shed_json.clone());
+    let shed_response: BucketsMdapiWrappedError =
+        serde_json::from_value(shed_result[0].data.d[0].clone()).unwrap();
+    assert_eq!(shed_response.error.name, "OverloadedError");
+    assert_eq!(metrics.admission_shed.get(), shed_before + 1.0);
+
+    let get_routed_json =
+        serde_json::to_value(vec![object::GetObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "routedobject".into(),
+            vnode: 1,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            if_content_length: None,
+            include_properties: false,
+            unpin_first: false,
+        }])
+        .unwrap();
+    let get_result = serve_admitted("getobject", get_routed_json);
+    let got: Result<object::ObjectResponse, _> =
+        serde_json::from_value(get_result[0].data.d[0].clone());
+    assert!(got.is_ok());
+
+    // Creates are admitted again once the lag recovers
+    admission.observe(0, 0);
+    let admitted_result = serve_admitted("createobject", shed_json);
+    let admitted: Result<object::ObjectResponse, _> =
+        serde_json::from_value(admitted_result[0].data.d[0].clone());
+    assert!(admitted.is_ok());
+    assert_eq!(metrics.admission_shed.get(), shed_before + 1.0);
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Database error codes
+    //
+    // A database error carries its SQLSTATE code through to the error object
+    // returned to the client.
+    ////////////////////////////////////////////////////////////////////////////
+    let mut conn = pool.claim().unwrap();
+    let duplicate = sql::query(
+        sql::Method::BucketCreate,
+        &mut conn,
+        "INSERT INTO manta_bucket_0.manta_bucket (id, owner, name) \
+         SELECT gen_random_uuid(), owner, name \
+         FROM manta_bucket_0.manta_bucket \
+         WHERE owner = $1 AND name = 'countbucket1'",
+        &[&owner_id],
+        &metrics,
+        &log,
+    );
+    drop(conn);
+    let err = BucketsMdapiError::from(duplicate.unwrap_err());
+    assert_eq!(err.sql_state(), Some("23505"));
+    let fast_err: BucketsMdapiWrappedError =
+        serde_json::from_value(err.into_fast()).unwrap();
+    assert_eq!(fast_err.error.name, "PostgresError");
+    assert_eq!(fast_err.error.code, Some("23505".into()));
+
+    // Errors without a code serialize without one
+    assert_eq!(
+        BucketsMdapiError::postgres_error("unexpected".into()).into_fast(),
+        json!({
+            "error": {
+                "name": "PostgresError",
+                "message": "unexpected"
+            }
+        })
+    );
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Listing size metrics
+    //
+    // Each listing records the number of rows it returned, and listings asking
+    // for the largest page allowed are counted.
+    ////////////////////////////////////////////////////////////////////////////
+    let list_rows = |method: &str| {
+        let histogram = metrics.list_result_rows.with_label_values(&[method]);
+        let metric = &histogram.collect()[0].get_metric()[0];
+        (
+            metric.get_histogram().get_sample_count(),
+            metric.get_histogram().get_sample_sum(),
+        )
+    };
+    let ceiling = |method: &str| {
+        metrics
+            .list_limit_ceiling
+            .with_label_values(&[method])
+            .get()
+    };
+
+    let (count_before, sum_before) = list_rows("listobjects");
+    let ceiling_before = ceiling("listobjects");
+    assert_eq!(
+        list_ordered(types::ListOrder::NameAsc, None),
+        vec!["ordered/a", "ordered/b"]
+    );
+    let (count_after, sum_after) = list_rows("listobjects");
+    assert_eq!(count_after, count_before + 1);
+    assert_eq!(sum_after, sum_before + 2.0);
+    assert_eq!(ceiling("listobjects"), ceiling_before);
+
+    let max_page = object::list::ListObjectsPayload {
+        owner: owner_id,
+        bucket_id,
+        vnode: 1,
+        prefix: Some("ordered/".into()),
+        limit: 1024,
+        marker: None,
+        marker_created: None,
+        request_id,
+        index_filter: None,
+        parent_prefix: None,
+        include_properties: false,
+        report_status: false,
+        modified_after: None,
+        modified_before: None,
+        skip_sharks: false,
+        delimiter: None,
+        count_only: false,
+        order_by: Default::default(),
+        continuation_token: None,
+        report_continuation: false,
+    };
+    let max_page_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "listobjects".into(),
+                serde_json::to_value(vec![max_page]).unwrap(),
+            ),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert_eq!(max_page_result.unwrap().len(), 3);
+    assert_eq!(ceiling("listobjects"), ceiling_before + 1.0);
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Compact responses
+    //
+    // A sparse object is returned without its null and empty fields when the
+    // request asks for a compact response, and complete otherwise.
+    ////////////////////////////////////////////////////////////////////////////
+    let mut sparse_get = serde_json::to_value(object::GetObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "routedobject".into(),
+        vnode: 1,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        if_content_length: None,
+        include_properties: false,
+        unpin_first: false,
+    })
+    .unwrap();
+    let full = serve_routed("getobject", json!([sparse_get.clone()]));
+    sparse_get["compact"] = json!(true);
+    let compact = serve_routed("getobject", json!([sparse_get]));
+
+    let full = full[0].data.d[0].as_object().unwrap().clone();
+    let compact = compact[0].data.d[0].as_object().unwrap().clone();
+    assert_eq!(full["headers"], json!({}));
+    assert_eq!(full["sharks"], json!([]));
+    assert_eq!(full["properties"], json!(null));
+    for field in &["headers", "sharks", "properties"] {
+        assert!(!compact.contains_key(*field));
+    }
+    assert_eq!(compact["id"], full["id"]);
+    assert_eq!(compact["content_length"], full["content_length"]);
+    assert!(compact.len() < full.len());
+    assert!(compact
+        .iter()
+        .all(|(name, value)| full.get(name) == Some(value)));
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Batch bucket object counts
+    //
+    // The objects of each named bucket stored in the requested vnode are
+    // counted in one call, buckets that do not exist are left out, and the
+    // number of names is capped.
+    ////////////////////////////////////////////////////////////////////////////
+    for (bucket_name, objects) in &[("batchcount1", 2), ("batchcount2", 0)] {
+        let json =
+            serde_json::to_value(vec![bucket::create::CreateBucketPayload {
+                owner: owner_id,
+                name: (*bucket_name).into(),
+                vnode: 0,
+                request_id,
+                max_total_bytes: None,
+            }])
+            .unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("createbucket".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        let created: bucket::BucketResponse =
+            serde_json::from_value(result.unwrap()[0].data.d[0].clone())
+                .unwrap();
+
+        // An object stored in another vnode is not counted
+        for (i, vnode) in (0..*objects).map(|i| (i, 0)).chain(vec![(0, 1)]) {
+            let payload = object::create::CreateObjectPayload {
+                owner: owner_id,
+                bucket_id: created.id,
+                name: format!("batchcountobject{}", i),
+                id: Uuid::new_v4(),
+                vnode,
+                content_length: 5,
+                content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+                content_type: "text/plain".into(),
+                headers: HashMap::new(),
+                sharks: vec![],
+                properties: None,
+                request_id,
+                conditions: Default::default(),
+                bucket_vnode: None,
+                indexed_metadata: HashMap::new(),
+                dry_run: false,
+            };
+            let json = serde_json::to_value(vec![payload]).unwrap();
+            let result = util::handle_msg(
+                &FastMessage::data(
+                    msg_id,
+                    FastMessageData::new("createobject".into(), json),
+                ),
+                &pool,
+                &mdapi_config,
+                &write_limiter,
+                &metrics,
+                &log,
+            );
+            let created_object: Result<object::ObjectResponse, _> =
+                serde_json::from_value(result.unwrap()[0].data.d[0].clone());
+            assert!(created_object.is_ok());
+        }
+    }
+
+    let bucket_counts_msg = |names: Vec<String>| {
+        let payload = bucket::batch_counts::BucketCountsPayload {
+            owner: owner_id,
+            vnode: 0,
+            request_id,
+            names,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        FastMessage::data(
+            msg_id,
+            FastMessageData::new("getbucketcounts".into(), json),
+        )
+    };
+
+    let bucket_counts_result = util::handle_msg(
+        &bucket_counts_msg(vec![
+            "batchcount1".into(),
+            "batchcount2".into(),
+            "nosuchbucket".into(),
+        ]),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(bucket_counts_result.is_ok());
+    let bucket_counts: bucket::batch_counts::BucketCountsResponse =
+        serde_json::from_value(
+            bucket_counts_result.unwrap()[0].data.d[0].clone(),
+        )
+        .unwrap();
+    let mut expected_counts = HashMap::new();
+    expected_counts.insert("batchcount1".to_string(), 2);
+    expected_counts.insert("batchcount2".to_string(), 0);
+    assert_eq!(bucket_counts.counts, expected_counts);
+
+    let too_many_names = (0..=bucket::batch_counts::MAX_BUCKET_COUNTS)
+        .map(|i| format!("batchcount{}", i))
+        .collect();
+    let bucket_counts_result = util::handle_msg(
+        &bucket_counts_msg(too_many_names),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    let bucket_counts_limit: BucketsMdapiError = serde_json::from_value(
+        bucket_counts_result.unwrap()[0].data.d[0].clone(),
+    )
+    .unwrap();
+    match bucket_counts_limit {
+        BucketsMdapiError::LimitConstraintError(_) => (),
+        e => panic!("unexpected getbucketcounts limit error {:?}", e),
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Bucket object count
+    //
+    // The objects of one bucket in a vnode are counted, and a bucket without
+    // objects, or that does not exist, has a count of zero.
+    ////////////////////////////////////////////////////////////////////////////
+    let counted_bucket_id = Uuid::new_v4();
+    for i in 0..3 {
+        let payload = object::create::CreateObjectPayload {
+            owner: owner_id,
+            bucket_id: counted_bucket_id,
+            name: format!("countedobject{}", i),
+            id: Uuid::new_v4(),
+            vnode: 1,
+            content_length: 5,
+            content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+            content_type: "text/plain".into(),
+            headers: HashMap::new(),
+            sharks: vec![],
+            properties: None,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            indexed_metadata: HashMap::new(),
+            dry_run: false,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("createobject".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        let created_object: Result<object::ObjectResponse, _> =
+            serde_json::from_value(result.unwrap()[0].data.d[0].clone());
+        assert!(created_object.is_ok());
+    }
+
+    let bucket_object_count = |bucket_id: Uuid, vnode: u64| {
+        let payload = bucket::object_count::BucketObjectCountPayload {
+            owner: owner_id,
+            bucket_id,
+            vnode,
+            request_id,
+        };
+        let json = serde_json::to_value(vec![payload]).unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("getbucketobjectcount".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        let response: bucket::object_count::BucketObjectCountResponse =
+            serde_json::from_value(result.unwrap()[0].data.d[0].clone())
+                .unwrap();
+        assert_eq!(response.bucket_id, bucket_id);
+        response.count
+    };
+
+    assert_eq!(bucket_object_count(counted_bucket_id, 1), 3);
+    assert_eq!(bucket_object_count(counted_bucket_id, 0), 0);
+    assert_eq!(bucket_object_count(Uuid::new_v4(), 1), 0);
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Created object sizes
+    //
+    // A successful createobject records the content length of the object.
+    ////////////////////////////////////////////////////////////////////////////
+    let content_length_totals = || {
+        let histogram = metrics.object_create_content_length.collect()[0]
+            .get_metric()[0]
+            .get_histogram()
+            .clone();
+        (histogram.get_sample_sum(), histogram.get_sample_count())
+    };
+    let (sum_before, count_before) = content_length_totals();
+    let sized_object_payload = object::create::CreateObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "sizedobject".into(),
+        id: Uuid::new_v4(),
+        vnode: 1,
+        content_length: 4096,
+        content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+        content_type: "text/plain".into(),
+        headers: HashMap::new(),
+        sharks: vec![],
+        properties: None,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        indexed_metadata: HashMap::new(),
+        dry_run: false,
+    };
+    let create_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "createobject".into(),
+                serde_json::to_value(vec![sized_object_payload]).unwrap(),
+            ),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+    let (sum_after, count_after) = content_length_totals();
+    assert_eq!(sum_after, sum_before + 4096.0);
+    assert_eq!(count_after, count_before + 1);
+
+    ////////////////////////////////////////////////////////////////////////////
+    // In-flight byte budget
+    //
+    // Requests are refused with an OverloadedError while the requests in
+    // progress hold the whole byte budget, and served once the bytes are
+    // released. The bytes of a request and its response are held until the
+    // response has been sent.
+    ////////////////////////////////////////////////////////////////////////////
+    let budget = limiter::ByteBudget::new(Some(4096), &metrics);
+    let budget_cache =
+        cache::ObjectCache::new(&mdapi_config.object_cache, &metrics);
+    let serve_budgeted = || {
+        let result = util::handle_connection_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new(
+                    "getbucket".into(),
+                    serde_json::to_value(vec![bucket::GetBucketPayload {
+                        owner: owner_id,
+                        name: "countbucket1".into(),
+                        vnode: 0,
+                        request_id,
+                    }])
+                    .unwrap(),
+                ),
+            ),
+            &pool,
+            &replica,
+            &health,
+            &mdapi_config,
+            &write_limiter,
+            &budget,
+            &admission_control,
+            &mut limiter::ListPacer::new(
+                None,
+                std::time::Duration::from_secs(60),
+            ),
+            &budget_cache,
+            &metrics,
+            &log,
+        );
+        assert!(result.is_ok());
+        result.unwrap()
+    };
+    let in_flight_before = metrics.in_flight_bytes.get();
+
+    let saturating_permit = budget.try_reserve(4096);
+    assert!(saturating_permit.is_some());
+    assert_eq!(metrics.in_flight_bytes.get(), in_flight_before + 4096.0);
+    let (budget_result, budget_permit) = serve_budgeted();
+    assert!(budget_permit.is_none());
+    let budget_error: BucketsMdapiWrappedError =
+        serde_json::from_value(budget_result[0].data.d[0].clone()).unwrap();
+    assert_eq!(budget_error.error.name, "OverloadedError");
+    assert_eq!(budget_error.error.message, "in-flight byte limit reached");
+
+    drop(saturating_permit);
+    let (budget_result, budget_permit) = serve_budgeted();
+    let budget_bucket: Result<bucket::BucketResponse, _> =
+        serde_json::from_value(budget_result[0].data.d[0].clone());
+    assert!(budget_bucket.is_ok());
+
+    // The response holds its bytes until it is sent, and a request
+    // arriving in the meantime that would exceed the budget is refused
+    assert!(budget_permit.is_some());
+    assert!(metrics.in_flight_bytes.get() > in_flight_before);
+    assert!(budget.try_reserve(4096).is_none());
+    drop(budget_permit);
+    assert_eq!(metrics.in_flight_bytes.get(), in_flight_before);
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Vnode usage
+    //
+    // The usage of a vnode counts its buckets and objects and sums the content
+    // length of its objects.
+    ////////////////////////////////////////////////////////////////////////////
+    let vnode_usage = |vnode: u64| {
+        let json = serde_json::to_value(vec![vnode::VnodeUsagePayload {
+            vnode,
+            request_id,
+        }])
+        .unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("getvnodeusage".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        let usage: vnode::VnodeUsageResponse =
+            serde_json::from_value(result.unwrap()[0].data.d[0].clone())
+                .unwrap();
+        usage
+    };
+
+    let usage_before = vnode_usage(1);
+    assert_eq!(usage_before.vnode, 1);
+    assert!(usage_before.objects > 0);
+    assert!(vnode_usage(0).buckets > 0);
+
+    let usage_object_payload = object::create::CreateObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "usageobject".into(),
+        id: Uuid::new_v4(),
+        vnode: 1,
+        content_length: 10,
+        content_md5: "xzY5jJbR9rcrMRhlcmi/8g==".into(),
+        content_type: "text/plain".into(),
+        headers: HashMap::new(),
+        sharks: vec![],
+        properties: None,
+        request_id,
+        conditions: Default::default(),
+        bucket_vnode: None,
+        indexed_metadata: HashMap::new(),
+        dry_run: false,
+    };
+    let create_object_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new(
+                "createobject".into(),
+                serde_json::to_value(vec![usage_object_payload]).unwrap(),
+            ),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(create_object_result.is_ok());
+
+    let usage_after = vnode_usage(1);
+    assert_eq!(usage_after.objects, usage_before.objects + 1);
+    assert_eq!(usage_after.bytes, usage_before.bytes + 10);
+    assert_eq!(usage_after.buckets, usage_before.buckets);
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Pinned objects
+    //
+    // A pinned object is neither deleted nor replaced until its pin is cleared,
+    // either with pinobject or by a delete that asks to unpin it first.
+    ////////////////////////////////////////////////////////////////////////////
+    let pin_request = |method: &str, value: serde_json::Value| {
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new(method.into(), value),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        result.unwrap()[0].data.d[0].clone()
+    };
+    let create_pinned = || {
+        let json =
+            serde_json::to_value(vec![batch_object("pinnedobject", None)])
+                .unwrap();
+        pin_request("createobject", json)
+    };
+    let set_pin = |pinned: bool| {
+        let json = serde_json::to_value(vec![object::pin::PinObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "pinnedobject".into(),
+            vnode: 1,
+            request_id,
+            pinned,
+        }])
+        .unwrap();
+        let response: object::pin::PinObjectResponse =
+            serde_json::from_value(pin_request("pinobject", json)).unwrap();
+        response
+    };
+    let delete_pinned = |unpin_first: bool| {
+        let json = serde_json::to_value(vec![object::GetObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: "pinnedobject".into(),
+            vnode: 1,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            if_content_length: None,
+            include_properties: false,
+            unpin_first,
+        }])
+        .unwrap();
+        pin_request("deleteobject", json)
+    };
+    let pinned_error = |value: serde_json::Value| {
+        let error: BucketsMdapiWrappedError =
+            serde_json::from_value(value).unwrap();
+        error.error.name
+    };
+
+    let created_pinned: object::ObjectResponse =
+        serde_json::from_value(create_pinned()).unwrap();
+    let pin_response = set_pin(true);
+    assert_eq!(pin_response.id, created_pinned.id);
+    assert!(pin_response.pinned);
+
+    // Deleting or replacing the pinned object is rejected
+    assert_eq!(pinned_error(delete_pinned(false)), "ObjectPinned");
+    assert_eq!(pinned_error(create_pinned()), "ObjectPinned");
+
+    // Once unpinned the object is deleted
+    assert!(!set_pin(false).pinned);
+    let deleted: Result<Vec<object::DeleteObjectResponse>, _> =
+        serde_json::from_value(delete_pinned(false));
+    assert_eq!(deleted.unwrap()[0].id, created_pinned.id);
+
+    // A delete may unpin a pinned object first
+    let recreated_pinned: object::ObjectResponse =
+        serde_json::from_value(create_pinned()).unwrap();
+    assert!(set_pin(true).pinned);
+    let deleted: Result<Vec<object::DeleteObjectResponse>, _> =
+        serde_json::from_value(delete_pinned(true));
+    assert_eq!(deleted.unwrap()[0].id, recreated_pinned.id);
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Server time
+    //
+    // The server time reports the database clock, which sets object modified
+    // times, along with the clock of the process and the skew between them.
+    ////////////////////////////////////////////////////////////////////////////
+    let get_time = || {
+        let json =
+            serde_json::to_value(vec![clock::GetTimePayload { request_id }])
+                .unwrap();
+        let result = util::handle_msg(
+            &FastMessage::data(
+                msg_id,
+                FastMessageData::new("gettime".into(), json),
+            ),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        let time: clock::GetTimeResponse =
+            serde_json::from_value(result.unwrap()[0].data.d[0].clone())
+                .unwrap();
+        time
+    };
+
+    let time_before = chrono::Utc::now();
+    let first_time = get_time();
+    let second_time = get_time();
+    let time_after = chrono::Utc::now();
+
+    // The database runs on the test system, so its clock agrees with the clock
+    // of the test to well within a minute.
+    let tolerance = chrono::Duration::seconds(60);
+    assert!(first_time.time > time_before - tolerance);
+    assert!(second_time.time < time_after + tolerance);
+    assert!(first_time.app_time >= time_before);
+    assert!(second_time.app_time <= time_after);
+    assert!(first_time.app_skew_ms.abs() < tolerance.num_milliseconds());
+    assert_eq!(
+        first_time.app_skew_ms,
+        (first_time.app_time - first_time.time).num_milliseconds()
+    );
+
+    // Successive requests never see either clock go backwards
+    assert!(second_time.time >= first_time.time);
+    assert!(second_time.app_time >= first_time.app_time);
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Delete by tag
+    //
+    // The objects of a bucket tagged with an indexed metadata entry are
+    // deleted in capped batches, resuming from the marker of each response,
+    // while pinned objects and objects without the tag are left in place.
+    ////////////////////////////////////////////////////////////////////////////
+    for (name, indexed_metadata) in vec![
+        ("tagdelete-a", json!({ "expired": true })),
+        ("tagdelete-b", json!({ "expired": true })),
+        ("tagdelete-c", json!({ "expired": true })),
+        ("tagdelete-keep", json!({ "expired": false })),
+        ("tagdelete-pinned", json!({ "expired": true })),
+        ("tagdelete-untagged", json!({})),
+    ] {
+        let create_object_result = util::handle_msg(
+            &indexed_object_fast_msg(name, indexed_metadata),
+            &pool,
+            &mdapi_config,
+            &write_limiter,
+            &metrics,
+            &log,
+        );
+        let created: Result<object::ObjectResponse, _> = serde_json::from_value(
+            create_object_result.unwrap()[0].data.d[0].clone(),
+        );
+        assert!(created.is_ok());
+    }
+    let pin_json = serde_json::to_value(vec![object::pin::PinObjectPayload {
+        owner: owner_id,
+        bucket_id,
+        name: "tagdelete-pinned".into(),
+        vnode: 1,
+        request_id,
+        pinned: true,
+    }])
+    .unwrap();
+    let _ = pin_request("pinobject", pin_json);
+
+    let delete_by_tag = |confirm: Option<Uuid>, marker: Option<String>| {
+        let json = serde_json::to_value(vec![
+            object::delete_by_tag::DeleteByTagPayload {
+                owner: owner_id,
+                bucket_id,
+                vnode: 1,
+                request_id,
+                key: "expired".into(),
+                value: serde_json::from_value(json!(true)).unwrap(),
+                limit: 2,
+                marker,
+                confirm,
+                bucket_vnode: None,
+            },
+        ])
+        .unwrap();
+        pin_request("deleteobjectsbytag", json)
+    };
+
+    // The request must confirm the bucket whose objects it deletes
+    let unconfirmed: BucketsMdapiWrappedError =
+        serde_json::from_value(delete_by_tag(None, None)).unwrap();
+    assert_eq!(unconfirmed.error.name, "BadRequestError");
+
+    let first_batch: object::delete_by_tag::DeleteByTagResponse =
+        serde_json::from_value(delete_by_tag(Some(bucket_id), None)).unwrap();
+    assert_eq!(first_batch.deleted, 2);
+    assert_eq!(first_batch.deleted_bytes, 10);
+    assert_eq!(first_batch.next_marker, Some("tagdelete-b".to_string()));
+
+    let second_batch: object::delete_by_tag::DeleteByTagResponse =
+        serde_json::from_value(delete_by_tag(
+            Some(bucket_id),
+            first_batch.next_marker,
+        ))
+        .unwrap();
+    assert_eq!(second_batch.deleted, 1);
+    assert_eq!(second_batch.next_marker, None);
+
+    // Repeating a request deletes nothing more
+    let repeated_batch: object::delete_by_tag::DeleteByTagResponse =
+        serde_json::from_value(delete_by_tag(Some(bucket_id), None)).unwrap();
+    assert_eq!(repeated_batch.deleted, 0);
+    assert_eq!(repeated_batch.next_marker, None);
+
+    let tag_delete_exists = |name: &str| {
+        let json = serde_json::to_value(vec![object::GetObjectPayload {
+            owner: owner_id,
+            bucket_id,
+            name: name.into(),
+            vnode: 1,
+            request_id,
+            conditions: Default::default(),
+            bucket_vnode: None,
+            if_content_length: None,
+            include_properties: false,
+            unpin_first: false,
+        }])
+        .unwrap();
+        let found: Result<object::ObjectResponse, _> =
+            serde_json::from_value(pin_request("getobject", json));
+        found.is_ok()
+    };
+    assert!(!tag_delete_exists("tagdelete-a"));
+    assert!(!tag_delete_exists("tagdelete-b"));
+    assert!(!tag_delete_exists("tagdelete-c"));
+    assert!(tag_delete_exists("tagdelete-keep"));
+    assert!(tag_delete_exists("tagdelete-pinned"));
+    assert!(tag_delete_exists("tagdelete-untagged"));
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Health checks are served while the main pool is saturated
+    ////////////////////////////////////////////////////////////////////////////
+
+    // Hold every connection of the main pool so that a request served by it
+    // would wait indefinitely
+    let saturating_claims: Vec<_> =
+        (0..5).map(|_| pool.claim().unwrap()).collect();
+
+    let ping_json =
+        serde_json::to_value(vec![health::PingPayload { request_id }]).unwrap();
+    let ping_result = util::handle_connection_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("ping".into(), ping_json),
+        ),
+        &pool,
+        &replica,
+        &health,
+        &mdapi_config,
+        &write_limiter,
+        &byte_budget,
+        &admission_control,
+        &mut limiter::ListPacer::new(None, std::time::Duration::from_secs(60)),
+        &cache::ObjectCache::new(&mdapi_config.object_cache, &metrics),
+        &metrics,
+        &log,
+    );
+    assert!(ping_result.is_ok());
+    let ping_response: health::PingResponse =
+        serde_json::from_value(ping_result.unwrap().0[0].data.d[0].clone())
+            .unwrap();
+    assert!(ping_response.ok);
+
+    drop(saturating_claims);
+
+    ////////////////////////////////////////////////////////////////////////////
+    // The trace id of a traced request appears in the log records emitted
+    // while handling it
+    ////////////////////////////////////////////////////////////////////////////
+
+    let trace_records: CapturedRecords = Arc::new(Mutex::new(vec![]));
+    let trace_log =
+        Logger::root(CaptureDrain(trace_records.clone()).fuse(), o!());
+    let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+    let traced_json = json!([{
+        "request_id": request_id,
+        "trace_id": trace_id,
+        "span_id": "00f067aa0ba902b7",
+    }]);
+    let traced_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("gettime".into(), traced_json),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &trace_log,
+    );
+    assert!(traced_result.is_ok());
+
+    {
+        let records = trace_records.lock().unwrap();
+        let parsed = records
+            .iter()
+            .find(|(msg, _)| msg == "parsed payload")
+            .expect("no parsed payload log record");
+        assert_eq!(
+            parsed.1.get("trace_id").map(String::as_str),
+            Some(trace_id)
+        );
+        assert_eq!(parsed.1.get("req_id"), Some(&request_id.to_string()));
+        assert!(records
+            .iter()
+            .all(|(_, kvs)| kvs.get("trace_id").map(String::as_str)
+                == Some(trace_id)));
+    }
+
+    // Requests without a trace id are logged as before
+    trace_records.lock().unwrap().clear();
+    let untraced_json = json!([{ "request_id": request_id }]);
+    let untraced_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("gettime".into(), untraced_json),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &trace_log,
+    );
+    assert!(untraced_result.is_ok());
+    let untraced_records = trace_records.lock().unwrap();
+    assert!(untraced_records
+        .iter()
+        .any(|(msg, _)| msg == "parsed payload"));
+    assert!(untraced_records
+        .iter()
+        .all(|(_, kvs)| !kvs.contains_key("trace_id")));
+
+    ////////////////////////////////////////////////////////////////////////////
+    // The metrics server binds an operating system assigned port for port 0
+    ////////////////////////////////////////////////////////////////////////////
+
+    let (metrics_addr, metrics_server) =
+        metrics::bind_server("127.0.0.1", 0, metrics.clone(), &log).unwrap();
+    assert_ne!(metrics_addr.port(), 0);
+    std::thread::spawn(move || hyper::rt::run(metrics_server));
+
+    let mut metrics_stream =
+        std::net::TcpStream::connect(metrics_addr).unwrap();
+    metrics_stream
+        .write_all(b"GET /metrics HTTP/1.0\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut metrics_scrape = String::new();
+    metrics_stream.read_to_string(&mut metrics_scrape).unwrap();
+    assert!(metrics_scrape.starts_with("HTTP/1.0 200 OK"));
+    assert!(metrics_scrape.contains("metrics_request_count"));
+
+    ////////////////////////////////////////////////////////////////////////////
+    // Pool connections are counted as in use while claimed by a request
+    ////////////////////////////////////////////////////////////////////////////
+
+    metrics.init_pool_connections(metrics::PRIMARY_POOL, 5);
+    let pool_in_use = || {
+        metrics
+            .pool_connections_in_use
+            .with_label_values(&[metrics::PRIMARY_POOL])
+            .get()
+    };
+    let pool_idle = || {
+        metrics
+            .pool_connections_idle
+            .with_label_values(&[metrics::PRIMARY_POOL])
+            .get()
+    };
+
+    let pool_claim = metrics.claim_pool_connection(metrics::PRIMARY_POOL);
+    assert_eq!(pool_in_use(), 1.0);
+    assert_eq!(pool_idle(), 4.0);
+    drop(pool_claim);
+    assert_eq!(pool_in_use(), 0.0);
+    assert_eq!(pool_idle(), 5.0);
+
+    // The connection claimed by a request is released with its response
+    let pool_gauge_json =
+        serde_json::to_value(vec![clock::GetTimePayload { request_id }])
+            .unwrap();
+    let pool_gauge_result = util::handle_msg(
+        &FastMessage::data(
+            msg_id,
+            FastMessageData::new("gettime".into(), pool_gauge_json),
+        ),
+        &pool,
+        &mdapi_config,
+        &write_limiter,
+        &metrics,
+        &log,
+    );
+    assert!(pool_gauge_result.is_ok());
+    assert_eq!(pool_in_use(), 0.0);
+    assert_eq!(pool_idle(), 5.0);
+}
+
+// The message and key-value pairs of each captured log record
+type CapturedRecords = Arc<Mutex<Vec<(String, HashMap<String, String>)>>>;
+
+struct CaptureSerializer(HashMap<String, String>);
+
+impl Serializer for CaptureSerializer {
+    fn emit_arguments(
+        &mut self,
+        key: Key,
+        val: &fmt::Arguments,
+    ) -> slog::Result {
+        self.0.insert(key.to_string(), val.to_string());
+        Ok(())
+    }
+}
+
+// A drain that keeps every log record, so that the fields added to the log
+// output while handling a request can be checked
+struct CaptureDrain(CapturedRecords);
+
+impl Drain for CaptureDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(
+        &self,
+        record: &Record,
+        values: &OwnedKVList,
+    ) -> Result<(), slog::Never> {
+        let mut ser = CaptureSerializer(HashMap::new());
+        let _ = values.serialize(record, &mut ser);
+        let _ = record.kv().serialize(record, &mut ser);
+        self.0
+            .lock()
+            .unwrap()
+            .push((record.msg().to_string(), ser.0));
+        Ok(())
+    }
 }
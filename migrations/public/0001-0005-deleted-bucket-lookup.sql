@@ -0,0 +1,25 @@
+START TRANSACTION;
+
+SELECT execute($$
+
+CREATE OR REPLACE FUNCTION deleted_bucket_ids(ids uuid[])
+RETURNS TABLE(id uuid) AS $INNER$
+DECLARE
+  schema RECORD;
+BEGIN
+  FOR schema IN EXECUTE
+      'SELECT schema_name FROM information_schema.schemata WHERE left(schema_name, 13) = ''manta_bucket_'''
+  LOOP
+    RETURN QUERY EXECUTE
+      format('SELECT id FROM %I.manta_bucket_deleted_bucket WHERE id = ANY($1)', schema.schema_name)
+      USING ids;
+  END LOOP;
+END;
+$INNER$ LANGUAGE plpgsql;
+
+INSERT INTO migrations (major, minor, note) VALUES (1, 5, 'Create deleted bucket lookup function for the orphan scan');
+
+$$)
+WHERE NOT public_migration_exists(1, 5);
+
+COMMIT;
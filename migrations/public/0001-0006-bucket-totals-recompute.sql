@@ -0,0 +1,25 @@
+START TRANSACTION;
+
+SELECT execute($$
+
+CREATE OR REPLACE FUNCTION recompute_bucket_totals(bucket_schema text)
+RETURNS VOID AS $INNER$
+DECLARE
+  schema RECORD;
+BEGIN
+  EXECUTE format('UPDATE %I.manta_bucket SET total_bytes = 0, total_objects = 0', bucket_schema);
+  FOR schema IN EXECUTE
+      'SELECT schema_name FROM information_schema.schemata WHERE left(schema_name, 13) = ''manta_bucket_'''
+  LOOP
+    EXECUTE
+      format('UPDATE %I.manta_bucket b SET total_bytes = b.total_bytes + t.total_bytes, total_objects = b.total_objects + t.total_objects FROM (SELECT o.owner, o.bucket_id, COALESCE(sum(o.content_length), 0) AS total_bytes, count(*) AS total_objects FROM %I.manta_bucket_object o JOIN %I.manta_bucket ob ON o.owner = ob.owner AND o.bucket_id = ob.id GROUP BY o.owner, o.bucket_id) t WHERE b.owner = t.owner AND b.id = t.bucket_id', bucket_schema, schema.schema_name, bucket_schema);
+  END LOOP;
+END;
+$INNER$ LANGUAGE plpgsql;
+
+INSERT INTO migrations (major, minor, note) VALUES (1, 6, 'Create bucket totals recompute function');
+
+$$)
+WHERE NOT public_migration_exists(1, 6);
+
+COMMIT;
@@ -6,6 +6,7 @@
 /// implementations in this module.  These can first be overridden by a config
 /// file specified with `-c <config>`, and then overridden again by specific
 /// command line arguments.
+use std::collections::HashMap;
 use std::convert::Into;
 use std::ffi::OsStr;
 use std::fs;
@@ -14,7 +15,8 @@ use std::str::FromStr;
 
 use clap::{value_t, ArgMatches};
 use num_cpus;
-use serde_derive::Deserialize;
+use serde::Serializer;
+use serde_derive::{Deserialize, Serialize};
 
 use cueball_manatee_primary_resolver::ZkConnectString;
 use cueball_postgres_connection::TlsConnectMode;
@@ -24,7 +26,7 @@ use cueball_postgres_connection::TlsConnectMode;
 ///
 /// This is necessary only because of there is not a serde `Derserialize`
 /// implementation available for the `slog::Level` type.
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub enum LogLevel {
     /// Log critical level only
     #[serde(alias = "critical")]
@@ -94,7 +96,7 @@ impl From<LogLevel> for slog::Level {
     }
 }
 
-#[derive(Clone, Default, Deserialize)]
+#[derive(Clone, Default, Deserialize, Serialize)]
 pub struct Config {
     /// The logging configuration entries
     pub log: ConfigLog,
@@ -111,28 +113,236 @@ pub struct Config {
     /// The configuration entries controlling the behavior of the tokio runtime
     /// used by buckets-mdapi.
     pub tokio: ConfigTokio,
+    /// The configuration entries controlling garbage collection behavior
+    #[serde(default)]
+    pub gc: ConfigGc,
+    /// The configuration entries controlling bucket quota enforcement
+    #[serde(default)]
+    pub quota: ConfigQuota,
+    /// The configuration entries controlling the `selftest` RPC
+    #[serde(default)]
+    pub selftest: ConfigSelfTest,
+    /// The configuration entries controlling the object audit log
+    #[serde(default)]
+    pub audit: ConfigAudit,
+    /// The configuration entries controlling validation of object placement
+    #[serde(default)]
+    pub placement: ConfigPlacement,
+    /// The configuration entries controlling object listings
+    #[serde(default)]
+    pub listing: ConfigListing,
+    /// The configuration entries controlling object timestamps
+    #[serde(default)]
+    pub timestamps: ConfigTimestamps,
+    /// The configuration entries controlling the storage of object properties
+    #[serde(default)]
+    pub properties: ConfigProperties,
+    /// The configuration entries controlling the background orphan scan
+    #[serde(default)]
+    pub orphan_scan: ConfigOrphanScan,
+    /// The configuration entries controlling the handling of object names
+    #[serde(default)]
+    pub names: ConfigNames,
+    /// The configuration entries controlling the administrative RPCs
+    #[serde(default)]
+    pub admin: ConfigAdmin,
+    /// The configuration entries controlling the cache of object metadata
+    #[serde(default)]
+    pub object_cache: ConfigObjectCache,
+    /// The configuration entries controlling the use of a database read replica
+    #[serde(default)]
+    pub replica: ConfigReplica,
+    /// The configuration entries controlling the shedding of creates while the
+    /// database is overloaded
+    #[serde(default)]
+    pub admission: ConfigAdmission,
+    /// The configuration entries controlling the `ping` health check RPC
+    #[serde(default)]
+    pub health_check: ConfigHealthCheck,
 }
 
-#[derive(Clone, Deserialize)]
+/// The value reported in place of a sensitive configuration entry when the
+/// configuration is serialized.
+pub const REDACTED: &str = "[redacted]";
+
+// Serialize a sensitive configuration entry, such as the path to a TLS
+// certificate, without revealing its value. An entry that is not set is
+// serialized as `null`.
+fn serialize_redacted<T, S>(
+    value: &Option<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match value {
+        Some(_) => serializer.serialize_str(REDACTED),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_display<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: std::fmt::Display,
+    S: Serializer,
+{
+    serializer.collect_str(value)
+}
+
+fn serialize_tls_mode<S>(
+    mode: &TlsConnectMode,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let mode = match mode {
+        TlsConnectMode::Disable => "disable",
+        TlsConnectMode::Allow => "allow",
+        TlsConnectMode::Prefer => "prefer",
+        TlsConnectMode::Require => "require",
+        TlsConnectMode::VerifyCa => "verify-ca",
+        TlsConnectMode::VerifyFull => "verify-full",
+    };
+    serializer.serialize_str(mode)
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ConfigLog {
     /// The logging level for buckets-mdapi to use.
     pub level: LogLevel,
+    /// The configuration entries controlling the rotated log file
+    #[serde(default)]
+    pub file: ConfigLogFile,
 }
 
 impl Default for ConfigLog {
     fn default() -> Self {
         Self {
             level: LogLevel::Info,
+            file: ConfigLogFile::default(),
         }
     }
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigLogFile {
+    /// Write the log to a file, rotated as it grows, in addition to stdout.
+    /// The default is `false`.
+    pub enabled: bool,
+    /// The path of the log file.
+    pub path: String,
+    /// The size in bytes the log file may reach before it is rotated.
+    pub max_bytes: u64,
+    /// The age in seconds the log file may reach before it is rotated. `None`
+    /// means the log file is only rotated by size.
+    pub max_age_secs: Option<u64>,
+    /// The number of rotated log files retained.
+    pub retained_files: usize,
+}
+
+impl Default for ConfigLogFile {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "/var/log/buckets-mdapi.log".into(),
+            max_bytes: 100 * 1024 * 1024,
+            max_age_secs: None,
+            retained_files: 5,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ConfigServer {
     /// The IP address buckets-mdapi should use to listen for incoming connections.
     pub host: String,
     /// The port number buckets-mdapi should listen on for incoming connections.
     pub port: u16,
+    /// The maximum number of write transactions (object and bucket creates,
+    /// updates, and deletes) allowed to run concurrently. `None` means writes
+    /// are only bounded by the connection pool.
+    #[serde(default)]
+    pub max_write_transactions: Option<usize>,
+    /// The maximum total bytes of the requests in progress and their
+    /// responses. `None` means in-flight bytes are not limited.
+    #[serde(default)]
+    pub max_in_flight_bytes: Option<u64>,
+    /// The largest vnode number buckets-mdapi accepts in a request. Requests
+    /// for any greater vnode are rejected before a schema name is built.
+    #[serde(default = "default_max_vnode")]
+    pub max_vnode: u64,
+    /// The maximum number of consecutive list requests served on a single
+    /// connection before further list requests are throttled. `None` means
+    /// listing is not throttled.
+    #[serde(default)]
+    pub max_consecutive_lists: Option<u32>,
+    /// The time in milliseconds a connection must go without a list request
+    /// before its count of consecutive list requests is reset.
+    #[serde(default = "default_list_idle_ms")]
+    pub list_idle_ms: u64,
+    /// The number of rows a mutation of a single object, such as an object
+    /// update or delete, is expected to affect at most. A mutation affecting
+    /// more rows is logged and counted as a wide mutation.
+    #[serde(default = "default_wide_mutation_rows")]
+    pub wide_mutation_rows: u64,
+    /// How an object read from the database with a `content_md5` that is not
+    /// the length of an MD5 digest is handled.
+    #[serde(default)]
+    pub content_md5_check: ContentMd5Check,
+    /// How a stored header value that is not valid UTF-8 is returned.
+    #[serde(default)]
+    pub header_value_fallback: HeaderValueFallback,
+    /// Whether a `createobjectbatch` request in which any object fails creates
+    /// none of its objects.
+    #[serde(default)]
+    pub batch_create_atomic: bool,
+    /// The statement timeout in milliseconds applied to requests for methods
+    /// not named in `method_timeouts_ms`. `None` means those requests use the
+    /// statement timeout of the database session.
+    #[serde(default)]
+    pub default_timeout_ms: Option<u64>,
+    /// The statement timeout in milliseconds applied to requests for each
+    /// method named.
+    #[serde(default)]
+    pub method_timeouts_ms: HashMap<String, u64>,
+    /// The maximum number of seconds to wait on SIGTERM for the requests in
+    /// progress to complete before the process is forced to exit.
+    #[serde(
+        default = "default_shutdown_drain_timeout_secs",
+        alias = "shutdown_timeout_secs"
+    )]
+    pub shutdown_drain_timeout_secs: u64,
+    /// The content types objects may be created or updated with, each either
+    /// a full type such as `text/plain` or a wildcard such as `image/*`.
+    /// `None` means any content type is allowed.
+    #[serde(default)]
+    pub allowed_content_types: Option<Vec<String>>,
+    /// The longest object or bucket name, in bytes, accepted in a request.
+    #[serde(default = "default_max_name_length")]
+    pub max_name_length: usize,
+}
+
+// The Manta buckets hash ring is created with one million vnodes by default.
+fn default_max_vnode() -> u64 {
+    999_999
+}
+
+fn default_list_idle_ms() -> u64 {
+    1000
+}
+
+fn default_wide_mutation_rows() -> u64 {
+    1
+}
+
+fn default_shutdown_drain_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_name_length() -> usize {
+    1024
 }
 
 impl Default for ConfigServer {
@@ -140,19 +350,90 @@ impl Default for ConfigServer {
         Self {
             host: "127.0.0.1".into(),
             port: 2030,
+            max_write_transactions: None,
+            max_in_flight_bytes: None,
+            max_vnode: default_max_vnode(),
+            max_consecutive_lists: None,
+            list_idle_ms: default_list_idle_ms(),
+            wide_mutation_rows: default_wide_mutation_rows(),
+            content_md5_check: ContentMd5Check::default(),
+            header_value_fallback: HeaderValueFallback::default(),
+            batch_create_atomic: false,
+            default_timeout_ms: None,
+            method_timeouts_ms: HashMap::new(),
+            shutdown_drain_timeout_secs: default_shutdown_drain_timeout_secs(),
+            allowed_content_types: None,
+            max_name_length: default_max_name_length(),
         }
     }
 }
 
-#[derive(Clone, Deserialize)]
+/// The handling of an object whose stored `content_md5` does not decode to
+/// the 16 bytes of an MD5 digest, which indicates corruption of the object's
+/// metadata.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ContentMd5Check {
+    /// Return the object without checking its `content_md5`.
+    #[serde(alias = "ignore")]
+    Ignore,
+    /// Return the object, but log a warning and count the corruption.
+    #[serde(alias = "warn")]
+    Warn,
+    /// Count the corruption and fail the request with a `MetadataCorruption`
+    /// error.
+    #[serde(alias = "reject")]
+    Reject,
+}
+
+impl Default for ContentMd5Check {
+    fn default() -> Self {
+        ContentMd5Check::Ignore
+    }
+}
+
+/// The encoding of a stored object header value that is not valid UTF-8, such
+/// as one written by a legacy writer, when the object is returned.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum HeaderValueFallback {
+    /// Replace each invalid sequence of the value with U+FFFD.
+    #[serde(alias = "lossy")]
+    Lossy,
+    /// Return the bytes of the value base64 encoded.
+    #[serde(alias = "base64")]
+    Base64,
+}
+
+impl Default for HeaderValueFallback {
+    fn default() -> Self {
+        HeaderValueFallback::Lossy
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ConfigMetrics {
-    /// The IP address buckets-mdapi should use to listen for metrics requests
+    /// The IPv4 or IPv6 address buckets-mdapi should use to listen for
+    /// metrics requests
     pub host: String,
     /// The port number buckets-mdapi should listen on for incoming metrics request connections.
+    /// A port of `0` has the operating system assign a port, which is logged.
     pub port: u16,
     pub datacenter: String,
     pub service: String,
     pub server: String,
+    /// The configuration entries controlling the StatsD metrics emitter
+    #[serde(default)]
+    pub statsd: ConfigStatsd,
+    /// The configuration entries controlling the per-owner usage metrics
+    #[serde(default)]
+    pub owner_usage: ConfigOwnerUsage,
+    /// The number of groups vnodes are divided into wherever a metric is
+    /// labelled by vnode. Vnode `v` is labelled with group `v % vnode_groups`.
+    #[serde(default = "default_vnode_groups")]
+    pub vnode_groups: u64,
+}
+
+fn default_vnode_groups() -> u64 {
+    16
 }
 
 impl Default for ConfigMetrics {
@@ -163,11 +444,79 @@ impl Default for ConfigMetrics {
             datacenter: "development".into(),
             service: "1.buckets-mdapi.localhost".into(),
             server: "127.0.0.1".into(),
+            statsd: ConfigStatsd::default(),
+            owner_usage: ConfigOwnerUsage::default(),
+            vnode_groups: default_vnode_groups(),
         }
     }
 }
 
-#[derive(Clone, Deserialize)]
+/// The line formats understood by StatsD servers.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum StatsdFormat {
+    /// Plain StatsD lines. Metric labels are appended to the metric name.
+    #[serde(alias = "statsd")]
+    StatsD,
+    /// DogStatsD lines. Metric labels are sent as tags.
+    #[serde(alias = "dogstatsd")]
+    DogStatsD,
+}
+
+impl Default for StatsdFormat {
+    fn default() -> Self {
+        StatsdFormat::StatsD
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigStatsd {
+    /// Mirror the request, connection claim, and fast request metrics to a
+    /// StatsD server in addition to serving them from the metrics server. The
+    /// default is `false`.
+    pub enabled: bool,
+    /// The host name or IP address of the StatsD server.
+    pub host: String,
+    /// The UDP port number of the StatsD server.
+    pub port: u16,
+    /// The prefix added to the name of every emitted metric.
+    pub prefix: String,
+    /// The line format the StatsD server expects.
+    pub format: StatsdFormat,
+}
+
+impl Default for ConfigStatsd {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "127.0.0.1".into(),
+            port: 8125,
+            prefix: "buckets_mdapi".into(),
+            format: StatsdFormat::default(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigOwnerUsage {
+    /// Count the requests and object bytes of the heaviest owners and export
+    /// them as metrics. The default is `false`.
+    pub enabled: bool,
+    /// The number of heaviest owners exported.
+    pub top_n: usize,
+}
+
+impl Default for ConfigOwnerUsage {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            top_n: 10,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ConfigDatabase {
     /// The database admin username
     pub admin_user: String,
@@ -182,9 +531,50 @@ pub struct ConfigDatabase {
     /// The name of the application to use when connecting to the database
     pub application_name: String,
     /// The TLS connection mode
+    #[serde(serialize_with = "serialize_tls_mode")]
     pub tls_mode: TlsConnectMode,
     /// The optional path to a TLS certificate file
+    #[serde(serialize_with = "serialize_redacted")]
     pub certificate: Option<PathBuf>,
+    /// The maximum number of schema-manager instances that may apply
+    /// migrations to the shard at once. `None` means migrations are not
+    /// limited.
+    #[serde(default)]
+    pub max_concurrent_migrations: Option<u32>,
+    /// The statement timeout in milliseconds of every database session, after
+    /// which a statement is cancelled. `None` leaves the timeout of the
+    /// database server in effect.
+    #[serde(default)]
+    pub statement_timeout_ms: Option<u64>,
+    /// The number of times a transaction rolled back with one of the
+    /// `retryable_sql_states` is retried before the error is returned.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// The time in milliseconds waited before the first retry of a
+    /// transaction. The wait doubles with each further retry.
+    #[serde(default = "default_base_backoff_ms")]
+    pub base_backoff_ms: u64,
+    /// The SQLSTATE codes of the database errors for which a transaction is
+    /// retried.
+    #[serde(default = "default_retryable_sql_states")]
+    pub retryable_sql_states: Vec<String>,
+    /// The duration in milliseconds beyond which a query is logged and counted
+    /// as slow. `None` means no query is reported as slow.
+    #[serde(default)]
+    pub slow_query_threshold_ms: Option<u64>,
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_base_backoff_ms() -> u64 {
+    10
+}
+
+// Serialization failures and deadlocks.
+fn default_retryable_sql_states() -> Vec<String> {
+    vec!["40001".into(), "40P01".into()]
 }
 
 impl Default for ConfigDatabase {
@@ -198,13 +588,20 @@ impl Default for ConfigDatabase {
             application_name: "buckets_mdapi".into(),
             tls_mode: TlsConnectMode::Disable,
             certificate: None,
+            max_concurrent_migrations: None,
+            statement_timeout_ms: None,
+            max_retries: default_max_retries(),
+            base_backoff_ms: default_base_backoff_ms(),
+            retryable_sql_states: default_retryable_sql_states(),
+            slow_query_threshold_ms: None,
         }
     }
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ConfigZookeeper {
     pub path: String,
+    #[serde(serialize_with = "serialize_display")]
     pub connection_string: ZkConnectString,
 }
 
@@ -217,7 +614,7 @@ impl Default for ConfigZookeeper {
     }
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ConfigCueball {
     /// The maximum number of connections in the postgres connection pool. The default value is 64 connections.
     pub max_connections: u32,
@@ -229,6 +626,15 @@ pub struct ConfigCueball {
     /// primary resolver this delay should not be very high. The default value
     /// is 20 ms.
     pub rebalancer_action_delay: Option<u64>,
+    /// The age in seconds after which a pooled connection is closed and
+    /// reestablished the next time it is claimed. `None` means connections
+    /// are kept for as long as they remain usable.
+    #[serde(default)]
+    pub max_connection_age_secs: Option<u64>,
+    /// The number of times a request retries claiming a connection after a
+    /// transient connection failure before failing. The default value is 0.
+    #[serde(default)]
+    pub handler_retries: u32,
 }
 
 impl Default for ConfigCueball {
@@ -237,11 +643,13 @@ impl Default for ConfigCueball {
             max_connections: 64,
             claim_timeout: Some(500),
             rebalancer_action_delay: Some(20),
+            max_connection_age_secs: None,
+            handler_retries: 0,
         }
     }
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct ConfigTokio {
     /// The maximum number of worker threads for the Tokio Runtime's thread
     /// pool. This must be a number between 1 and 32,768 though it is advised to
@@ -282,6 +690,259 @@ impl Default for ConfigTokio {
     }
 }
 
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigGc {
+    /// Serve `getgcbatch` requests from the `GARBAGE_BATCH` materialized view
+    /// rather than with a keyset scan of each vnode's deleted object table. The
+    /// default is `true`.
+    pub use_materialized_view: bool,
+    /// Return `getgcbatch` errors in the legacy unwrapped
+    /// `{ "name": "...", "message": "..." }` form rather than wrapped in an
+    /// `error` object like the errors of every other RPC function. This only
+    /// exists for garbage collectors that have not yet been updated to expect
+    /// the wrapped form. The default is `false`.
+    pub legacy_error_envelope: bool,
+    /// The number of seconds a deleted object record is withheld from garbage
+    /// collection after the object is deleted or overwritten. Records younger
+    /// than this are not returned by a keyset `getgcbatch` scan and are not
+    /// removed by `deletegcbatch`. The default is `0`, which disables the grace
+    /// period.
+    pub grace_period_seconds: u32,
+}
+
+impl Default for ConfigGc {
+    fn default() -> Self {
+        Self {
+            use_materialized_view: true,
+            legacy_error_envelope: false,
+            grace_period_seconds: 0,
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigQuota {
+    /// Maintain a running total of the bytes stored in each bucket and reject
+    /// object creation that would exceed the bucket's `max_total_bytes`. The
+    /// default is `false`.
+    pub enabled: bool,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigSelfTest {
+    /// Allow the `selftest` RPC to create, read, and delete a scratch object.
+    /// The default is `false`.
+    pub enabled: bool,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigAudit {
+    /// Record each object create, update, and delete in the audit table of the
+    /// object's vnode. The default is `false`.
+    pub enabled: bool,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigPlacement {
+    /// The datacenters that objects may be stored in. When set, a
+    /// `createobject` request with a shark in any other datacenter is
+    /// rejected. The default is `None`, which disables the check.
+    pub known_datacenters: Option<Vec<String>>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigListing {
+    /// Store the parent prefix of each object when it is created and list the
+    /// objects under a parent prefix using the indexed `parent_prefix` column.
+    /// This requires the 1.4 vnode migration. The default value is `false`.
+    pub parent_prefix: bool,
+    /// The time in milliseconds after which a `listobjects` request stops
+    /// fetching objects and returns those fetched so far. `None` means
+    /// listings run until the limit is reached or the objects are exhausted.
+    pub deadline_ms: Option<u64>,
+    /// The secret with which `listobjects` continuation tokens are signed.
+    /// Every instance serving a shard must share the same secret for a token
+    /// issued by one to be accepted by another. `None` disables continuation
+    /// tokens.
+    #[serde(serialize_with = "serialize_redacted")]
+    pub continuation_secret: Option<String>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigTimestamps {
+    /// Ensure the `modified` time of an object always increases when the
+    /// object is updated or replaced, even if the database clock has moved
+    /// backwards since the previous write. The default value is `false`.
+    pub monotonic_modified: bool,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigProperties {
+    /// The size in bytes of the largest JSON encoded object `properties`
+    /// stored in the object row. Larger properties are stored in a separate
+    /// table and only returned when a read requests them. This requires the
+    /// 1.6 vnode migration. The default is `None`, which stores all properties
+    /// in the object row.
+    pub max_inline_bytes: Option<usize>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigNames {
+    /// Compare object names case-insensitively by storing them in lowercase,
+    /// along with the name as given by the client. This requires the 1.7 vnode
+    /// migration. The default value is `false`.
+    pub case_insensitive_names: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigOrphanScan {
+    /// Periodically scan the objects of each vnode for objects whose bucket
+    /// has been deleted and move them to the deleted object table. The default
+    /// is `false`.
+    pub enabled: bool,
+    /// The number of seconds between scans of successive batches of objects.
+    pub interval_secs: u64,
+    /// The number of objects examined by each scan.
+    pub batch_size: i64,
+}
+
+impl Default for ConfigOrphanScan {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 60,
+            batch_size: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigAdmin {
+    /// Allow the `getconfig` RPC, which returns the effective configuration
+    /// of the server with sensitive entries redacted, and the `repairvnode`
+    /// RPC. The default is `false`.
+    pub enabled: bool,
+    /// The directory holding the `vnode` migrations applied by the
+    /// `repairvnode` RPC.
+    pub migrations_dir: String,
+}
+
+impl Default for ConfigAdmin {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            migrations_dir: "/opt/smartdc/buckets-mdapi/migrations".into(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigObjectCache {
+    /// Cache the objects returned by `getobject` and answer repeated reads of
+    /// the same object from the cache. The default is `false`.
+    pub enabled: bool,
+    /// The maximum number of objects cached.
+    pub capacity: usize,
+    /// The number of milliseconds an object is cached for.
+    pub ttl_ms: u64,
+}
+
+impl Default for ConfigObjectCache {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            capacity: 10_000,
+            ttl_ms: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigReplica {
+    /// The IP address or host name of a read replica of the database. When
+    /// set, read-only requests are served by connections to the replica. The
+    /// default is `None`, which serves every request from the primary.
+    pub host: Option<String>,
+    /// The port number of the read replica. The default is 5432.
+    pub port: u16,
+    /// The maximum number of connections to the read replica. The default
+    /// value is 64 connections.
+    pub max_connections: u32,
+    /// The number of milliseconds after a write to a bucket during which reads
+    /// of that bucket are served by the primary. The default is 1000 ms.
+    pub read_your_writes_ms: u64,
+}
+
+impl Default for ConfigReplica {
+    fn default() -> Self {
+        Self {
+            host: None,
+            port: 5432,
+            max_connections: 64,
+            read_your_writes_ms: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigHealthCheck {
+    /// Serve `ping` requests with a dedicated connection to the database
+    /// rather than the connection pool shared with other requests. The default
+    /// is `false`.
+    pub dedicated_connection: bool,
+}
+
+impl Default for ConfigHealthCheck {
+    fn default() -> Self {
+        Self {
+            dedicated_connection: false,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConfigAdmission {
+    /// Shed create requests while the replication lag or the connection claim
+    /// time exceeds its threshold. The default is `false`.
+    pub enabled: bool,
+    /// The replication lag in milliseconds of the slowest standby above which
+    /// creates are shed. `None` means the lag is not considered.
+    pub max_replication_lag_ms: Option<u64>,
+    /// The mean connection claim time in milliseconds over the last probe
+    /// interval above which creates are shed. `None` means the claim time is
+    /// not considered.
+    pub max_claim_ms: Option<u64>,
+    /// The number of milliseconds between probes of the replication lag and
+    /// the connection claim time. The default is 1000 ms.
+    pub probe_interval_ms: u64,
+}
+
+impl Default for ConfigAdmission {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_replication_lag_ms: None,
+            max_claim_ms: None,
+            probe_interval_ms: 1000,
+        }
+    }
+}
+
 pub fn read_file<F: AsRef<OsStr> + ?Sized>(f: &F) -> Config {
     let s = match fs::read(Path::new(&f)) {
         Ok(s) => s,
@@ -353,6 +1014,11 @@ pub mod tls {
     pub enum TlsError {
         NoCertificate,
         CertError(CertificateError),
+        /// The certificate file could be read neither as DER nor as PEM
+        CertFormat {
+            der: CertificateError,
+            pem: CertificateError,
+        },
         IOError(io::Error),
     }
 
@@ -375,6 +1041,11 @@ pub mod tls {
                     write!(fmt, "no TLS certificate file given")
                 }
                 TlsError::CertError(ref e) => e.fmt(fmt),
+                TlsError::CertFormat { ref der, ref pem } => write!(
+                    fmt,
+                    "TLS certificate is neither DER ({}) nor PEM ({})",
+                    der, pem
+                ),
                 TlsError::IOError(ref e) => e.fmt(fmt),
             }
         }
@@ -433,10 +1104,16 @@ pub mod tls {
         o_p.ok_or(TlsError::NoCertificate)
             .and_then(|p| File::open(p).map_err(Into::into))
             .and_then(|mut f| f.read_to_end(&mut buf).map_err(Into::into))
-            .and_then(|_| read_certificate(&buf).map_err(Into::into))
+            .and_then(|_| read_certificate(&buf))
     }
 
-    fn read_certificate(buf: &[u8]) -> Result<Certificate, CertificateError> {
-        Certificate::from_der(buf).or_else(|_| Certificate::from_pem(buf))
+    /// Interpret the bytes of a certificate file as a DER certificate, or
+    /// failing that as a PEM certificate. Either error may explain why the
+    /// file is unusable, so both are returned when neither succeeds.
+    fn read_certificate(buf: &[u8]) -> Result<Certificate, TlsError> {
+        Certificate::from_der(buf).or_else(|der| {
+            Certificate::from_pem(buf)
+                .map_err(|pem| TlsError::CertFormat { der, pem })
+        })
     }
 }
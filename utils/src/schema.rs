@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::fs;
 use std::io::{Error, ErrorKind};
 use std::path::Path;
+use std::time::Duration;
 
 use slog::{error, info, o, Logger};
 use string_template::Template;
@@ -20,6 +21,7 @@ pub mod migrations;
 const SCHEMA_TEMPLATE: &str = "schema.in";
 const ADMIN_TEMPLATE: &str = "admin.in";
 const DB_TEMPLATE: &str = "db.in";
+const MIGRATION_SLOT_RETRY_MS: u64 = 1000;
 
 // create users, role, database and schemas
 pub fn create_bucket_schemas<R>(
@@ -121,6 +123,23 @@ where
             result.and_then(|_| Ok(conn))
         })
         .and_then(|mut conn| {
+            // Wait for a migration slot so that the number of instances
+            // migrating the shard at once is bounded
+            let slot = match database_config.max_concurrent_migrations {
+                Some(max_concurrent) => {
+                    info!(log, "Acquiring a migration slot";
+                        "max_concurrent_migrations" => max_concurrent);
+                    Some(migrations::acquire_migration_slot(
+                        &mut conn,
+                        max_concurrent,
+                        Duration::from_millis(MIGRATION_SLOT_RETRY_MS),
+                    )?)
+                }
+                None => None,
+            };
+            Ok((conn, slot))
+        })
+        .and_then(|(mut conn, slot)| {
             // Run the public schema migrations
             info!(log, "Running public schema migrations");
             let public_migrations_dir = migrations_dir.join("public");
@@ -129,16 +148,20 @@ where
                 &mut conn,
             )
             .unwrap();
-            Ok(conn)
+            Ok((conn, slot))
         })
-        .and_then(|mut conn| {
+        .and_then(|(mut conn, slot)| {
             info!(log, "Running vnode schema migrations");
             let vnode_migrations_dir = migrations_dir.join("vnode");
-            migrations::run_vnode_schema_migrations(
+            let result = migrations::run_vnode_schema_migrations(
                 vnodes,
                 &vnode_migrations_dir,
                 &mut conn,
-            )
+            );
+            if let Some(slot) = slot {
+                migrations::release_migration_slot(&mut conn, slot)?;
+            }
+            result
         })
         .or_else(|e| {
             error!(log, "{}", e);
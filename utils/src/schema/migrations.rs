@@ -3,12 +3,90 @@ use std::fs;
 use std::io::{Error, ErrorKind};
 use std::path::Path;
 use std::result::Result;
+use std::thread;
+use std::time::Duration;
 
 use itertools::Itertools;
 use string_template::Template;
 
 use cueball_postgres_connection::PostgresConnection;
 
+// The first key of the advisory locks that serve as migration slots. The second
+// key of each lock is the slot number.
+const MIGRATION_SLOT_LOCK_CLASS: i32 = 0x6d69_6772;
+
+// The first key of the advisory locks that serialize the repair of a vnode
+// schema. The second key of each lock is the vnode.
+const VNODE_REPAIR_LOCK_CLASS: i32 = 0x7265_7061;
+
+/// One of a fixed number of slots that bound how many schema-manager instances
+/// may apply migrations to a shard at once.
+///
+/// Each slot is a session-level postgres advisory lock on the shard's primary,
+/// keyed by `(MIGRATION_SLOT_LOCK_CLASS, slot)` for `slot` in `0..K`. A runner
+/// takes a slot by trying each lock in turn with `pg_try_advisory_lock` and
+/// waits while every one of the `K` locks is held by another session, so no
+/// more than `K` runners migrate at once however many are started. A slot is
+/// released when the runner is done with it, or by postgres when the holding
+/// connection closes, so a runner that fails never leaves its slot taken.
+#[derive(Debug, PartialEq)]
+pub struct MigrationSlot(i32);
+
+/// Try to take one of `max_concurrent` migration slots on the connection.
+/// Returns `None` when every slot is held by another session.
+pub fn try_acquire_migration_slot(
+    conn: &mut PostgresConnection,
+    max_concurrent: u32,
+) -> Result<Option<MigrationSlot>, Error> {
+    for slot in 0..max_concurrent as i32 {
+        let acquired: bool = conn
+            .query(
+                "SELECT pg_try_advisory_lock($1, $2)",
+                &[&MIGRATION_SLOT_LOCK_CLASS, &slot],
+            )
+            .map_err(|e| {
+                let err_str = format!("error acquiring migration slot: {}", e);
+                Error::new(ErrorKind::Other, err_str)
+            })?[0]
+            .get(0);
+        if acquired {
+            return Ok(Some(MigrationSlot(slot)));
+        }
+    }
+    Ok(None)
+}
+
+/// Take one of `max_concurrent` migration slots on the connection, checking
+/// again every `retry_interval` while every slot is held.
+pub fn acquire_migration_slot(
+    conn: &mut PostgresConnection,
+    max_concurrent: u32,
+    retry_interval: Duration,
+) -> Result<MigrationSlot, Error> {
+    loop {
+        if let Some(slot) = try_acquire_migration_slot(conn, max_concurrent)? {
+            return Ok(slot);
+        }
+        thread::sleep(retry_interval);
+    }
+}
+
+/// Release a migration slot taken on the connection.
+pub fn release_migration_slot(
+    conn: &mut PostgresConnection,
+    slot: MigrationSlot,
+) -> Result<(), Error> {
+    conn.query(
+        "SELECT pg_advisory_unlock($1, $2)",
+        &[&MIGRATION_SLOT_LOCK_CLASS, &slot.0],
+    )
+    .map(|_| ())
+    .map_err(|e| {
+        let err_str = format!("error releasing migration slot: {}", e);
+        Error::new(ErrorKind::Other, err_str)
+    })
+}
+
 pub(crate) fn run_public_schema_migrations(
     migration_path: &Path,
     conn: &mut PostgresConnection,
@@ -83,3 +161,130 @@ pub(crate) fn run_vnode_schema_migrations(
             }
         })
 }
+
+// The `(major, minor)` version of a migration, parsed from the leading
+// `MMMM-NNNN` of its file name.
+fn migration_version(migration: &Path) -> Option<(i32, i32)> {
+    let name = migration.file_name()?.to_str()?;
+    let mut parts = name.splitn(3, '-');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Bring the schema of a single vnode to the version of the migrations in
+/// `migration_path`, applying each migration the vnode is missing in order.
+/// Returns the file stems of the migrations applied, which is empty if the
+/// vnode was already up to date.
+///
+/// The repair holds a session-level advisory lock on the vnode, keyed by
+/// `(VNODE_REPAIR_LOCK_CLASS, vnode)`, so that concurrent repairs of the same
+/// vnode apply each migration once. The migrations are themselves idempotent,
+/// so a repair racing with a schema-manager run is harmless.
+pub fn repair_vnode_schema(
+    vnode: u64,
+    migration_path: &Path,
+    conn: &mut PostgresConnection,
+) -> Result<Vec<String>, Error> {
+    let repair_error = |e: &dyn std::fmt::Display| {
+        let err_str = format!("error on vnode schema repair: {}", e);
+        Error::new(ErrorKind::Other, err_str)
+    };
+
+    let lock_key = vnode as i32;
+    conn.query(
+        "SELECT pg_advisory_lock($1, $2)",
+        &[&VNODE_REPAIR_LOCK_CLASS, &lock_key],
+    )
+    .map_err(|e| repair_error(&e))?;
+
+    let result = apply_missing_vnode_migrations(vnode, migration_path, conn);
+
+    conn.query(
+        "SELECT pg_advisory_unlock($1, $2)",
+        &[&VNODE_REPAIR_LOCK_CLASS, &lock_key],
+    )
+    .map_err(|e| repair_error(&e))?;
+
+    result
+}
+
+fn apply_missing_vnode_migrations(
+    vnode: u64,
+    migration_path: &Path,
+    conn: &mut PostgresConnection,
+) -> Result<Vec<String>, Error> {
+    let schema = format!("manta_bucket_{}", vnode);
+    let vnode_str = vnode.to_string();
+    let repair_error = |e: &dyn std::fmt::Display| {
+        let err_str =
+            format!("error on vnode schema repair: {}, vnode: {}", e, vnode);
+        Error::new(ErrorKind::Other, err_str)
+    };
+
+    let schema_exists: bool = conn
+        .query(
+            "SELECT exists(SELECT 1 FROM information_schema.schemata \
+             WHERE schema_name = $1)",
+            &[&schema],
+        )
+        .map_err(|e| repair_error(&e))?[0]
+        .get(0);
+    if !schema_exists {
+        return Err(repair_error(&"the vnode schema does not exist"));
+    }
+
+    // The setup migration creates the table recording the other migrations and
+    // is applied when that table is missing
+    let migrations_table = format!("{}.migrations", schema);
+    let has_migrations_table: bool = conn
+        .query("SELECT to_regclass($1) IS NOT NULL", &[&migrations_table])
+        .map_err(|e| repair_error(&e))?[0]
+        .get(0);
+    let mut applied_versions: Vec<(i32, i32)> = if has_migrations_table {
+        conn.query(
+            format!("SELECT major, minor FROM {}", migrations_table).as_str(),
+            &[],
+        )
+        .map_err(|e| repair_error(&e))?
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect()
+    } else {
+        vec![]
+    };
+    if has_migrations_table {
+        applied_versions.push((0, 0));
+    }
+
+    let mut applied = Vec::new();
+    let migrations = fs::read_dir(migration_path)?
+        .filter(Result::is_ok)
+        .map(|dir_entry| dir_entry.unwrap().path())
+        .filter(|path_buf| !path_buf.as_path().is_dir())
+        .sorted();
+    for migration in migrations {
+        let version = migration_version(&migration).ok_or_else(|| {
+            repair_error(&format!(
+                "invalid migration name {}",
+                migration.display()
+            ))
+        })?;
+        if applied_versions.contains(&version) {
+            continue;
+        }
+
+        let migration_template = fs::read_to_string(&migration)?;
+        let template = Template::new(&migration_template);
+        let mut args = HashMap::new();
+        args.insert("vnode", vnode_str.as_str());
+        conn.simple_query(&template.render(&args))
+            .map_err(|e| repair_error(&e))?;
+
+        if let Some(stem) = migration.file_stem().and_then(|s| s.to_str()) {
+            applied.push(stem.to_string());
+        }
+    }
+
+    Ok(applied)
+}
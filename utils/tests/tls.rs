@@ -0,0 +1,31 @@
+// Copyright 2023 MNX Cloud, Inc.
+
+use std::path::PathBuf;
+
+use cueball_postgres_connection::TlsConnectMode;
+
+use utils::config::tls::{tls_config, TlsError};
+
+fn data_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("data")
+        .join(name)
+}
+
+#[test]
+fn certificate_read_as_der_or_pem() {
+    // The same certificate in each encoding
+    for name in &["cert.der", "cert.pem"] {
+        let config =
+            tls_config(TlsConnectMode::VerifyCa, Some(data_path(name)));
+        assert!(config.is_ok(), "failed to read {}", name);
+    }
+
+    // A file that is neither reports the error of each encoding
+    match tls_config(TlsConnectMode::VerifyCa, Some(data_path("README.md"))) {
+        Err(TlsError::CertFormat { .. }) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("read a certificate from README.md"),
+    }
+}